@@ -0,0 +1,53 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    grpc::compile();
+}
+
+/// The admin service is generated from the messages in `src/grpc.rs`, which keeps protoc out of
+/// the build
+#[cfg(feature = "grpc")]
+mod grpc {
+    use tonic_build::manual::{Builder, Method, Service};
+
+    fn method(name: &str, route_name: &str, input: &str, output: &str) -> Method {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("crate::grpc::{input}"))
+            .output_type(format!("crate::grpc::{output}"))
+            .codec_path("tonic::codec::ProstCodec")
+            .build()
+    }
+
+    pub fn compile() {
+        let service = Service::builder()
+            .name("Admin")
+            .package("course_bot.admin")
+            .method(method(
+                "list_users",
+                "ListUsers",
+                "ListUsersRequest",
+                "ListUsersResponse",
+            ))
+            .method(method(
+                "get_watches",
+                "GetWatches",
+                "GetWatchesRequest",
+                "GetWatchesResponse",
+            ))
+            .method(method(
+                "trigger_cycle",
+                "TriggerCycle",
+                "TriggerCycleRequest",
+                "TriggerCycleResponse",
+            ))
+            .method(method(
+                "dump_metrics",
+                "DumpMetrics",
+                "DumpMetricsRequest",
+                "DumpMetricsResponse",
+            ))
+            .build();
+        Builder::new().build_client(false).compile(&[service]);
+    }
+}
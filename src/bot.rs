@@ -1,25 +1,91 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{atomic::Ordering, Arc, PoisonError},
+    time::Duration,
+};
 
 use anyhow::Result;
-use kv::{Msgpack, Store};
-use log::{debug, error, info, trace};
-use serenity::{all::GatewayIntents, Client};
+use poise::CreateReply;
+use serenity::{
+    all::{
+        Attachment, ButtonStyle, ComponentInteraction, ComponentInteractionCollector,
+        ComponentInteractionDataKind, ConnectionStage, CreateActionRow, CreateAllowedMentions,
+        CreateAttachment, CreateButton, CreateInteractionResponse,
+        CreateInteractionResponseFollowup, CreateInteractionResponseMessage, CreateMessage,
+        CreateSelectMenu, CreateSelectMenuKind, CreateSelectMenuOption, FullEvent, GatewayIntents,
+        GuildChannel, GuildId, Interaction, Role, User,
+    },
+    Client,
+};
+use tokio::{
+    sync::watch,
+    time::{timeout_at, Instant},
+};
+use tracing::{debug, error, info, trace, warn};
 
-use crate::config::Config;
+use crate::{
+    crawler::{
+        normalize_course_code, normalize_dept_code, validate_serial_no, CourseInfo, CourseQuery,
+        NtnuCrawlerManager,
+    },
+    db::{
+        now, openings, AddOutcome, BlockEntry, DepartmentOutcome, DepartmentWatch, GuildSettings,
+        GuildWatchlist, QuietHours, Repository, SeatSnapshot, UserSettings, WatchCounts,
+        WatchEntry, WatchMode,
+    },
+    export::{WatchlistExport, EXPORT_VERSION},
+    i18n::{course_label, search_label, Lang, Msg},
+    metrics::{LATENCIES, METRICS, QUANTILES},
+    notify::{DigestMode, NotifyTarget},
+    open_course::OpenCourseCrawler,
+    paginate::{pages, paginate, Group, PAGE_LEN},
+    permission::Tier,
+    progress::CheckerState,
+    ratelimit::CommandCooldown,
+    reload::SharedConfig,
+    source::{split_course_id, Route, Sources},
+};
 
 pub struct BotContext {
-    db: Arc<tokio::sync::RwLock<Store>>,
+    db: Arc<dyn Repository>,
     sender: tokio::sync::mpsc::Sender<()>,
+    /// Checks the periodic checker starts and finishes
+    progress: watch::Receiver<CheckerState>,
+    force_updates: std::sync::Mutex<CommandCooldown>,
+    course_checks: std::sync::Mutex<CommandCooldown>,
+    config: Arc<SharedConfig>,
+    /// Shared with the periodic checker so both reuse one login session
+    crawler: Arc<tokio::sync::Mutex<NtnuCrawlerManager>>,
+    /// Every school courses can be watched in, NTNU's being `crawler`
+    sources: Arc<Sources>,
+    /// Answers course lookups that need no seat counts
+    open_courses: Arc<OpenCourseCrawler>,
 }
 
-type Error = Box<dyn std::error::Error + Send + Sync>;
-type Context<'a> = poise::Context<'a, BotContext, Error>;
+pub(crate) type Error = Box<dyn std::error::Error + Send + Sync>;
+pub(crate) type Context<'a> = poise::Context<'a, BotContext, Error>;
 
 async fn on_error(error: poise::FrameworkError<'_, BotContext, Error>) {
     match error {
         poise::FrameworkError::Setup { error, .. } => panic!("Failed to start bot: {:?}", error),
         poise::FrameworkError::Command { error, ctx, .. } => {
-            error!("Error in command `{}`: {:?}", ctx.command().name, error,);
+            error!(
+                command = ctx.command().name,
+                user_id = ctx.author().id.get(),
+                invocation = ctx.invocation_string(),
+                "Error in command `{}`: {:?}",
+                ctx.command().name,
+                error,
+            );
+        }
+        // refused by `command_check` or `is_admin`, which already told the user
+        poise::FrameworkError::CommandCheckFailed {
+            error: None, ctx, ..
+        } => {
+            debug!(
+                "Refused command `{}` from user {}",
+                ctx.command().name,
+                ctx.author().id
+            );
         }
         error => {
             if let Err(e) = poise::builtins::on_error(error).await {
@@ -29,14 +95,44 @@ async fn on_error(error: poise::FrameworkError<'_, BotContext, Error>) {
     }
 }
 
+/// Every command by category, paged as the list outgrows a message
+async fn command_list(ctx: Context<'_>) -> Result<(), Error> {
+    let mut groups: Vec<Group> = Vec::new();
+    let commands = ctx.framework().options().commands.iter();
+    // context menu commands are found by right-clicking, not typed
+    let commands = commands.filter(|command| {
+        !command.hide_in_help && (command.slash_action.is_some() || command.prefix_action.is_some())
+    });
+    for command in commands {
+        let heading = format!("**{}**", command.category.as_deref().unwrap_or("Commands"));
+        let line = format!(
+            "`/{}` {}",
+            command.name,
+            command.description.as_deref().unwrap_or_default()
+        );
+        match groups.iter_mut().find(|group| group.heading == heading) {
+            Some(group) => group.lines.push(line),
+            None => groups.push(Group {
+                heading,
+                lines: vec![line],
+            }),
+        }
+    }
+    let ephemeral = reply_style(ctx).await?.ephemeral;
+    paginate(ctx, &pages(&groups, PAGE_LEN), ephemeral).await
+}
+
 /// Show this help menu
-#[poise::command(prefix_command, track_edits, slash_command)]
+#[poise::command(prefix_command, track_edits, slash_command, category = "General")]
 pub async fn help(
     ctx: Context<'_>,
     #[description = "Specific command to show help about"]
     #[autocomplete = "poise::builtins::autocomplete_command"]
     command: Option<String>,
 ) -> Result<(), Error> {
+    if command.is_none() {
+        return command_list(ctx).await;
+    }
     poise::builtins::help(
         ctx,
         command.as_deref(),
@@ -48,100 +144,2029 @@ pub async fn help(
     Ok(())
 }
 
+/// The tier of the invoking user, following reloads of the ID lists
+async fn tier(ctx: Context<'_>) -> Tier {
+    let user_id = ctx.author().id;
+    if ctx.framework().options().owners.contains(&user_id) {
+        return Tier::Owner;
+    }
+    let config = ctx.data().config.get();
+    let tier = Tier::of(&config, user_id.get(), &[]);
+    // only look the member up when their roles can make a difference
+    if tier > Tier::User || config.admin_role_ids.0.is_empty() {
+        return tier;
+    }
+    let roles = match ctx.author_member().await {
+        Some(member) => member.roles.iter().map(|role| role.get()).collect(),
+        None => Vec::new(),
+    };
+    Tier::of(&config, user_id.get(), &roles)
+}
+
+/// Let only users of `tier` or above run a command, telling the others why not
+async fn require_tier(ctx: Context<'_>, required: Tier) -> Result<bool, Error> {
+    if tier(ctx).await >= required {
+        return Ok(true);
+    }
+    let content = match required {
+        Tier::Owner => "Only bot owners can use this command.",
+        Tier::Admin | Tier::User => "Only bot admins can use this command.",
+    };
+    ctx.send(CreateReply::default().content(content).ephemeral(true))
+        .await?;
+    Ok(false)
+}
+
+/// Let only admins run a command
+async fn is_admin(ctx: Context<'_>) -> Result<bool, Error> {
+    require_tier(ctx, Tier::Admin).await
+}
+
+/// Let only owners run a command
+async fn is_owner(ctx: Context<'_>) -> Result<bool, Error> {
+    require_tier(ctx, Tier::Owner).await
+}
+
+/// Refuse every command from blocked users, admins can never lock themselves out
+///
+/// Anyone running a command is still around, so users disabled as unreachable are re-enabled.
+async fn command_check(ctx: Context<'_>) -> Result<bool, Error> {
+    let user_id = ctx.author().id;
+    if tier(ctx).await < Tier::Admin && ctx.data().db.is_blocked(user_id.get()).await? {
+        reply(ctx, Msg::Blocked).await?;
+        return Ok(false);
+    }
+    if ctx.data().db.mark_reachable(user_id.get()).await? {
+        info!("Re-enabled unreachable user {user_id}");
+    }
+    Ok(true)
+}
+
+/// Tell a user whose alerts bounced off their closed DMs to open them, once per bounce
+async fn remind_dm_closed(ctx: Context<'_>) -> Result<(), Error> {
+    let user_id = ctx.author().id.get();
+    if !ctx.data().db.settings(user_id).await?.dm_closed {
+        return Ok(());
+    }
+    ctx.data()
+        .db
+        .update_settings(user_id, &|s| s.dm_closed = false)
+        .await?;
+    reply(ctx, Msg::DmsClosed).await
+}
+
+/// How replies to the invoking user should be rendered
+struct ReplyStyle {
+    lang: Lang,
+    ephemeral: bool,
+}
+
+async fn reply_style(ctx: Context<'_>) -> Result<ReplyStyle, Error> {
+    let settings = ctx.data().db.settings(ctx.author().id.get()).await?;
+    // fall back to the client locale until the user picks a language
+    let lang = settings
+        .language
+        .or_else(|| ctx.locale().and_then(Lang::from_locale))
+        .unwrap_or_default();
+    Ok(ReplyStyle {
+        lang,
+        ephemeral: !settings.public_replies,
+    })
+}
+
+/// Acknowledge a command that has to wait for NTNU, the enrollment system may need to log in again
+async fn defer(ctx: Context<'_>, style: &ReplyStyle) -> Result<(), Error> {
+    if style.ephemeral {
+        ctx.defer_ephemeral().await?;
+    } else {
+        ctx.defer().await?;
+    }
+    Ok(())
+}
+
+/// Reply in the user's language, ephemerally unless they opted into public replies
+async fn reply(ctx: Context<'_>, msg: Msg<'_>) -> Result<(), Error> {
+    let style = reply_style(ctx).await?;
+    ctx.send(
+        CreateReply::default()
+            .content(msg.render(style.lang))
+            .ephemeral(style.ephemeral),
+    )
+    .await?;
+    Ok(())
+}
+
+impl AddOutcome {
+    fn msg(self, course_id: &str) -> Msg<'_> {
+        match self {
+            AddOutcome::Duplicate(entry) => Msg::AlreadyWatching {
+                course_id,
+                added_at: entry.added_at,
+            },
+            AddOutcome::LimitReached(limit) => Msg::LimitReached { course_id, limit },
+            AddOutcome::Updated(entry) => Msg::WatchUpdated {
+                course_id,
+                min_seats: entry.min_seats,
+                mode: entry.mode,
+            },
+            AddOutcome::Added => Msg::CourseAdded { course_id },
+        }
+    }
+}
+
 /// Add course for user
-#[poise::command(prefix_command, slash_command)]
+#[poise::command(prefix_command, slash_command, category = "Courses")]
 pub async fn add_course(
     ctx: Context<'_>,
-    #[description = "Course ID"] course_id: String,
-) -> Result<(), Error> {
-    if !course_id.chars().all(|x| x.is_digit(10)) {
-        let response =
-            format!("Course ID consists only by decimal digits! `{course_id}` is not a valid one");
-        ctx.say(response).await?;
-        return Ok(());
-    }
-    {
-        let db = ctx.data().db.write().await;
-        let bucket = db.bucket::<String, Msgpack<Vec<String>>>(Some("user_courses"))?;
-        let user_id = ctx.author().id;
-        let mut current = bucket
-            .get(&user_id.to_string())
-            .unwrap()
-            .map(|v| v.0)
-            .unwrap_or(Vec::new());
-        current.push(course_id.clone());
-        current.sort();
-        current.dedup();
-        bucket.set(&user_id.to_string(), &Msgpack(current))?;
-    }
-    let response = format!("Course added for {course_id}.");
-    ctx.say(response).await?;
+    #[description = "Course ID (serial number), school:ID for another school, ID@114-1 for another semester"]
+    course_id: Option<String>,
+    #[description = "Course code such as CSU0001, watches every section this semester"]
+    code: Option<String>,
+    #[description = "Only alert when at least this many seats are free (default 1)"]
+    #[min = 1]
+    min_seats: Option<u32>,
+    #[description = "Alert on free seats (default) or on any seat change"] mode: Option<WatchMode>,
+) -> Result<(), Error> {
+    let Some(course_id) = course_id else {
+        return match code {
+            Some(code) => add_course_code(ctx, &code, min_seats, mode).await,
+            None => reply(ctx, Msg::CourseIdOrCodeRequired).await,
+        };
+    };
+    let Some((source_name, source, id, term)) = route_course(ctx, &course_id).await? else {
+        return Ok(());
+    };
+    let data = ctx.data();
+    let course_id = data
+        .sources
+        .canonical(&course_id, data.config.get().term(now()));
+    defer(ctx, &reply_style(ctx).await?).await?;
+    let mut restrictions = Vec::new();
+    match lookup_course(data, (source_name, source, id, term), &course_id).await {
+        Ok(Some(course)) => {
+            restrictions.clone_from(&course.restrictions);
+            data.db.cache_course_meta(&[course], now()).await?
+        }
+        Ok(None) => {
+            reply(
+                ctx,
+                Msg::UnknownCourse {
+                    course_id: &course_id,
+                },
+            )
+            .await?;
+            return Ok(());
+        }
+        // the checker finds out about a bad serial number anyway
+        Err(e) => warn!("fail to look up course {course_id}: {e:#}"),
+    }
+    let outcome = data
+        .db
+        .add_watch(
+            ctx.author().id.get(),
+            &course_id,
+            min_seats,
+            mode,
+            data.config.get().max_courses_per_user,
+        )
+        .await?;
+    let added = matches!(outcome, AddOutcome::Added);
+    reply(ctx, outcome.msg(&course_id)).await?;
+    // a seat opening up is of no use when the user may not take it
+    if added && !restrictions.is_empty() {
+        let msg = Msg::CourseRestricted {
+            course_id: &course_id,
+            restrictions: &restrictions,
+        };
+        reply(ctx, msg).await?;
+    }
     Ok(())
 }
 
+/// The source of `course_id`, the ID within it and its semester, replying why when it is not one
+async fn route_course<'a>(
+    ctx: Context<'a>,
+    course_id: &'a str,
+) -> Result<Option<Route<'a, 'a>>, Error> {
+    let data = ctx.data();
+    let Some((source_name, source, id, term)) = data.sources.route(course_id) else {
+        let source = split_course_id(course_id).0.unwrap_or_default();
+        reply(ctx, Msg::UnknownSource { source }).await?;
+        return Ok(None);
+    };
+    if source_name == "ntnu" {
+        let range = data.config.get().serial_no_range();
+        if let Err(error) = validate_serial_no(id, &range) {
+            let msg = Msg::InvalidCourseId {
+                course_id,
+                error: &error,
+            };
+            reply(ctx, msg).await?;
+            return Ok(None);
+        }
+    }
+    Ok(Some((source_name, source, id, term)))
+}
+
+/// A course as its school lists it, under `course_id` as stored
+async fn lookup_course(
+    data: &BotContext,
+    (source_name, source, id, term): Route<'_, '_>,
+    course_id: &str,
+) -> Result<Option<CourseInfo>> {
+    // NTNU's public site answers without spending a login
+    let course = if source_name == "ntnu" {
+        data.open_courses.course(id, term).await?
+    } else {
+        source.lock().await.fetch_info(id, term).await?
+    };
+    Ok(course.map(|course| CourseInfo {
+        serial_no: course_id.to_owned(),
+        ..course
+    }))
+}
+
+/// Resolve a course code to this semester's serial numbers and watch all of them
+async fn add_course_code(
+    ctx: Context<'_>,
+    code: &str,
+    min_seats: Option<u32>,
+    mode: Option<WatchMode>,
+) -> Result<(), Error> {
+    let Some(code) = normalize_course_code(code) else {
+        reply(ctx, Msg::InvalidCourseCode { code }).await?;
+        return Ok(());
+    };
+    let style = reply_style(ctx).await?;
+    defer(ctx, &style).await?;
+    let course_ids = ctx.data().open_courses.resolve_course_code(&code).await?;
+    if course_ids.is_empty() {
+        reply(ctx, Msg::UnknownCourseCode { code: &code }).await?;
+        return Ok(());
+    }
+    let mut lines = vec![Msg::CourseCodeResolved {
+        code: &code,
+        course_ids: &course_ids,
+    }
+    .render(style.lang)];
+    for course_id in &course_ids {
+        let data = ctx.data();
+        let outcome = data
+            .db
+            .add_watch(
+                ctx.author().id.get(),
+                course_id,
+                min_seats,
+                mode,
+                data.config.get().max_courses_per_user,
+            )
+            .await?;
+        lines.push(outcome.msg(course_id).render(style.lang));
+    }
+    ctx.send(
+        CreateReply::default()
+            .content(lines.join("\n"))
+            .ephemeral(style.ephemeral),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Load the invoking user's watchlist
+async fn watchlist(ctx: Context<'_>) -> Result<Vec<WatchEntry>, Error> {
+    Ok(ctx.data().db.watchlist(ctx.author().id.get()).await?)
+}
+
 /// List course for user
-#[poise::command(prefix_command, slash_command)]
+#[poise::command(prefix_command, slash_command, category = "Courses")]
 pub async fn list_course(ctx: Context<'_>) -> Result<(), Error> {
-    let list = {
-        let db = ctx.data().db.read().await;
-        let bucket = db.bucket::<String, Msgpack<Vec<String>>>(Some("user_courses"))?;
-        let user_id = ctx.author().id;
-        bucket
-            .get(&user_id.to_string())
-            .unwrap()
-            .map(|v| v.0)
-            .unwrap_or(Vec::new())
-    };
-    let response = if list.len() > 0 {
-        format!("Current registered courses:\n{}", list.join("\n"))
+    let list = watchlist(ctx).await?;
+    let departments = department_watches(ctx).await?;
+    if list.is_empty() && departments.is_empty() {
+        reply(ctx, Msg::NoCourse).await?;
+        return Ok(());
+    }
+    let style = reply_style(ctx).await?;
+    let mut snapshots = Vec::with_capacity(list.len());
+    let mut metas = Vec::with_capacity(list.len());
+    for entry in &list {
+        snapshots.push(ctx.data().db.seat_snapshot(&entry.course_id).await?);
+        metas.push(ctx.data().db.course_meta(&entry.course_id).await?);
+    }
+    let lines = list
+        .iter()
+        .zip(snapshots.iter().zip(&metas))
+        .map(|(entry, (snapshot, meta))| {
+            Msg::CourseListEntry {
+                course_id: &entry.course_id,
+                added_at: entry.added_at,
+                min_seats: entry.min_seats,
+                mode: entry.mode,
+                snapshot: snapshot.as_ref(),
+                meta: meta.as_ref(),
+            }
+            .render(style.lang)
+        })
+        .collect::<Vec<_>>();
+    let departments = departments
+        .iter()
+        .map(|watch| {
+            Msg::DepartmentListEntry {
+                dept_code: &watch.dept_code,
+                elective_only: watch.elective_only,
+                open: watch.open.len(),
+            }
+            .render(style.lang)
+        })
+        .collect::<Vec<_>>();
+    let groups = [
+        Group {
+            heading: Msg::CourseListHeader.render(style.lang),
+            lines,
+        },
+        Group {
+            heading: Msg::DepartmentListHeader.render(style.lang),
+            lines: departments,
+        },
+    ];
+    paginate(ctx, &pages(&groups, PAGE_LEN), style.ephemeral).await
+}
+
+/// Load the invoking user's department watches
+async fn department_watches(ctx: Context<'_>) -> Result<Vec<DepartmentWatch>, Error> {
+    Ok(ctx
+        .data()
+        .db
+        .department_watches(ctx.author().id.get())
+        .await?)
+}
+
+/// Department watches allowed per user, each one costs a grid query every check
+const MAX_DEPARTMENT_WATCHES: usize = 5;
+
+/// Watch every course of a department for free seats
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn watch_department(
+    ctx: Context<'_>,
+    #[description = "Department code as shown in the course system, e.g. CSU"] dept_code: String,
+    #[description = "Only watch electives (default false)"] elective_only: Option<bool>,
+) -> Result<(), Error> {
+    let Some(code) = normalize_dept_code(&dept_code) else {
+        reply(
+            ctx,
+            Msg::InvalidDeptCode {
+                dept_code: &dept_code,
+            },
+        )
+        .await?;
+        return Ok(());
+    };
+    let elective_only = elective_only.unwrap_or(false);
+    let outcome = ctx
+        .data()
+        .db
+        .watch_department(
+            ctx.author().id.get(),
+            &code,
+            elective_only,
+            MAX_DEPARTMENT_WATCHES,
+        )
+        .await?;
+    let msg = match outcome {
+        DepartmentOutcome::Duplicate => Msg::AlreadyWatchingDepartment { dept_code: &code },
+        DepartmentOutcome::LimitReached(limit) => Msg::DepartmentLimitReached { limit },
+        DepartmentOutcome::Added | DepartmentOutcome::Updated => Msg::DepartmentWatched {
+            dept_code: &code,
+            elective_only,
+        },
+    };
+    reply(ctx, msg).await?;
+    Ok(())
+}
+
+/// Suggest department codes from the invoking user's department watches
+async fn autocomplete_watched_department(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let list = match department_watches(ctx).await {
+        Ok(list) => list,
+        Err(e) => {
+            warn!("fail to load department watches for autocomplete: {e:?}");
+            return Vec::new();
+        }
+    };
+    let partial = partial.to_ascii_uppercase();
+    list.into_iter()
+        .map(|watch| watch.dept_code)
+        .filter(|code| code.starts_with(&partial))
+        .collect()
+}
+
+/// Stop watching a department
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn unwatch_department(
+    ctx: Context<'_>,
+    #[description = "Department code"]
+    #[autocomplete = "autocomplete_watched_department"]
+    dept_code: String,
+) -> Result<(), Error> {
+    let code = normalize_dept_code(&dept_code).unwrap_or(dept_code);
+    let removed = ctx
+        .data()
+        .db
+        .remove_department_watch(ctx.author().id.get(), &code)
+        .await?;
+    let msg = if removed {
+        Msg::DepartmentRemoved { dept_code: &code }
     } else {
-        "No course registered!".to_owned()
+        Msg::NotWatchingDepartment { dept_code: &code }
     };
-    ctx.say(response).await?;
+    reply(ctx, msg).await?;
+    Ok(())
+}
+
+/// Send the invoking user their watchlist as a JSON file
+#[poise::command(prefix_command, slash_command, category = "Settings")]
+pub async fn export(ctx: Context<'_>) -> Result<(), Error> {
+    let list = watchlist(ctx).await?;
+    let departments = department_watches(ctx).await?;
+    if list.is_empty() && departments.is_empty() {
+        reply(ctx, Msg::NoCourse).await?;
+        return Ok(());
+    }
+    let style = reply_style(ctx).await?;
+    let file = serde_json::to_vec_pretty(&WatchlistExport::new(&list, &departments))?;
+    let msg = Msg::ExportReady {
+        courses: list.len(),
+        departments: departments.len(),
+    };
+    ctx.send(
+        CreateReply::default()
+            .content(msg.render(style.lang))
+            .attachment(CreateAttachment::bytes(file, "watchlist.json"))
+            .ephemeral(style.ephemeral),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Delete everything the bot stores about the invoking user, after confirmation
+#[poise::command(prefix_command, slash_command, category = "Settings")]
+pub async fn forget_me(ctx: Context<'_>) -> Result<(), Error> {
+    let style = reply_style(ctx).await?;
+    let confirm_id = format!("{}forget_me", ctx.id());
+    let cancel_id = format!("{}cancel", ctx.id());
+    let reply = CreateReply::default()
+        .content(Msg::ForgetMeConfirm.render(style.lang))
+        .components(vec![CreateActionRow::Buttons(vec![
+            CreateButton::new(&confirm_id)
+                .style(ButtonStyle::Danger)
+                .label(Msg::ForgetMeButton.render(style.lang)),
+            CreateButton::new(&cancel_id)
+                .style(ButtonStyle::Secondary)
+                .label(Msg::CancelButton.render(style.lang)),
+        ])]);
+    let handle = ctx.send(reply.ephemeral(style.ephemeral)).await?;
+
+    let ids = [confirm_id.clone(), cancel_id];
+    let interaction = ComponentInteractionCollector::new(ctx)
+        .author_id(ctx.author().id)
+        .channel_id(ctx.channel_id())
+        .timeout(Duration::from_secs(120))
+        .filter(move |mci| ids.contains(&mci.data.custom_id))
+        .await;
+    let Some(interaction) = interaction else {
+        handle
+            .edit(
+                ctx,
+                CreateReply::default()
+                    .content(Msg::ForgetMeCancelled.render(style.lang))
+                    .components(vec![]),
+            )
+            .await?;
+        return Ok(());
+    };
+    let msg = if interaction.data.custom_id == confirm_id {
+        ctx.data().db.forget_user(ctx.author().id.get()).await?;
+        Msg::ForgetMeDone
+    } else {
+        Msg::ForgetMeCancelled
+    };
+    interaction
+        .create_response(
+            ctx,
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content(msg.render(style.lang))
+                    .components(vec![]),
+            ),
+        )
+        .await?;
     Ok(())
 }
 
+/// Custom ID prefix of the course menus `/share` sends, followed by the sharing user's ID
+pub const SHARE_MENU_PREFIX: &str = "share:";
+
+/// Send a friend a menu of your watched courses to import into their watchlist
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn share(
+    ctx: Context<'_>,
+    #[description = "User to share your watchlist with"] friend: User,
+) -> Result<(), Error> {
+    if friend.bot || friend.id == ctx.author().id {
+        reply(ctx, Msg::ShareInvalidFriend).await?;
+        return Ok(());
+    }
+    let list = watchlist(ctx).await?;
+    if list.is_empty() {
+        reply(ctx, Msg::NoCourse).await?;
+        return Ok(());
+    }
+    let db = &ctx.data().db;
+    // the menu is read by the friend, in their language
+    let lang = db.settings(friend.id.get()).await?.lang();
+    // discord caps select menus at 25 options
+    let mut options = Vec::with_capacity(list.len().min(25));
+    for entry in list.iter().take(25) {
+        let meta = db.course_meta(&entry.course_id).await?;
+        let label = course_label(&entry.course_id, meta.as_ref());
+        // option labels are capped at 100 characters
+        let label = label.chars().take(100).collect::<String>();
+        options.push(CreateSelectMenuOption::new(label, &entry.course_id));
+    }
+    let max_values = options.len() as u8;
+    let offer = Msg::ShareOffer {
+        user_id: ctx.author().id.get(),
+        courses: options.len(),
+    };
+    let menu = CreateSelectMenu::new(
+        format!("{SHARE_MENU_PREFIX}{}", ctx.author().id),
+        CreateSelectMenuKind::String { options },
+    )
+    .placeholder(Msg::SharePlaceholder.render(lang))
+    .min_values(1)
+    .max_values(max_values);
+    let message = CreateMessage::new()
+        .content(offer.render(lang))
+        .components(vec![CreateActionRow::SelectMenu(menu)]);
+    let user_id = friend.id.get();
+    let msg = match friend.id.direct_message(ctx, message).await {
+        Ok(_) => Msg::ShareSent { user_id },
+        Err(e) => {
+            debug!("fail to send a shared watchlist to {user_id}: {e:?}");
+            Msg::ShareUndeliverable { user_id }
+        }
+    };
+    reply(ctx, msg).await
+}
+
+/// Handle a pick in a `/share` menu: import the picked courses with the sharer's settings
+async fn handle_share(
+    ctx: &serenity::all::Context,
+    data: &BotContext,
+    interaction: &ComponentInteraction,
+    sharer_id: u64,
+) -> Result<(), Error> {
+    let user_id = interaction.user.id.get();
+    let picked = match &interaction.data.kind {
+        ComponentInteractionDataKind::StringSelect { values } => values.as_slice(),
+        _ => &[],
+    };
+    let report = if data.db.is_blocked(user_id).await? {
+        None
+    } else {
+        // courses the sharer stopped watching since are left out
+        let shared = data
+            .db
+            .watchlist(sharer_id)
+            .await?
+            .into_iter()
+            .filter(|entry| picked.contains(&entry.course_id))
+            .collect::<Vec<_>>();
+        let config = data.config.get();
+        let (current, range) = (config.term(now()), config.serial_no_range());
+        let canonical = |id: &str| data.sources.checked(id, current, &range);
+        let report = data
+            .db
+            .import(
+                user_id,
+                &WatchlistExport::new(&shared, &[]),
+                &canonical,
+                config.max_courses_per_user,
+                MAX_DEPARTMENT_WATCHES,
+            )
+            .await?;
+        Some(report)
+    };
+    let lang = data
+        .db
+        .settings(user_id)
+        .await?
+        .language
+        .or_else(|| Lang::from_locale(&interaction.locale))
+        .unwrap_or_default();
+    let msg = match &report {
+        None => Msg::Blocked,
+        Some(report) => Msg::ImportResult { report },
+    };
+    interaction
+        .create_response(
+            ctx,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(msg.render(lang))
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Largest file `/import` accepts, far above any real export
+const IMPORT_MAX_BYTES: u32 = 256 * 1024;
+
+/// Merge a file written by `/export` into the invoking user's watchlist
+#[poise::command(slash_command, category = "Settings")]
+pub async fn import(
+    ctx: Context<'_>,
+    #[description = "JSON file produced by /export"] file: Attachment,
+) -> Result<(), Error> {
+    let import = if file.size <= IMPORT_MAX_BYTES {
+        let bytes = file.download().await?;
+        serde_json::from_slice::<WatchlistExport>(&bytes)
+            .ok()
+            .filter(|import| import.version <= EXPORT_VERSION)
+    } else {
+        None
+    };
+    let Some(import) = import else {
+        reply(ctx, Msg::ImportInvalid).await?;
+        return Ok(());
+    };
+    let data = ctx.data();
+    let config = data.config.get();
+    let (current, range) = (config.term(now()), config.serial_no_range());
+    let canonical = |id: &str| data.sources.checked(id, current, &range);
+    let report = data
+        .db
+        .import(
+            ctx.author().id.get(),
+            &import,
+            &canonical,
+            config.max_courses_per_user,
+            MAX_DEPARTMENT_WATCHES,
+        )
+        .await?;
+    reply(ctx, Msg::ImportResult { report: &report }).await?;
+    Ok(())
+}
+
+/// Suggest course IDs from the invoking user's watchlist
+async fn autocomplete_watched_course(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let list = match watchlist(ctx).await {
+        Ok(list) => list,
+        Err(e) => {
+            warn!("fail to load watchlist for autocomplete: {e:?}");
+            return Vec::new();
+        }
+    };
+    list.into_iter()
+        .map(|entry| entry.course_id)
+        .filter(|id| id.starts_with(partial))
+        .take(25)
+        .collect()
+}
+
 /// Remove course for user
-#[poise::command(prefix_command, slash_command)]
+#[poise::command(prefix_command, slash_command, category = "Courses")]
 pub async fn remove_course(
     ctx: Context<'_>,
-    #[description = "Course ID"] course_id: String,
+    #[description = "Course ID (omit to pick from your list)"]
+    #[autocomplete = "autocomplete_watched_course"]
+    course_id: Option<String>,
 ) -> Result<(), Error> {
-    if !course_id.chars().all(|x| x.is_digit(10)) {
-        let response =
-            format!("Course ID consists only by decimal digits! `{course_id}` is not a valid one");
-        ctx.say(response).await?;
+    let Some(course_id) = course_id else {
+        return remove_course_menu(ctx).await;
+    };
+    if route_course(ctx, &course_id).await?.is_none() {
         return Ok(());
     }
-    {
-        let db = ctx.data().db.write().await;
-        let bucket = db.bucket::<String, Msgpack<Vec<String>>>(Some("user_courses"))?;
-        let user_id = ctx.author().id;
-        let mut current = bucket
-            .get(&user_id.to_string())
-            .unwrap()
-            .map(|v| v.0)
-            .unwrap_or(Vec::new());
-        current.retain(|id| *id != course_id);
-        bucket.set(&user_id.to_string(), &Msgpack(current))?;
+    let data = ctx.data();
+    let course_id = data
+        .sources
+        .canonical(&course_id, data.config.get().term(now()));
+    let course_ids = std::slice::from_ref(&course_id);
+    remove_courses(ctx, course_ids).await?;
+    reply(ctx, Msg::CourseRemoved { course_ids }).await?;
+    Ok(())
+}
+
+async fn remove_courses(ctx: Context<'_>, course_ids: &[String]) -> Result<(), Error> {
+    Ok(ctx
+        .data()
+        .db
+        .remove_watches(ctx.author().id.get(), course_ids)
+        .await?)
+}
+
+/// Show a select menu of the user's watchlist and remove whatever is picked
+async fn remove_course_menu(ctx: Context<'_>) -> Result<(), Error> {
+    let list = watchlist(ctx).await?;
+    if list.is_empty() {
+        reply(ctx, Msg::NoCourse).await?;
+        return Ok(());
     }
-    let response = format!("Course removed for {course_id}.");
-    ctx.say(response).await?;
+    let style = reply_style(ctx).await?;
+
+    // discord caps select menus at 25 options
+    let options = list
+        .iter()
+        .take(25)
+        .map(|entry| CreateSelectMenuOption::new(&entry.course_id, &entry.course_id))
+        .collect::<Vec<_>>();
+    let max_values = options.len() as u8;
+    let menu_id = format!("{}remove_course", ctx.id());
+    let reply = CreateReply::default()
+        .content(Msg::SelectToRemove.render(style.lang))
+        .components(vec![CreateActionRow::SelectMenu(
+            CreateSelectMenu::new(&menu_id, CreateSelectMenuKind::String { options })
+                .min_values(1)
+                .max_values(max_values),
+        )]);
+    let handle = ctx.send(reply.ephemeral(style.ephemeral)).await?;
+
+    let interaction = ComponentInteractionCollector::new(ctx)
+        .author_id(ctx.author().id)
+        .channel_id(ctx.channel_id())
+        .timeout(Duration::from_secs(120))
+        .filter(move |mci| mci.data.custom_id == menu_id)
+        .await;
+    let Some(interaction) = interaction else {
+        handle
+            .edit(
+                ctx,
+                CreateReply::default()
+                    .content(Msg::SelectionTimedOut.render(style.lang))
+                    .components(vec![]),
+            )
+            .await?;
+        return Ok(());
+    };
+    let selected = match &interaction.data.kind {
+        ComponentInteractionDataKind::StringSelect { values } => values.clone(),
+        _ => Vec::new(),
+    };
+    remove_courses(ctx, &selected).await?;
+
+    interaction
+        .create_response(
+            ctx,
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .content(
+                        Msg::Selected {
+                            course_ids: &selected,
+                        }
+                        .render(style.lang),
+                    )
+                    .components(vec![]),
+            ),
+        )
+        .await?;
+    interaction
+        .create_followup(
+            ctx,
+            CreateInteractionResponseFollowup::new()
+                .content(
+                    Msg::CourseRemoved {
+                        course_ids: &selected,
+                    }
+                    .render(style.lang),
+                )
+                .ephemeral(style.ephemeral),
+        )
+        .await?;
+    Ok(())
+}
+
+/// How long `/force_update` follows its check, Discord lets replies be edited for 15 minutes
+const FOLLOW_LIMIT: Duration = Duration::from_secs(14 * 60);
+
+/// Check your courses now, or start the next check of everyone's
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn force_update(
+    ctx: Context<'_>,
+    #[description = "Start the next check of every user's courses instead, admins only"]
+    everyone: Option<bool>,
+) -> Result<(), Error> {
+    let everyone = everyone.unwrap_or(false);
+    if everyone && !is_admin(ctx).await? {
+        return Ok(());
+    }
+    let config = ctx.data().config.get();
+    let (global, per_user) = (
+        config.force_update_cooldown,
+        config.force_update_user_cooldown,
+    );
+    if let Some(wait) = cooldown_left(ctx, &ctx.data().force_updates, global, per_user).await {
+        let minutes = wait.div_ceil(60);
+        reply(ctx, Msg::ForceUpdateCooldown { minutes }).await?;
+        return Ok(());
+    }
+    if everyone {
+        force_cycle(ctx).await
+    } else {
+        check_own_courses(ctx).await
+    }
+}
+
+/// Least time between two progress edits of a reply, Discord rate limits edits per channel
+const PROGRESS_EDIT_GAP: Duration = Duration::from_secs(2);
+
+/// Query the invoking user's courses right away, leaving the periodic check on its schedule
+///
+/// The reply shows each course as it is checked rather than a typing indicator, which Discord
+/// drops after a few seconds. The seats are stored like the periodic check's, which still sends
+/// the alerts.
+async fn check_own_courses(ctx: Context<'_>) -> Result<(), Error> {
+    let list = watchlist(ctx).await?;
+    if list.is_empty() {
+        reply(ctx, Msg::NoCourse).await?;
+        return Ok(());
+    }
+    let style = reply_style(ctx).await?;
+    let data = ctx.data();
+    let count = list.len();
+    let progress = |lines: &[String]| {
+        let header = Msg::CheckingOwnCourses {
+            done: lines.len(),
+            count,
+        };
+        let content = std::iter::once(header.render(style.lang))
+            .chain(lines.iter().cloned())
+            .collect::<Vec<_>>()
+            .join("\n");
+        CreateReply::default()
+            .content(content)
+            .ephemeral(style.ephemeral)
+    };
+    let handle = ctx.send(progress(&[])).await?;
+    let mut edited = Instant::now();
+    let mut lines = Vec::with_capacity(count);
+    for entry in &list {
+        let course_id = entry.course_id.as_str();
+        let status = match data.sources.route(course_id) {
+            Some((_, source, id, term)) => {
+                // lock per query so the periodic check can go on in between
+                let result = source.lock().await.query(id, term).await;
+                result
+                    .inspect_err(|e| warn!("fail to check course {course_id}: {e:?}"))
+                    .ok()
+            }
+            None => None,
+        };
+        if let Some(status) = status {
+            let snapshot = SeatSnapshot {
+                seats: status.seats(),
+                checked_at: now(),
+            };
+            data.db.record_seats(course_id, &snapshot).await?;
+        }
+        let meta = data.db.course_meta(course_id).await?;
+        let msg = Msg::CourseChecked {
+            course_id,
+            status,
+            meta: meta.as_ref(),
+        };
+        lines.push(msg.render(style.lang));
+        if lines.len() < count && edited.elapsed() >= PROGRESS_EDIT_GAP {
+            handle.edit(ctx, progress(&lines)).await?;
+            edited = Instant::now();
+        }
+    }
+    let header = Msg::OwnCoursesChecked { count }.render(style.lang);
+    let content = format!("{header}\n{}", lines.join("\n"));
+    handle
+        .edit(ctx, CreateReply::default().content(content))
+        .await?;
     Ok(())
 }
 
-#[poise::command(prefix_command, slash_command)]
-pub async fn force_update(ctx: Context<'_>) -> Result<(), Error> {
-    match ctx.data().sender.try_send(()) {
+/// Start the next check of every course now and follow it until it is done
+async fn force_cycle(ctx: Context<'_>) -> Result<(), Error> {
+    let data = ctx.data();
+    let mut progress = data.progress.clone();
+    let cycle = progress.borrow_and_update().next_cycle();
+    match data.sender.try_send(()) {
+        // a check is already queued, this request is served by it too
         Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => (),
         Err(e) => return Err(Box::new(e)),
         Ok(_) => (),
     }
-    let response = format!("Initiate force update...\n (Do not abuse and spam this command!)");
+    let lang = reply_style(ctx).await?.lang;
+    let handle = ctx.say(Msg::ForceUpdate.render(lang)).await?;
+    // the check may wait for a running one, each taking up to the deadline
+    let wait = Duration::from_secs(2 * data.config.get().cycle_deadline).min(FOLLOW_LIMIT);
+    let deadline = Instant::now() + wait;
+    let edit = |msg: Msg| CreateReply::default().content(msg.render(lang));
+    // the state is copied out right away, borrowing it would hold the channel's lock
+    let started = timeout_at(deadline, progress.wait_for(|state| state.started(cycle)))
+        .await
+        .is_ok_and(|started| started.is_ok());
+    if !started {
+        handle.edit(ctx, edit(Msg::ForceUpdateTimedOut)).await?;
+        return Ok(());
+    }
+    handle
+        .edit(ctx, edit(Msg::ForceUpdateRunning { cycle }))
+        .await?;
+    let finished = timeout_at(deadline, progress.wait_for(|state| state.finished(cycle)))
+        .await
+        .ok()
+        .and_then(|state| state.ok().map(|state| *state));
+    let Some(state) = finished else {
+        handle.edit(ctx, edit(Msg::ForceUpdateTimedOut)).await?;
+        return Ok(());
+    };
+    let msg = match state.outcome.filter(|_| state.cycle == cycle) {
+        Some(outcome) => Msg::ForceUpdateDone {
+            checked: outcome.checked,
+            available: outcome.available,
+        },
+        None => Msg::ForceUpdateSkipped,
+    };
+    handle.edit(ctx, edit(msg)).await?;
+    Ok(())
+}
+
+/// Seconds until the invoking user may query on demand again, `None` when they may now
+///
+/// Admins are never held back. Being let through counts as a query.
+async fn cooldown_left(
+    ctx: Context<'_>,
+    cooldown: &std::sync::Mutex<CommandCooldown>,
+    global: u64,
+    per_user: u64,
+) -> Option<u64> {
+    if tier(ctx).await >= Tier::Admin {
+        return None;
+    }
+    let user_id = ctx.author().id;
+    let mut cooldown = cooldown.lock().unwrap();
+    cooldown.admit(user_id.get(), now(), global, per_user).err()
+}
+
+/// Query a course's seats right now and compare them with the stored ones
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn check_course(
+    ctx: Context<'_>,
+    #[description = "Course ID (serial number), school:ID for another school, ID@114-1 for another semester"]
+    course_id: String,
+) -> Result<(), Error> {
+    let Some((_, source, id, term)) = route_course(ctx, &course_id).await? else {
+        return Ok(());
+    };
+    let data = ctx.data();
+    let config = data.config.get();
+    let (global, per_user) = (
+        config.check_course_cooldown,
+        config.check_course_user_cooldown,
+    );
+    if let Some(seconds) = cooldown_left(ctx, &data.course_checks, global, per_user).await {
+        reply(ctx, Msg::CheckCourseCooldown { seconds }).await?;
+        return Ok(());
+    }
+    let style = reply_style(ctx).await?;
+    defer(ctx, &style).await?;
+    let course_id = data.sources.canonical(&course_id, config.term(now()));
+    let before = data.db.seat_snapshot(&course_id).await?;
+    let status = source
+        .lock()
+        .await
+        .query(id, term)
+        .await
+        .inspect_err(|e| warn!("fail to check course {course_id}: {e:?}"))
+        .ok();
+    if let Some(status) = status {
+        let snapshot = SeatSnapshot {
+            seats: status.seats(),
+            checked_at: now(),
+        };
+        data.db.record_seats(&course_id, &snapshot).await?;
+    }
+    let meta = data.db.course_meta(&course_id).await?;
+    let lines = [
+        Msg::CourseChecked {
+            course_id: &course_id,
+            status,
+            meta: meta.as_ref(),
+        },
+        Msg::StoredSeats {
+            snapshot: before.as_ref(),
+        },
+    ]
+    .map(|msg| msg.render(style.lang));
+    ctx.send(
+        CreateReply::default()
+            .content(lines.join("\n"))
+            .ephemeral(style.ephemeral),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Show a course's name, teacher and course code from the public course list
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn course_info(
+    ctx: Context<'_>,
+    #[description = "Course ID (serial number), school:ID for another school, ID@114-1 for another semester"]
+    course_id: String,
+) -> Result<(), Error> {
+    let Some(route) = route_course(ctx, &course_id).await? else {
+        return Ok(());
+    };
+    defer(ctx, &reply_style(ctx).await?).await?;
+    let data = ctx.data();
+    let course_id = data
+        .sources
+        .canonical(&course_id, data.config.get().term(now()));
+    let Some(course) = lookup_course(data, route, &course_id).await? else {
+        let msg = Msg::UnknownCourse {
+            course_id: &course_id,
+        };
+        return reply(ctx, msg).await;
+    };
+    data.db
+        .cache_course_meta(std::slice::from_ref(&course), now())
+        .await?;
+    reply(ctx, Msg::CourseInfo { course: &course }).await
+}
+
+/// Results listed by `/search_course`, keeping the reply within Discord's length limit
+const SEARCH_RESULT_LIMIT: usize = 15;
+
+/// Find courses by name or teacher, or list them by department, domain and grade
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn search_course(
+    ctx: Context<'_>,
+    #[description = "Part of the course name or teacher name"]
+    #[min_length = 1]
+    keyword: Option<String>,
+    #[description = "Only courses of this department code, such as CSU"] department: Option<String>,
+    #[description = "Only general education courses of this domain, such as 人文藝術"]
+    #[min_length = 1]
+    domain: Option<String>,
+    #[description = "Only courses offered to this year of study"]
+    #[min = 1]
+    #[max = 7]
+    grade: Option<u32>,
+) -> Result<(), Error> {
+    let keyword = keyword.as_deref().map(str::trim);
+    let mut filters = CourseQuery::default();
+    if let Some(dept_code) = department {
+        let Some(dept_code) = normalize_dept_code(&dept_code) else {
+            reply(
+                ctx,
+                Msg::InvalidDeptCode {
+                    dept_code: &dept_code,
+                },
+            )
+            .await?;
+            return Ok(());
+        };
+        filters = filters.with_dept_code(&dept_code);
+    }
+    if let Some(domain) = &domain {
+        filters = filters.with_general_core(domain.trim());
+    }
+    if let Some(grade) = grade {
+        filters = filters.with_grade(grade);
+    }
+    // a keyword matches names or teachers, which the form only takes one at a time
+    let queries = match keyword {
+        Some(keyword) => vec![
+            filters.clone().with_name(keyword),
+            filters.clone().with_teacher(keyword),
+        ],
+        None if !filters.is_empty() => vec![filters.clone()],
+        None => return reply(ctx, Msg::SearchTermRequired).await,
+    };
+    let style = reply_style(ctx).await?;
+    defer(ctx, &style).await?;
+    let mut courses = {
+        let mut crawler = ctx.data().crawler.lock().await;
+        let mut courses = Vec::new();
+        for query in &queries {
+            courses.extend(crawler.search(query).await?);
+        }
+        courses
+    };
+    courses.sort_by(|a, b| a.serial_no.cmp(&b.serial_no));
+    courses.dedup_by(|a, b| a.serial_no == b.serial_no);
+    ctx.data().db.cache_course_meta(&courses, now()).await?;
+    let keyword = &search_label(keyword, &filters, style.lang);
+    let msg = if courses.is_empty() {
+        Msg::NoSearchResults { keyword }
+    } else {
+        Msg::SearchResults {
+            keyword,
+            courses: &courses[..courses.len().min(SEARCH_RESULT_LIMIT)],
+            total: courses.len(),
+        }
+    };
+    reply(ctx, msg).await?;
+    Ok(())
+}
+
+/// Show when a course last had free seats and how long openings last
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn history(
+    ctx: Context<'_>,
+    #[description = "Course ID (serial number), school:ID for another school, ID@114-1 for another semester"]
+    #[autocomplete = "autocomplete_watched_course"]
+    course_id: String,
+) -> Result<(), Error> {
+    if route_course(ctx, &course_id).await?.is_none() {
+        return Ok(());
+    }
+    let data = ctx.data();
+    let course_id = data
+        .sources
+        .canonical(&course_id, data.config.get().term(now()));
+    let history = data.db.history(&course_id).await?;
+    let openings = openings(&history);
+    let Some(&(last_opened, last_closed)) = openings.last() else {
+        reply(
+            ctx,
+            Msg::NoHistory {
+                course_id: &course_id,
+            },
+        )
+        .await?;
+        return Ok(());
+    };
+    let closed = openings
+        .iter()
+        .filter_map(|(start, end)| end.map(|end| end - start))
+        .collect::<Vec<_>>();
+    let average_duration =
+        (!closed.is_empty()).then(|| closed.iter().sum::<u64>() / closed.len() as u64);
+    let msg = Msg::History {
+        course_id: &course_id,
+        last_opened,
+        open_now: last_closed.is_none(),
+        openings: openings.len(),
+        average_duration,
+    };
+    reply(ctx, msg).await?;
+    Ok(())
+}
+
+/// Alerts shown by `/notifications`, the log itself keeps more
+const NOTIFICATION_LIST_LIMIT: usize = 15;
+
+/// Show the most recent alerts sent to you and whether they arrived
+#[poise::command(prefix_command, slash_command, category = "Courses")]
+pub async fn notifications(ctx: Context<'_>) -> Result<(), Error> {
+    let records = ctx.data().db.notifications(ctx.author().id.get()).await?;
+    if records.is_empty() {
+        reply(ctx, Msg::NoNotifications).await?;
+        return Ok(());
+    }
+    let style = reply_style(ctx).await?;
+    let lines = records
+        .iter()
+        .take(NOTIFICATION_LIST_LIMIT)
+        .map(|record| Msg::NotificationListEntry { record }.render(style.lang))
+        .collect::<Vec<_>>();
+    let response = format!(
+        "{}\n{}",
+        Msg::NotificationListHeader.render(style.lang),
+        lines.join("\n")
+    );
+    ctx.send(
+        CreateReply::default()
+            .content(response)
+            .ephemeral(style.ephemeral),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Show moderators how many courses a member watches and when they were last alerted, leaving
+/// out which courses
+#[poise::command(
+    context_menu_command = "Watch count",
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    category = "Server"
+)]
+pub async fn member_watches(ctx: Context<'_>, user: User) -> Result<(), Error> {
+    let db = &ctx.data().db;
+    let user_id = user.id.get();
+    let msg = Msg::MemberWatches {
+        user_id,
+        courses: db.watchlist(user_id).await?.len(),
+        departments: db.department_watches(user_id).await?.len(),
+        last_alert: db
+            .notifications(user_id)
+            .await?
+            .first()
+            .map(|record| record.at),
+    };
+    let lang = reply_style(ctx).await?.lang;
+    ctx.send(
+        CreateReply::default()
+            .content(msg.render(lang))
+            .ephemeral(true),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Choose whether command replies are visible to everyone in the channel
+#[poise::command(prefix_command, slash_command, category = "Settings")]
+pub async fn set_public_replies(
+    ctx: Context<'_>,
+    #[description = "Show replies to everyone (default: only you)"] enabled: bool,
+) -> Result<(), Error> {
+    ctx.data()
+        .db
+        .update_settings(ctx.author().id.get(), &|s| s.public_replies = enabled)
+        .await?;
+    reply(ctx, Msg::PublicReplies { enabled }).await?;
+    Ok(())
+}
+
+/// Choose the language the bot talks to you in
+#[poise::command(prefix_command, slash_command, category = "Settings")]
+pub async fn set_language(
+    ctx: Context<'_>,
+    #[description = "Language for replies and notifications"] language: Lang,
+) -> Result<(), Error> {
+    ctx.data()
+        .db
+        .update_settings(ctx.author().id.get(), &|s| s.language = Some(language))
+        .await?;
+    reply(ctx, Msg::LanguageSet).await?;
+    Ok(())
+}
+
+/// Choose where availability alerts are delivered
+#[poise::command(prefix_command, slash_command, category = "Settings")]
+pub async fn set_notify(
+    ctx: Context<'_>,
+    #[description = "Where to send alerts"] destination: NotifyTarget,
+    #[description = "Server channel for alerts (defaults to this channel)"]
+    #[channel_types("Text")]
+    channel: Option<GuildChannel>,
+) -> Result<(), Error> {
+    let channel = match (destination, channel) {
+        (NotifyTarget::Dm, _) => None,
+        (_, Some(channel)) => Some(channel.id),
+        (_, None) if ctx.guild_id().is_some() => Some(ctx.channel_id()),
+        (_, None) => {
+            reply(ctx, Msg::NotifyChannelRequired).await?;
+            return Ok(());
+        }
+    };
+    let update = |settings: &mut UserSettings| {
+        settings.notify_target = destination;
+        settings.notify_channel = channel.map(|c| c.get());
+    };
+    ctx.data()
+        .db
+        .update_settings(ctx.author().id.get(), &update)
+        .await?;
+    let msg = Msg::NotifyTargetSet {
+        target: destination,
+        channel: channel.map(|c| c.get()),
+    };
+    reply(ctx, msg).await?;
+    Ok(())
+}
+
+/// Post alerts for courses watched by members of this server in a channel
+#[poise::command(
+    prefix_command,
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    category = "Server"
+)]
+pub async fn guild_notify(
+    ctx: Context<'_>,
+    #[description = "Channel for public alerts (omit to disable)"]
+    #[channel_types("Text")]
+    channel: Option<GuildChannel>,
+    #[description = "Role to ping with every alert"] role: Option<Role>,
+) -> Result<(), Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(());
+    };
+    let style = reply_style(ctx).await?;
+    let msg = {
+        let db = &ctx.data().db;
+        match channel {
+            Some(channel) => {
+                let settings = GuildSettings {
+                    channel: channel.id.get(),
+                    role: role.map(|r| r.id.get()),
+                    language: style.lang,
+                };
+                db.save_guild_settings(guild_id.get(), &settings).await?;
+                Msg::GuildNotifySet {
+                    channel: settings.channel,
+                    role: settings.role,
+                }
+            }
+            None => {
+                db.remove_guild_settings(guild_id.get()).await?;
+                Msg::GuildNotifyDisabled
+            }
+        }
+    };
+    ctx.say(msg.render(style.lang)).await?;
+    Ok(())
+}
+
+/// Shared watchlists allowed per guild
+const MAX_GUILD_WATCHLISTS: usize = 10;
+
+/// Courses allowed per shared watchlist
+const MAX_GUILD_WATCHLIST_COURSES: usize = 25;
+
+/// Manage watchlists shared by this server, their openings posted in a channel
+#[poise::command(
+    prefix_command,
+    slash_command,
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    subcommands("guild_watch_add", "guild_watch_remove", "guild_watch_list"),
+    subcommand_required,
+    category = "Server"
+)]
+pub async fn guild_watch(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Load the invoking guild's shared watchlists
+async fn guild_watchlists(ctx: Context<'_>) -> Result<Vec<GuildWatchlist>, Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(Vec::new());
+    };
+    Ok(ctx.data().db.guild_watchlists(guild_id.get()).await?)
+}
+
+/// Suggest the names of this server's shared watchlists
+async fn autocomplete_guild_watchlist(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let lists = match guild_watchlists(ctx).await {
+        Ok(lists) => lists,
+        Err(e) => {
+            warn!("fail to load guild watchlists for autocomplete: {e:?}");
+            return Vec::new();
+        }
+    };
+    let partial = partial.to_lowercase();
+    lists
+        .into_iter()
+        .map(|list| list.name)
+        .filter(|name| name.to_lowercase().starts_with(&partial))
+        .take(25)
+        .collect()
+}
+
+/// Suggest course IDs from this server's shared watchlists
+async fn autocomplete_guild_watch(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let lists = match guild_watchlists(ctx).await {
+        Ok(lists) => lists,
+        Err(e) => {
+            warn!("fail to load guild watchlists for autocomplete: {e:?}");
+            return Vec::new();
+        }
+    };
+    let mut course_ids = lists
+        .into_iter()
+        .flat_map(|list| list.courses)
+        .filter(|id| id.starts_with(partial))
+        .collect::<Vec<_>>();
+    course_ids.sort();
+    course_ids.dedup();
+    course_ids.truncate(25);
+    course_ids
+}
+
+/// Add a course to a shared watchlist, creating the list if needed
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "add",
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn guild_watch_add(
+    ctx: Context<'_>,
+    #[description = "Watchlist name, such as CSIE required courses"]
+    #[autocomplete = "autocomplete_guild_watchlist"]
+    #[max_length = 50]
+    list: String,
+    #[description = "Course ID (serial number), school:ID for another school, ID@114-1 for another semester"]
+    course_id: String,
+    #[description = "Channel for the list's alerts (default this channel, or the list's current one)"]
+    #[channel_types("Text")]
+    channel: Option<GuildChannel>,
+) -> Result<(), Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(());
+    };
+    let name = list.trim();
+    let Some(route) = route_course(ctx, &course_id).await? else {
+        return Ok(());
+    };
+    let data = ctx.data();
+    let course_id = data
+        .sources
+        .canonical(&course_id, data.config.get().term(now()));
+    let style = reply_style(ctx).await?;
+    let lists = guild_watchlists(ctx).await?;
+    let mut list = match lists.iter().find(|l| l.name == name) {
+        Some(list) => list.clone(),
+        None if lists.len() >= MAX_GUILD_WATCHLISTS => {
+            let msg = Msg::GuildWatchlistLimitReached {
+                limit: MAX_GUILD_WATCHLISTS,
+            };
+            reply(ctx, msg).await?;
+            return Ok(());
+        }
+        None => GuildWatchlist::new(name.to_owned(), ctx.channel_id().get(), style.lang),
+    };
+    if let Some(channel) = &channel {
+        list.channel = channel.id.get();
+    }
+    if list.courses.contains(&course_id) {
+        let msg = Msg::AlreadyInGuildWatchlist {
+            name,
+            course_id: &course_id,
+        };
+        reply(ctx, msg).await?;
+        return Ok(());
+    }
+    if list.courses.len() >= MAX_GUILD_WATCHLIST_COURSES {
+        let msg = Msg::GuildWatchlistFull {
+            name,
+            limit: MAX_GUILD_WATCHLIST_COURSES,
+        };
+        reply(ctx, msg).await?;
+        return Ok(());
+    }
+    defer(ctx, &style).await?;
+    match lookup_course(data, route, &course_id).await {
+        Ok(Some(course)) => data.db.cache_course_meta(&[course], now()).await?,
+        Ok(None) => {
+            let msg = Msg::UnknownCourse {
+                course_id: &course_id,
+            };
+            reply(ctx, msg).await?;
+            return Ok(());
+        }
+        // the checker finds out about a bad serial number anyway
+        Err(e) => warn!("fail to look up course {course_id}: {e:#}"),
+    }
+    list.courses.push(course_id.clone());
+    data.db.save_guild_watchlist(guild_id.get(), &list).await?;
+    let msg = Msg::GuildWatchAdded {
+        name,
+        course_id: &course_id,
+        channel: list.channel,
+    };
+    reply(ctx, msg).await?;
+    Ok(())
+}
+
+/// Remove a course from a shared watchlist, or the whole list
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "remove",
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn guild_watch_remove(
+    ctx: Context<'_>,
+    #[description = "Watchlist name"]
+    #[autocomplete = "autocomplete_guild_watchlist"]
+    list: String,
+    #[description = "Course ID (omit to delete the whole list)"]
+    #[autocomplete = "autocomplete_guild_watch"]
+    course_id: Option<String>,
+) -> Result<(), Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(());
+    };
+    let name = list.trim();
+    let db = &ctx.data().db;
+    let lists = guild_watchlists(ctx).await?;
+    let Some(mut list) = lists.into_iter().find(|l| l.name == name) else {
+        reply(ctx, Msg::NoGuildWatchlist { name }).await?;
+        return Ok(());
+    };
+    let Some(course_id) = course_id else {
+        db.remove_guild_watchlist(guild_id.get(), name).await?;
+        reply(ctx, Msg::GuildWatchlistRemoved { name }).await?;
+        return Ok(());
+    };
+    let course_id = course_id.trim();
+    let count = list.courses.len();
+    list.courses.retain(|id| id != course_id);
+    let msg = if list.courses.len() < count {
+        list.open.retain(|id| id != course_id);
+        db.save_guild_watchlist(guild_id.get(), &list).await?;
+        Msg::GuildWatchRemoved { name, course_id }
+    } else {
+        Msg::NotInGuildWatchlist { name, course_id }
+    };
+    reply(ctx, msg).await?;
+    Ok(())
+}
+
+/// Show this server's shared watchlists
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "list",
+    guild_only,
+    required_permissions = "MANAGE_GUILD"
+)]
+pub async fn guild_watch_list(ctx: Context<'_>) -> Result<(), Error> {
+    let lists = guild_watchlists(ctx).await?;
+    if lists.is_empty() {
+        reply(ctx, Msg::NoGuildWatchlists).await?;
+        return Ok(());
+    }
+    let style = reply_style(ctx).await?;
+    let db = &ctx.data().db;
+    let mut groups = Vec::with_capacity(lists.len());
+    for list in &lists {
+        let mut lines = Vec::with_capacity(list.courses.len());
+        for course_id in &list.courses {
+            let snapshot = db.seat_snapshot(course_id).await?;
+            let meta = db.course_meta(course_id).await?;
+            let msg = Msg::GuildWatchEntry {
+                course_id,
+                snapshot: snapshot.as_ref(),
+                meta: meta.as_ref(),
+            };
+            lines.push(msg.render(style.lang));
+        }
+        let heading = Msg::GuildWatchlistHeader {
+            name: &list.name,
+            channel: list.channel,
+        };
+        groups.push(Group {
+            heading: heading.render(style.lang),
+            lines,
+        });
+    }
+    paginate(ctx, &pages(&groups, PAGE_LEN), style.ephemeral).await
+}
+
+/// Choose how availability alerts are grouped
+#[poise::command(prefix_command, slash_command, category = "Settings")]
+pub async fn set_digest(
+    ctx: Context<'_>,
+    #[description = "Group alerts per course or per check"] mode: DigestMode,
+    #[description = "Also send a daily summary of alerts"] daily_summary: Option<bool>,
+) -> Result<(), Error> {
+    let update = |settings: &mut UserSettings| {
+        settings.digest = mode;
+        if let Some(daily_summary) = daily_summary {
+            settings.daily_summary = daily_summary;
+        }
+    };
+    let settings = ctx
+        .data()
+        .db
+        .update_settings(ctx.author().id.get(), &update)
+        .await?;
+    let msg = Msg::DigestSet {
+        digest: mode,
+        daily_summary: settings.daily_summary,
+    };
+    reply(ctx, msg).await?;
+    Ok(())
+}
+
+/// Be told when a course you were alerted about fills up again before you got a seat
+#[poise::command(prefix_command, slash_command, category = "Settings")]
+pub async fn set_fill_alerts(
+    ctx: Context<'_>,
+    #[description = "Alert when an alerted course fills up again"] enabled: bool,
+) -> Result<(), Error> {
+    ctx.data()
+        .db
+        .update_settings(ctx.author().id.get(), &|settings| {
+            settings.fill_alerts = enabled
+        })
+        .await?;
+    reply(ctx, Msg::FillAlertsSet { enabled }).await
+}
+
+/// Hold back alerts during some hours of the day (Taiwan time), leave both empty to turn it off
+#[poise::command(prefix_command, slash_command, category = "Settings")]
+pub async fn set_quiet_hours(
+    ctx: Context<'_>,
+    #[description = "First quiet hour (0-23)"]
+    #[max = 23]
+    start: Option<u8>,
+    #[description = "Hour alerts resume (0-23)"]
+    #[max = 23]
+    end: Option<u8>,
+) -> Result<(), Error> {
+    let quiet_hours = match (start, end) {
+        (None, None) => None,
+        (Some(start), Some(end)) if start != end && start < 24 && end < 24 => {
+            Some(QuietHours { start, end })
+        }
+        _ => {
+            reply(ctx, Msg::InvalidQuietHours).await?;
+            return Ok(());
+        }
+    };
+    ctx.data()
+        .db
+        .update_settings(ctx.author().id.get(), &|s| s.quiet_hours = quiet_hours)
+        .await?;
+    reply(ctx, Msg::QuietHoursSet { quiet_hours }).await?;
+    Ok(())
+}
+
+/// Override how many courses a user may watch
+#[poise::command(prefix_command, slash_command, check = "is_admin", category = "Admin")]
+pub async fn set_course_limit(
+    ctx: Context<'_>,
+    #[description = "User to override"] user: User,
+    #[description = "Course cap (omit to restore the default)"] limit: Option<usize>,
+) -> Result<(), Error> {
+    ctx.data().db.set_course_limit(user.id.get(), limit).await?;
+    let response = match limit {
+        Some(limit) => format!("Course cap for {} set to {limit}.", user.name),
+        None => format!(
+            "Course cap for {} restored to the default ({}).",
+            user.name,
+            ctx.data().config.get().max_courses_per_user
+        ),
+    };
+    ctx.say(response).await?;
+    Ok(())
+}
+
+/// Lock a user out of every command and stop checking their watches
+#[poise::command(prefix_command, slash_command, check = "is_admin", category = "Admin")]
+pub async fn block_user(
+    ctx: Context<'_>,
+    #[description = "User to block"] user: User,
+    #[description = "Why, for the record"] reason: Option<String>,
+) -> Result<(), Error> {
+    let entry = BlockEntry {
+        blocked_at: now(),
+        reason,
+    };
+    ctx.data().db.block_user(user.id.get(), &entry).await?;
+    ctx.say(format!(
+        "{} is blocked, their watches are skipped until unblocked.",
+        user.name
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Lift a block placed with `/block_user`
+#[poise::command(prefix_command, slash_command, check = "is_admin", category = "Admin")]
+pub async fn unblock_user(
+    ctx: Context<'_>,
+    #[description = "User to unblock"] user: User,
+) -> Result<(), Error> {
+    let removed = ctx.data().db.unblock_user(user.id.get()).await?;
+    let response = if removed {
+        format!("{} is no longer blocked.", user.name)
+    } else {
+        format!("{} was not blocked.", user.name)
+    };
+    ctx.say(response).await?;
+    Ok(())
+}
+
+/// Watch a course for a user, such as one who cannot run commands
+#[poise::command(prefix_command, slash_command, check = "is_admin", category = "Admin")]
+pub async fn admin_add(
+    ctx: Context<'_>,
+    #[description = "User to watch the course for"] user: User,
+    #[description = "Course ID (serial number), school:ID for another school, ID@114-1 for another semester"]
+    course_id: String,
+    #[description = "Only alert when at least this many seats are free (default 1)"]
+    #[min = 1]
+    min_seats: Option<u32>,
+    #[description = "Alert on free seats (default) or on any seat change"] mode: Option<WatchMode>,
+) -> Result<(), Error> {
+    let Some(route) = route_course(ctx, &course_id).await? else {
+        return Ok(());
+    };
+    let data = ctx.data();
+    let course_id = data
+        .sources
+        .canonical(&course_id, data.config.get().term(now()));
+    defer(ctx, &reply_style(ctx).await?).await?;
+    match lookup_course(data, route, &course_id).await {
+        Ok(Some(course)) => data.db.cache_course_meta(&[course], now()).await?,
+        Ok(None) => {
+            let msg = Msg::UnknownCourse {
+                course_id: &course_id,
+            };
+            reply(ctx, msg).await?;
+            return Ok(());
+        }
+        // the checker finds out about a bad serial number anyway
+        Err(e) => warn!("fail to look up course {course_id}: {e:#}"),
+    }
+    let outcome = data
+        .db
+        .add_watch(
+            user.id.get(),
+            &course_id,
+            min_seats,
+            mode,
+            data.config.get().max_courses_per_user,
+        )
+        .await?;
+    let response = match outcome {
+        AddOutcome::Added => format!("Added {course_id} to the watchlist of {}.", user.name),
+        AddOutcome::Updated(_) => format!("Updated the watch of {} on {course_id}.", user.name),
+        AddOutcome::Duplicate(_) => format!("{} already watches {course_id}.", user.name),
+        AddOutcome::LimitReached(limit) => format!(
+            "{} already watches {limit} courses, raise their cap with /set_course_limit first.",
+            user.name
+        ),
+    };
+    if matches!(outcome, AddOutcome::Added | AddOutcome::Updated(_)) {
+        info!(
+            target: "audit",
+            admin = ctx.author().id.get(),
+            user = user.id.get(),
+            course_id,
+            "watch added for the user"
+        );
+    }
+    ctx.say(response).await?;
+    Ok(())
+}
+
+/// Remove a course from a user's watchlist, such as an entry they cannot remove themselves
+#[poise::command(prefix_command, slash_command, check = "is_admin", category = "Admin")]
+pub async fn admin_remove(
+    ctx: Context<'_>,
+    #[description = "User whose watch to remove"] user: User,
+    #[description = "Course ID as listed for the user"] course_id: String,
+) -> Result<(), Error> {
+    let data = ctx.data();
+    let course_id = data
+        .sources
+        .canonical(course_id.trim(), data.config.get().term(now()));
+    let watched = data.db.watchlist(user.id.get()).await?;
+    if !watched.iter().any(|entry| entry.course_id == course_id) {
+        ctx.say(format!("{} does not watch {course_id}.", user.name))
+            .await?;
+        return Ok(());
+    }
+    data.db
+        .remove_watches(user.id.get(), std::slice::from_ref(&course_id))
+        .await?;
+    info!(
+        target: "audit",
+        admin = ctx.author().id.get(),
+        user = user.id.get(),
+        course_id,
+        "watch removed for the user"
+    );
+    ctx.say(format!(
+        "Removed {course_id} from the watchlist of {}.",
+        user.name
+    ))
+    .await?;
+    Ok(())
+}
+
+/// List users whose alerts keep failing and the ones no longer checked because of it
+#[poise::command(prefix_command, slash_command, check = "is_admin", category = "Admin")]
+pub async fn unreachable_users(ctx: Context<'_>) -> Result<(), Error> {
+    let failures = ctx.data().db.delivery_failures().await?;
+    if failures.is_empty() {
+        ctx.say("Every user is reachable.").await?;
+        return Ok(());
+    }
+    let lines = failures
+        .iter()
+        .map(|(user_id, failures)| {
+            let mut line = format!(
+                "- <@{user_id}>: {} failed alerts since <t:{}:R>",
+                failures.count, failures.since
+            );
+            if let Some(at) = failures.disabled_at {
+                let purge_at = at + ctx.data().config.get().unreachable_purge_days * 86400;
+                line += &format!(", disabled <t:{at}:R>, data deleted <t:{purge_at}:R>");
+            }
+            line
+        })
+        .collect::<Vec<_>>();
+    ctx.send(
+        CreateReply::default()
+            .content(lines.join("\n"))
+            .allowed_mentions(CreateAllowedMentions::new()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Show whether the bot is running and when it checks next
+#[poise::command(prefix_command, slash_command, category = "General")]
+pub async fn status(ctx: Context<'_>) -> Result<(), Error> {
+    let stats = ctx.data().db.stats().await?;
+    let accounts = ctx.data().config.get().ntnu_credentials().len() as u64;
+    let last_check = stats.last_cycle_at.zip(stats.last_cycle_secs);
+    let next_check = stats
+        .last_cycle_at
+        .map(|at| at + ctx.data().config.get().check_interval)
+        .filter(|at| *at > now());
+    reply(
+        ctx,
+        Msg::Status {
+            started_at: stats.started_at,
+            last_check,
+            next_check,
+            session_healthy: METRICS.failing_checks.load(Ordering::Relaxed) == 0,
+            disabled: METRICS.locked_accounts.load(Ordering::Relaxed) >= accounts,
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+/// Show how many users and courses the bot tracks and how the checker is doing
+#[poise::command(prefix_command, slash_command, check = "is_admin", category = "Admin")]
+pub async fn stats(ctx: Context<'_>) -> Result<(), Error> {
+    let WatchCounts {
+        users,
+        courses: total,
+        unique_courses: unique,
+        departments,
+    } = ctx.data().db.watch_counts().await?;
+    let stats = ctx.data().db.stats().await?;
+    let last_cycle = match (stats.last_cycle_at, stats.last_cycle_secs) {
+        (Some(at), Some(secs)) => format!("took {secs}s, finished <t:{at}:R>"),
+        _ => "not finished yet".to_owned(),
+    };
+    let latency = {
+        let latencies = LATENCIES.lock().unwrap_or_else(PoisonError::into_inner);
+        let quantiles = QUANTILES
+            .iter()
+            .filter_map(|q| {
+                Some(format!(
+                    "p{} {}ms",
+                    q * 100.0,
+                    latencies.queries.quantile(*q)?
+                ))
+            })
+            .collect::<Vec<_>>();
+        if quantiles.is_empty() {
+            "no queries yet".to_owned()
+        } else {
+            format!(
+                "{} over the last {} queries{}",
+                quantiles.join(", "),
+                latencies.queries.len(),
+                if latencies.degraded {
+                    ", slower than usual"
+                } else {
+                    ""
+                }
+            )
+        }
+    };
+    let response = format!(
+        "Users: {users}\n\
+         Watched courses: {total} ({unique} unique)\n\
+         Watched departments: {departments}\n\
+         Last check: {last_cycle}\n\
+         Query latency: {latency}\n\
+         Since <t:{}:R>: {} failed queries, {} logins",
+        stats.started_at, stats.query_failures, stats.logins
+    );
+    ctx.say(response).await?;
+    Ok(())
+}
+
+/// Apply changes to the config file without restarting
+#[poise::command(prefix_command, slash_command, check = "is_owner", category = "Admin")]
+pub async fn reload_config(ctx: Context<'_>) -> Result<(), Error> {
+    let data = ctx.data();
+    let response = match data.config.reload(&data.crawler).await {
+        Ok(ignored) if ignored.is_empty() => "Settings reloaded.".to_owned(),
+        Ok(ignored) => format!(
+            "Settings reloaded. Changes to {} apply after a restart.",
+            ignored.join(", ")
+        ),
+        Err(e) => format!("Reload failed, the previous settings stay: {e:#}"),
+    };
     ctx.say(response).await?;
     Ok(())
 }
 
+/// Custom ID prefix of the "re-add" buttons attached to availability alerts
+pub const READD_BUTTON_PREFIX: &str = "readd:";
+
+/// Handle a click on a "re-add" button: put the course back and re-arm its alerts
+async fn handle_readd(
+    ctx: &serenity::all::Context,
+    data: &BotContext,
+    interaction: &ComponentInteraction,
+    course_id: &str,
+) -> Result<(), Error> {
+    let user_id = interaction.user.id;
+    let blocked = data.db.is_blocked(user_id.get()).await?;
+    let outcome = if blocked {
+        None
+    } else {
+        let outcome = data
+            .db
+            .add_watch(
+                user_id.get(),
+                course_id,
+                None,
+                None,
+                data.config.get().max_courses_per_user,
+            )
+            .await?;
+        Some(outcome)
+    };
+    if let Some(AddOutcome::Duplicate(_)) = outcome {
+        data.db.clear_notified(user_id.get(), course_id).await?;
+    }
+    let lang = data
+        .db
+        .settings(user_id.get())
+        .await?
+        .language
+        .or_else(|| Lang::from_locale(&interaction.locale))
+        .unwrap_or_default();
+    let msg = match outcome {
+        None => Msg::Blocked,
+        Some(AddOutcome::LimitReached(limit)) => Msg::LimitReached { course_id, limit },
+        Some(AddOutcome::Added | AddOutcome::Duplicate(_) | AddOutcome::Updated(_)) => {
+            Msg::CourseReadded { course_id }
+        }
+    };
+    interaction
+        .create_response(
+            ctx,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(msg.render(lang))
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn event_handler(
+    ctx: &serenity::all::Context,
+    event: &FullEvent,
+    data: &BotContext,
+) -> Result<(), Error> {
+    trace!(
+        "Got an event in event handler: {:?}",
+        event.snake_case_name()
+    );
+    match event {
+        FullEvent::InteractionCreate {
+            interaction: Interaction::Component(interaction),
+        } => {
+            let custom_id = &interaction.data.custom_id;
+            if let Some(course_id) = custom_id.strip_prefix(READD_BUTTON_PREFIX) {
+                handle_readd(ctx, data, interaction, course_id).await?;
+            } else if let Some(sharer_id) = custom_id.strip_prefix(SHARE_MENU_PREFIX) {
+                if let Ok(sharer_id) = sharer_id.parse() {
+                    handle_share(ctx, data, interaction, sharer_id).await?;
+                }
+            }
+        }
+        FullEvent::Ready { .. } => METRICS.gateway_connected.store(true, Ordering::Relaxed),
+        FullEvent::ShardStageUpdate { event } => METRICS
+            .gateway_connected
+            .store(event.new == ConnectionStage::Connected, Ordering::Relaxed),
+        _ => (),
+    }
+    Ok(())
+}
+
 pub struct Bot {
     token: String,
     context: Option<BotContext>,
@@ -149,18 +2174,33 @@ pub struct Bot {
 
 impl Bot {
     pub fn new(
-        config: &Config,
-        db: Arc<tokio::sync::RwLock<Store>>,
+        config: Arc<SharedConfig>,
+        db: Arc<dyn Repository>,
         sender: tokio::sync::mpsc::Sender<()>,
+        progress: watch::Receiver<CheckerState>,
+        crawler: Arc<tokio::sync::Mutex<NtnuCrawlerManager>>,
+        sources: Arc<Sources>,
+        open_courses: Arc<OpenCourseCrawler>,
     ) -> Self {
-        let context = Some(BotContext { db, sender });
-        Self {
-            token: config.discord_token.clone(),
-            context,
-        }
+        let token = config.get().discord_token.clone();
+        let context = Some(BotContext {
+            db,
+            sender,
+            progress,
+            force_updates: Default::default(),
+            course_checks: Default::default(),
+            config,
+            crawler,
+            sources,
+            open_courses,
+        });
+        Self { token, context }
     }
 
     pub async fn client(&mut self) -> Result<Client> {
+        let config = self.context.as_ref().unwrap().config.get();
+        let text_commands = config.discord_prefix_commands;
+        let prefix = Some(config.discord_prefix.clone()).filter(|prefix| !prefix.is_empty());
         let options = poise::FrameworkOptions {
             commands: vec![
                 help(),
@@ -168,9 +2208,39 @@ impl Bot {
                 list_course(),
                 remove_course(),
                 force_update(),
+                history(),
+                notifications(),
+                member_watches(),
+                status(),
+                search_course(),
+                course_info(),
+                check_course(),
+                watch_department(),
+                unwatch_department(),
+                export(),
+                import(),
+                share(),
+                forget_me(),
+                set_public_replies(),
+                set_language(),
+                set_notify(),
+                set_digest(),
+                set_fill_alerts(),
+                set_quiet_hours(),
+                guild_notify(),
+                guild_watch(),
+                set_course_limit(),
+                block_user(),
+                unblock_user(),
+                admin_add(),
+                admin_remove(),
+                unreachable_users(),
+                stats(),
+                reload_config(),
             ],
             prefix_options: poise::PrefixFrameworkOptions {
-                prefix: Some("/".into()),
+                prefix: prefix.filter(|_| text_commands),
+                mention_as_prefix: text_commands && config.discord_mention_prefix,
                 edit_tracker: Some(Arc::new(poise::EditTracker::for_timespan(
                     Duration::from_secs(3600),
                 ))),
@@ -185,18 +2255,14 @@ impl Bot {
             post_command: |ctx| {
                 Box::pin(async move {
                     debug!("Done process command {}!", ctx.command().qualified_name);
+                    if let Err(e) = remind_dm_closed(ctx).await {
+                        warn!("fail to remind user of closed DMs: {e:?}");
+                    }
                 })
             },
+            command_check: Some(|ctx| Box::pin(command_check(ctx))),
             skip_checks_for_owners: false,
-            event_handler: |_ctx, event, _framework, _data| {
-                Box::pin(async move {
-                    trace!(
-                        "Got an event in event handler: {:?}",
-                        event.snake_case_name()
-                    );
-                    Ok(())
-                })
-            },
+            event_handler: |ctx, event, _framework, data| Box::pin(event_handler(ctx, event, data)),
             ..Default::default()
         };
         let framework = {
@@ -205,8 +2271,15 @@ impl Bot {
                 .setup(move |ctx, ready, framework| {
                     Box::pin(async move {
                         info!("Logged in as {}", ready.user.name);
-                        poise::builtins::register_globally(ctx, &framework.options().commands)
-                            .await?;
+                        let commands = &framework.options().commands;
+                        match tmp.config.get().dev_guild_id {
+                            Some(guild_id) => {
+                                info!("Registering the commands in guild {guild_id} only");
+                                let guild_id = GuildId::new(guild_id);
+                                poise::builtins::register_in_guild(ctx, commands, guild_id).await?;
+                            }
+                            None => poise::builtins::register_globally(ctx, commands).await?,
+                        }
                         Ok(tmp)
                     })
                 })
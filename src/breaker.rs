@@ -0,0 +1,136 @@
+//! Stops querying the enrollment system while it is down
+//!
+//! After enough failed queries in a row the breaker opens and queries fail at once for a backoff
+//! window. The first query after the window decides: success closes the breaker, failure opens it
+//! again for twice as long, up to a limit.
+
+use std::time::{Duration, Instant};
+
+/// Change of the breaker state, for telling the owner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerEvent {
+    /// Queries are refused for `backoff`, after `failures` failed in a row
+    Opened { failures: u32, backoff: Duration },
+    /// Queries work again after being refused since `paused` ago
+    Closed { paused: Duration },
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// Failures in a row that open the breaker, 0 never opens it
+    threshold: u32,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    failures: u32,
+    /// Current window, doubled every time the trial query fails
+    backoff: Duration,
+    open_until: Option<Instant>,
+    opened_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, min_backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            threshold,
+            min_backoff,
+            max_backoff,
+            failures: 0,
+            backoff: min_backoff,
+            open_until: None,
+            opened_at: None,
+        }
+    }
+
+    /// Take up reloaded settings, an open breaker stays open for its current window
+    pub fn configure(&mut self, threshold: u32, min_backoff: Duration, max_backoff: Duration) {
+        self.threshold = threshold;
+        self.min_backoff = min_backoff;
+        self.max_backoff = max_backoff;
+    }
+
+    /// How much longer queries are refused at `now`, `None` when they may be sent
+    pub fn refused_for(&self, now: Instant) -> Option<Duration> {
+        self.open_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    /// Count the outcome of a query sent at `now`
+    pub fn record(&mut self, success: bool, now: Instant) -> Option<BreakerEvent> {
+        if success {
+            self.failures = 0;
+            self.backoff = self.min_backoff;
+            self.open_until.take()?;
+            let paused = now.saturating_duration_since(self.opened_at.take()?);
+            return Some(BreakerEvent::Closed { paused });
+        }
+        self.failures += 1;
+        if self.open_until.is_some() {
+            // the trial query after the window failed
+            self.backoff = (self.backoff * 2).min(self.max_backoff.max(self.min_backoff));
+        } else if self.threshold == 0 || self.failures < self.threshold {
+            return None;
+        } else {
+            self.backoff = self.min_backoff;
+            self.opened_at = Some(now);
+        }
+        self.open_until = Some(now + self.backoff);
+        Some(BreakerEvent::Opened {
+            failures: self.failures,
+            backoff: self.backoff,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_breaker() {
+        let minute = Duration::from_secs(60);
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::new(3, minute, 3 * minute);
+        assert_eq!(breaker.record(false, start), None);
+        assert_eq!(breaker.record(true, start), None);
+        assert_eq!(breaker.record(false, start), None);
+        assert_eq!(breaker.record(false, start), None);
+        assert_eq!(
+            breaker.record(false, start),
+            Some(BreakerEvent::Opened {
+                failures: 3,
+                backoff: minute
+            })
+        );
+        assert_eq!(breaker.refused_for(start), Some(minute));
+
+        // the trial query fails, the window doubles up to the limit
+        let trial = start + minute;
+        assert_eq!(breaker.refused_for(trial), None);
+        assert_eq!(
+            breaker.record(false, trial),
+            Some(BreakerEvent::Opened {
+                failures: 4,
+                backoff: 2 * minute
+            })
+        );
+        let trial = trial + 2 * minute;
+        breaker.record(false, trial);
+        assert_eq!(breaker.refused_for(trial), Some(3 * minute));
+
+        let trial = trial + 3 * minute;
+        assert_eq!(
+            breaker.record(true, trial),
+            Some(BreakerEvent::Closed {
+                paused: trial - start
+            })
+        );
+        assert_eq!(breaker.refused_for(trial), None);
+        assert_eq!(breaker.record(true, trial), None);
+
+        let mut never = CircuitBreaker::new(0, minute, minute);
+        for _ in 0..100 {
+            assert_eq!(never.record(false, start), None);
+        }
+    }
+}
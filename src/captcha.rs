@@ -0,0 +1,556 @@
+//! Turns the login captcha into the answer the enrollment system expects
+//!
+//! Reading the image is left to a [`CaptchaBackend`], picked by `BOT_CAPTCHA_BACKEND`; whatever it
+//! reads goes through the same answer logic, so arithmetic captchas get computed the same way
+//! whichever backend read them.
+
+use std::{
+    collections::HashMap,
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::Config,
+    metrics::{self, METRICS},
+};
+
+#[derive(Debug, Error)]
+pub enum CaptchaServiceError {
+    #[error("no viable value")]
+    NoAnswer,
+
+    #[error("service response invalid")]
+    Invalid,
+
+    #[error("parse error: {0}")]
+    ParseInt(ParseIntError),
+
+    #[error("service refused the captcha: {0}")]
+    Rejected(String),
+}
+
+/// Which backend reads captchas
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptchaKind {
+    /// The captcha service at `BOT_CAPTCHA_URI`
+    Service,
+    /// Tesseract on this machine, in builds with the `local-captcha` feature
+    Local,
+    /// A paid solving service speaking the 2Captcha API
+    TwoCaptcha,
+}
+
+impl FromStr for CaptchaKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "service" => Ok(Self::Service),
+            "local" => Ok(Self::Local),
+            "2captcha" => Ok(Self::TwoCaptcha),
+            other => {
+                bail!("unknown captcha backend `{other}`, expected service, local or 2captcha")
+            }
+        }
+    }
+}
+
+/// Reads a captcha image
+#[async_trait]
+pub trait CaptchaBackend: Send + Sync {
+    /// What the image may say, the likeliest reading last unless one is arithmetic
+    async fn read(&self, img: &[u8]) -> Result<Vec<String>>;
+}
+
+#[derive(Clone)]
+pub struct CaptchaSolver {
+    backend: Arc<dyn CaptchaBackend>,
+    calc_regex: regex::Regex,
+    /// Readings taken of every captcha, which vote on the answer
+    samples: u32,
+    /// Percentage of `samples` the answer needs, or a new captcha is asked for instead of
+    /// spending a login on a doubtful one
+    min_agreement: u32,
+    /// Where captchas whose answer was rejected are kept, to find solver regressions and collect
+    /// training data
+    rejected_dir: Option<PathBuf>,
+}
+
+impl CaptchaSolver {
+    pub fn new(backend: Arc<dyn CaptchaBackend>) -> Self {
+        Self {
+            backend,
+            calc_regex: regex::Regex::new(r"([0-9])([+x\-])([0-9])").unwrap(),
+            samples: 1,
+            min_agreement: 0,
+            rejected_dir: None,
+        }
+    }
+
+    /// Read every captcha `samples` times and answer only once `min_agreement` percent agree
+    pub fn with_voting(self, samples: u32, min_agreement: u32) -> Self {
+        Self {
+            samples: samples.max(1),
+            min_agreement,
+            ..self
+        }
+    }
+
+    /// Keep the images of rejected captchas in `dir`, named after the time and the wrong answer
+    pub fn with_rejected_dir(self, dir: Option<PathBuf>) -> Self {
+        Self {
+            rejected_dir: dir,
+            ..self
+        }
+    }
+
+    /// The backend the settings pick, which were checked when they were read
+    pub fn from_config(config: &Config) -> Self {
+        let backend: Arc<dyn CaptchaBackend> = match config.captcha_kind() {
+            CaptchaKind::Service => Arc::new(ServiceBackend::new(
+                config
+                    .captcha_uris()
+                    .into_iter()
+                    .map(str::to_owned)
+                    .collect(),
+            )),
+            #[cfg(feature = "local-captcha")]
+            CaptchaKind::Local => Arc::new(LocalBackend),
+            #[cfg(not(feature = "local-captcha"))]
+            CaptchaKind::Local => unreachable!("rejected when the settings were read"),
+            CaptchaKind::TwoCaptcha => Arc::new(TwoCaptchaBackend::new(
+                config.captcha_uris()[0].to_owned(),
+                config.captcha_api_key.clone().unwrap_or_default(),
+            )),
+        };
+        Self::new(backend)
+            .with_voting(config.captcha_samples, config.captcha_min_agreement)
+            .with_rejected_dir(config.captcha_rejected_dir.as_ref().map(PathBuf::from))
+    }
+
+    /// Readings stop as soon as enough agree, the backend may charge for each
+    pub async fn recognize(&self, img: &[u8]) -> Result<String> {
+        let mut votes = HashMap::new();
+        let mut last_error = None;
+        for _ in 0..self.samples {
+            let readings = self.backend.read(img).await?;
+            match self.process(readings) {
+                Ok(answer) => *votes.entry(answer).or_default() += 1,
+                Err(e) => last_error = Some(e),
+            }
+            if let Some(answer) = self.elect(&votes) {
+                return Ok(answer);
+            }
+        }
+        if votes.is_empty() {
+            if let Some(e) = last_error {
+                return Err(e.into());
+            }
+        }
+        debug!(?votes, "captcha readings disagree");
+        metrics::inc(&METRICS.captcha_doubtful);
+        Err(CaptchaServiceError::NoAnswer.into())
+    }
+
+    /// Take note of whether the enrollment system accepted `answer` to the captcha `img`
+    pub async fn feedback(&self, img: &[u8], answer: &str, accepted: bool) {
+        if accepted {
+            metrics::inc(&METRICS.captcha_solved);
+            return;
+        }
+        metrics::inc(&METRICS.captcha_rejected);
+        info!(answer, bytes = img.len(), "captcha answer rejected");
+        let Some(dir) = &self.rejected_dir else {
+            return;
+        };
+        match keep_image(dir, img, answer).await {
+            Ok(path) => debug!(path = %path.display(), "kept the rejected captcha"),
+            Err(e) => warn!(
+                "fail to keep the rejected captcha in {}: {e}",
+                dir.display()
+            ),
+        }
+    }
+
+    /// The answer with the most votes, once they are enough
+    fn elect(&self, votes: &HashMap<String, u32>) -> Option<String> {
+        let (answer, count) = votes
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then_with(|| b.0.cmp(a.0)))?;
+        (count * 100 >= self.min_agreement * self.samples).then(|| answer.clone())
+    }
+
+    fn process(&self, resps: Vec<String>) -> std::result::Result<String, CaptchaServiceError> {
+        let mut last_option: Option<String> = None;
+        for resp in resps {
+            // first word is digit
+            if let Some(cap) = self.calc_regex.captures(&resp) {
+                let opd1: i32 = cap
+                    .get(1)
+                    .ok_or(CaptchaServiceError::Invalid)?
+                    .as_str()
+                    .parse()
+                    .map_err(CaptchaServiceError::ParseInt)?;
+                let op = cap.get(2).ok_or(CaptchaServiceError::Invalid)?.as_str();
+                let opd2: i32 = cap
+                    .get(3)
+                    .ok_or(CaptchaServiceError::Invalid)?
+                    .as_str()
+                    .parse()
+                    .map_err(CaptchaServiceError::ParseInt)?;
+                return match op {
+                    "+" => Ok((opd1 + opd2).to_string()),
+                    "-" => Ok((opd1 - opd2).to_string()),
+                    "x" => Ok((opd1 * opd2).to_string()),
+                    _ => Err(CaptchaServiceError::Invalid),
+                };
+            } else {
+                last_option = Some(resp)
+            }
+        }
+        last_option.ok_or(CaptchaServiceError::Invalid)
+    }
+}
+
+/// Write `img` into `dir` as `{unix millis}-{answer}.{extension}`
+async fn keep_image(dir: &Path, img: &[u8], answer: &str) -> std::io::Result<PathBuf> {
+    tokio::fs::create_dir_all(dir).await?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    // only characters that are safe in any file name
+    let answer = answer
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect::<String>();
+    let extension = infer::get(img).map_or("bin", |kind| kind.extension());
+    let path = dir.join(format!("{millis}-{answer}.{extension}"));
+    tokio::fs::write(&path, img).await?;
+    Ok(path)
+}
+
+impl Default for CaptchaSolver {
+    /// The captcha service on localhost, as without settings
+    fn default() -> Self {
+        Self::new(Arc::new(ServiceBackend::new(vec![
+            "http://localhost:8080".to_owned()
+        ])))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CaptchaResponse {
+    response: Vec<String>,
+}
+
+/// How long an instance of the captcha service that failed is passed over
+const ENDPOINT_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// The captcha service, which answers `POST /solve` with its readings
+///
+/// Several instances may be given; one that fails is passed over for a while and the next one
+/// asked, so a dead instance does not stop logins. Once every instance failed they are all tried
+/// again, the longest failed first.
+pub struct ServiceBackend {
+    endpoints: Vec<String>,
+    /// When each endpoint last failed, `None` while it works
+    failed_at: Mutex<Vec<Option<Instant>>>,
+    client: reqwest::Client,
+}
+
+impl ServiceBackend {
+    pub fn new(endpoints: Vec<String>) -> Self {
+        Self {
+            failed_at: Mutex::new(vec![None; endpoints.len()]),
+            endpoints,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Endpoints in the order to try them at `now`, working ones first as configured
+    fn order(&self, now: Instant) -> Vec<usize> {
+        let failed_at = self.failed_at.lock().unwrap();
+        let mut order = (0..self.endpoints.len()).collect::<Vec<_>>();
+        // stable, so working endpoints keep their configured order
+        order.sort_by_key(|i| {
+            failed_at[*i].filter(|at| now.saturating_duration_since(*at) < ENDPOINT_COOLDOWN)
+        });
+        order
+    }
+
+    async fn solve(&self, endpoint_root: &str, img: &[u8]) -> Result<Vec<String>> {
+        let typ = infer::get(img).context("unknown captcha image type")?;
+        let res = self
+            .client
+            .post(format!("{endpoint_root}/solve").as_str())
+            .header("Content-Type", typ.mime_type())
+            .body(Vec::from(img))
+            .send()
+            .await?
+            .error_for_status()?;
+        let resp: CaptchaResponse = res.json().await?;
+        Ok(resp.response)
+    }
+}
+
+#[async_trait]
+impl CaptchaBackend for ServiceBackend {
+    async fn read(&self, img: &[u8]) -> Result<Vec<String>> {
+        let mut last_error = None;
+        for i in self.order(Instant::now()) {
+            let endpoint = &self.endpoints[i];
+            match self.solve(endpoint, img).await {
+                Ok(readings) => {
+                    self.failed_at.lock().unwrap()[i] = None;
+                    return Ok(readings);
+                }
+                Err(e) => {
+                    warn!(endpoint, "captcha service failed: {e:#}");
+                    self.failed_at.lock().unwrap()[i] = Some(Instant::now());
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow!("no captcha service configured")))
+    }
+}
+
+/// Tesseract on this machine
+#[cfg(feature = "local-captcha")]
+pub struct LocalBackend;
+
+#[cfg(feature = "local-captcha")]
+#[async_trait]
+impl CaptchaBackend for LocalBackend {
+    async fn read(&self, img: &[u8]) -> Result<Vec<String>> {
+        let img = img.to_vec();
+        tokio::task::spawn_blocking(move || crate::ocr::read(&img)).await?
+    }
+}
+
+/// Time a solving service gets to answer, asked every [`TWO_CAPTCHA_POLL`]
+const TWO_CAPTCHA_TIMEOUT: Duration = Duration::from_secs(120);
+const TWO_CAPTCHA_POLL: Duration = Duration::from_secs(5);
+
+/// Answer of the 2Captcha API, with `status` 1 when `request` is the result
+#[derive(Debug, Deserialize)]
+struct TwoCaptchaAnswer {
+    status: u8,
+    request: String,
+}
+
+/// A solving service speaking the 2Captcha API, which several services imitate
+///
+/// Captchas are uploaded to `in.php` and their answer is polled from `res.php`.
+pub struct TwoCaptchaBackend {
+    endpoint_root: String,
+    api_key: String,
+    client: reqwest::Client,
+    poll: Duration,
+}
+
+impl TwoCaptchaBackend {
+    pub fn new(endpoint_root: String, api_key: String) -> Self {
+        Self {
+            endpoint_root,
+            api_key,
+            client: reqwest::Client::new(),
+            poll: TWO_CAPTCHA_POLL,
+        }
+    }
+}
+
+#[async_trait]
+impl CaptchaBackend for TwoCaptchaBackend {
+    async fn read(&self, img: &[u8]) -> Result<Vec<String>> {
+        let body = BASE64_STANDARD.encode(img);
+        let upload: TwoCaptchaAnswer = self
+            .client
+            .post(format!("{}/in.php", self.endpoint_root))
+            .form(&[
+                ("key", self.api_key.as_str()),
+                ("method", "base64"),
+                ("body", &body),
+                ("json", "1"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        if upload.status != 1 {
+            bail!(CaptchaServiceError::Rejected(upload.request));
+        }
+        for _ in 0..TWO_CAPTCHA_TIMEOUT.as_secs() / TWO_CAPTCHA_POLL.as_secs() {
+            sleep(self.poll).await;
+            let answer: TwoCaptchaAnswer = self
+                .client
+                .get(format!("{}/res.php", self.endpoint_root))
+                .query(&[
+                    ("key", self.api_key.as_str()),
+                    ("action", "get"),
+                    ("id", &upload.request),
+                    ("json", "1"),
+                ])
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            match (answer.status, answer.request.as_str()) {
+                (1, _) => return Ok(vec![answer.request]),
+                (_, "CAPCHA_NOT_READY") => continue,
+                (_, "ERROR_CAPTCHA_UNSOLVABLE") => bail!(CaptchaServiceError::NoAnswer),
+                _ => bail!(CaptchaServiceError::Rejected(answer.request)),
+            }
+        }
+        bail!(CaptchaServiceError::NoAnswer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_captcha_process() -> Result<()> {
+        let solver = CaptchaSolver::default();
+        let testcases = vec![
+            (vec!["asdf".to_string()], "asdf"),
+            (vec!["lxzz".to_string(), "1+2".to_string()], "3"),
+            (vec!["lxzz".to_string(), "1-2".to_string()], "-1"),
+            (vec!["lxzz".to_string(), "2x2".to_string()], "4"),
+        ];
+        for testcase in testcases {
+            println!("running testcase {:?} ,ans: {:?}", testcase.0, testcase.1);
+            let ans = solver.process(testcase.0)?;
+            assert_eq!(testcase.1, ans);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failover() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let up = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read(&mut [0; 4096]).await;
+                let body = r#"{"response":["1+2"]}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let backend = ServiceBackend::new(vec!["http://127.0.0.1:1".to_owned(), up]);
+        assert_eq!(backend.read(b"GIF89a").await?, ["1+2"]);
+        // the dead instance is tried last until it cooled down
+        let now = Instant::now();
+        assert_eq!(backend.order(now), [1, 0]);
+        assert_eq!(backend.order(now + ENDPOINT_COOLDOWN), [0, 1]);
+
+        let down = ServiceBackend::new(vec!["http://127.0.0.1:1".to_owned()]);
+        assert!(down.read(b"GIF89a").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_keep_image() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("course-bot-captcha-{}", std::process::id()));
+        let path = keep_image(&dir, b"GIF89a", "1+2 ../x").await?;
+        assert!(path.starts_with(&dir));
+        let name = path.file_name().unwrap().to_str().unwrap();
+        assert!(name.ends_with("-12x.gif"), "{name}");
+        assert_eq!(tokio::fs::read(&path).await?, b"GIF89a");
+        tokio::fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    /// Reads the captcha as the next of its readings every time, as nothing when it is empty
+    struct Scripted(std::sync::Mutex<Vec<&'static str>>);
+
+    #[async_trait]
+    impl CaptchaBackend for Scripted {
+        async fn read(&self, _img: &[u8]) -> Result<Vec<String>> {
+            let reading = self.0.lock().unwrap().remove(0);
+            Ok(Some(reading.to_owned())
+                .filter(|r| !r.is_empty())
+                .into_iter()
+                .collect())
+        }
+    }
+
+    fn scripted(readings: &[&'static str]) -> Arc<Scripted> {
+        Arc::new(Scripted(std::sync::Mutex::new(readings.to_vec())))
+    }
+
+    #[tokio::test]
+    async fn test_voting() -> Result<()> {
+        // the first two agree, the third is not read
+        let backend = scripted(&["1+2", "3", "8"]);
+        let solver = CaptchaSolver::new(backend.clone()).with_voting(3, 60);
+        assert_eq!(solver.recognize(b"").await?, "3");
+        assert_eq!(backend.0.lock().unwrap().len(), 1);
+
+        let solver = CaptchaSolver::new(scripted(&["abcd", "abed", "abcf"])).with_voting(3, 60);
+        let error = solver.recognize(b"").await.unwrap_err();
+        assert!(matches!(error.downcast()?, CaptchaServiceError::NoAnswer));
+
+        // without voting the only reading is taken
+        let solver = CaptchaSolver::new(scripted(&["abed"]));
+        assert_eq!(solver.recognize(b"").await?, "abed");
+        let solver = CaptchaSolver::new(scripted(&[""]));
+        let error = solver.recognize(b"").await.unwrap_err();
+        assert!(matches!(error.downcast()?, CaptchaServiceError::Invalid));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_two_captcha() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // uploads get an ID, the answer is ready on the first poll
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let root = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0; 4096];
+                let read = stream.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..read]);
+                let body = if request.starts_with("POST /in.php") {
+                    r#"{"status":1,"request":"42"}"#
+                } else if request.contains("id=42") {
+                    r#"{"status":1,"request":"7"}"#
+                } else {
+                    r#"{"status":0,"request":"ERROR_WRONG_CAPTCHA_ID"}"#
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let mut backend = TwoCaptchaBackend::new(root, "key".to_owned());
+        backend.poll = Duration::ZERO;
+        assert_eq!(backend.read(b"GIF89a").await?, ["7"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,101 @@
+//! Command line of the binary, which picks what the process does and where settings come from
+
+use std::path::PathBuf;
+
+use anyhow::bail;
+
+pub const USAGE: &str = "\
+usage: course-bot [--config PATH] [check-once [SERIAL_NO]]
+
+  (no command)            run the Discord bot and the periodic checker
+  check-once [SERIAL_NO]  query every watched course, or only SERIAL_NO, print the seats and exit
+
+  --config PATH           read settings from a TOML file, BOT_* variables override it";
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    pub mode: Mode,
+}
+
+/// What the process was started to do
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    Bot,
+    /// Query the watched courses, or only the given one, without touching Discord or the stored seats
+    CheckOnce(Option<String>),
+}
+
+/// Read the arguments after the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> anyhow::Result<Args> {
+    let mut config = None;
+    let mut positional = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            let Some(path) = args.next() else {
+                bail!("--config needs a path\n{USAGE}");
+            };
+            config = Some(path.into());
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            config = Some(path.into());
+        } else if arg.starts_with('-') {
+            bail!("unknown option `{arg}`\n{USAGE}");
+        } else {
+            positional.push(arg);
+        }
+    }
+    let mut positional = positional.into_iter();
+    let mode = match positional.next().as_deref() {
+        None => Mode::Bot,
+        Some("check-once") => Mode::CheckOnce(positional.next()),
+        Some(other) => bail!("unknown command `{other}`\n{USAGE}"),
+    };
+    if let Some(extra) = positional.next() {
+        bail!("unexpected argument `{extra}`\n{USAGE}");
+    }
+    Ok(Args { config, mode })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mode(args: &[&str]) -> anyhow::Result<Mode> {
+        parse(args.iter().map(|arg| arg.to_string())).map(|args| args.mode)
+    }
+
+    fn config(args: &[&str]) -> Option<PathBuf> {
+        parse(args.iter().map(|arg| arg.to_string()))
+            .unwrap()
+            .config
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(mode(&[]).unwrap(), Mode::Bot);
+        assert_eq!(mode(&["check-once"]).unwrap(), Mode::CheckOnce(None));
+        assert_eq!(
+            mode(&["check-once", "1234"]).unwrap(),
+            Mode::CheckOnce(Some("1234".to_owned()))
+        );
+        assert!(mode(&["check-once", "1234", "5678"]).is_err());
+        assert!(mode(&["serve"]).is_err());
+
+        assert_eq!(config(&[]), None);
+        assert_eq!(
+            config(&["--config", "bot.toml", "check-once"]),
+            Some("bot.toml".into())
+        );
+        assert_eq!(
+            config(&["check-once", "--config=bot.toml"]),
+            Some("bot.toml".into())
+        );
+        assert_eq!(
+            mode(&["--config", "bot.toml", "check-once", "1234"]).unwrap(),
+            Mode::CheckOnce(Some("1234".to_owned()))
+        );
+        assert!(mode(&["--config"]).is_err());
+        assert!(mode(&["--verbose"]).is_err());
+    }
+}
@@ -1,20 +1,681 @@
+use std::{collections::HashMap, ops::RangeInclusive, path::Path, str::FromStr};
+
+use anyhow::{bail, Context};
 use envconfig::Envconfig;
+use toml::{Table, Value};
+
+use crate::{captcha::CaptchaKind, schedule::Phase, source::SOURCES};
+
+/// Prefix of every setting's environment variable
+const ENV_PREFIX: &str = "BOT";
+
+/// Settings that may instead be read from the file named by the variable plus `_FILE`, the way
+/// Docker and Kubernetes mount secrets
+const SECRETS: [&str; 10] = [
+    "BOT_NTNU_PASSWORD",
+    "BOT_NTNU_ACCOUNTS",
+    "BOT_DISCORD_TOKEN",
+    "BOT_OAUTH_CLIENT_SECRET",
+    "BOT_GRPC_TOKEN",
+    "BOT_STORAGE_URL",
+    "BOT_STORAGE_KEY",
+    "BOT_SENTRY_DSN",
+    "BOT_HTTP_PROXY",
+    "BOT_CAPTCHA_API_KEY",
+];
 
+/// Settings from the environment, optionally on top of a TOML file
+///
+/// A file key is its variable without the `BOT_` prefix, lower-cased, so `BOT_NTNU_RETRY` is
+/// `retry` under `[ntnu]` or `ntnu_retry` at the top; see `config.example.toml`. Secrets can be
+/// read from files, see [`SECRETS`].
 #[derive(Debug, Envconfig)]
 pub struct Config {
+    /// School whose enrollment system bare course IDs are in, `ntnu` or `ntu`; others are named
+    /// as `ntu:12345`
+    #[envconfig(from = "BOT_COURSE_SOURCE", default = "ntnu")]
+    pub course_source: String,
     #[envconfig(from = "BOT_NTNU_ACCOUNT")]
     pub ntnu_account: String,
     #[envconfig(from = "BOT_NTNU_PASSWORD")]
     pub ntnu_password: String,
-    #[envconfig(from = "BOT_CAPTCHA_URI", default = "http://localhost:8080")]
-    pub captcha_service_uri: String,
+    /// More accounts the crawler takes turns with, spreading the queries
+    #[envconfig(from = "BOT_NTNU_ACCOUNTS", default = "")]
+    pub ntnu_accounts: AccountList,
+    /// Enrollment system the crawler logs into
+    #[envconfig(from = "BOT_NTNU_URL", default = "https://cos1s.ntnu.edu.tw")]
+    pub ntnu_url: String,
+    /// Other subsites of the enrollment system, taken over by when the current one keeps failing
+    #[envconfig(
+        from = "BOT_NTNU_FALLBACK_URLS",
+        default = "https://cos2s.ntnu.edu.tw,https://cos3s.ntnu.edu.tw"
+    )]
+    pub ntnu_fallback_urls: UrlList,
+    /// `http://`, `https://` or `socks5://` proxy the enrollment system is reached through,
+    /// credentials go in the URL
+    #[envconfig(from = "BOT_HTTP_PROXY")]
+    pub http_proxy: Option<String>,
+    /// User agents the crawler passes for, one picked per session, separated by `|` since they
+    /// contain commas; a built-in set of current browsers when empty
+    #[envconfig(from = "BOT_NTNU_USER_AGENTS", default = "")]
+    pub ntnu_user_agents: UserAgentList,
+    /// Public course query site, looked up for course names and whether a course exists
+    #[envconfig(
+        from = "BOT_OPEN_COURSE_URL",
+        default = "https://courseap2.itc.ntnu.edu.tw/acadmOpenCourse"
+    )]
+    pub open_course_url: String,
+    /// NTU's course search, which courses watched as `ntu:12345` are read from
+    #[envconfig(from = "BOT_NTU_URL", default = "https://nol.ntu.edu.tw")]
+    pub ntu_url: String,
+    /// Term such as `113-2` the public site is asked about, derived from the date when empty
+    #[envconfig(from = "BOT_ACADEMIC_TERM", default = "")]
+    pub academic_term: AcademicTerm,
+    /// `service`, `local` or `2captcha`, read through [`Config::captcha_kind`]
+    #[envconfig(from = "BOT_CAPTCHA_BACKEND")]
+    pub captcha_backend: Option<CaptchaKind>,
+    /// Captcha service instances to fail over between, or the 2Captcha-style API, read through
+    /// [`Config::captcha_uris`]
+    #[envconfig(from = "BOT_CAPTCHA_URI", default = "")]
+    pub captcha_service_uris: UrlList,
+    /// Key of the 2Captcha-style API
+    #[envconfig(from = "BOT_CAPTCHA_API_KEY")]
+    pub captcha_api_key: Option<String>,
     #[envconfig(from = "BOT_NTNU_RETRY", default = "10")]
     pub api_retry: i32,
+    /// Seconds between requests that keep the enrollment system session alive while the checker
+    /// waits, 0 to let it time out
+    #[envconfig(from = "BOT_NTNU_KEEPALIVE", default = "60")]
+    pub ntnu_keepalive: u64,
+    /// Requests the crawlers may send the enrollment system per minute together, 0 for no limit
+    #[envconfig(from = "BOT_NTNU_REQUESTS_PER_MINUTE", default = "120")]
+    pub ntnu_requests_per_minute: u32,
+    /// Milliseconds at least between two requests to the enrollment system
+    #[envconfig(from = "BOT_NTNU_MIN_GAP_MS", default = "250")]
+    pub ntnu_min_gap_ms: u64,
     #[envconfig(from = "BOT_CAPTCHA_RETRY", default = "20")]
     pub captcha_retry: i32,
+    /// Readings taken of every captcha, which vote on the answer
+    #[envconfig(from = "BOT_CAPTCHA_SAMPLES", default = "1")]
+    pub captcha_samples: u32,
+    /// Percentage of the readings that has to agree, or a new captcha is fetched instead of trying
+    /// to log in
+    #[envconfig(from = "BOT_CAPTCHA_MIN_AGREEMENT", default = "50")]
+    pub captcha_min_agreement: u32,
+    /// Directory the images of rejected captchas are kept in, unset to keep none
+    #[envconfig(from = "BOT_CAPTCHA_REJECTED_DIR")]
+    pub captcha_rejected_dir: Option<String>,
+    #[envconfig(from = "BOT_SERIAL_NO_MIN", default = "1")]
+    pub serial_no_min: u32,
+    #[envconfig(from = "BOT_SERIAL_NO_MAX", default = "9999")]
+    pub serial_no_max: u32,
+
+    /// Failed queries in a row after which queries pause, 0 to never pause
+    #[envconfig(from = "BOT_BREAKER_THRESHOLD", default = "10")]
+    pub breaker_threshold: u32,
+    /// Seconds of the first pause, doubled while the query after each pause fails
+    #[envconfig(from = "BOT_BREAKER_BACKOFF", default = "300")]
+    pub breaker_backoff: u64,
+    /// Seconds the pause grows to at most
+    #[envconfig(from = "BOT_BREAKER_MAX_BACKOFF", default = "3600")]
+    pub breaker_max_backoff: u64,
+
+    /// Periods students can enroll in, outside of which the checker pauses; always checking when
+    /// empty
+    #[envconfig(from = "BOT_ENROLLMENT_PHASES", default = "")]
+    pub enrollment_phases: PhaseList,
+    /// Seconds before a phase opens that users with watches are told about it
+    #[envconfig(from = "BOT_PHASE_NOTICE", default = "86400")]
+    pub phase_notice: u64,
+
+    /// Seconds between two checks, `/force_update everyone` starts the next one early
+    #[envconfig(from = "BOT_CHECK_INTERVAL", default = "180")]
+    pub check_interval: u64,
+    /// Seconds after a `/force_update` before anyone can check their courses again, admins excepted
+    #[envconfig(from = "BOT_FORCE_UPDATE_COOLDOWN", default = "600")]
+    pub force_update_cooldown: u64,
+    /// Seconds after a user's `/force_update` before they can check their courses again
+    #[envconfig(from = "BOT_FORCE_UPDATE_USER_COOLDOWN", default = "3600")]
+    pub force_update_user_cooldown: u64,
+    /// Seconds after a `/check_course` before anyone can check a course again, admins excepted
+    #[envconfig(from = "BOT_CHECK_COURSE_COOLDOWN", default = "30")]
+    pub check_course_cooldown: u64,
+    /// Seconds after a user's `/check_course` before they can check a course again
+    #[envconfig(from = "BOT_CHECK_COURSE_USER_COOLDOWN", default = "300")]
+    pub check_course_user_cooldown: u64,
+    /// Times a course whose query failed is tried again at the end of the same check
+    #[envconfig(from = "BOT_COURSE_RETRIES", default = "2")]
+    pub course_retries: u32,
+    /// Seconds before the first retry of a course, doubled for each one after
+    #[envconfig(from = "BOT_COURSE_RETRY_BACKOFF", default = "10")]
+    pub course_retry_backoff: u64,
+    #[envconfig(from = "BOT_MAX_COURSES_PER_USER", default = "20")]
+    pub max_courses_per_user: usize,
+    /// Seconds to hold back repeated alerts for the same course
+    #[envconfig(from = "BOT_NOTIFY_COOLDOWN", default = "1800")]
+    pub notify_cooldown: u64,
+    /// Seconds before cached course names and teachers are fetched again
+    #[envconfig(from = "BOT_COURSE_META_TTL", default = "86400")]
+    pub course_meta_ttl: u64,
+    /// Seconds between checks that every watched course is still offered, 0 to never check
+    #[envconfig(from = "BOT_WATCH_SWEEP_INTERVAL", default = "86400")]
+    pub watch_sweep_interval: u64,
+    /// Failed alerts in a row after which a user's watches are no longer checked
+    #[envconfig(from = "BOT_UNREACHABLE_AFTER", default = "10")]
+    pub unreachable_after: u32,
+    /// Days a disabled user's data is kept before it is deleted
+    #[envconfig(from = "BOT_UNREACHABLE_PURGE_DAYS", default = "30")]
+    pub unreachable_purge_days: u64,
+    /// Guild channel alerts go to, mentioning the user, when they cannot be DMed
+    #[envconfig(from = "BOT_DM_FALLBACK_CHANNEL")]
+    pub dm_fallback_channel: Option<u64>,
 
     #[envconfig(from = "BOT_DISCORD_TOKEN")]
     pub discord_token: String,
+    /// Prefix of text commands such as `/add_course 1234` sent as messages, empty for none
+    #[envconfig(from = "BOT_DISCORD_PREFIX", default = "/")]
+    pub discord_prefix: String,
+    /// Whether mentioning the bot works as a prefix, as in `@bot add_course 1234`
+    #[envconfig(from = "BOT_DISCORD_MENTION_PREFIX", default = "true")]
+    pub discord_mention_prefix: bool,
+    /// Whether commands can be sent as messages at all, slash commands always work
+    #[envconfig(from = "BOT_DISCORD_PREFIX_COMMANDS", default = "true")]
+    pub discord_prefix_commands: bool,
+    /// User DMed when the enrollment system cannot be queried at all
+    #[envconfig(from = "BOT_OWNER_ID")]
+    pub owner_id: Option<u64>,
+    /// Users allowed to run every command besides the application's owners and `BOT_OWNER_ID`
+    #[envconfig(from = "BOT_OWNER_IDS", default = "")]
+    pub owner_ids: IdList,
+    /// Users allowed to run the admin commands
+    #[envconfig(from = "BOT_ADMIN_IDS", default = "")]
+    pub admin_ids: IdList,
+    /// Guild roles whose members may run the admin commands in that guild
+    #[envconfig(from = "BOT_ADMIN_ROLE_IDS", default = "")]
+    pub admin_role_ids: IdList,
+    /// Guild the commands are registered in instead of globally, which takes effect at once
+    /// rather than within an hour; for development
+    #[envconfig(from = "BOT_DEV_GUILD_ID")]
+    pub dev_guild_id: Option<u64>,
+    /// Sentry DSN receiving errors and panics, unset disables reporting
+    #[envconfig(from = "BOT_SENTRY_DSN")]
+    pub sentry_dsn: Option<String>,
+    /// `sqlite://` path, or a `redis://` URL when built with the `redis` feature
+    #[envconfig(from = "BOT_STORAGE_URL", default = "sqlite://course-bot.sqlite")]
+    pub storage_url: String,
+    /// Base64 of a 32 byte key encrypting stored user data, unset or blank to store it as is
+    #[envconfig(from = "BOT_STORAGE_KEY")]
+    pub storage_key: Option<String>,
+    /// Old `kv` database, imported into SQLite once when present
     #[envconfig(from = "BOT_DB_PATH", default = "./db")]
     pub db_path: String,
+    /// Port serving `/metrics` and `/healthz`, unset disables the HTTP server
+    #[envconfig(from = "BOT_HTTP_PORT")]
+    pub http_port: Option<u16>,
+    /// Address the HTTP server is reached at, such as `https://bot.example.com`
+    #[envconfig(from = "BOT_PUBLIC_URL")]
+    pub public_url: Option<String>,
+    /// Discord OAuth2 client signing users into the dashboard, which is off until this, the
+    /// secret and the public URL are set
+    #[envconfig(from = "BOT_OAUTH_CLIENT_ID")]
+    pub oauth_client_id: Option<String>,
+    #[envconfig(from = "BOT_OAUTH_CLIENT_SECRET")]
+    pub oauth_client_secret: Option<String>,
+    /// Port of the gRPC admin service, unset disables it; needs the `grpc` feature
+    #[envconfig(from = "BOT_GRPC_PORT")]
+    pub grpc_port: Option<u16>,
+    /// Bearer token every admin call must carry
+    #[envconfig(from = "BOT_GRPC_TOKEN")]
+    pub grpc_token: Option<String>,
+    /// Seconds a replica holds the checker lease, unset when only one instance shares the
+    /// storage and it always runs the checker
+    #[envconfig(from = "BOT_LEADER_LEASE")]
+    pub leader_lease: Option<u64>,
+    /// Seconds without a finished check cycle before `/healthz` reports failure
+    #[envconfig(from = "BOT_HEALTH_MAX_CYCLE_AGE", default = "900")]
+    pub health_max_cycle_age: u64,
+    /// Seconds a check cycle may run before the watchdog aborts it and resets the crawler
+    #[envconfig(from = "BOT_CYCLE_DEADLINE", default = "600")]
+    pub cycle_deadline: u64,
+    /// Directory for periodic database snapshots, unset disables them
+    #[envconfig(from = "BOT_SNAPSHOT_DIR")]
+    pub snapshot_dir: Option<String>,
+    /// Seconds between snapshots
+    #[envconfig(from = "BOT_SNAPSHOT_INTERVAL", default = "86400")]
+    pub snapshot_interval: u64,
+    /// Snapshots kept, older ones are deleted
+    #[envconfig(from = "BOT_SNAPSHOT_KEEP", default = "7")]
+    pub snapshot_keep: usize,
+}
+
+/// Comma-separated Discord IDs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IdList(pub Vec<u64>);
+
+impl FromStr for IdList {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Comma-separated URLs, without trailing slashes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UrlList(pub Vec<String>);
+
+impl FromStr for UrlList {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split(',')
+                .map(|url| url.trim().trim_end_matches('/'))
+                .filter(|url| !url.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+}
+
+/// `{year}-{term}` with the year in the ROC calendar, such as `113-2`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AcademicTerm(pub Option<(u32, u32)>);
+
+impl FromStr for AcademicTerm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(Self(None));
+        }
+        match s.split_once('-') {
+            Some((year, term @ ("1" | "2"))) => Ok(Self(Some((year.parse()?, term.parse()?)))),
+            _ => bail!("expected a term such as `113-2`, got `{s}`"),
+        }
+    }
+}
+
+/// Comma-separated enrollment phases, see [`Phase`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PhaseList(pub Vec<Phase>);
+
+impl FromStr for PhaseList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|phase| !phase.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// `|`-separated user agents
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserAgentList(pub Vec<String>);
+
+impl FromStr for UserAgentList {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(
+            s.split('|')
+                .map(str::trim)
+                .filter(|agent| !agent.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+}
+
+/// Comma-separated `account:password` pairs, split at the first colon
+#[derive(Clone, Default, PartialEq)]
+pub struct AccountList(pub Vec<(String, String)>);
+
+impl FromStr for AccountList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once(':') {
+                Some((account, password)) if !account.is_empty() => {
+                    Ok((account.to_owned(), password.to_owned()))
+                }
+                _ => bail!("expected `account:password`, got `{pair}`"),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// Only the accounts, the passwords stay out of logs
+impl std::fmt::Debug for AccountList {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|(account, _)| account))
+            .finish()
+    }
+}
+
+impl Config {
+    /// Every enrollment system account with its password, the main one first
+    pub fn ntnu_credentials(&self) -> Vec<(String, String)> {
+        let mut credentials = vec![(self.ntnu_account.clone(), self.ntnu_password.clone())];
+        for pair in &self.ntnu_accounts.0 {
+            if !credentials.iter().any(|(account, _)| account == &pair.0) {
+                credentials.push(pair.clone());
+            }
+        }
+        credentials
+    }
+
+    /// Least time between two requests to the enrollment system
+    pub fn ntnu_min_gap(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.ntnu_min_gap_ms)
+    }
+
+    /// Which backend reads captchas
+    ///
+    /// Unless chosen, the captcha service, or Tesseract when no service is set in builds with the
+    /// `local-captcha` feature.
+    pub fn captcha_kind(&self) -> CaptchaKind {
+        let service_set = !self.captcha_service_uris.0.is_empty();
+        self.captcha_backend
+            .unwrap_or(if service_set || !cfg!(feature = "local-captcha") {
+                CaptchaKind::Service
+            } else {
+                CaptchaKind::Local
+            })
+    }
+
+    /// Where captchas are sent in order of preference, nowhere when they are read locally
+    pub fn captcha_uris(&self) -> Vec<&str> {
+        let uris = self
+            .captcha_service_uris
+            .0
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        match self.captcha_kind() {
+            CaptchaKind::Local => Vec::new(),
+            CaptchaKind::Service if uris.is_empty() => vec!["http://localhost:8080"],
+            CaptchaKind::TwoCaptcha if uris.is_empty() => vec!["https://2captcha.com"],
+            _ => uris,
+        }
+    }
+
+    /// Academic year and term enrolled in at `now`, derived from the date unless configured
+    pub fn term(&self, now: u64) -> (u32, u32) {
+        self.academic_term
+            .0
+            .unwrap_or_else(|| crate::open_course::academic_term(now))
+    }
+
+    /// Proxy of the enrollment system requests, checked when the settings are read
+    pub fn proxy(&self) -> Option<reqwest::Proxy> {
+        self.http_proxy
+            .as_deref()
+            .map(|url| reqwest::Proxy::all(url).expect("proxy URL checked on load"))
+    }
+
+    /// Subsites of the enrollment system in order of preference, without duplicates
+    pub fn ntnu_urls(&self) -> Vec<String> {
+        let mut urls = vec![self.ntnu_url.trim_end_matches('/').to_owned()];
+        for url in &self.ntnu_fallback_urls.0 {
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+
+    pub fn serial_no_range(&self) -> RangeInclusive<u32> {
+        self.serial_no_min..=self.serial_no_max
+    }
+
+    /// Read the settings, environment variables overriding the ones in the file at `path`
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let file = path
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("fail to read config file {}", path.display()))
+            })
+            .transpose()?;
+        Self::from_sources(file.as_deref(), std::env::vars())
+    }
+
+    pub fn from_sources(
+        file: Option<&str>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> anyhow::Result<Self> {
+        let mut vars = HashMap::new();
+        if let Some(file) = file {
+            let table = toml::from_str::<Table>(file).context("invalid config file")?;
+            flatten(ENV_PREFIX, &table, &mut vars)?;
+        }
+        vars.extend(env);
+        read_secrets(&mut vars)?;
+        let config = Self::init_from_hashmap(&vars)?;
+        if let Some(url) = &config.http_proxy {
+            reqwest::Proxy::all(url).context("invalid BOT_HTTP_PROXY")?;
+        }
+        if !SOURCES.contains(&config.course_source.as_str()) {
+            bail!(
+                "BOT_COURSE_SOURCE must be one of {}, got `{}`",
+                SOURCES.join(", "),
+                config.course_source
+            );
+        }
+        match config.captcha_kind() {
+            CaptchaKind::Local if !cfg!(feature = "local-captcha") => {
+                bail!("BOT_CAPTCHA_BACKEND=local needs a build with the local-captcha feature")
+            }
+            CaptchaKind::TwoCaptcha if config.captcha_api_key.is_none() => {
+                bail!("BOT_CAPTCHA_BACKEND=2captcha needs BOT_CAPTCHA_API_KEY")
+            }
+            _ => (),
+        }
+        Ok(config)
+    }
+}
+
+/// Replace `*_FILE` variables of secrets with the content of the files they name
+fn read_secrets(vars: &mut HashMap<String, String>) -> anyhow::Result<()> {
+    for name in SECRETS {
+        let Some(path) = vars.remove(&format!("{name}_FILE")) else {
+            continue;
+        };
+        if vars.contains_key(name) {
+            bail!("set either {name} or {name}_FILE, not both");
+        }
+        let secret = std::fs::read_to_string(&path)
+            .with_context(|| format!("fail to read {name}_FILE {path}"))?;
+        // editors and `echo` leave a trailing newline
+        vars.insert(
+            name.to_owned(),
+            secret.trim_end_matches(['\r', '\n']).to_owned(),
+        );
+    }
+    Ok(())
+}
+
+/// Collect the values of `table` under the variable names they stand for
+fn flatten(prefix: &str, table: &Table, vars: &mut HashMap<String, String>) -> anyhow::Result<()> {
+    for (key, value) in table {
+        let name = format!("{prefix}_{}", key.to_uppercase());
+        let value = match value {
+            Value::Table(table) => {
+                flatten(&name, table, vars)?;
+                continue;
+            }
+            // lists are comma-separated in the environment
+            Value::Array(items) => items
+                .iter()
+                .map(|item| scalar(key, item))
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .join(","),
+            value => scalar(key, value)?,
+        };
+        vars.insert(name, value);
+    }
+    Ok(())
+}
+
+fn scalar(key: &str, value: &Value) -> anyhow::Result<String> {
+    Ok(match value {
+        Value::String(value) => value.clone(),
+        Value::Integer(value) => value.to_string(),
+        Value::Float(value) => value.to_string(),
+        Value::Boolean(value) => value.to_string(),
+        _ => bail!("config key `{key}` must be a string, number, boolean or a list of them"),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_from_sources() {
+        let file = r#"
+            owner_id = 42
+            admin_ids = [1, 2]
+
+            [ntnu]
+            account = "41000000S"
+            password = "from file"
+            retry = 3
+
+            [discord]
+            token = "token"
+
+            [snapshot]
+            dir = "./snapshots"
+        "#;
+        let env = [("BOT_NTNU_PASSWORD".to_owned(), "from env".to_owned())];
+        let config = Config::from_sources(Some(file), env).unwrap();
+        assert_eq!(config.ntnu_account, "41000000S");
+        assert_eq!(config.ntnu_password, "from env");
+        assert_eq!(config.api_retry, 3);
+        assert_eq!(config.owner_id, Some(42));
+        assert_eq!(config.admin_ids, IdList(vec![1, 2]));
+        assert_eq!(config.snapshot_dir.as_deref(), Some("./snapshots"));
+        // untouched settings keep their defaults
+        assert_eq!(config.captcha_retry, 20);
+        assert_eq!(config.ntnu_keepalive, 60);
+        assert_eq!(config.ntnu_requests_per_minute, 120);
+        assert_eq!(
+            config.captcha_uris().is_empty(),
+            cfg!(feature = "local-captcha")
+        );
+        let with_captcha = |backend: &str| {
+            let env = [("BOT_CAPTCHA_BACKEND".to_owned(), backend.to_owned())];
+            Config::from_sources(Some(file), env)
+        };
+        assert!(with_captcha("2captcha").is_err());
+        assert!(with_captcha("tesseract").is_err());
+        assert_eq!(config.course_source, "ntnu");
+        let env = [("BOT_COURSE_SOURCE".to_owned(), "nthu".to_owned())];
+        assert!(Config::from_sources(Some(file), env).is_err());
+        let paid = Config::from_sources(
+            Some(file),
+            [
+                ("BOT_CAPTCHA_BACKEND".to_owned(), "2captcha".to_owned()),
+                ("BOT_CAPTCHA_API_KEY".to_owned(), "key".to_owned()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(paid.captcha_uris(), ["https://2captcha.com"]);
+        assert_eq!("".parse(), Ok(IdList::default()));
+        assert_eq!(" 1, 2,".parse(), Ok(IdList(vec![1, 2])));
+        assert!("1;2".parse::<IdList>().is_err());
+
+        let accounts: AccountList = "41000001S:a:b, 41000002S:c,".parse().unwrap();
+        assert_eq!(
+            accounts.0,
+            [
+                ("41000001S".to_owned(), "a:b".to_owned()),
+                ("41000002S".to_owned(), "c".to_owned())
+            ]
+        );
+        assert_eq!(format!("{accounts:?}"), r#"["41000001S", "41000002S"]"#);
+        assert!("41000001S".parse::<AccountList>().is_err());
+        assert_eq!("113-2".parse::<AcademicTerm>().unwrap().0, Some((113, 2)));
+        assert_eq!("".parse::<AcademicTerm>().unwrap().0, None);
+        assert!("113-4".parse::<AcademicTerm>().is_err());
+        let phases: PhaseList =
+            "初選=2025-01-06T09:00/2025-01-10T17:00, 2025-02-17T09:00/2025-02-28T17:00"
+                .parse()
+                .unwrap();
+        assert_eq!(phases.0.len(), 2);
+        let agents: UserAgentList = "A (X11, Linux) | B |".parse().unwrap();
+        assert_eq!(agents.0, ["A (X11, Linux)", "B"]);
+        let config = Config {
+            ntnu_accounts: "41000000S:again,41000001S:x".parse().unwrap(),
+            ..config
+        };
+        let accounts = config.ntnu_credentials();
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].1, "from env");
+
+        // the example spells out the defaults
+        let example = Config::from_sources(Some(include_str!("../config.example.toml")), []);
+        let example = example.unwrap();
+        assert_eq!(
+            example.ntnu_urls(),
+            [
+                "https://cos1s.ntnu.edu.tw",
+                "https://cos2s.ntnu.edu.tw",
+                "https://cos3s.ntnu.edu.tw"
+            ]
+        );
+
+        assert!(example.proxy().is_none());
+
+        let proxied = "[discord]\ntoken = \"t\"\n[ntnu]\naccount = \"a\"\npassword = \"b\"";
+        let env = [(
+            "BOT_HTTP_PROXY".to_owned(),
+            "socks5://127.0.0.1:1080".to_owned(),
+        )];
+        assert!(Config::from_sources(Some(proxied), env)
+            .unwrap()
+            .proxy()
+            .is_some());
+        let env = [("BOT_HTTP_PROXY".to_owned(), "not a url".to_owned())];
+        assert!(Config::from_sources(Some(proxied), env).is_err());
+
+        assert!(Config::from_sources(Some("[ntnu]\nretry = [[1]]"), []).is_err());
+        // the token is required
+        assert!(
+            Config::from_sources(Some("[ntnu]\naccount = \"a\"\npassword = \"b\""), []).is_err()
+        );
+    }
+
+    #[test]
+    fn test_secret_files() {
+        let path = std::env::temp_dir().join(format!("course-bot-token-{}", std::process::id()));
+        std::fs::write(&path, "from file\n").unwrap();
+        let file = format!(
+            "[ntnu]\naccount = \"a\"\npassword = \"b\"\n[discord]\ntoken_file = {:?}",
+            path.display().to_string()
+        );
+        let config = Config::from_sources(Some(&file), []).unwrap();
+        assert_eq!(config.discord_token, "from file");
+
+        let env = [("BOT_DISCORD_TOKEN".to_owned(), "from env".to_owned())];
+        assert!(Config::from_sources(Some(&file), env).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(Config::from_sources(Some(&file), []).is_err());
+    }
 }
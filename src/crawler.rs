@@ -1,69 +1,648 @@
 use core::str;
-use std::{collections::HashMap, num::ParseIntError, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    ops::RangeInclusive,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
-use anyhow::{bail, Result};
-use log::{trace, warn};
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
 use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::time::sleep;
+use tracing::{error, info, instrument, trace, warn};
+
+use crate::{
+    breaker::{BreakerEvent, CircuitBreaker},
+    captcha::{CaptchaServiceError, CaptchaSolver},
+    metrics::{self, METRICS},
+    open_course::academic_term,
+    page::{Grid, GridRow, IndexPage, LoginPage, PageError},
+    ratelimit::RateLimiter,
+    source::CourseSource,
+};
 
 #[derive(Debug, Error, PartialEq)]
 pub enum NtnuCrawlerError {
     #[error("course system entered invalid state")]
     BrokenStateMachine,
+    #[error("login max retry reached")]
+    LoginFailed,
+    #[error("course system is under maintenance")]
+    Maintenance,
+    #[error("queries paused for {0}s after repeated failures")]
+    Paused(u64),
+    #[error("the enrollment system locked the account")]
+    Locked,
+    #[error("the enrollment system wants the account's password changed")]
+    CredentialAction,
+    #[error("the enrollment system kept answering queries with empty pages")]
+    EmptyResponse,
+    #[error("the enrollment system only serves the term being enrolled, not {0}-{1}")]
+    OtherTerm(u32, u32),
 }
 
+/// What the enrollment system answers instead of logging a locked account in
+const LOCKOUT_TEXTS: [&str; 2] = ["帳號已被鎖定", "帳號已鎖定"];
+
+/// What the page forcing a password change says, which takes the place of the index page
+const CREDENTIAL_ACTION_TEXTS: [&str; 3] = ["密碼已過期", "請先變更密碼", "請先修改密碼"];
+
 impl NtnuCrawlerError {
     pub fn check_response(text: &str) -> Result<(), Self> {
+        if LOCKOUT_TEXTS.iter().any(|lockout| text.contains(lockout)) {
+            return Err(Self::Locked);
+        }
+        if CREDENTIAL_ACTION_TEXTS
+            .iter()
+            .any(|prompt| text.contains(prompt))
+        {
+            return Err(Self::CredentialAction);
+        }
         if text.contains("不合法執行選課系統") {
             return Err(Self::BrokenStateMachine);
         }
+        if text.contains("系統維護") {
+            return Err(Self::Maintenance);
+        }
         Ok(())
     }
+
+    /// Whether logging in again may get past `error`
+    fn needs_login(error: &anyhow::Error) -> bool {
+        match error.downcast_ref() {
+            Some(Self::Maintenance | Self::Locked | Self::CredentialAction) => false,
+            Some(_) => true,
+            None => error.is::<CaptchaServiceError>(),
+        }
+    }
 }
 
-pub struct NtnuCrawlerManager {
+/// Why the enrollment system cannot be queried, as far as an error tells
+#[derive(Debug, Error, PartialEq)]
+pub enum FailureCause {
+    #[error("the captcha service is unreachable")]
+    CaptchaUnreachable,
+    #[error("logging into the enrollment system keeps failing")]
+    LoginFailed,
+    #[error("the enrollment system locked the account")]
+    AccountLocked,
+    #[error("the enrollment system wants the account's password changed")]
+    CredentialAction,
+    #[error("the enrollment system is under maintenance")]
+    Maintenance,
+    #[error("the enrollment system keeps returning errors")]
+    Other,
+}
+
+impl FailureCause {
+    /// `captcha_uris` is empty when captchas are read locally
+    pub fn of(error: &anyhow::Error, captcha_uris: &[&str]) -> Self {
+        let mut causes = error.chain();
+        if causes.any(|cause| {
+            let url = cause
+                .downcast_ref::<reqwest::Error>()
+                .and_then(reqwest::Error::url);
+            url.is_some_and(|url| captcha_uris.iter().any(|uri| url.as_str().starts_with(uri)))
+        }) {
+            Self::CaptchaUnreachable
+        } else if error
+            .chain()
+            .any(|cause| cause.downcast_ref() == Some(&NtnuCrawlerError::Locked))
+        {
+            Self::AccountLocked
+        } else if error
+            .chain()
+            .any(|cause| cause.downcast_ref() == Some(&NtnuCrawlerError::CredentialAction))
+        {
+            Self::CredentialAction
+        } else if error
+            .chain()
+            .any(|cause| cause.downcast_ref() == Some(&NtnuCrawlerError::LoginFailed))
+        {
+            Self::LoginFailed
+        } else if error
+            .chain()
+            .any(|cause| cause.downcast_ref() == Some(&NtnuCrawlerError::Maintenance))
+        {
+            Self::Maintenance
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// Serial numbers of NTNU courses are always this many digits, zero padded
+pub const SERIAL_NO_LEN: usize = 4;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum SerialNoError {
+    #[error("course ID consists only by decimal digits")]
+    NotDigits,
+    #[error("course ID should be exactly {SERIAL_NO_LEN} digits, got {0}")]
+    Length(usize),
+    #[error("course ID should be between {0:04} and {1:04} this semester")]
+    OutOfRange(u32, u32),
+}
+
+/// Check that `id` looks like a serial number the enrollment system could know about
+pub fn validate_serial_no(id: &str, range: &RangeInclusive<u32>) -> Result<(), SerialNoError> {
+    if id.is_empty() || !id.chars().all(|x| x.is_ascii_digit()) {
+        return Err(SerialNoError::NotDigits);
+    }
+    if id.len() != SERIAL_NO_LEN {
+        return Err(SerialNoError::Length(id.len()));
+    }
+    let value: u32 = id.parse().map_err(|_| SerialNoError::NotDigits)?;
+    if !range.contains(&value) {
+        return Err(SerialNoError::OutOfRange(*range.start(), *range.end()));
+    }
+    Ok(())
+}
+
+/// Upper-case a department code such as `csu`, `None` when it cannot be one
+pub fn normalize_dept_code(code: &str) -> Option<String> {
+    let code = code.trim();
+    let valid = (1..=6).contains(&code.len()) && code.chars().all(|c| c.is_ascii_alphanumeric());
+    valid.then(|| code.to_ascii_uppercase())
+}
+
+/// Upper-case a course code such as `csu0001`, `None` when it cannot be one
+pub fn normalize_course_code(code: &str) -> Option<String> {
+    let code = code.trim();
+    let valid = (4..=12).contains(&code.len())
+        && code.chars().all(|c| c.is_ascii_alphanumeric())
+        && code.chars().any(|c| c.is_ascii_alphabetic());
+    valid.then(|| code.to_ascii_uppercase())
+}
+
+/// Seat numbers of a course as listed in the query grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeatCount {
+    pub enrolled: i32,
+    pub quota: i32,
+}
+
+impl SeatCount {
+    pub fn available(&self) -> i32 {
+        (self.quota - self.enrolled).max(0)
+    }
+}
+
+/// What a seat query found out about a course
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CourseStatus {
+    /// Seats anyone may take
+    Available(SeatCount),
+    Full(SeatCount),
+    /// Seats left, but only for students the course's restrictions let in
+    Restricted(SeatCount),
+    /// Not offered this term
+    NotFound,
+}
+
+impl CourseStatus {
+    pub fn new(seats: SeatCount, restricted: bool) -> Self {
+        if seats.available() == 0 {
+            Self::Full(seats)
+        } else if restricted {
+            Self::Restricted(seats)
+        } else {
+            Self::Available(seats)
+        }
+    }
+
+    /// Seat numbers, `None` when the course is not offered
+    pub fn seats(&self) -> Option<SeatCount> {
+        match self {
+            Self::Available(seats) | Self::Full(seats) | Self::Restricted(seats) => Some(*seats),
+            Self::NotFound => None,
+        }
+    }
+}
+
+/// One row of the query grid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CourseInfo {
+    pub serial_no: String,
+    pub course_code: String,
+    pub name: String,
+    pub teacher: String,
+    /// `必` for required, `選` for elective, as shown by the grid
+    pub option_code: String,
+    pub seats: SeatCount,
+    /// Who may enroll, such as `限本系` or a required authorization code, empty when anyone may
+    pub restrictions: Vec<String>,
+}
+
+/// Filters of the course query form, unset fields are left blank
+///
+/// Besides serial numbers the form lists courses by department, general education (通識) domain
+/// and grade, which the `with_*` methods combine.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CourseQuery {
+    pub serial_no: Option<String>,
+    pub name: Option<String>,
+    pub teacher: Option<String>,
+    pub dept_code: Option<String>,
+    pub course_code: Option<String>,
+    /// General education domain such as `人文藝術`
+    pub general_core: Option<String>,
+    /// Year of study the course is offered to
+    pub grade: Option<u32>,
+}
+
+impl CourseQuery {
+    /// The course with serial number `serial_no`
+    pub fn serial_no(serial_no: &str) -> Self {
+        Self::default().with_serial_no(serial_no)
+    }
+
+    pub fn with_serial_no(mut self, serial_no: &str) -> Self {
+        self.serial_no = Some(serial_no.to_owned());
+        self
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn with_teacher(mut self, teacher: &str) -> Self {
+        self.teacher = Some(teacher.to_owned());
+        self
+    }
+
+    pub fn with_dept_code(mut self, dept_code: &str) -> Self {
+        self.dept_code = Some(dept_code.to_owned());
+        self
+    }
+
+    pub fn with_general_core(mut self, domain: &str) -> Self {
+        self.general_core = Some(domain.to_owned());
+        self
+    }
+
+    pub fn with_grade(mut self, grade: u32) -> Self {
+        self.grade = Some(grade);
+        self
+    }
+
+    /// Whether nothing narrows the query down, which would list every course
+    pub fn is_empty(&self) -> bool {
+        self.form().is_empty()
+    }
+
+    fn form(&self) -> Vec<(&'static str, String)> {
+        [
+            ("serialNo", self.serial_no.clone()),
+            ("chnName", self.name.clone()),
+            ("teacher", self.teacher.clone()),
+            ("deptCode", self.dept_code.clone()),
+            ("courseCode", self.course_code.clone()),
+            ("generalCore", self.general_core.clone()),
+            ("formS", self.grade.map(|grade| grade.to_string())),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.map(|value| (key, value)))
+        .collect()
+    }
+}
+
+/// How long an account whose logins keep failing sits out of the rotation, in case it was locked
+const PARK_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Queries in a row the subsite must fail before moving to another one
+const SUBSITE_FAILURE_LIMIT: u32 = 3;
+
+/// Something the owner has to act on about an enrollment system account
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountEvent {
+    /// Locked, left out of the rotation until restart
+    Locked(String),
+    /// The enrollment system wants the password changed before it lets the account in
+    CredentialAction(String),
+}
+
+/// An enrollment system account with its own session
+struct Account {
     crawler: NtnuCrawler,
+    /// Left out of the rotation until then
+    parked_until: Option<Instant>,
+    /// Locked by the enrollment system, which further logins would only prolong, so left out of
+    /// the rotation until restart
+    locked: bool,
+    /// Sent to a forced password change, the owner told once until a login gets through
+    credential_action: bool,
+    /// Logged in since the session was last handed out by [`NtnuCrawlerManager::take_sessions`]
+    session_changed: bool,
+}
+
+/// Queries the enrollment system, taking turns among the configured accounts
+pub struct NtnuCrawlerManager {
+    /// Never empty
+    accounts: Vec<Account>,
+    /// Account the next query is sent from
+    next: usize,
+    /// Subsites in order of preference, every account uses the current one
+    subsites: Vec<String>,
+    subsite: usize,
+    /// Queries in a row that failed because of the current subsite
+    subsite_failures: u32,
+    /// Asks other subsites whether they are up before moving there
+    probe: reqwest::Client,
+    /// Shared by the crawlers of every account and the probe
+    limiter: Arc<RateLimiter>,
+    breaker: CircuitBreaker,
+    /// Breaker changes not yet picked up by [`Self::take_breaker_events`]
+    breaker_events: Vec<BreakerEvent>,
+    /// Account events not yet picked up by [`Self::take_account_events`]
+    account_events: Vec<AccountEvent>,
     max_retries: i32,
+    logins: u64,
+    /// Academic year in the ROC calendar and term, derived from the date when unset
+    term: Option<(u32, u32)>,
 }
 
 impl NtnuCrawlerManager {
-    pub fn new(config: &crate::config::Config, subsite: i32) -> Self {
-        let crawler = NtnuCrawler::new(
-            format!("https://cos{}s.ntnu.edu.tw", subsite),
-            config.captcha_service_uri.clone(),
-            config.ntnu_account.clone(),
-            config.ntnu_password.clone(),
-            config.api_retry,
-            config.captcha_retry,
-        );
+    pub fn new(config: &crate::config::Config) -> Self {
+        let subsites = config.ntnu_urls();
+        let limiter = Arc::new(RateLimiter::new(
+            config.ntnu_requests_per_minute,
+            config.ntnu_min_gap(),
+        ));
+        let client = ClientSettings::new(config, limiter.clone());
+        let captcha = CaptchaSolver::from_config(config);
+        let accounts = config
+            .ntnu_credentials()
+            .into_iter()
+            .map(|(account, password)| Account {
+                crawler: NtnuCrawler::new(
+                    subsites[0].clone(),
+                    captcha.clone(),
+                    account,
+                    password,
+                    config.api_retry,
+                    config.captcha_retry,
+                    &client,
+                ),
+                parked_until: None,
+                locked: false,
+                credential_action: false,
+                session_changed: false,
+            })
+            .collect();
         Self {
-            crawler,
+            accounts,
+            next: 0,
+            subsites,
+            subsite: 0,
+            subsite_failures: 0,
+            probe: client
+                .builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            limiter,
+            breaker: CircuitBreaker::new(
+                config.breaker_threshold,
+                Duration::from_secs(config.breaker_backoff),
+                Duration::from_secs(config.breaker_max_backoff),
+            ),
+            breaker_events: Vec::new(),
+            account_events: Vec::new(),
             max_retries: config.api_retry,
+            logins: 0,
+            term: config.academic_term.0,
         }
     }
 
-    pub async fn init(&mut self) -> Result<()> {
+    /// Start over with fresh HTTP clients and no sessions, keeping the login count, subsite,
+    /// request budget, breaker and locked accounts
+    pub fn reset(&mut self, config: &crate::config::Config) {
+        let locked = self
+            .accounts
+            .iter()
+            .filter(|account| account.locked)
+            .map(|account| account.crawler.account.clone())
+            .collect::<Vec<_>>();
+        self.accounts = Self::new(config).accounts;
+        let subsite = self.subsites[self.subsite].clone();
+        for account in &mut self.accounts {
+            account.crawler.move_to(subsite.clone());
+            account.crawler.limiter = self.limiter.clone();
+            account.locked = locked.contains(&account.crawler.account);
+        }
+    }
+
+    /// Take up reloaded retry counts, request budget and breaker settings, the sessions are kept
+    pub fn configure(&mut self, config: &crate::config::Config) {
+        self.limiter
+            .configure(config.ntnu_requests_per_minute, config.ntnu_min_gap());
+        self.breaker.configure(
+            config.breaker_threshold,
+            Duration::from_secs(config.breaker_backoff),
+            Duration::from_secs(config.breaker_max_backoff),
+        );
+        self.max_retries = config.api_retry;
+        for account in &mut self.accounts {
+            account.crawler.max_retry = config.api_retry;
+            account.crawler.captcha_retry = config.captcha_retry;
+            account.crawler.captcha_solver = account
+                .crawler
+                .captcha_solver
+                .clone()
+                .with_voting(config.captcha_samples, config.captcha_min_agreement)
+                .with_rejected_dir(
+                    config
+                        .captcha_rejected_dir
+                        .as_ref()
+                        .map(std::path::PathBuf::from),
+                );
+        }
+    }
+
+    /// How many times a session was (re)established since start
+    pub fn logins(&self) -> u64 {
+        self.logins
+    }
+
+    /// Names of the accounts, in rotation order
+    pub fn accounts(&self) -> impl Iterator<Item = &str> {
+        self.accounts
+            .iter()
+            .map(|account| account.crawler.account.as_str())
+    }
+
+    /// Touch the enrollment system so the sessions do not time out between checks
+    ///
+    /// Accounts that never logged in are skipped; a session found dead is left to the next query.
+    pub async fn keep_alive(&mut self) -> Result<()> {
+        if self.paused().is_some() {
+            return Ok(());
+        }
+        for account in &mut self.accounts {
+            if account.crawler.has_session() {
+                account.crawler.keep_alive().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Cookies of the sessions logged into since the last call, by account, to be stored for the
+    /// next start
+    pub fn take_sessions(&mut self) -> Vec<(String, String)> {
+        let mut sessions = Vec::new();
+        for account in &mut self.accounts {
+            if !std::mem::take(&mut account.session_changed) {
+                continue;
+            }
+            match account.crawler.save_cookies() {
+                Ok(cookies) => sessions.push((account.crawler.account.clone(), cookies)),
+                Err(e) => warn!("fail to save the enrollment system session: {e}"),
+            }
+        }
+        sessions
+    }
+
+    /// Continue a session of `account` stored by an earlier run
+    ///
+    /// An expired session is rejected by the first query like no session at all, which logs in
+    /// afresh.
+    pub fn restore_session(&mut self, account: &str, cookies: &str) -> Result<()> {
+        match self
+            .accounts
+            .iter_mut()
+            .find(|candidate| candidate.crawler.account == account)
+        {
+            Some(account) => account.crawler.load_cookies(cookies),
+            None => bail!("no account {account} is configured"),
+        }
+    }
+
+    /// Whether every account is locked, which leaves nothing to query with until restart
+    pub fn disabled(&self) -> bool {
+        self.accounts.iter().all(|account| account.locked)
+    }
+
+    /// Account events since the last call, oldest first
+    pub fn take_account_events(&mut self) -> Vec<AccountEvent> {
+        std::mem::take(&mut self.account_events)
+    }
+
+    /// Index of the next account in turn that is neither parked nor locked
+    fn rotate(&mut self) -> Result<usize> {
+        if self.disabled() {
+            bail!(anyhow!(NtnuCrawlerError::Locked).context("every account is locked"));
+        }
+        let now = Instant::now();
+        for offset in 0..self.accounts.len() {
+            let index = (self.next + offset) % self.accounts.len();
+            let account = &mut self.accounts[index];
+            if account.locked || account.parked_until.is_some_and(|until| until > now) {
+                continue;
+            }
+            if account.parked_until.take().is_some() {
+                info!(
+                    account = account.crawler.account,
+                    "account back in rotation"
+                );
+            }
+            self.next = index + 1;
+            return Ok(index);
+        }
+        Err(anyhow!(NtnuCrawlerError::LoginFailed).context("every account is parked"))
+    }
+
+    /// Log `accounts[index]` in again, parking it when that keeps failing and others can stand in
+    #[instrument(skip(self))]
+    async fn init(&mut self, index: usize) -> Result<()> {
         trace!("start init");
-        self.crawler.clear();
+        let account = &mut self.accounts[index];
+        account.crawler.clear();
         trace!("start login");
-        self.crawler.login().await?;
-        trace!("start landing page");
-        self.crawler.landing_page().await?;
+        self.logins += 1;
+        let mut result = account.crawler.login().await;
+        if result.is_ok() {
+            trace!("start landing page");
+            result = account.crawler.landing_page().await;
+        }
+        if let Err(e) = result {
+            self.account_failed(index, &e);
+            return Err(e);
+        }
+        let account = &mut self.accounts[index];
+        account.credential_action = false;
+        account.session_changed = true;
         trace!("end init");
         Ok(())
     }
 
-    pub async fn query(&mut self, course_id: &str) -> Result<bool> {
+    /// Take `accounts[index]` out of the rotation as far as `error` calls for
+    fn account_failed(&mut self, index: usize, error: &anyhow::Error) {
+        let others = self.accounts.len() > 1;
+        let account = &mut self.accounts[index];
+        let name = account.crawler.account.clone();
+        match error.downcast_ref() {
+            Some(NtnuCrawlerError::Locked) => {
+                error!(
+                    account = name,
+                    "enrollment system locked the account, no longer logging in with it"
+                );
+                account.locked = true;
+                self.account_events.push(AccountEvent::Locked(name));
+                METRICS.locked_accounts.fetch_add(1, Ordering::Relaxed);
+            }
+            Some(NtnuCrawlerError::CredentialAction) => {
+                error!(
+                    account = name,
+                    "enrollment system wants the password changed"
+                );
+                if others {
+                    account.parked_until = Some(Instant::now() + PARK_DURATION);
+                }
+                if !std::mem::replace(&mut account.credential_action, true) {
+                    self.account_events
+                        .push(AccountEvent::CredentialAction(name));
+                }
+            }
+            Some(NtnuCrawlerError::LoginFailed) if others => {
+                warn!(
+                    account = name,
+                    "parking account for {PARK_DURATION:?} after failed logins"
+                );
+                account.parked_until = Some(Instant::now() + PARK_DURATION);
+            }
+            _ => (),
+        }
+    }
+
+    /// Seats of a course and whether it is listed at all
+    pub async fn query(&mut self, course_id: &str) -> Result<CourseStatus> {
+        self.check_breaker()?;
+        let result = self.query_subsite(course_id).await;
+        self.track_subsite(result.as_ref().err()).await;
+        self.track_breaker(result.is_ok());
+        result
+    }
+
+    #[instrument(skip(self))]
+    async fn query_subsite(&mut self, course_id: &str) -> Result<CourseStatus> {
+        let index = self.rotate()?;
         let mut retries = 0;
         loop {
-            match self.crawler.query(course_id).await {
-                Ok(result) => break Ok(result != 0),
+            metrics::inc(&METRICS.queries);
+            match self.accounts[index].crawler.query(course_id).await {
+                Ok(result) => break Ok(result),
                 Err(e) => {
-                    if e.is::<NtnuCrawlerError>() || e.is::<CaptchaServiceError>() {
-                        self.init().await?;
+                    if NtnuCrawlerError::needs_login(&e) {
+                        self.init(index).await?;
                         if retries > self.max_retries {
+                            if e.is::<NtnuCrawlerError>() {
+                                error!(course_id, retries, "enrollment system kept breaking: {e}");
+                            }
                             break Err(e);
                         }
                     } else {
@@ -74,6 +653,187 @@ impl NtnuCrawlerManager {
             retries += 1;
         }
     }
+
+    /// Every course matching all filters of `query`
+    pub async fn search(&mut self, query: &CourseQuery) -> Result<Vec<CourseInfo>> {
+        self.check_breaker()?;
+        let result = self.search_subsite(query).await;
+        self.track_subsite(result.as_ref().err()).await;
+        self.track_breaker(result.is_ok());
+        result
+    }
+
+    #[instrument(skip(self))]
+    async fn search_subsite(&mut self, query: &CourseQuery) -> Result<Vec<CourseInfo>> {
+        let index = self.rotate()?;
+        let mut retries = 0;
+        loop {
+            metrics::inc(&METRICS.queries);
+            match self.accounts[index].crawler.search(query).await {
+                Ok(result) => break Ok(result),
+                Err(e) => {
+                    if NtnuCrawlerError::needs_login(&e) {
+                        self.init(index).await?;
+                        if retries > self.max_retries {
+                            if e.is::<NtnuCrawlerError>() {
+                                error!(?query, retries, "enrollment system kept breaking: {e}");
+                            }
+                            break Err(e);
+                        }
+                    } else {
+                        break Err(e);
+                    }
+                }
+            }
+            retries += 1;
+        }
+    }
+
+    /// How much longer queries are refused after the enrollment system kept failing
+    pub fn paused(&self) -> Option<Duration> {
+        self.breaker.refused_for(Instant::now())
+    }
+
+    /// Breaker changes since the last call, oldest first
+    pub fn take_breaker_events(&mut self) -> Vec<BreakerEvent> {
+        std::mem::take(&mut self.breaker_events)
+    }
+
+    /// Refuse `term` unless it is the one being enrolled, the only one the system serves
+    fn check_term(&self, term: Option<(u32, u32)>) -> Result<()> {
+        let current = self.term.unwrap_or_else(|| academic_term(crate::db::now()));
+        match term {
+            Some((year, term)) if (year, term) != current => {
+                bail!(NtnuCrawlerError::OtherTerm(year, term))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_breaker(&self) -> Result<()> {
+        match self.paused() {
+            Some(left) => bail!(NtnuCrawlerError::Paused(left.as_secs().max(1))),
+            None => Ok(()),
+        }
+    }
+
+    fn track_breaker(&mut self, success: bool) {
+        let Some(event) = self.breaker.record(success, Instant::now()) else {
+            return;
+        };
+        match event {
+            BreakerEvent::Opened { failures, backoff } => warn!(
+                failures,
+                "pausing queries for {}s after failing in a row",
+                backoff.as_secs()
+            ),
+            BreakerEvent::Closed { paused } => {
+                info!("queries resumed after a {}s pause", paused.as_secs())
+            }
+        }
+        METRICS
+            .breaker_open
+            .store(self.paused().is_some(), Ordering::Relaxed);
+        self.breaker_events.push(event);
+    }
+
+    /// Whether `error` says the current subsite is down rather than the session or the query
+    fn is_subsite_failure(&self, error: &anyhow::Error) -> bool {
+        let subsite = &self.subsites[self.subsite];
+        error.chain().any(|cause| {
+            if cause.downcast_ref() == Some(&NtnuCrawlerError::Maintenance) {
+                return true;
+            }
+            cause.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+                e.url()
+                    .is_some_and(|url| url.as_str().starts_with(subsite.as_str()))
+                    && (e.is_connect()
+                        || e.is_timeout()
+                        || e.status().is_some_and(|status| status.is_server_error()))
+            })
+        })
+    }
+
+    /// Count the failures of the current subsite, moving to another one after too many
+    async fn track_subsite(&mut self, error: Option<&anyhow::Error>) {
+        match error {
+            Some(e) if self.is_subsite_failure(e) => self.subsite_failures += 1,
+            _ => self.subsite_failures = 0,
+        }
+        if self.subsite_failures >= SUBSITE_FAILURE_LIMIT && self.subsites.len() > 1 {
+            self.fail_over().await;
+        }
+    }
+
+    /// Move every account to the first other subsite that is up, in order of preference
+    ///
+    /// Sessions do not carry over, the next query on each account logs in.
+    async fn fail_over(&mut self) {
+        let current = self.subsites[self.subsite].clone();
+        for offset in 1..self.subsites.len() {
+            let index = (self.subsite + offset) % self.subsites.len();
+            let candidate = &self.subsites[index];
+            if let Err(e) = self.check_subsite(candidate).await {
+                warn!("subsite {candidate} is unavailable too: {e:#}");
+                continue;
+            }
+            warn!("Moving from subsite {current} to {candidate} after repeated failures");
+            for account in &mut self.accounts {
+                account.crawler.move_to(candidate.clone());
+            }
+            self.subsite = index;
+            self.subsite_failures = 0;
+            return;
+        }
+        // tried again after the next failures
+        self.subsite_failures = 0;
+    }
+
+    /// Load the login page of a subsite, which must answer without a maintenance notice
+    async fn check_subsite(&self, subsite: &str) -> Result<()> {
+        self.limiter.acquire().await;
+        let text = self
+            .probe
+            .get(format!("{subsite}/AasEnrollStudent/LoginCheckCtrl"))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        if text.contains("系統維護") {
+            bail!(NtnuCrawlerError::Maintenance);
+        }
+        Ok(())
+    }
+
+    /// Every course offered by a department this semester
+    pub async fn department(&mut self, dept_code: &str) -> Result<Vec<CourseInfo>> {
+        self.search(&CourseQuery::default().with_dept_code(dept_code))
+            .await
+    }
+}
+
+#[async_trait]
+impl CourseSource for NtnuCrawlerManager {
+    async fn init(&mut self) -> Result<()> {
+        let index = self.rotate()?;
+        NtnuCrawlerManager::init(self, index).await
+    }
+
+    async fn query(&mut self, course_id: &str, term: Option<(u32, u32)>) -> Result<CourseStatus> {
+        self.check_term(term)?;
+        NtnuCrawlerManager::query(self, course_id).await
+    }
+
+    async fn fetch_info(
+        &mut self,
+        course_id: &str,
+        term: Option<(u32, u32)>,
+    ) -> Result<Option<CourseInfo>> {
+        self.check_term(term)?;
+        let courses = self.search(&CourseQuery::serial_no(course_id)).await?;
+        Ok(courses.into_iter().find(|c| c.serial_no == course_id))
+    }
 }
 
 struct NtnuCrawler {
@@ -83,29 +843,94 @@ struct NtnuCrawler {
     cookie_store: Arc<CookieStoreMutex>,
     account: String,
     password: String,
-    magic_regex: regex::Regex,
-    name_regex: regex::Regex,
-    count_regex: regex::Regex,
     max_retry: i32,
     captcha_retry: i32,
+    limiter: Arc<RateLimiter>,
+}
+
+/// Browsers the crawler passes for unless others are configured
+const USER_AGENTS: [&str; 5] = [
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.0.0",
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:125.0) Gecko/20100101 Firefox/125.0",
+    "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+];
+
+/// How the HTTP clients reaching the enrollment system are set up
+#[derive(Clone, Default)]
+struct ClientSettings {
+    proxy: Option<reqwest::Proxy>,
+    /// Every crawler passes for one of them, [`USER_AGENTS`] when empty
+    user_agents: Vec<String>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl ClientSettings {
+    fn new(config: &crate::config::Config, limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            proxy: config.proxy(),
+            user_agents: config.ntnu_user_agents.0.clone(),
+            limiter,
+        }
+    }
+
+    fn builder(&self) -> reqwest::ClientBuilder {
+        let builder = reqwest::Client::builder();
+        match &self.proxy {
+            Some(proxy) => builder.proxy(proxy.clone()),
+            None => builder,
+        }
+    }
+
+    /// A user agent from the pool, picked at random
+    fn user_agent(&self) -> &str {
+        let mut nonce = [0; 4];
+        getrandom::getrandom(&mut nonce).expect("no OS randomness");
+        let pick = u32::from_le_bytes(nonce) as usize;
+        match self.user_agents.len() {
+            0 => USER_AGENTS[pick % USER_AGENTS.len()],
+            len => &self.user_agents[pick % len],
+        }
+    }
+
+    /// A browser session's client, sending the headers a browser would
+    fn session(&self, cookie_store: Arc<CookieStoreMutex>) -> reqwest::Client {
+        use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, ACCEPT_LANGUAGE};
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT,
+            HeaderValue::from_static(
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+            ),
+        );
+        headers.insert(
+            ACCEPT_LANGUAGE,
+            HeaderValue::from_static("zh-TW,zh;q=0.9,en-US;q=0.8,en;q=0.7"),
+        );
+        self.builder()
+            .cookie_provider(cookie_store)
+            .user_agent(self.user_agent())
+            .default_headers(headers)
+            .build()
+            .unwrap()
+    }
 }
 
 impl NtnuCrawler {
     fn new(
         ntnu_endpoint_root: String,
-        captcha_endpoint_root: String,
+        captcha_solver: CaptchaSolver,
         account: String,
         password: String,
         max_retries: i32,
         captcha_retries: i32,
+        client: &ClientSettings,
     ) -> Self {
-        let captcha_solver = CaptchaSolver::new(captcha_endpoint_root);
         let cookie_store = Arc::from(CookieStoreMutex::new(CookieStore::new(None)));
-        let client = reqwest::Client::builder()
-            .cookie_provider(cookie_store.clone())
-            .user_agent("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15")
-            .build()
-            .unwrap();
+        let limiter = client.limiter.clone();
+        let client = client.session(cookie_store.clone());
         Self {
             captcha_solver,
             endpoint_root: ntnu_endpoint_root,
@@ -113,14 +938,9 @@ impl NtnuCrawler {
             cookie_store,
             account,
             password,
-            magic_regex: regex::Regex::new(r"url:'.+id='\s+\+\s+'(.+)',?").unwrap(),
-            name_regex: regex::RegexBuilder::new(r"name: ?'stdName',(\r\n.+)+ +value: '(.+)'")
-                .multi_line(true)
-                .build()
-                .unwrap(),
-            count_regex: regex::Regex::new(r#"['"]Count['"] *: *([0-9]+)"#).unwrap(),
             max_retry: max_retries,
             captcha_retry: captcha_retries,
+            limiter,
         }
     }
 
@@ -128,8 +948,62 @@ impl NtnuCrawler {
         self.cookie_store.lock().unwrap().clear();
     }
 
-    async fn captcha(&mut self) -> Result<String> {
+    /// Send the following requests to another subsite, starting without a session
+    fn move_to(&mut self, endpoint_root: String) {
+        self.endpoint_root = endpoint_root;
+        self.clear();
+    }
+
+    fn has_session(&self) -> bool {
+        self.cookie_store
+            .lock()
+            .unwrap()
+            .iter_any()
+            .next()
+            .is_some()
+    }
+
+    /// Reload the course select page, which queries are sent from anyway
+    async fn keep_alive(&mut self) -> Result<()> {
+        self.limiter.acquire().await;
+        let text = self
+            .client
+            .get(format!(
+                "{}/AasEnrollStudent/CourseQueryCtrl",
+                self.endpoint_root
+            ))
+            .query(&[("action", "query")])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        NtnuCrawlerError::check_response(&text)?;
+        Ok(())
+    }
+
+    /// Every cookie as JSON, including the session cookies a browser would drop on exit
+    fn save_cookies(&self) -> Result<String> {
+        let mut json = Vec::new();
+        cookie_store::serde::json::save_incl_expired_and_nonpersistent(
+            &self.cookie_store.lock().unwrap(),
+            &mut json,
+        )
+        .map_err(|e| anyhow!(e))?;
+        Ok(String::from_utf8(json)?)
+    }
+
+    /// Replace the cookies with ones from [`Self::save_cookies`], dropping the expired ones
+    fn load_cookies(&mut self, json: &str) -> Result<()> {
+        let store = cookie_store::serde::json::load(json.as_bytes()).map_err(|e| anyhow!(e))?;
+        *self.cookie_store.lock().unwrap() = store;
+        Ok(())
+    }
+
+    /// The captcha image and the solver's answer
+    async fn captcha(&mut self) -> Result<(Vec<u8>, String)> {
         trace!("get captcha image");
+        self.limiter.acquire().await;
         let res = self
             .client
             .get(format!("{}/AasEnrollStudent/RandImage", self.endpoint_root))
@@ -138,13 +1012,15 @@ impl NtnuCrawler {
             .error_for_status()?;
         let img = res.bytes().await?;
         if let Ok(text) = str::from_utf8(&img) {
-            NtnuCrawlerError::check_response(&text)?;
+            NtnuCrawlerError::check_response(text)?;
         }
         trace!("recognize captcha");
-        self.captcha_solver.recognize(&img).await
+        let answer = self.captcha_solver.recognize(&img).await?;
+        Ok((img.to_vec(), answer))
     }
 
     pub async fn login_magic(&mut self) -> Result<String> {
+        self.limiter.acquire().await;
         let resp = self
             .client
             .get(format!(
@@ -156,14 +1032,7 @@ impl NtnuCrawler {
             .error_for_status()?;
         let text = resp.text().await?;
         NtnuCrawlerError::check_response(&text)?;
-        let mtch = self
-            .magic_regex
-            .captures(&text)
-            .unwrap()
-            .get(1)
-            .unwrap()
-            .as_str();
-        Ok(mtch.to_owned())
+        Ok(LoginPage::parse(&text)?.magic)
     }
 
     async fn login(&mut self) -> Result<()> {
@@ -171,13 +1040,15 @@ impl NtnuCrawler {
         for i in 0..self.captcha_retry {
             retries = i;
             let magic = self.login_magic().await?;
+            metrics::inc(&METRICS.captcha_attempts);
             match self.captcha().await {
-                Ok(challenge) => {
+                Ok((img, challenge)) => {
                     let mut param = HashMap::new();
                     param.insert("userid", self.account.as_str());
                     param.insert("password", self.password.as_str());
                     param.insert("checkTW", "1");
                     param.insert("validateCode", challenge.as_str());
+                    self.limiter.acquire().await;
                     let resp = self
                         .client
                         .post(format!(
@@ -191,20 +1062,26 @@ impl NtnuCrawler {
                         .await?
                         .error_for_status()?;
                     let result = resp.text().await?;
-                    if result.contains("success:true") {
+                    // retrying a locked account only keeps it locked
+                    NtnuCrawlerError::check_response(&result)?;
+                    let accepted = result.contains("success:true");
+                    self.captcha_solver
+                        .feedback(&img, &challenge, accepted)
+                        .await;
+                    if accepted {
                         break;
                     } else {
                         self.cookie_store.lock().unwrap().clear();
                     }
                 }
                 Err(e) => match e.downcast() {
-                    Ok(CaptchaServiceError::InvalidErr)
-                    | Ok(CaptchaServiceError::NoneErr)
-                    | Ok(CaptchaServiceError::ParseIntErr(_)) => {
+                    Ok(CaptchaServiceError::Invalid)
+                    | Ok(CaptchaServiceError::NoAnswer)
+                    | Ok(CaptchaServiceError::ParseInt(_)) => {
                         self.clear();
                     }
-                    Ok(_) => {
-                        warn!("captcha service currently unavailable");
+                    Ok(e) => {
+                        warn!("captcha service currently unavailable: {e}");
                         sleep(Duration::from_secs(5)).await;
                     }
                     Err(e) => return Err(e),
@@ -212,12 +1089,13 @@ impl NtnuCrawler {
             }
         }
         if retries >= self.captcha_retry {
-            bail!("login max retry reached")
+            bail!(NtnuCrawlerError::LoginFailed)
         }
         Ok(())
     }
 
     async fn landing_page(&mut self) -> Result<()> {
+        self.limiter.acquire().await;
         let resp = self
             .client
             .get(format!("{}/AasEnrollStudent/IndexCtrl", self.endpoint_root))
@@ -228,19 +1106,14 @@ impl NtnuCrawler {
         let name = {
             let text = resp.text().await?;
             NtnuCrawlerError::check_response(&text)?;
-            self.name_regex
-                .captures(text.as_str())
-                .unwrap()
-                .get(2)
-                .unwrap()
-                .as_str()
-                .to_owned()
+            IndexPage::parse(&text)?.student_name
         };
         let mut param = HashMap::new();
         param.insert("userid", self.account.as_str());
         param.insert("stdName", &name);
         param.insert("checkTW", "1");
 
+        self.limiter.acquire().await;
         self.client
             .post(format!("{}/AasEnrollStudent/LoginCtrl", self.endpoint_root))
             .header(reqwest::header::REFERER, self.endpoint_root.clone())
@@ -250,6 +1123,7 @@ impl NtnuCrawler {
             .error_for_status()?;
 
         // load main page
+        self.limiter.acquire().await;
         let resp = self
             .client
             .get(format!(
@@ -266,6 +1140,7 @@ impl NtnuCrawler {
         }
 
         // load course select page
+        self.limiter.acquire().await;
         let resp = self
             .client
             .get(format!(
@@ -283,15 +1158,49 @@ impl NtnuCrawler {
         Ok(())
     }
 
-    async fn query(&mut self, id: &str) -> Result<i32> {
+    fn parse_status(&self, text: &str) -> Result<CourseStatus> {
+        let grid = Grid::parse(text)?;
+        if grid.count == 0 {
+            return Ok(CourseStatus::NotFound);
+        }
+        let row = grid.rows.first().ok_or(PageError::MissingField("List"))?;
+        Ok(CourseStatus::new(
+            row.seats()?,
+            !row.restrictions().is_empty(),
+        ))
+    }
+
+    fn parse_courses(&self, text: &str) -> Result<Vec<CourseInfo>> {
+        Ok(Grid::parse(text)?
+            .rows
+            .iter()
+            .map(GridRow::course)
+            .collect::<Result<_, _>>()?)
+    }
+
+    async fn query(&mut self, id: &str) -> Result<CourseStatus> {
+        let text = self.grid(&CourseQuery::serial_no(id)).await?;
+        self.parse_status(&text)
+    }
+
+    async fn search(&mut self, query: &CourseQuery) -> Result<Vec<CourseInfo>> {
+        let text = self.grid(query).await?;
+        self.parse_courses(&text)
+    }
+
+    /// Submit the course query form and return the raw grid
+    async fn grid(&mut self, query: &CourseQuery) -> Result<String> {
+        let form = query.form();
         let mut retries = 0;
         loop {
-            let mut param = HashMap::new();
-            param.insert("serialNo", id);
-            param.insert("notFull", "1");
+            let mut param: HashMap<&str, &str> = form
+                .iter()
+                .map(|(key, value)| (*key, value.as_str()))
+                .collect();
             param.insert("action", "showGrid");
             param.insert("actionButton", "query");
             trace!("start query request");
+            self.limiter.acquire().await;
             match self
                 .client
                 .post(format!(
@@ -309,18 +1218,13 @@ impl NtnuCrawler {
                     let text = resp.text().await?;
                     NtnuCrawlerError::check_response(&text)?;
                     if !text.is_empty() {
-                        let count_str = self
-                            .count_regex
-                            .captures(text.as_str())
-                            .unwrap()
-                            .get(1)
-                            .unwrap()
-                            .as_str();
-                        let count: i32 = count_str.parse()?;
-                        break Ok(count);
-                    } else {
+                        break Ok(text);
+                    } else if retries < self.max_retry {
                         // sleep before retry
                         sleep(Duration::from_secs(5)).await;
+                    } else {
+                        // a fresh login may get past it, which the manager decides
+                        break Err(NtnuCrawlerError::EmptyResponse.into());
                     }
                 }
                 Err(e) => {
@@ -336,107 +1240,349 @@ impl NtnuCrawler {
     }
 }
 
-#[derive(Debug, Error)]
-pub enum CaptchaServiceError {
-    #[error("service respond status: {0}")]
-    HttpErr(reqwest::StatusCode),
+#[cfg(test)]
+mod test {
+    use super::*;
 
-    #[error("request errror: {0}")]
-    ReqwestErr(reqwest::Error),
+    #[test]
+    fn test_rotation() -> Result<()> {
+        let config = crate::config::Config::from_sources(
+            Some(
+                "[ntnu]\naccount = \"a\"\npassword = \"x\"\naccounts = [\"b:y\", \"c:z\"]\n\
+                 [discord]\ntoken = \"t\"",
+            ),
+            [],
+        )?;
+        let mut manager = NtnuCrawlerManager::new(&config);
+        assert_eq!(manager.accounts().collect::<Vec<_>>(), ["a", "b", "c"]);
+        assert_eq!(
+            (0..4)
+                .map(|_| manager.rotate().unwrap())
+                .collect::<Vec<_>>(),
+            [0, 1, 2, 0]
+        );
+        manager.accounts[2].parked_until = Some(Instant::now() + PARK_DURATION);
+        assert_eq!(manager.rotate()?, 1);
+        assert_eq!(manager.rotate()?, 0);
+        // parking runs out
+        manager.accounts[2].parked_until = Some(Instant::now());
+        assert_eq!(manager.rotate()?, 1);
+        assert_eq!(manager.rotate()?, 2);
+        assert_eq!(manager.accounts[2].parked_until, None);
 
-    #[error("no viable value")]
-    NoneErr,
+        for account in &mut manager.accounts {
+            account.parked_until = Some(Instant::now() + PARK_DURATION);
+        }
+        let error = manager.rotate().unwrap_err();
+        assert_eq!(
+            FailureCause::of(&error, &config.captcha_uris()),
+            FailureCause::LoginFailed
+        );
 
-    #[error("service response invalid")]
-    InvalidErr,
+        // locked accounts stay out even after a reset
+        manager.accounts[1].locked = true;
+        manager.reset(&config);
+        assert_eq!(manager.rotate()?, 0);
+        assert_eq!(manager.rotate()?, 2);
+        assert!(!manager.disabled());
+        manager.accounts[0].locked = true;
+        manager.accounts[2].locked = true;
+        assert!(manager.disabled());
+        let error = manager.rotate().unwrap_err();
+        assert_eq!(
+            FailureCause::of(&error, &config.captcha_uris()),
+            FailureCause::AccountLocked
+        );
+        Ok(())
+    }
 
-    #[error("parse error: {0}")]
-    ParseIntErr(ParseIntError),
-}
+    #[tokio::test]
+    async fn test_failover() -> Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-#[derive(Debug, Deserialize)]
-struct CaptchaResponse {
-    response: Vec<String>,
-}
+        // a subsite that is up, answering every request with an empty page
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let up = format!("http://{}", listener.local_addr()?);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read(&mut [0; 1024]).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                    .await;
+            }
+        });
+        let down = "http://127.0.0.1:1";
+        let config = crate::config::Config::from_sources(
+            None,
+            [
+                ("BOT_NTNU_ACCOUNT", "a"),
+                ("BOT_NTNU_PASSWORD", "x"),
+                ("BOT_DISCORD_TOKEN", "t"),
+                ("BOT_NTNU_URL", down),
+                ("BOT_NTNU_FALLBACK_URLS", &format!("{down},{up}/")),
+            ]
+            .map(|(key, value)| (key.to_owned(), value.to_owned())),
+        )?;
+        let mut manager = NtnuCrawlerManager::new(&config);
+        assert_eq!(manager.subsites, [down, up.as_str()]);
 
-struct CaptchaSolver {
-    endpoint_root: String,
-    client: reqwest::Client,
-    calc_regex: regex::Regex,
-}
+        let refused = anyhow!(reqwest::get(down).await.unwrap_err());
+        assert!(manager.is_subsite_failure(&refused));
+        assert!(manager.is_subsite_failure(&anyhow!(NtnuCrawlerError::Maintenance)));
+        assert!(!manager.is_subsite_failure(&anyhow!(NtnuCrawlerError::BrokenStateMachine)));
 
-impl CaptchaSolver {
-    fn new(endpoint_root: String) -> Self {
-        Self {
-            endpoint_root,
-            client: reqwest::Client::new(),
-            calc_regex: regex::Regex::new(r"([0-9])([+x\-])([0-9])").unwrap(),
+        for _ in 1..SUBSITE_FAILURE_LIMIT {
+            manager.track_subsite(Some(&refused)).await;
+        }
+        // a success in between starts the count over
+        manager.track_subsite(None).await;
+        manager.track_subsite(Some(&refused)).await;
+        assert_eq!(manager.subsite, 0);
+        for _ in 1..SUBSITE_FAILURE_LIMIT {
+            manager.track_subsite(Some(&refused)).await;
         }
+        assert_eq!(manager.subsite, 1);
+        assert_eq!(manager.accounts[0].crawler.endpoint_root, up);
+        Ok(())
     }
 
-    async fn recognize(&self, img: &[u8]) -> Result<String> {
-        let typ = infer::get(img).unwrap();
-        let res = self
-            .client
-            .post(format!("{}/solve", self.endpoint_root).as_str())
-            .header("Content-Type", typ.mime_type())
-            .body(Vec::from(img))
-            .send()
-            .await?
-            .error_for_status()?;
-        let resp: CaptchaResponse = res.json().await?;
-        self.process(resp.response).map_err(|e| e.into())
-    }
-
-    fn process(&self, resps: Vec<String>) -> std::result::Result<String, CaptchaServiceError> {
-        let mut last_option: Option<String> = None;
-        for resp in resps {
-            // first word is digit
-            if let Some(cap) = self.calc_regex.captures(&resp) {
-                let opd1: i32 = cap
-                    .get(1)
-                    .ok_or(CaptchaServiceError::InvalidErr)?
-                    .as_str()
-                    .parse()
-                    .map_err(|e| CaptchaServiceError::ParseIntErr(e))?;
-                let op = cap.get(2).ok_or(CaptchaServiceError::InvalidErr)?.as_str();
-                let opd2: i32 = cap
-                    .get(3)
-                    .ok_or(CaptchaServiceError::InvalidErr)?
-                    .as_str()
-                    .parse()
-                    .map_err(|e| CaptchaServiceError::ParseIntErr(e))?;
-                return match op {
-                    "+" => Ok((opd1 + opd2).to_string()),
-                    "-" => Ok((opd1 - opd2).to_string()),
-                    "x" => Ok((opd1 * opd2).to_string()),
-                    _ => Err(CaptchaServiceError::InvalidErr),
-                };
-            } else {
-                last_option = Some(resp)
-            }
+    #[test]
+    fn test_user_agent() {
+        let settings = ClientSettings::default();
+        assert!(USER_AGENTS.contains(&settings.user_agent()));
+        let settings = ClientSettings {
+            user_agents: vec!["A".to_owned(), "B".to_owned()],
+            ..settings
+        };
+        for _ in 0..10 {
+            assert!(["A", "B"].contains(&settings.user_agent()));
         }
-        last_option.ok_or(CaptchaServiceError::InvalidErr)
     }
-}
 
-mod test {
-    use super::*;
+    #[test]
+    fn test_session_cookies() -> Result<()> {
+        let crawler = || {
+            NtnuCrawler::new(
+                "https://cos1s.ntnu.edu.tw".to_owned(),
+                CaptchaSolver::default(),
+                String::new(),
+                String::new(),
+                1,
+                1,
+                &ClientSettings::default(),
+            )
+        };
+        let url = reqwest::Url::parse("https://cos1s.ntnu.edu.tw/AasEnrollStudent/IndexCtrl")?;
+        let old = crawler();
+        // a session cookie, which lives until the browser closes
+        old.cookie_store
+            .lock()
+            .unwrap()
+            .parse("JSESSIONID=abc; Path=/", &url)?;
+        let saved = old.save_cookies()?;
+
+        let mut new = crawler();
+        new.load_cookies(&saved)?;
+        let store = new.cookie_store.lock().unwrap();
+        let cookie = store.get("cos1s.ntnu.edu.tw", "/", "JSESSIONID").unwrap();
+        assert_eq!(cookie.value(), "abc");
+        drop(store);
+        assert!(new.load_cookies("not json").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failure_cause() {
+        let uri = "http://127.0.0.1:1";
+        let captcha = &["http://127.0.0.2:1", uri][..];
+        let unreachable = anyhow!(reqwest::get(format!("{uri}/solve")).await.unwrap_err())
+            .context("fail to login");
+        assert_eq!(
+            FailureCause::of(&unreachable, captcha),
+            FailureCause::CaptchaUnreachable
+        );
+        // captchas read locally leave no service to be unreachable
+        assert_eq!(FailureCause::of(&unreachable, &[]), FailureCause::Other);
+        assert_eq!(
+            FailureCause::of(&anyhow!(NtnuCrawlerError::LoginFailed), captcha),
+            FailureCause::LoginFailed
+        );
+        assert_eq!(
+            FailureCause::of(&anyhow!(NtnuCrawlerError::Maintenance), captcha),
+            FailureCause::Maintenance
+        );
+        assert_eq!(
+            FailureCause::of(&anyhow!(NtnuCrawlerError::BrokenStateMachine), captcha),
+            FailureCause::Other
+        );
+        let expired = NtnuCrawlerError::check_response(
+            "<html><body>您的密碼已過期，請先變更密碼</body></html>",
+        );
+        assert_eq!(expired, Err(NtnuCrawlerError::CredentialAction));
+        assert_eq!(
+            FailureCause::of(&anyhow!(expired.unwrap_err()), captcha),
+            FailureCause::CredentialAction
+        );
+        let locked =
+            NtnuCrawlerError::check_response("{success:false, msg:'帳號已被鎖定，請洽註冊組'}");
+        assert_eq!(locked, Err(NtnuCrawlerError::Locked));
+        assert!(!NtnuCrawlerError::needs_login(
+            &anyhow!(locked.unwrap_err())
+        ));
+    }
 
     #[test]
-    fn test_captcha_process() -> Result<()> {
-        let solver = CaptchaSolver::new("".to_owned());
-        let testcases = vec![
-            (vec!["asdf".to_string()], "asdf"),
-            (vec!["lxzz".to_string(), "1+2".to_string()], "3"),
-            (vec!["lxzz".to_string(), "1-2".to_string()], "-1"),
-            (vec!["lxzz".to_string(), "2x2".to_string()], "4"),
-        ];
-        for testcase in testcases {
-            println!("running testcase {:?} ,ans: {:?}", testcase.0, testcase.1);
-            let ans = solver.process(testcase.0)?;
-            assert_eq!(testcase.1, ans);
-        }
+    fn test_parse_seats() -> Result<()> {
+        let crawler = NtnuCrawler::new(
+            "".to_owned(),
+            CaptchaSolver::default(),
+            "".to_owned(),
+            "".to_owned(),
+            0,
+            0,
+            &ClientSettings::default(),
+        );
+        let grid = r#"{"Count":1,"List":[{"serialNo":"1234","limitCountH":"50","counter":"47"}]}"#;
+        let seats = SeatCount {
+            enrolled: 47,
+            quota: 50,
+        };
+        let status = crawler.parse_status(grid)?;
+        assert_eq!(status, CourseStatus::Available(seats));
+        assert_eq!(status.seats().map(|s| s.available()), Some(3));
+        let restricted = grid.replace(r#""counter""#, r#""restrict1":"限本系","counter""#);
+        assert_eq!(
+            crawler.parse_status(&restricted)?,
+            CourseStatus::Restricted(seats)
+        );
+        let full = restricted.replace(r#""47""#, r#""50""#);
+        assert!(matches!(
+            crawler.parse_status(&full)?,
+            CourseStatus::Full(_)
+        ));
+        assert_eq!(
+            crawler.parse_status(r#"{'Count': 0, 'List': []}"#)?,
+            CourseStatus::NotFound
+        );
+        assert!(crawler.parse_status(r#"{"Count":1,"List":[{}]}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_courses() -> Result<()> {
+        let crawler = NtnuCrawler::new(
+            "".to_owned(),
+            CaptchaSolver::default(),
+            "".to_owned(),
+            "".to_owned(),
+            0,
+            0,
+            &ClientSettings::default(),
+        );
+        let grid = r#"{"Count":2,"List":[
+            {"serialNo":"1234","courseCode":"CSU0001","chnName":"計算機概論 ","teacher":"王小明","optionCode":"必","limitCountH":"50","counter":"47","restrict1":"限本系","restrict2":" 需授權碼 "},
+            {"serialNo":"0042","chnName":"資料結構","limitCountH":60,"counter":60,"restrict1":""}
+        ]}"#;
+        assert_eq!(
+            crawler.parse_courses(grid)?,
+            vec![
+                CourseInfo {
+                    serial_no: "1234".to_owned(),
+                    course_code: "CSU0001".to_owned(),
+                    name: "計算機概論".to_owned(),
+                    teacher: "王小明".to_owned(),
+                    option_code: "必".to_owned(),
+                    seats: SeatCount {
+                        enrolled: 47,
+                        quota: 50
+                    },
+                    restrictions: vec!["限本系".to_owned(), "需授權碼".to_owned()],
+                },
+                CourseInfo {
+                    serial_no: "0042".to_owned(),
+                    course_code: "".to_owned(),
+                    name: "資料結構".to_owned(),
+                    teacher: "".to_owned(),
+                    option_code: "".to_owned(),
+                    seats: SeatCount {
+                        enrolled: 60,
+                        quota: 60
+                    },
+                    restrictions: vec![],
+                },
+            ]
+        );
+        assert_eq!(crawler.parse_courses(r#"{"Count":0}"#)?, vec![]);
+        assert!(crawler.parse_courses(r#"{"Count":1,"List":[{}]}"#).is_err());
         Ok(())
     }
+
+    #[test]
+    fn test_query_form() {
+        assert!(CourseQuery::default().is_empty());
+        let query = CourseQuery::default()
+            .with_dept_code("CSU")
+            .with_general_core("人文藝術")
+            .with_grade(2);
+        assert!(!query.is_empty());
+        assert_eq!(
+            query.form(),
+            [
+                ("deptCode", "CSU".to_owned()),
+                ("generalCore", "人文藝術".to_owned()),
+                ("formS", "2".to_owned())
+            ]
+        );
+        assert_eq!(
+            CourseQuery::serial_no("0042").form(),
+            [("serialNo", "0042".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_validate_serial_no() {
+        let range = 1..=9000;
+        assert_eq!(validate_serial_no("0123", &range), Ok(()));
+        assert_eq!(validate_serial_no("9000", &range), Ok(()));
+        assert_eq!(
+            validate_serial_no("", &range),
+            Err(SerialNoError::NotDigits)
+        );
+        assert_eq!(
+            validate_serial_no("12a4", &range),
+            Err(SerialNoError::NotDigits)
+        );
+        assert_eq!(
+            validate_serial_no("1", &range),
+            Err(SerialNoError::Length(1))
+        );
+        assert_eq!(
+            validate_serial_no("123456789012", &range),
+            Err(SerialNoError::Length(12))
+        );
+        assert_eq!(
+            validate_serial_no("0000", &range),
+            Err(SerialNoError::OutOfRange(1, 9000))
+        );
+        assert_eq!(
+            validate_serial_no("9001", &range),
+            Err(SerialNoError::OutOfRange(1, 9000))
+        );
+    }
+
+    #[test]
+    fn test_normalize_dept_code() {
+        assert_eq!(normalize_dept_code(" csu "), Some("CSU".to_owned()));
+        assert_eq!(normalize_dept_code("9UAA"), Some("9UAA".to_owned()));
+        assert_eq!(normalize_dept_code(""), None);
+        assert_eq!(normalize_dept_code("CS U"), None);
+    }
+
+    #[test]
+    fn test_normalize_course_code() {
+        assert_eq!(normalize_course_code("csu0001"), Some("CSU0001".to_owned()));
+        assert_eq!(normalize_course_code("1234"), None);
+        assert_eq!(normalize_course_code("CSU"), None);
+        assert_eq!(normalize_course_code("CSU-0001"), None);
+    }
 }
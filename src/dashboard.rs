@@ -0,0 +1,453 @@
+//! Read-only web page of a user's watchlist and alerts, signed into with Discord OAuth2
+//!
+//! Sessions live in memory, so a restart signs everyone out.
+
+use std::{collections::HashMap, fmt::Write, sync::Mutex, time::Duration};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    config::Config,
+    db::{now, CourseMeta, NotificationRecord, Repository, SeatSnapshot, WatchEntry, WatchMode},
+    http::Response,
+    i18n::Lang,
+};
+
+const SESSION_COOKIE: &str = "course_bot_session";
+/// Seconds a sign-in lasts
+const SESSION_TTL: u64 = 7 * 24 * 60 * 60;
+/// Seconds to come back from Discord's consent screen
+const STATE_TTL: u64 = 600;
+
+const AUTHORIZE_URL: &str = "https://discord.com/oauth2/authorize";
+const TOKEN_URL: &str = "https://discord.com/api/oauth2/token";
+const USER_URL: &str = "https://discord.com/api/users/@me";
+
+/// Turns the UTC seconds in `data-ts` into the browser's local time
+const LOCAL_TIME_SCRIPT: &str = "document.querySelectorAll('time[data-ts]').forEach(t => \
+    t.textContent = new Date(t.dataset.ts * 1000).toLocaleString(document.documentElement.lang))";
+
+struct Session {
+    user_id: u64,
+    expires_at: u64,
+}
+
+pub struct Dashboard {
+    client_id: String,
+    client_secret: String,
+    /// Public URL without a trailing slash
+    public_url: String,
+    client: reqwest::Client,
+    /// Signed in users by session token
+    sessions: Mutex<HashMap<String, Session>>,
+    /// OAuth2 `state` values handed out, with when they were issued
+    states: Mutex<HashMap<String, u64>>,
+}
+
+impl Dashboard {
+    /// `None` unless the public URL and both OAuth2 client settings are set
+    pub fn new(config: &Config) -> Option<Self> {
+        let public_url = config
+            .public_url
+            .as_deref()?
+            .trim_end_matches('/')
+            .to_owned();
+        Some(Self {
+            client_id: config.oauth_client_id.clone()?,
+            client_secret: config.oauth_client_secret.clone()?,
+            public_url,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap(),
+            sessions: Mutex::new(HashMap::new()),
+            states: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Answer `/dashboard`, `/oauth/callback` or `/logout`
+    pub async fn handle(
+        &self,
+        path: &str,
+        query: &[(String, String)],
+        cookies: Option<&str>,
+        db: &dyn Repository,
+    ) -> Response {
+        let token = cookies.and_then(session_token);
+        match path {
+            "/oauth/callback" => self.callback(query).await,
+            "/logout" => {
+                if let Some(token) = token {
+                    self.sessions.lock().unwrap().remove(token);
+                }
+                Response::text("200 OK", "signed out\n")
+                    .with_header("Set-Cookie", self.cookie("", 0))
+            }
+            _ => match token.and_then(|token| self.user(token)) {
+                Some(user_id) => match page(db, user_id).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        warn!("fail to build the dashboard of user {user_id}: {e:#}");
+                        Response::text("500 Internal Server Error", "storage unavailable\n")
+                    }
+                },
+                None => self.sign_in(),
+            },
+        }
+    }
+
+    /// User of an unexpired session
+    fn user(&self, token: &str) -> Option<u64> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(token)?;
+        (session.expires_at > now()).then_some(session.user_id)
+    }
+
+    fn redirect_uri(&self) -> String {
+        format!("{}/oauth/callback", self.public_url)
+    }
+
+    /// Send the browser to Discord's consent screen
+    fn sign_in(&self) -> Response {
+        let state = random_token();
+        {
+            let mut states = self.states.lock().unwrap();
+            let now = now();
+            states.retain(|_, issued_at| now.saturating_sub(*issued_at) < STATE_TTL);
+            states.insert(state.clone(), now);
+        }
+        let mut url = reqwest::Url::parse(AUTHORIZE_URL).unwrap();
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.client_id)
+            .append_pair("response_type", "code")
+            .append_pair("scope", "identify")
+            .append_pair("redirect_uri", &self.redirect_uri())
+            .append_pair("state", &state);
+        Response::redirect(url.as_str())
+    }
+
+    /// Where Discord sends the browser back to after the consent screen
+    async fn callback(&self, query: &[(String, String)]) -> Response {
+        let param = |name: &str| {
+            query
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let issued_at = param("state").and_then(|state| self.states.lock().unwrap().remove(state));
+        if issued_at.is_none_or(|issued_at| now().saturating_sub(issued_at) >= STATE_TTL) {
+            return Response::text("400 Bad Request", "sign-in expired, try again\n");
+        }
+        let Some(code) = param("code") else {
+            // `error=access_denied` when the user cancelled
+            return Response::text("400 Bad Request", "sign-in cancelled\n");
+        };
+        match self.identify(code).await {
+            Ok(user_id) => {
+                let token = random_token();
+                {
+                    let mut sessions = self.sessions.lock().unwrap();
+                    let now = now();
+                    sessions.retain(|_, session| session.expires_at > now);
+                    sessions.insert(
+                        token.clone(),
+                        Session {
+                            user_id,
+                            expires_at: now + SESSION_TTL,
+                        },
+                    );
+                }
+                Response::redirect("/dashboard")
+                    .with_header("Set-Cookie", self.cookie(&token, SESSION_TTL))
+            }
+            Err(e) => {
+                warn!("Discord sign-in failed: {e:#}");
+                Response::text("502 Bad Gateway", "sign-in with Discord failed\n")
+            }
+        }
+    }
+
+    /// Trade the authorization code for the Discord user ID
+    async fn identify(&self, code: &str) -> anyhow::Result<u64> {
+        #[derive(Deserialize)]
+        struct Token {
+            access_token: String,
+        }
+        #[derive(Deserialize)]
+        struct User {
+            id: String,
+        }
+
+        let token: Token = self
+            .client
+            .post(TOKEN_URL)
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.redirect_uri()),
+            ])
+            .send()
+            .await?
+            .error_for_status()
+            .context("token exchange")?
+            .json()
+            .await?;
+        let user: User = self
+            .client
+            .get(USER_URL)
+            .bearer_auth(token.access_token)
+            .send()
+            .await?
+            .error_for_status()
+            .context("user lookup")?
+            .json()
+            .await?;
+        Ok(user.id.parse()?)
+    }
+
+    fn cookie(&self, value: &str, max_age: u64) -> String {
+        let secure = if self.public_url.starts_with("https://") {
+            "; Secure"
+        } else {
+            ""
+        };
+        format!(
+            "{SESSION_COOKIE}={value}; Max-Age={max_age}; Path=/; HttpOnly; SameSite=Lax{secure}"
+        )
+    }
+}
+
+/// Session token in a `Cookie` header
+fn session_token(cookies: &str) -> Option<&str> {
+    cookies
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+/// 32 random bytes in hex
+fn random_token() -> String {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).expect("no OS randomness");
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// A watched course with what is stored about it
+struct WatchRow {
+    entry: WatchEntry,
+    meta: Option<CourseMeta>,
+    snapshot: Option<SeatSnapshot>,
+}
+
+async fn page(db: &dyn Repository, user_id: u64) -> anyhow::Result<Response> {
+    let lang = db.settings(user_id).await?.lang();
+    if db.is_blocked(user_id).await? {
+        return Ok(Response::html(
+            "403 Forbidden",
+            render(lang, None, &[], &[]),
+        ));
+    }
+    let mut rows = Vec::new();
+    for entry in db.watchlist(user_id).await? {
+        rows.push(WatchRow {
+            meta: db.course_meta(&entry.course_id).await?,
+            snapshot: db.seat_snapshot(&entry.course_id).await?,
+            entry,
+        });
+    }
+    let notifications = db.notifications(user_id).await?;
+    Ok(Response::html(
+        "200 OK",
+        render(lang, Some(user_id), &rows, &notifications),
+    ))
+}
+
+/// The whole page, `user_id` is `None` for a blocked user
+fn render(
+    lang: Lang,
+    user_id: Option<u64>,
+    rows: &[WatchRow],
+    notifications: &[NotificationRecord],
+) -> String {
+    let text = lang.dashboard();
+    let time = |at: u64| format!("<time data-ts=\"{at}\">{at}</time>");
+    let mut body = String::new();
+    if user_id.is_none() {
+        body += &format!("<p>{}</p>", escape(text.blocked));
+    } else {
+        body += &format!("<h2>{}</h2>", escape(text.watchlist));
+        if rows.is_empty() {
+            body += &format!("<p>{}</p>", escape(text.no_watches));
+        } else {
+            body += &format!(
+                "<table><tr><th>{}</th><th>{}</th><th>{}</th></tr>",
+                escape(text.course),
+                escape(text.alerts_on),
+                escape(text.last_seen),
+            );
+            for row in rows {
+                let course = match &row.meta {
+                    Some(meta) => {
+                        format!("{} {} ({})", row.entry.course_id, meta.name, meta.teacher)
+                    }
+                    None => row.entry.course_id.clone(),
+                };
+                let alerts_on = match row.entry.mode {
+                    WatchMode::Availability => lang.min_seats(row.entry.min_seats),
+                    WatchMode::Changes => text.any_change.to_owned(),
+                };
+                let last_seen = match &row.snapshot {
+                    Some(snapshot) => format!(
+                        "{} · {}",
+                        match snapshot.seats {
+                            Some(seats) => escape(&lang.seats_free(seats)),
+                            None => escape(text.not_listed),
+                        },
+                        time(snapshot.checked_at),
+                    ),
+                    None => escape(text.not_checked),
+                };
+                body += &format!(
+                    "<tr><td>{}</td><td>{}</td><td>{last_seen}</td></tr>",
+                    escape(&course),
+                    escape(&alerts_on),
+                );
+            }
+            body += "</table>";
+        }
+
+        body += &format!("<h2>{}</h2>", escape(text.notifications));
+        if notifications.is_empty() {
+            body += &format!("<p>{}</p>", escape(text.no_notifications));
+        } else {
+            body += &format!(
+                "<table><tr><th>{}</th><th>{}</th><th>{}</th><th>{}</th></tr>",
+                escape(text.sent_at),
+                escape(text.course),
+                escape(text.kind),
+                escape(text.seats),
+            );
+            for record in notifications {
+                let mut seats = record
+                    .seats
+                    .map(|seats| lang.seats_free(seats))
+                    .unwrap_or_default();
+                if !record.delivered {
+                    seats += &format!(" ({})", text.not_delivered);
+                }
+                body += &format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    time(record.at),
+                    escape(&record.course_id),
+                    escape(record.kind.label(lang)),
+                    escape(&seats),
+                );
+            }
+            body += "</table>";
+        }
+    }
+    let html_lang = match lang {
+        Lang::En => "en",
+        Lang::ZhTw => "zh-TW",
+    };
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"{html_lang}\"><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\"><title>{title}</title>\
+         <style>body{{font-family:sans-serif;max-width:50em;margin:auto;padding:1em}}\
+         table{{border-collapse:collapse;width:100%}}\
+         th,td{{border-bottom:1px solid #ccc;padding:.3em;text-align:left}}</style></head>\
+         <body><h1>{title}</h1>{body}<p><a href=\"/logout\">⏏</a></p>\
+         <script>{LOCAL_TIME_SCRIPT}</script></body></html>\n",
+        title = escape(text.title),
+    )
+}
+
+/// Escape text for an HTML element or quoted attribute
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{crawler::SeatCount, db::NotificationKind};
+
+    #[test]
+    fn test_session_token() {
+        assert_eq!(
+            session_token("theme=dark; course_bot_session=abc; x=y"),
+            Some("abc")
+        );
+        assert_eq!(session_token("course_bot_session="), None);
+        assert_eq!(session_token("theme=dark"), None);
+        let token = random_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, random_token());
+    }
+
+    #[test]
+    fn test_render() {
+        let seats = SeatCount {
+            enrolled: 28,
+            quota: 30,
+        };
+        let rows = [WatchRow {
+            entry: WatchEntry {
+                course_id: "1234".to_owned(),
+                added_at: 0,
+                notified_at: None,
+                min_seats: 1,
+                mode: WatchMode::Availability,
+                last_seen: None,
+            },
+            meta: Some(CourseMeta {
+                name: "<script>alert(1)</script>".to_owned(),
+                teacher: "Wang & Lee".to_owned(),
+                quota: 30,
+                fetched_at: 0,
+                restrictions: Vec::new(),
+            }),
+            snapshot: Some(SeatSnapshot {
+                seats: Some(seats),
+                checked_at: 1700000000,
+            }),
+        }];
+        let notifications = [NotificationRecord {
+            course_id: "1234".to_owned(),
+            kind: NotificationKind::Available,
+            seats: Some(seats),
+            delivered: false,
+            at: 1700000100,
+        }];
+        let page = render(Lang::En, Some(1), &rows, &notifications);
+        assert!(page.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+        assert!(!page.contains("<script>alert"));
+        assert!(page.contains("Wang &amp; Lee"));
+        assert!(page.contains("2/30 free · <time data-ts=\"1700000000\">"));
+        assert!(page.contains("2/30 free (not delivered)"));
+
+        let page = render(Lang::ZhTw, None, &rows, &notifications);
+        assert!(page.contains("你已被禁止使用此機器人"));
+        assert!(!page.contains("1234"));
+    }
+}
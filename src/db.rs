@@ -0,0 +1,1266 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    crawler::{CourseInfo, SeatCount},
+    export::{ImportReport, WatchlistExport},
+    i18n::Lang,
+    notify::{DigestMode, NotifyTarget},
+};
+
+mod cipher;
+mod legacy;
+#[cfg(test)]
+mod memory;
+#[cfg(feature = "redis")]
+mod redis;
+mod sqlite;
+
+pub use cipher::Cipher;
+pub use legacy::import_kv;
+#[cfg(test)]
+pub use memory::MemoryRepository;
+#[cfg(feature = "redis")]
+pub use redis::RedisRepository;
+pub use sqlite::Db;
+
+/// How many availability transitions are kept per course
+pub const HISTORY_LIMIT: usize = 200;
+
+/// How many sent notifications are kept per user
+pub const NOTIFICATION_LIMIT: usize = 50;
+
+/// Key in the `meta` table holding the day of the last daily summary
+pub const META_LAST_DAILY_SUMMARY: &str = "last_daily_summary";
+
+/// Key in the `meta` table holding the start of the last enrollment phase users were told about
+pub const META_ANNOUNCED_PHASE: &str = "announced_phase";
+
+/// Key in the `meta` table holding the semester watches belong to, as `{year}{term}`
+pub const META_SEMESTER: &str = "semester";
+
+/// Key in the `meta` table holding when watched courses were last checked for still being offered
+pub const META_LAST_WATCH_SWEEP: &str = "last_watch_sweep";
+
+/// Key in the `meta` table recording when the old kv database was imported
+pub const META_KV_IMPORTED: &str = "kv_imported";
+
+/// Key in the `meta` table holding a value sealed with the storage key SQLite user data is sealed
+/// with, so starting with another key or none fails instead of misreading it
+pub const META_STORAGE_KEY: &str = "storage_key";
+
+/// A course on a user's watchlist
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchEntry {
+    pub course_id: String,
+    /// Unix timestamp (seconds) of when the course was added
+    pub added_at: u64,
+    /// Unix timestamp (seconds) of the last availability alert
+    pub notified_at: Option<u64>,
+    /// Only alert once at least this many seats are free
+    pub min_seats: u32,
+    pub mode: WatchMode,
+    /// Seat numbers seen by the previous check, used by [`WatchMode::Changes`]
+    pub last_seen: Option<SeatCount>,
+}
+
+/// What kind of events a watch alerts on
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    poise::ChoiceParameter,
+    sqlx::Type,
+)]
+#[sqlx(rename_all = "snake_case")]
+pub enum WatchMode {
+    /// Free seats reached the threshold
+    #[default]
+    #[name = "Free seats"]
+    Availability,
+    /// Enrolled count or quota changed in any way
+    #[name = "Any seat change"]
+    Changes,
+}
+
+impl WatchEntry {
+    pub fn new(course_id: String, min_seats: u32, mode: WatchMode) -> Self {
+        Self {
+            course_id,
+            added_at: now(),
+            notified_at: None,
+            min_seats,
+            mode,
+            last_seen: None,
+        }
+    }
+
+    /// Whether an alert was sent recently enough that another one should be held back
+    pub fn in_cooldown(&self, cooldown: u64, now: u64) -> bool {
+        self.notified_at
+            .is_some_and(|at| now.saturating_sub(at) < cooldown)
+    }
+}
+
+/// A whole department on a user's watchlist, expanded into its courses every check
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DepartmentWatch {
+    pub dept_code: String,
+    /// Skip required courses
+    pub elective_only: bool,
+    pub added_at: u64,
+    /// Matching courses that had free seats at the previous check
+    #[serde(default)]
+    pub open: Vec<String>,
+}
+
+impl DepartmentWatch {
+    pub fn new(dept_code: String, elective_only: bool) -> Self {
+        Self {
+            dept_code,
+            elective_only,
+            added_at: now(),
+            open: Vec::new(),
+        }
+    }
+
+    /// Serial numbers of the matching courses with free seats, sorted
+    pub fn open_courses(&self, courses: &[CourseInfo]) -> Vec<String> {
+        let mut open = courses
+            .iter()
+            .filter(|c| !self.elective_only || c.option_code == "選")
+            .filter(|c| c.seats.available() > 0)
+            .map(|c| c.serial_no.clone())
+            .collect::<Vec<_>>();
+        open.sort();
+        open.dedup();
+        open
+    }
+}
+
+/// Per-user preferences, new fields must be `#[serde(default)]` to keep old records readable
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserSettings {
+    /// Reply to slash commands publicly instead of ephemerally
+    #[serde(default)]
+    pub public_replies: bool,
+    /// Explicitly chosen language, otherwise the Discord client locale is used
+    #[serde(default)]
+    pub language: Option<Lang>,
+    #[serde(default)]
+    pub notify_target: NotifyTarget,
+    /// Guild channel used when `notify_target` includes a channel
+    #[serde(default)]
+    pub notify_channel: Option<u64>,
+    #[serde(default)]
+    pub digest: DigestMode,
+    /// Also send a summary of the day's alerts once a day
+    #[serde(default)]
+    pub daily_summary: bool,
+    /// Hold back alerts during these hours
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+    /// Set when an alert could not be DMed, so the next command reminds the user to open DMs
+    #[serde(default)]
+    pub dm_closed: bool,
+    /// Tell the user when a course they were alerted about fills up again, and alert again as
+    /// soon as it reopens instead of after the cooldown
+    #[serde(default)]
+    pub fill_alerts: bool,
+}
+
+impl UserSettings {
+    /// Language for messages sent outside of a command, where there is no client locale
+    pub fn lang(&self) -> Lang {
+        self.language.unwrap_or_default()
+    }
+
+    /// Whether alerts should be held back at `timestamp`
+    pub fn is_quiet(&self, timestamp: u64) -> bool {
+        self.quiet_hours
+            .is_some_and(|quiet| quiet.contains(local_hour(timestamp)))
+    }
+}
+
+/// Daily span of Taiwan hours, `start` inclusive and `end` exclusive, may wrap past midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: u8,
+    pub end: u8,
+}
+
+impl QuietHours {
+    pub fn contains(&self, hour: u8) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&hour)
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+/// Last observed seat numbers of a course
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SeatSnapshot {
+    /// `None` when the enrollment system did not list the course
+    pub seats: Option<SeatCount>,
+    pub checked_at: u64,
+}
+
+impl SeatSnapshot {
+    pub fn is_open(&self) -> bool {
+        self.seats.is_some_and(|seats| seats.available() > 0)
+    }
+}
+
+/// Name, teacher, quota and restrictions of a course, cached so IDs can be labelled without a
+/// query
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CourseMeta {
+    pub name: String,
+    pub teacher: String,
+    pub quota: i32,
+    pub fetched_at: u64,
+    /// Missing from entries cached before restrictions were parsed
+    #[serde(default)]
+    pub restrictions: Vec<String>,
+}
+
+impl From<(&CourseInfo, u64)> for CourseMeta {
+    fn from((course, fetched_at): (&CourseInfo, u64)) -> Self {
+        Self {
+            name: course.name.clone(),
+            teacher: course.teacher.clone(),
+            quota: course.seats.quota,
+            fetched_at,
+            restrictions: course.restrictions.clone(),
+        }
+    }
+}
+
+impl CourseMeta {
+    /// Whether the cached copy is older than `ttl` seconds
+    pub fn is_stale(&self, ttl: u64, now: u64) -> bool {
+        now.saturating_sub(self.fetched_at) >= ttl
+    }
+}
+
+/// A course switching between having free seats and being full
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AvailabilityChange {
+    pub at: u64,
+    /// Free seats right after the change, zero when the course closed
+    pub available: i32,
+}
+
+impl AvailabilityChange {
+    pub fn is_open(&self) -> bool {
+        self.available > 0
+    }
+}
+
+/// Periods during which a course had free seats, as `(opened, closed)`
+///
+/// The last period has no end when the course is still open.
+pub fn openings(history: &[AvailabilityChange]) -> Vec<(u64, Option<u64>)> {
+    let mut openings = Vec::new();
+    let mut opened: Option<u64> = None;
+    for change in history {
+        match (change.is_open(), opened) {
+            (true, None) => opened = Some(change.at),
+            (false, Some(start)) => {
+                openings.push((start, Some(change.at)));
+                opened = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(start) = opened {
+        openings.push((start, None));
+    }
+    openings
+}
+
+/// An alert remembered for the daily summary
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DigestEvent {
+    pub course_id: String,
+    pub at: u64,
+}
+
+/// Which alert a notification was
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(rename_all = "snake_case")]
+pub enum NotificationKind {
+    Available,
+    SeatsChanged,
+    Department,
+    FilledUp,
+}
+
+/// A notification sent to a user, recorded once per course it mentioned
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotificationRecord {
+    pub course_id: String,
+    pub kind: NotificationKind,
+    /// Seat numbers the alert was based on
+    pub seats: Option<SeatCount>,
+    /// Whether at least one destination accepted the message
+    pub delivered: bool,
+    pub at: u64,
+}
+
+/// Counters of the periodic checker, reset on every start
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CheckerStats {
+    pub started_at: u64,
+    /// When the last full check finished
+    pub last_cycle_at: Option<u64>,
+    pub last_cycle_secs: Option<u64>,
+    /// Course and department queries that failed after all retries
+    pub query_failures: u64,
+    /// Logins into the enrollment system
+    pub logins: u64,
+}
+
+/// A user the owners locked out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockEntry {
+    pub blocked_at: u64,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Alerts that failed to reach a user in a row
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryFailures {
+    pub count: u32,
+    /// First failure of the current streak
+    pub since: u64,
+    /// When the checker stopped checking the user's watches
+    pub disabled_at: Option<u64>,
+}
+
+/// Public alert configuration of a guild
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GuildSettings {
+    pub channel: u64,
+    /// Role pinged along with every alert
+    #[serde(default)]
+    pub role: Option<u64>,
+    #[serde(default)]
+    pub language: Lang,
+}
+
+/// A named course list a guild shares, its openings posted in a channel instead of DMed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GuildWatchlist {
+    pub name: String,
+    pub channel: u64,
+    #[serde(default)]
+    pub language: Lang,
+    /// Course IDs as stored, in the order they were added
+    pub courses: Vec<String>,
+    /// Courses that had free seats at the previous check
+    #[serde(default)]
+    pub open: Vec<String>,
+}
+
+impl GuildWatchlist {
+    pub fn new(name: String, channel: u64, language: Lang) -> Self {
+        Self {
+            name,
+            channel,
+            language,
+            courses: Vec::new(),
+            open: Vec::new(),
+        }
+    }
+}
+
+/// Result of adding a course to a watchlist
+pub enum AddOutcome {
+    Added,
+    Duplicate(WatchEntry),
+    Updated(WatchEntry),
+    LimitReached(usize),
+}
+
+/// Result of adding a department watch
+pub enum DepartmentOutcome {
+    Added,
+    Duplicate,
+    Updated,
+    LimitReached(usize),
+}
+
+/// Aggregate numbers for `/stats`
+pub struct WatchCounts {
+    /// Users with at least one watched course
+    pub users: usize,
+    pub courses: usize,
+    pub unique_courses: usize,
+    pub departments: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("database error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+    #[error("database schema version {found} is newer than the supported {supported}")]
+    UnknownVersion { found: usize, supported: usize },
+    #[cfg(feature = "redis")]
+    #[error("redis error: {0}")]
+    Redis(#[from] ::redis::RedisError),
+    #[error("undecodable stored value: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("storage key must be the base64 of 32 bytes")]
+    InvalidKey,
+    #[error("stored value could not be decrypted, is the storage key right?")]
+    Undecryptable,
+}
+
+/// Everything stored per user or guild: watchlists, departments, settings, limits and blocks
+#[async_trait]
+pub trait UserRepository {
+    async fn watchlist(&self, user_id: u64) -> Result<Vec<WatchEntry>, StoreError>;
+
+    /// Distinct courses watched by at least one user who is not blocked, sorted
+    async fn watched_courses(&self) -> Result<Vec<String>, StoreError>;
+
+    /// Users who are not blocked and watch a course, with their watch of it
+    async fn subscribers(&self, course_id: &str) -> Result<Vec<(u64, WatchEntry)>, StoreError>;
+
+    /// Add a course, or only update its options when some are given for a watched one
+    async fn add_watch(
+        &self,
+        user_id: u64,
+        course_id: &str,
+        min_seats: Option<u32>,
+        mode: Option<WatchMode>,
+        default_limit: usize,
+    ) -> Result<AddOutcome, StoreError>;
+
+    async fn remove_watches(&self, user_id: u64, course_ids: &[String]) -> Result<(), StoreError>;
+
+    /// Re-arm alerts of a watched course
+    async fn clear_notified(&self, user_id: u64, course_id: &str) -> Result<(), StoreError>;
+
+    /// Store what one check saw for a user's watches
+    ///
+    /// Returns `false` when the user no longer watches anything, e.g. after `/forget_me`.
+    async fn record_check(
+        &self,
+        user_id: u64,
+        notified: &[&str],
+        seen: &HashMap<&str, Option<SeatCount>>,
+        at: u64,
+    ) -> Result<bool, StoreError>;
+
+    /// Override the watchlist cap of a user, `None` restores the default
+    async fn set_course_limit(&self, user_id: u64, limit: Option<usize>) -> Result<(), StoreError>;
+
+    async fn settings(&self, user_id: u64) -> Result<UserSettings, StoreError>;
+
+    async fn save_settings(&self, user_id: u64, settings: &UserSettings) -> Result<(), StoreError>;
+
+    /// Change some settings in one step, so concurrent commands cannot undo each other
+    async fn update_settings(
+        &self,
+        user_id: u64,
+        update: &(dyn for<'s> Fn(&'s mut UserSettings) + Send + Sync),
+    ) -> Result<UserSettings, StoreError>;
+
+    async fn guild_settings(&self) -> Result<Vec<(u64, GuildSettings)>, StoreError>;
+
+    async fn save_guild_settings(
+        &self,
+        guild_id: u64,
+        settings: &GuildSettings,
+    ) -> Result<(), StoreError>;
+
+    async fn remove_guild_settings(&self, guild_id: u64) -> Result<(), StoreError>;
+
+    /// Shared watchlists of a guild, sorted by name
+    async fn guild_watchlists(&self, guild_id: u64) -> Result<Vec<GuildWatchlist>, StoreError>;
+
+    /// Every guild's shared watchlists, for the checker
+    async fn all_guild_watchlists(&self) -> Result<Vec<(u64, Vec<GuildWatchlist>)>, StoreError>;
+
+    /// Create the guild's watchlist, or replace the one of the same name
+    async fn save_guild_watchlist(
+        &self,
+        guild_id: u64,
+        list: &GuildWatchlist,
+    ) -> Result<(), StoreError>;
+
+    /// Delete one of the guild's watchlists, `false` when it has none of that name
+    async fn remove_guild_watchlist(&self, guild_id: u64, name: &str) -> Result<bool, StoreError>;
+
+    /// Store which courses of the guild's watchlists, by name, had free seats this check
+    async fn record_guild_watchlist_check(
+        &self,
+        guild_id: u64,
+        open: &HashMap<String, Vec<String>>,
+    ) -> Result<(), StoreError>;
+
+    async fn push_digest_events(
+        &self,
+        user_id: u64,
+        events: &[DigestEvent],
+    ) -> Result<(), StoreError>;
+
+    /// Append to a user's notification log, dropping the oldest beyond [`NOTIFICATION_LIMIT`]
+    async fn log_notifications(
+        &self,
+        user_id: u64,
+        records: &[NotificationRecord],
+    ) -> Result<(), StoreError>;
+
+    /// A user's logged notifications, newest first
+    async fn notifications(&self, user_id: u64) -> Result<Vec<NotificationRecord>, StoreError>;
+
+    /// Remove and return every pending daily summary event, keyed by user
+    async fn take_digest_events(&self) -> Result<Vec<(u64, Vec<DigestEvent>)>, StoreError>;
+
+    async fn department_watches(&self, user_id: u64) -> Result<Vec<DepartmentWatch>, StoreError>;
+
+    /// Every user's department watches
+    async fn all_department_watches(&self) -> Result<Vec<(u64, Vec<DepartmentWatch>)>, StoreError>;
+
+    /// Watch a department, or switch the elective filter of a watched one
+    async fn watch_department(
+        &self,
+        user_id: u64,
+        dept_code: &str,
+        elective_only: bool,
+        limit: usize,
+    ) -> Result<DepartmentOutcome, StoreError>;
+
+    /// Returns whether the department was watched
+    async fn remove_department_watch(
+        &self,
+        user_id: u64,
+        dept_code: &str,
+    ) -> Result<bool, StoreError>;
+
+    /// Store the open courses seen for each of a user's departments
+    ///
+    /// Returns `false` when the user no longer watches any department, e.g. after `/forget_me`.
+    async fn record_department_check(
+        &self,
+        user_id: u64,
+        open: &HashMap<String, Vec<String>>,
+    ) -> Result<bool, StoreError>;
+
+    /// Merge an `/import` file into a user's lists, reporting what was added or skipped
+    ///
+    /// `canonical` gives how an imported course ID is stored, `None` when it is not a valid one.
+    async fn import(
+        &self,
+        user_id: u64,
+        import: &WatchlistExport,
+        canonical: &(dyn for<'s> Fn(&'s str) -> Option<String> + Send + Sync),
+        default_limit: usize,
+        department_limit: usize,
+    ) -> Result<ImportReport, StoreError>;
+
+    async fn block_user(&self, user_id: u64, entry: &BlockEntry) -> Result<(), StoreError>;
+
+    /// Returns whether the user was blocked
+    async fn unblock_user(&self, user_id: u64) -> Result<bool, StoreError>;
+
+    async fn is_blocked(&self, user_id: u64) -> Result<bool, StoreError>;
+
+    /// IDs of every blocked user
+    async fn blocked_users(&self) -> Result<HashSet<u64>, StoreError>;
+
+    /// Track whether an alert reached a user, returning their streak of failures if any
+    async fn record_delivery(
+        &self,
+        user_id: u64,
+        delivered: bool,
+        at: u64,
+    ) -> Result<Option<DeliveryFailures>, StoreError>;
+
+    /// Stop checking the watches of a user who cannot be reached
+    async fn disable_user(&self, user_id: u64, at: u64) -> Result<(), StoreError>;
+
+    /// Forget a user's failure streak, returns whether they were disabled
+    async fn mark_reachable(&self, user_id: u64) -> Result<bool, StoreError>;
+
+    /// Every user with a failure streak
+    async fn delivery_failures(&self) -> Result<Vec<(u64, DeliveryFailures)>, StoreError>;
+
+    /// Delete everything stored about a user for `/forget_me`
+    ///
+    /// Owner-set course caps and blocks are kept, they are not the user's data to remove.
+    async fn forget_user(&self, user_id: u64) -> Result<(), StoreError>;
+
+    /// Set the course watches of `semester`, such as `113-1`, aside and drop the seat data of
+    /// their courses, then store the courses of `next`, the semester now enrolled, under bare IDs
+    ///
+    /// Serial numbers are reassigned each semester, so old watches would follow unrelated
+    /// courses. Watches of later semesters and department watches are kept. Commands store the
+    /// semester being enrolled bare, so `{id}@{next}` becomes `id` and is not watched twice.
+    /// Returns the archived course IDs by user.
+    async fn archive_semester(
+        &self,
+        semester: &str,
+        next: &str,
+    ) -> Result<Vec<(u64, Vec<String>)>, StoreError>;
+}
+
+/// Per-course seat data and the checker's own bookkeeping
+#[async_trait]
+pub trait CourseRepository {
+    async fn seat_snapshot(&self, course_id: &str) -> Result<Option<SeatSnapshot>, StoreError>;
+
+    /// Store the latest seat numbers of a course, recording a transition when it opened or closed
+    async fn record_seats(
+        &self,
+        course_id: &str,
+        snapshot: &SeatSnapshot,
+    ) -> Result<(), StoreError>;
+
+    async fn history(&self, course_id: &str) -> Result<Vec<AvailabilityChange>, StoreError>;
+
+    async fn course_meta(&self, course_id: &str) -> Result<Option<CourseMeta>, StoreError>;
+
+    /// Cache the metadata of every listed course, replacing older copies
+    async fn cache_course_meta(
+        &self,
+        courses: &[CourseInfo],
+        fetched_at: u64,
+    ) -> Result<(), StoreError>;
+
+    async fn stats(&self) -> Result<CheckerStats, StoreError>;
+
+    async fn save_stats(&self, stats: &CheckerStats) -> Result<(), StoreError>;
+
+    /// Bytes taken by the stored data, `None` when the backend cannot tell
+    async fn storage_size(&self) -> Result<Option<u64>, StoreError>;
+
+    /// Wait for pending writes and release the storage, called once at shutdown
+    async fn close(&self);
+
+    async fn meta(&self, key: &str) -> Result<Option<u64>, StoreError>;
+
+    async fn set_meta(&self, key: &str, value: u64) -> Result<(), StoreError>;
+
+    /// Cookies of the enrollment system session of an account, kept across restarts
+    async fn crawler_session(&self, account: &str) -> Result<Option<String>, StoreError>;
+
+    async fn save_crawler_session(&self, account: &str, cookies: &str) -> Result<(), StoreError>;
+
+    /// Take the lease `name` for `ttl` seconds, or renew it when `holder` already has it
+    ///
+    /// False while another holder's lease has not expired; replicas sharing the storage use this
+    /// to pick the one that runs the checker.
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: u64) -> Result<bool, StoreError>;
+
+    /// Give up the lease early, if `holder` still has it
+    async fn release_lease(&self, name: &str, holder: &str) -> Result<(), StoreError>;
+
+    async fn watch_counts(&self) -> Result<WatchCounts, StoreError>;
+}
+
+/// A complete storage backend, shared by the bot and the periodic checker
+pub trait Repository: UserRepository + CourseRepository + Send + Sync {}
+
+impl<T: UserRepository + CourseRepository + Send + Sync> Repository for T {}
+
+/// Current unix timestamp in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Days since epoch in Taiwan time, used to roll over daily summaries at local midnight
+pub fn local_day(timestamp: u64) -> u64 {
+    (timestamp + 8 * 3600) / 86400
+}
+
+/// `list` with the watches of `semester` under bare IDs, a course also watched bare keeping that
+/// watch, and the IDs moved away from
+#[cfg(any(test, feature = "redis"))]
+fn watches_to_bare(list: Vec<WatchEntry>, semester: &str) -> (Vec<WatchEntry>, Vec<String>) {
+    let (mut kept, mut moving) = (Vec::new(), Vec::new());
+    for entry in list {
+        match crate::source::strip_semester(&entry.course_id, semester) {
+            Some(bare) => moving.push((bare.to_owned(), entry)),
+            None => kept.push(entry),
+        }
+    }
+    let mut moved = Vec::with_capacity(moving.len());
+    for (bare, mut entry) in moving {
+        moved.push(std::mem::replace(&mut entry.course_id, bare));
+        if !kept.iter().any(|e| e.course_id == entry.course_id) {
+            kept.push(entry);
+        }
+    }
+    kept.sort_by(|a, b| a.course_id.cmp(&b.course_id));
+    (kept, moved)
+}
+
+/// Hour of the day in Taiwan time
+pub fn local_hour(timestamp: u64) -> u8 {
+    ((timestamp + 8 * 3600) % 86400 / 3600) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    /// Every backend, so their behavior stays in sync
+    ///
+    /// Redis is included when `BOT_TEST_REDIS_URL` points at a server, each call under fresh keys.
+    async fn backends() -> Vec<Box<dyn Repository>> {
+        #[allow(unused_mut)]
+        let mut backends: Vec<Box<dyn Repository>> = vec![
+            Box::new(Db::connect("sqlite::memory:", None).await.unwrap()),
+            Box::new(
+                Db::connect(
+                    "sqlite::memory:",
+                    Some(Cipher::from_base64(TEST_KEY).unwrap()),
+                )
+                .await
+                .unwrap(),
+            ),
+            Box::new(MemoryRepository::default()),
+        ];
+        #[cfg(feature = "redis")]
+        if let Ok(url) = std::env::var("BOT_TEST_REDIS_URL") {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            static RUN: AtomicUsize = AtomicUsize::new(0);
+            let prefix = format!(
+                "course-bot-test:{}:{}:{}",
+                std::process::id(),
+                now(),
+                RUN.fetch_add(1, Ordering::Relaxed)
+            );
+            backends.push(Box::new(
+                RedisRepository::connect(
+                    &url,
+                    &prefix,
+                    Some(Cipher::from_base64(TEST_KEY).unwrap()),
+                )
+                .await
+                .unwrap(),
+            ));
+        }
+        backends
+    }
+
+    #[test]
+    fn test_openings() {
+        let change = |at, available| AvailabilityChange { at, available };
+        let history = vec![
+            change(10, 2),
+            change(20, 0),
+            change(30, 0),
+            change(40, 1),
+            change(45, 3),
+            change(50, 0),
+            change(60, 1),
+        ];
+        assert_eq!(
+            openings(&history),
+            vec![(10, Some(20)), (40, Some(50)), (60, None)]
+        );
+        assert_eq!(openings(&[]), vec![]);
+    }
+
+    #[test]
+    fn test_department_open_courses() {
+        let course = |serial_no: &str, option_code: &str, enrolled| CourseInfo {
+            serial_no: serial_no.to_owned(),
+            course_code: String::new(),
+            name: String::new(),
+            teacher: String::new(),
+            option_code: option_code.to_owned(),
+            seats: SeatCount {
+                enrolled,
+                quota: 30,
+            },
+            restrictions: Vec::new(),
+        };
+        let courses = vec![
+            course("0300", "選", 29),
+            course("0100", "必", 10),
+            course("0200", "選", 30),
+        ];
+        let watch = DepartmentWatch::new("CSU".to_owned(), false);
+        assert_eq!(watch.open_courses(&courses), vec!["0100", "0300"]);
+        let watch = DepartmentWatch::new("CSU".to_owned(), true);
+        assert_eq!(watch.open_courses(&courses), vec!["0300"]);
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let night = QuietHours { start: 23, end: 7 };
+        assert!(night.contains(23));
+        assert!(night.contains(3));
+        assert!(!night.contains(7));
+        assert!(!night.contains(12));
+        let lunch = QuietHours { start: 12, end: 13 };
+        assert!(lunch.contains(12));
+        assert!(!lunch.contains(13));
+
+        let settings = UserSettings {
+            quiet_hours: Some(night),
+            ..Default::default()
+        };
+        // 2023-11-14 22:13 UTC is 06:13 in Taiwan
+        assert_eq!(local_hour(1700000000), 6);
+        assert!(settings.is_quiet(1700000000));
+        assert!(!settings.is_quiet(1700000000 + 3600));
+        assert!(!UserSettings::default().is_quiet(1700000000));
+    }
+
+    #[test]
+    fn test_watch_entry_cooldown() {
+        let mut entry = WatchEntry::new("1234".to_owned(), 1, WatchMode::Availability);
+        assert!(!entry.in_cooldown(1800, 10_000));
+        entry.notified_at = Some(10_000);
+        assert!(entry.in_cooldown(1800, 10_000));
+        assert!(entry.in_cooldown(1800, 11_799));
+        assert!(!entry.in_cooldown(1800, 11_800));
+    }
+
+    #[tokio::test]
+    async fn test_add_watch() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_add_watch(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_add_watch(db: &dyn Repository) -> Result<(), StoreError> {
+        assert!(matches!(
+            db.add_watch(1, "0042", None, None, 2).await?,
+            AddOutcome::Added
+        ));
+        assert!(matches!(
+            db.add_watch(1, "0042", None, None, 2).await?,
+            AddOutcome::Duplicate(_)
+        ));
+        assert!(matches!(
+            db.add_watch(1, "0042", Some(3), Some(WatchMode::Changes), 2)
+                .await?,
+            AddOutcome::Updated(WatchEntry {
+                min_seats: 3,
+                mode: WatchMode::Changes,
+                ..
+            })
+        ));
+        assert!(matches!(
+            db.add_watch(1, "0001", None, None, 2).await?,
+            AddOutcome::Added
+        ));
+        assert!(matches!(
+            db.add_watch(1, "0100", None, None, 2).await?,
+            AddOutcome::LimitReached(2)
+        ));
+        db.set_course_limit(1, Some(3)).await?;
+        assert!(matches!(
+            db.add_watch(1, "0100", None, None, 2).await?,
+            AddOutcome::Added
+        ));
+
+        let seats = SeatCount {
+            enrolled: 29,
+            quota: 30,
+        };
+        let seen = HashMap::from([("0042", Some(seats))]);
+        assert!(db.record_check(1, &["0042"], &seen, 500).await?);
+        let list = db.watchlist(1).await?;
+        assert_eq!(
+            list.iter()
+                .map(|e| e.course_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["0001", "0042", "0100"]
+        );
+        assert_eq!(list[1].notified_at, Some(500));
+        assert_eq!(list[1].last_seen, Some(seats));
+
+        db.forget_user(1).await?;
+        assert!(db.watchlist(1).await?.is_empty());
+        assert!(!db.record_check(1, &["0042"], &seen, 600).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_record_seats() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_record_seats(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_record_seats(db: &dyn Repository) -> Result<(), StoreError> {
+        let snapshot = |enrolled, checked_at| SeatSnapshot {
+            seats: Some(SeatCount {
+                enrolled,
+                quota: 30,
+            }),
+            checked_at,
+        };
+        db.record_seats("0042", &snapshot(30, 10)).await?;
+        db.record_seats("0042", &snapshot(28, 20)).await?;
+        db.record_seats("0042", &snapshot(29, 30)).await?;
+        db.record_seats("0042", &snapshot(30, 40)).await?;
+        assert_eq!(
+            db.history("0042").await?,
+            vec![
+                AvailabilityChange {
+                    at: 20,
+                    available: 2
+                },
+                AvailabilityChange {
+                    at: 40,
+                    available: 0
+                },
+            ]
+        );
+        assert_eq!(db.seat_snapshot("0042").await?, Some(snapshot(30, 40)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_course_meta() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_course_meta(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_course_meta(db: &dyn Repository) -> Result<(), StoreError> {
+        let course = |name: &str| CourseInfo {
+            serial_no: "0042".to_owned(),
+            course_code: "CSU0001".to_owned(),
+            name: name.to_owned(),
+            teacher: "Lin".to_owned(),
+            option_code: "必".to_owned(),
+            seats: SeatCount {
+                enrolled: 10,
+                quota: 40,
+            },
+            restrictions: vec!["限本系".to_owned(), "需授權碼".to_owned()],
+        };
+        assert_eq!(db.course_meta("0042").await?, None);
+        db.cache_course_meta(&[course("Calculus")], 100).await?;
+        db.cache_course_meta(&[course("Calculus I")], 200).await?;
+        let meta = db.course_meta("0042").await?.unwrap();
+        assert_eq!(
+            meta,
+            CourseMeta {
+                name: "Calculus I".to_owned(),
+                teacher: "Lin".to_owned(),
+                quota: 40,
+                fetched_at: 200,
+                restrictions: vec!["限本系".to_owned(), "需授權碼".to_owned()],
+            }
+        );
+        assert!(!meta.is_stale(100, 299));
+        assert!(meta.is_stale(100, 300));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_archive_semester() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_archive_semester(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_archive_semester(db: &dyn Repository) -> Result<(), StoreError> {
+        db.add_watch(1, "0100", None, None, 20).await?;
+        db.add_watch(1, "0042", None, None, 20).await?;
+        db.add_watch(2, "0042", None, None, 20).await?;
+        db.add_watch(2, "0042@113-2", Some(3), None, 20).await?;
+        db.add_watch(3, "0100@114-1", None, None, 20).await?;
+        db.watch_department(2, "CSU", false, 20).await?;
+        let snapshot = SeatSnapshot {
+            seats: Some(SeatCount {
+                enrolled: 28,
+                quota: 30,
+            }),
+            checked_at: 10,
+        };
+        db.record_seats("0042", &snapshot).await?;
+        db.record_seats("0042@113-2", &snapshot).await?;
+        assert_eq!(
+            db.archive_semester("113-1", "113-2").await?,
+            vec![
+                (1, vec!["0042".to_owned(), "0100".to_owned()]),
+                (2, vec!["0042".to_owned()]),
+            ]
+        );
+        assert!(db.watchlist(1).await?.is_empty());
+        assert_eq!(db.department_watches(2).await?.len(), 1);
+        // the semester now enrolled is stored bare, as commands store it
+        assert_eq!(db.watched_courses().await?, ["0042", "0100@114-1"]);
+        assert_eq!(db.seat_snapshot("0042").await?, Some(snapshot.clone()));
+        assert_eq!(db.seat_snapshot("0042@113-2").await?, None);
+        assert_eq!(
+            db.subscribers("0042")
+                .await?
+                .into_iter()
+                .map(|(user_id, entry)| (user_id, entry.min_seats))
+                .collect::<Vec<_>>(),
+            [(2, 3)]
+        );
+        assert!(matches!(
+            db.add_watch(2, "0042", None, None, 20).await?,
+            AddOutcome::Duplicate(_)
+        ));
+        assert_eq!(
+            db.archive_semester("113-2", "114-1").await?,
+            vec![(2, vec!["0042".to_owned()])]
+        );
+        assert_eq!(db.watchlist(3).await?[0].course_id, "0100");
+        assert_eq!(
+            db.archive_semester("114-1", "114-2").await?,
+            vec![(3, vec!["0100".to_owned()])]
+        );
+        // nothing left for the next rollover
+        assert!(db.archive_semester("114-2", "115-1").await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_guild_watchlists() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_guild_watchlists(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_guild_watchlists(db: &dyn Repository) -> Result<(), StoreError> {
+        let mut required = GuildWatchlist::new("CSIE required".to_owned(), 7, Lang::ZhTw);
+        required.courses = vec!["0042".to_owned(), "0100".to_owned()];
+        db.save_guild_watchlist(1, &required).await?;
+        let mut electives = GuildWatchlist::new("Electives".to_owned(), 8, Lang::En);
+        electives.courses = vec!["ntu:12345".to_owned()];
+        db.save_guild_watchlist(2, &electives).await?;
+        assert_eq!(db.guild_watchlists(1).await?, [required.clone()]);
+
+        let open = HashMap::from([("CSIE required".to_owned(), vec!["0100".to_owned()])]);
+        db.record_guild_watchlist_check(1, &open).await?;
+        required.open = vec!["0100".to_owned()];
+        assert_eq!(
+            db.all_guild_watchlists().await?,
+            vec![(1, vec![required.clone()]), (2, vec![electives])]
+        );
+        // saving under the same name replaces the list
+        required.courses.pop();
+        db.save_guild_watchlist(1, &required).await?;
+        assert_eq!(db.guild_watchlists(1).await?[0].courses, ["0042"]);
+
+        assert!(db.remove_guild_watchlist(1, "CSIE required").await?);
+        assert!(!db.remove_guild_watchlist(1, "CSIE required").await?);
+        assert!(db.guild_watchlists(1).await?.is_empty());
+        assert_eq!(db.all_guild_watchlists().await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_subscribers() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_subscribers(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_subscribers(db: &dyn Repository) -> Result<(), StoreError> {
+        db.add_watch(1, "0042", None, None, 20).await?;
+        db.add_watch(2, "0042", Some(2), None, 20).await?;
+        db.add_watch(2, "0100", None, None, 20).await?;
+        db.add_watch(3, "0300", None, None, 20).await?;
+        let entry = BlockEntry {
+            blocked_at: 0,
+            reason: None,
+        };
+        db.block_user(3, &entry).await?;
+        assert_eq!(db.watched_courses().await?, vec!["0042", "0100"]);
+        let subscribers = db.subscribers("0042").await?;
+        assert_eq!(
+            subscribers
+                .iter()
+                .map(|(user_id, entry)| (*user_id, entry.min_seats))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (2, 2)]
+        );
+
+        db.remove_watches(1, &["0042".to_owned()]).await?;
+        assert_eq!(db.subscribers("0042").await?.len(), 1);
+        db.forget_user(2).await?;
+        assert!(db.subscribers("0042").await?.is_empty());
+        assert!(db.watched_courses().await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_notifications() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_notifications(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_notifications(db: &dyn Repository) -> Result<(), StoreError> {
+        let record = |at: u64| NotificationRecord {
+            course_id: format!("{at:04}"),
+            kind: NotificationKind::Available,
+            seats: Some(SeatCount {
+                enrolled: 9,
+                quota: 10,
+            }),
+            delivered: at != 3,
+            at,
+        };
+        let records = (0..NOTIFICATION_LIMIT as u64 + 5)
+            .map(record)
+            .collect::<Vec<_>>();
+        db.log_notifications(1, &records[..5]).await?;
+        db.log_notifications(1, &records[5..]).await?;
+        let log = db.notifications(1).await?;
+        assert_eq!(log.len(), NOTIFICATION_LIMIT);
+        assert_eq!(log[0], record(NOTIFICATION_LIMIT as u64 + 4));
+        assert_eq!(log[NOTIFICATION_LIMIT - 1], record(5));
+        assert!(db.notifications(2).await?.is_empty());
+
+        db.forget_user(1).await?;
+        assert!(db.notifications(1).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delivery_failures() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_delivery_failures(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_delivery_failures(db: &dyn Repository) -> Result<(), StoreError> {
+        db.add_watch(1, "0042", None, None, 20).await?;
+        assert_eq!(db.record_delivery(1, true, 100).await?, None);
+        db.record_delivery(1, false, 100).await?;
+        let failures = db.record_delivery(1, false, 200).await?.unwrap();
+        assert_eq!((failures.count, failures.since), (2, 100));
+        // a success ends the streak
+        db.record_delivery(1, true, 300).await?;
+        assert!(db.delivery_failures().await?.is_empty());
+
+        db.record_delivery(1, false, 400).await?;
+        db.disable_user(1, 500).await?;
+        assert!(db.watched_courses().await?.is_empty());
+        assert!(db.subscribers("0042").await?.is_empty());
+        db.record_delivery(1, true, 600).await?;
+        assert_eq!(db.delivery_failures().await?[0].1.disabled_at, Some(500));
+
+        assert!(db.mark_reachable(1).await?);
+        assert!(!db.mark_reachable(1).await?);
+        assert_eq!(db.watched_courses().await?, vec!["0042"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_crawler_session() -> Result<(), StoreError> {
+        for db in backends().await {
+            assert_eq!(db.crawler_session("41047000S").await?, None);
+            db.save_crawler_session("41047000S", "[]").await?;
+            db.save_crawler_session("41047000S", "[{}]").await?;
+            db.save_crawler_session("41047001S", "[]").await?;
+            assert_eq!(
+                db.crawler_session("41047000S").await?.as_deref(),
+                Some("[{}]")
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_lease() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_lease(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_lease(db: &dyn Repository) -> Result<(), StoreError> {
+        assert!(db.acquire_lease("checker", "a", 60).await?);
+        assert!(!db.acquire_lease("checker", "b", 60).await?);
+        // renewing, and other leases are independent
+        assert!(db.acquire_lease("checker", "a", 60).await?);
+        assert!(db.acquire_lease("other", "b", 60).await?);
+        // only the holder can release it
+        db.release_lease("checker", "b").await?;
+        assert!(!db.acquire_lease("checker", "b", 60).await?);
+        db.release_lease("checker", "a").await?;
+        assert!(db.acquire_lease("checker", "b", 60).await?);
+        assert!(!db.acquire_lease("checker", "a", 60).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_updates() -> Result<(), StoreError> {
+        for db in backends().await {
+            check_concurrent_updates(db.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    async fn check_concurrent_updates(db: &dyn Repository) -> Result<(), StoreError> {
+        db.add_watch(1, "0001", None, None, 20).await?;
+        // the checker works from this snapshot while the user adds another course
+        let subscribers = db.subscribers("0001").await?;
+        db.add_watch(1, "0002", None, None, 20).await?;
+        let seen = HashMap::from([("0001", None)]);
+        assert!(db.record_check(1, &["0001"], &seen, 500).await?);
+        let list = db.watchlist(1).await?;
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].notified_at, Some(500));
+        assert_eq!(list[1].notified_at, None);
+
+        let (a, b) = tokio::join!(
+            db.update_settings(1, &|s| s.public_replies = true),
+            db.update_settings(1, &|s| s.daily_summary = true),
+        );
+        a?;
+        b?;
+        let settings = db.settings(1).await?;
+        assert!(settings.public_replies && settings.daily_summary);
+
+        let quiet_hours = Some(QuietHours { start: 23, end: 7 });
+        db.update_settings(1, &|s| s.quiet_hours = quiet_hours)
+            .await?;
+        assert_eq!(db.settings(1).await?.quiet_hours, quiet_hours);
+
+        db.update_settings(1, &|s| s.dm_closed = true).await?;
+        let settings = db.settings(1).await?;
+        assert!(settings.dm_closed && settings.public_replies);
+        db.update_settings(1, &|s| s.fill_alerts = true).await?;
+        assert!(db.settings(1).await?.fill_alerts);
+        Ok(())
+    }
+}
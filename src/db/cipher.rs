@@ -0,0 +1,189 @@
+//! At-rest encryption of serialized stored values
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    aes::{
+        cipher::{generic_array::GenericArray, BlockEncrypt},
+        Aes256,
+    },
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+use super::StoreError;
+
+/// Marks an encrypted value, anything else is read as plain so existing data stays readable
+const PREFIX: &str = "enc1:";
+const NONCE_LEN: usize = 12;
+
+/// Blocks the user ID key is derived from, so it differs from the value key
+const ID_KEY_BLOCKS: [&[u8; 16]; 2] = [b"course-bot:ids:1", b"course-bot:ids:2"];
+
+/// Rounds of the Feistel network sealing user IDs kept in integer columns
+const USER_ROUNDS: u8 = 8;
+
+/// AES-256-GCM with a random nonce per value, stored as `enc1:` and the base64 of nonce and ciphertext
+///
+/// User IDs in key names and set members must stay the same each time they are written, so they
+/// are sealed as a single AES block under a derived key instead, or with a Feistel network over
+/// the same key where they must still fit an integer column.
+pub struct Cipher {
+    values: Aes256Gcm,
+    ids: Aes256,
+}
+
+impl Cipher {
+    /// Key given as the base64 of 32 bytes
+    pub fn from_base64(key: &str) -> Result<Self, StoreError> {
+        let key = BASE64_STANDARD
+            .decode(key.trim())
+            .ok()
+            .filter(|key| key.len() == 32)
+            .ok_or(StoreError::InvalidKey)?;
+        let key = Key::<Aes256Gcm>::from_slice(&key);
+        let block = Aes256::new(key);
+        let mut id_key = Key::<Aes256>::default();
+        for (half, constant) in id_key.chunks_mut(16).zip(ID_KEY_BLOCKS) {
+            let mut derived = GenericArray::clone_from_slice(constant);
+            block.encrypt_block(&mut derived);
+            half.copy_from_slice(&derived);
+        }
+        Ok(Self {
+            values: Aes256Gcm::new(key),
+            ids: Aes256::new(&id_key),
+        })
+    }
+
+    /// The same text for the same user ID, which only the key holder can read back
+    #[cfg(feature = "redis")]
+    pub fn seal_id(&self, user_id: u64) -> String {
+        let mut block = GenericArray::default();
+        block[8..].copy_from_slice(&user_id.to_be_bytes());
+        self.ids.encrypt_block(&mut block);
+        base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(block)
+    }
+
+    /// Reads plain user IDs too, written before encryption was turned on
+    #[cfg(feature = "redis")]
+    pub fn open_id(&self, stored: &str) -> Result<u64, StoreError> {
+        use aes_gcm::aes::cipher::BlockDecrypt;
+
+        if let Ok(user_id) = stored.parse() {
+            return Ok(user_id);
+        }
+        let sealed = base64::prelude::BASE64_URL_SAFE_NO_PAD
+            .decode(stored)
+            .ok()
+            .filter(|sealed| sealed.len() == 16)
+            .ok_or(StoreError::Undecryptable)?;
+        let mut block = GenericArray::clone_from_slice(&sealed);
+        self.ids.decrypt_block(&mut block);
+        let (zeros, user_id) = block.split_at(8);
+        if zeros.iter().any(|&b| b != 0) {
+            return Err(StoreError::Undecryptable);
+        }
+        Ok(u64::from_be_bytes(user_id.try_into().unwrap()))
+    }
+
+    /// The same integer for the same user ID, a keyed permutation of every 64 bit value
+    pub fn seal_user(&self, user_id: u64) -> u64 {
+        let (mut left, mut right) = ((user_id >> 32) as u32, user_id as u32);
+        for round in 0..USER_ROUNDS {
+            (left, right) = (right, left ^ self.round(round, right));
+        }
+        u64::from(left) << 32 | u64::from(right)
+    }
+
+    /// Any key opens any integer, so a wrong key goes unnoticed here
+    pub fn open_user(&self, sealed: u64) -> u64 {
+        let (mut left, mut right) = ((sealed >> 32) as u32, sealed as u32);
+        for round in (0..USER_ROUNDS).rev() {
+            (left, right) = (right ^ self.round(round, left), left);
+        }
+        u64::from(left) << 32 | u64::from(right)
+    }
+
+    /// Feistel round function, its blocks start with a byte `seal_id` blocks never have
+    fn round(&self, round: u8, half: u32) -> u32 {
+        let mut block = GenericArray::default();
+        block[..2].copy_from_slice(&[0xff, round]);
+        block[12..].copy_from_slice(&half.to_be_bytes());
+        self.ids.encrypt_block(&mut block);
+        u32::from_be_bytes(block[..4].try_into().unwrap())
+    }
+
+    pub fn encrypt(&self, plain: &str) -> Result<String, StoreError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.values
+                .encrypt(&nonce, plain.as_bytes())
+                .map_err(|_| StoreError::Undecryptable)?,
+        );
+        Ok(format!("{PREFIX}{}", BASE64_STANDARD.encode(sealed)))
+    }
+
+    pub fn decrypt(&self, stored: String) -> Result<String, StoreError> {
+        let Some(sealed) = stored.strip_prefix(PREFIX) else {
+            return Ok(stored);
+        };
+        let sealed = BASE64_STANDARD
+            .decode(sealed)
+            .map_err(|_| StoreError::Undecryptable)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(StoreError::Undecryptable);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plain = self
+            .values
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| StoreError::Undecryptable)?;
+        String::from_utf8(plain).map_err(|_| StoreError::Undecryptable)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[test]
+    fn test_cipher() {
+        let cipher = Cipher::from_base64(KEY).unwrap();
+        let sealed = cipher.encrypt(r#"{"course_id":"1234"}"#).unwrap();
+        assert!(sealed.starts_with(PREFIX));
+        assert!(!sealed.contains("1234"));
+        assert_ne!(sealed, cipher.encrypt(r#"{"course_id":"1234"}"#).unwrap());
+        assert_eq!(
+            cipher.decrypt(sealed.clone()).unwrap(),
+            r#"{"course_id":"1234"}"#
+        );
+        // values written before encryption was turned on
+        assert_eq!(cipher.decrypt("[1,2]".to_owned()).unwrap(), "[1,2]");
+
+        let other = Cipher::from_base64(&BASE64_STANDARD.encode([7; 32])).unwrap();
+        assert!(other.decrypt(sealed).is_err());
+        assert!(Cipher::from_base64("c2hvcnQ=").is_err());
+
+        #[cfg(feature = "redis")]
+        {
+            let user = cipher.seal_id(123456789);
+            assert_eq!(user, cipher.seal_id(123456789));
+            assert_ne!(user, cipher.seal_id(123456788));
+            assert!(!user.contains("123456789"));
+            assert_eq!(cipher.open_id(&user).unwrap(), 123456789);
+            assert_eq!(cipher.open_id("123456789").unwrap(), 123456789);
+            assert_ne!(other.seal_id(123456789), user);
+            assert!(other.open_id(&user).is_err());
+        }
+
+        for user_id in [0, 1, 123456789, u64::MAX] {
+            let sealed = cipher.seal_user(user_id);
+            assert_ne!(sealed, user_id);
+            assert_eq!(cipher.open_user(sealed), user_id);
+            assert_ne!(other.seal_user(user_id), sealed);
+        }
+        assert_ne!(cipher.seal_user(123456789), cipher.seal_user(123456788));
+    }
+}
@@ -0,0 +1,341 @@
+//! One-time import of the `kv` (sled) database used before the move to SQLite
+
+use std::{path::Path, str::FromStr};
+
+use kv::{Bucket, Msgpack, Raw, Store, Value};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::{
+    sqlite::insert_department_watch, AvailabilityChange, BlockEntry, CourseRepository, Db,
+    DepartmentWatch, DigestEvent, GuildSettings, SeatSnapshot, UserRepository, UserSettings,
+    WatchEntry, WatchMode, META_KV_IMPORTED, META_LAST_DAILY_SUMMARY,
+};
+use crate::crawler::SeatCount;
+
+const USER_COURSES: &str = "user_courses";
+const USER_LIMITS: &str = "user_limits";
+const USER_SETTINGS: &str = "user_settings";
+const GUILD_SETTINGS: &str = "guild_settings";
+const DIGEST_EVENTS: &str = "digest_events";
+const COURSE_SEATS: &str = "course_seats";
+const COURSE_HISTORY: &str = "course_history";
+const DEPARTMENT_WATCHES: &str = "department_watches";
+const BLOCKED_USERS: &str = "blocked_users";
+const META: &str = "meta";
+/// Records that could not be read, keyed `{bucket}/{key}` with their raw value
+const CORRUPT: &str = "corrupt";
+
+/// Watchlists used to be stored as bare course IDs, accept both shapes
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum WatchEntryRepr {
+    Legacy(String),
+    Entry(StoredWatchEntry),
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredWatchEntry {
+    course_id: String,
+    added_at: u64,
+    #[serde(default)]
+    notified_at: Option<u64>,
+    #[serde(default = "default_min_seats")]
+    min_seats: u32,
+    #[serde(default)]
+    mode: WatchMode,
+    #[serde(default)]
+    last_seen: Option<SeatCount>,
+}
+
+fn default_min_seats() -> u32 {
+    1
+}
+
+impl From<WatchEntryRepr> for WatchEntry {
+    fn from(value: WatchEntryRepr) -> Self {
+        match value {
+            WatchEntryRepr::Legacy(course_id) => Self {
+                course_id,
+                added_at: 0,
+                notified_at: None,
+                min_seats: default_min_seats(),
+                mode: WatchMode::default(),
+                last_seen: None,
+            },
+            WatchEntryRepr::Entry(StoredWatchEntry {
+                course_id,
+                added_at,
+                notified_at,
+                min_seats,
+                mode,
+                last_seen,
+            }) => Self {
+                course_id,
+                added_at,
+                notified_at,
+                min_seats,
+                mode,
+                last_seen,
+            },
+        }
+    }
+}
+
+/// Everything worth keeping from the old store
+struct KvData {
+    watchlists: Vec<(u64, Vec<WatchEntry>)>,
+    limits: Vec<(u64, usize)>,
+    settings: Vec<(u64, UserSettings)>,
+    guilds: Vec<(u64, GuildSettings)>,
+    digests: Vec<(u64, Vec<DigestEvent>)>,
+    seats: Vec<(String, SeatSnapshot)>,
+    history: Vec<(String, Vec<AvailabilityChange>)>,
+    departments: Vec<(u64, Vec<DepartmentWatch>)>,
+    blocks: Vec<(u64, BlockEntry)>,
+    last_daily_summary: Option<u64>,
+    /// Records moved to the `corrupt` bucket so far
+    quarantined: usize,
+}
+
+/// Read every entry of a bucket, moving records whose key or value cannot be decoded into the
+/// `corrupt` bucket
+///
+/// Buckets keyed by Discord ID read their keys as `u64`, a key such as `abc` there is corrupt.
+fn read_bucket<K, T>(store: &Store, name: &str) -> Result<Vec<(K, T)>, kv::Error>
+where
+    K: FromStr,
+    T: Serialize + for<'de> Deserialize<'de>,
+{
+    let bucket: Bucket<String, Raw> = store.bucket(Some(name))?;
+    let mut entries = Vec::new();
+    let mut corrupt = Vec::new();
+    for item in bucket.iter() {
+        let item = item?;
+        let key: String = item.key()?;
+        let raw: Raw = item.value()?;
+        let Ok(parsed) = key.parse() else {
+            warn!("Quarantining {name} record with invalid key {key}");
+            corrupt.push((key, raw));
+            continue;
+        };
+        match Msgpack::<T>::from_raw_value(raw.clone()) {
+            Ok(value) => entries.push((parsed, value.0)),
+            Err(e) => {
+                warn!("Quarantining undecodable {name} record {key}: {e}");
+                corrupt.push((key, raw));
+            }
+        }
+    }
+    let quarantine: Bucket<String, Raw> = store.bucket(Some(CORRUPT))?;
+    for (key, raw) in corrupt {
+        quarantine.set(&format!("{name}/{key}"), &raw)?;
+        bucket.remove(&key)?;
+    }
+    Ok(entries)
+}
+
+fn read_kv(path: &Path) -> Result<KvData, kv::Error> {
+    let store = Store::new(kv::Config::new(path).use_compression(true))?;
+    let meta = read_bucket::<String, u64>(&store, META)?;
+    let mut data = KvData {
+        watchlists: read_bucket::<_, Vec<WatchEntryRepr>>(&store, USER_COURSES)?
+            .into_iter()
+            .map(|(user_id, list)| (user_id, list.into_iter().map(WatchEntry::from).collect()))
+            .collect(),
+        limits: read_bucket(&store, USER_LIMITS)?,
+        settings: read_bucket(&store, USER_SETTINGS)?,
+        guilds: read_bucket(&store, GUILD_SETTINGS)?,
+        digests: read_bucket(&store, DIGEST_EVENTS)?,
+        seats: read_bucket(&store, COURSE_SEATS)?,
+        history: read_bucket(&store, COURSE_HISTORY)?,
+        departments: read_bucket(&store, DEPARTMENT_WATCHES)?,
+        blocks: read_bucket(&store, BLOCKED_USERS)?,
+        last_daily_summary: meta
+            .into_iter()
+            .find(|(key, _)| key == META_LAST_DAILY_SUMMARY)
+            .map(|(_, value)| value),
+        quarantined: 0,
+    };
+    // records moved by an interrupted import count as well
+    let corrupt: Bucket<String, Raw> = store.bucket(Some(CORRUPT))?;
+    data.quarantined = corrupt.len();
+    corrupt.flush()?;
+    Ok(data)
+}
+
+/// Copy the old `kv` database at `path` into `db`, once
+///
+/// Does nothing when there is no database at `path` or it was already imported. Every write
+/// replaces the row it targets, so an import interrupted halfway is simply redone on the next start.
+/// Returns how many records could not be read and were left in the old database's `corrupt`
+/// bucket instead.
+pub async fn import_kv(db: &Db, path: &str) -> anyhow::Result<usize> {
+    let path = Path::new(path);
+    if !path.exists() || db.meta(META_KV_IMPORTED).await?.is_some() {
+        return Ok(0);
+    }
+    info!("Importing kv database from {}", path.display());
+    let data = read_kv(path)?;
+
+    let mut tx = db.pool.begin().await?;
+    for (user_id, entries) in &data.watchlists {
+        for entry in entries {
+            db.insert_watch(&mut tx, *user_id, entry).await?;
+        }
+    }
+    for (user_id, watches) in &data.departments {
+        for watch in watches {
+            insert_department_watch(&mut tx, *user_id, watch).await?;
+        }
+    }
+    for (course_id, snapshot) in &data.seats {
+        sqlx::query(
+            "INSERT OR REPLACE INTO course_seats (course_id, enrolled, quota, checked_at)
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(course_id)
+        .bind(snapshot.seats.map(|s| s.enrolled))
+        .bind(snapshot.seats.map(|s| s.quota))
+        .bind(snapshot.checked_at as i64)
+        .execute(&mut *tx)
+        .await?;
+    }
+    for (course_id, changes) in &data.history {
+        sqlx::query("DELETE FROM course_history WHERE course_id = ?")
+            .bind(course_id)
+            .execute(&mut *tx)
+            .await?;
+        for change in changes {
+            db.push_history(&mut tx, course_id, change).await?;
+        }
+    }
+    for (user_id, events) in &data.digests {
+        sqlx::query("DELETE FROM digest_events WHERE user_id = ?")
+            .bind(*user_id as i64)
+            .execute(&mut *tx)
+            .await?;
+        for event in events {
+            sqlx::query("INSERT INTO digest_events (user_id, course_id, at) VALUES (?, ?, ?)")
+                .bind(*user_id as i64)
+                .bind(&event.course_id)
+                .bind(event.at as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await?;
+
+    for (user_id, limit) in data.limits {
+        db.set_course_limit(user_id, Some(limit)).await?;
+    }
+    for (user_id, settings) in &data.settings {
+        db.save_settings(*user_id, settings).await?;
+    }
+    for (guild_id, settings) in &data.guilds {
+        db.save_guild_settings(*guild_id, settings).await?;
+    }
+    for (user_id, entry) in &data.blocks {
+        db.block_user(*user_id, entry).await?;
+    }
+    if let Some(day) = data.last_daily_summary {
+        db.set_meta(META_LAST_DAILY_SUMMARY, day).await?;
+    }
+    db.set_meta(META_KV_IMPORTED, super::now()).await?;
+    info!(
+        "Imported {} watchlists and {} department watchlists from kv",
+        data.watchlists.len(),
+        data.departments.len()
+    );
+    Ok(data.quarantined)
+}
+
+#[cfg(test)]
+mod test {
+    use kv::Value;
+
+    use super::*;
+
+    fn decode(raw: kv::Raw) -> Result<Vec<WatchEntry>, kv::Error> {
+        let stored = Msgpack::<Vec<WatchEntryRepr>>::from_raw_value(raw)?.0;
+        Ok(stored.into_iter().map(WatchEntry::from).collect())
+    }
+
+    #[test]
+    fn test_legacy_watchlist_decode() -> Result<(), kv::Error> {
+        let raw = Msgpack(vec!["1234".to_owned()]).to_raw_value()?;
+        let decoded = decode(raw)?;
+        assert_eq!(
+            decoded,
+            vec![WatchEntry {
+                course_id: "1234".to_owned(),
+                added_at: 0,
+                notified_at: None,
+                min_seats: 1,
+                mode: WatchMode::Availability,
+                last_seen: None,
+            }]
+        );
+
+        let entry = WatchEntry {
+            course_id: "0042".to_owned(),
+            added_at: 1700000000,
+            notified_at: Some(1700000300),
+            min_seats: 3,
+            mode: WatchMode::Changes,
+            last_seen: Some(SeatCount {
+                enrolled: 10,
+                quota: 12,
+            }),
+        };
+        let stored = StoredWatchEntry {
+            course_id: entry.course_id.clone(),
+            added_at: entry.added_at,
+            notified_at: entry.notified_at,
+            min_seats: entry.min_seats,
+            mode: entry.mode,
+            last_seen: entry.last_seen,
+        };
+        let raw = Msgpack(vec![stored]).to_raw_value()?;
+        assert_eq!(decode(raw)?, vec![entry]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_import_kv() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("course-bot-kv-{}", std::process::id()));
+        {
+            let store = Store::new(kv::Config::new(&dir).use_compression(true))?;
+            let watches: Bucket<String, Msgpack<Vec<String>>> = store.bucket(Some(USER_COURSES))?;
+            watches.set(&"42".to_owned(), &Msgpack(vec!["0001".to_owned()]))?;
+            watches.set(&"nobody".to_owned(), &Msgpack(vec!["0002".to_owned()]))?;
+            let limits: Bucket<String, Msgpack<usize>> = store.bucket(Some(USER_LIMITS))?;
+            limits.set(&"42".to_owned(), &Msgpack(3))?;
+            watches.flush()?;
+        }
+
+        let db = Db::connect("sqlite::memory:", None).await?;
+        assert_eq!(import_kv(&db, dir.to_str().unwrap()).await?, 1);
+        // a second start must not import again
+        assert_eq!(import_kv(&db, dir.to_str().unwrap()).await?, 0);
+        {
+            let store = Store::new(kv::Config::new(&dir).use_compression(true))?;
+            let corrupt: Bucket<String, Raw> = store.bucket(Some(CORRUPT))?;
+            assert!(corrupt.contains(&format!("{USER_COURSES}/nobody"))?);
+            let watches: Bucket<String, Raw> = store.bucket(Some(USER_COURSES))?;
+            assert_eq!(watches.len(), 1);
+        }
+        std::fs::remove_dir_all(&dir)?;
+
+        let list = db.watchlist(42).await?;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].course_id, "0001");
+        assert!(db.meta(META_KV_IMPORTED).await?.is_some());
+        let mut conn = db.pool.acquire().await?;
+        assert_eq!(
+            super::super::sqlite::course_limit(&mut conn, 42, 20).await?,
+            3
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,728 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+
+use super::{
+    now, watches_to_bare, AddOutcome, AvailabilityChange, BlockEntry, CheckerStats, CourseMeta,
+    CourseRepository, DeliveryFailures, DepartmentOutcome, DepartmentWatch, DigestEvent,
+    GuildSettings, GuildWatchlist, NotificationRecord, SeatSnapshot, StoreError, UserRepository,
+    UserSettings, WatchCounts, WatchEntry, WatchMode, HISTORY_LIMIT, NOTIFICATION_LIMIT,
+};
+use crate::{
+    crawler::{CourseInfo, SeatCount},
+    export::{ImportReport, WatchlistExport},
+    source::{in_semester, strip_semester},
+};
+
+#[derive(Default)]
+struct State {
+    /// Never holds empty lists, so a missing key means the user watches nothing
+    watches: BTreeMap<u64, Vec<WatchEntry>>,
+    limits: HashMap<u64, usize>,
+    settings: HashMap<u64, UserSettings>,
+    guilds: BTreeMap<u64, GuildSettings>,
+    /// Never holds empty lists, each sorted by name
+    guild_watchlists: BTreeMap<u64, Vec<GuildWatchlist>>,
+    digests: BTreeMap<u64, Vec<DigestEvent>>,
+    /// Oldest first
+    notifications: HashMap<u64, Vec<NotificationRecord>>,
+    seats: HashMap<String, SeatSnapshot>,
+    history: HashMap<String, Vec<AvailabilityChange>>,
+    course_meta: HashMap<String, CourseMeta>,
+    /// Never holds empty lists, like `watches`
+    departments: BTreeMap<u64, Vec<DepartmentWatch>>,
+    stats: CheckerStats,
+    meta: HashMap<String, u64>,
+    crawler_sessions: HashMap<String, String>,
+    /// Holder and expiry by lease name
+    leases: HashMap<String, (String, u64)>,
+    blocks: HashMap<u64, BlockEntry>,
+    delivery_failures: BTreeMap<u64, DeliveryFailures>,
+    /// Watchlists set aside by semester
+    archived: HashMap<String, BTreeMap<u64, Vec<WatchEntry>>>,
+}
+
+impl State {
+    fn course_limit(&self, user_id: u64, default: usize) -> usize {
+        self.limits.get(&user_id).copied().unwrap_or(default)
+    }
+
+    /// Blocked and disabled users are left out of checks
+    fn is_skipped(&self, user_id: u64) -> bool {
+        self.blocks.contains_key(&user_id)
+            || self
+                .delivery_failures
+                .get(&user_id)
+                .is_some_and(|failures| failures.disabled_at.is_some())
+    }
+}
+
+/// Move the data of `{id}@{semester}` to the bare `id`, unless `id` already has some
+fn keys_to_bare<T>(map: &mut HashMap<String, T>, semester: &str) {
+    let keys = map
+        .keys()
+        .filter_map(|key| Some((key.clone(), strip_semester(key, semester)?.to_owned())))
+        .collect::<Vec<_>>();
+    for (key, bare) in keys {
+        if let Some(value) = map.remove(&key) {
+            map.entry(bare).or_insert(value);
+        }
+    }
+}
+
+/// Storage kept entirely in memory, for tests
+#[derive(Default)]
+pub struct MemoryRepository {
+    state: Mutex<State>,
+}
+
+impl MemoryRepository {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        // every update is applied in full before the lock is released
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryRepository {
+    async fn watchlist(&self, user_id: u64) -> Result<Vec<WatchEntry>, StoreError> {
+        Ok(self
+            .state()
+            .watches
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn watched_courses(&self) -> Result<Vec<String>, StoreError> {
+        let state = self.state();
+        let courses = state
+            .watches
+            .iter()
+            .filter(|(user_id, _)| !state.is_skipped(**user_id))
+            .flat_map(|(_, list)| list.iter().map(|entry| entry.course_id.clone()))
+            .collect::<BTreeSet<_>>();
+        Ok(courses.into_iter().collect())
+    }
+
+    async fn subscribers(&self, course_id: &str) -> Result<Vec<(u64, WatchEntry)>, StoreError> {
+        let state = self.state();
+        Ok(state
+            .watches
+            .iter()
+            .filter(|(user_id, _)| !state.is_skipped(**user_id))
+            .filter_map(|(user_id, list)| {
+                let entry = list.iter().find(|entry| entry.course_id == course_id)?;
+                Some((*user_id, entry.clone()))
+            })
+            .collect())
+    }
+
+    async fn add_watch(
+        &self,
+        user_id: u64,
+        course_id: &str,
+        min_seats: Option<u32>,
+        mode: Option<WatchMode>,
+        default_limit: usize,
+    ) -> Result<AddOutcome, StoreError> {
+        let mut state = self.state();
+        let limit = state.course_limit(user_id, default_limit);
+        let current = state.watches.entry(user_id).or_default();
+        let outcome = if let Some(entry) = current.iter_mut().find(|e| e.course_id == course_id) {
+            let updated = WatchEntry {
+                min_seats: min_seats.unwrap_or(entry.min_seats),
+                mode: mode.unwrap_or(entry.mode),
+                ..entry.clone()
+            };
+            if updated != *entry {
+                *entry = updated.clone();
+                AddOutcome::Updated(updated)
+            } else {
+                AddOutcome::Duplicate(updated)
+            }
+        } else if current.len() >= limit {
+            AddOutcome::LimitReached(limit)
+        } else {
+            current.push(WatchEntry::new(
+                course_id.to_owned(),
+                min_seats.unwrap_or(1),
+                mode.unwrap_or_default(),
+            ));
+            current.sort_by(|a, b| a.course_id.cmp(&b.course_id));
+            AddOutcome::Added
+        };
+        if current.is_empty() {
+            state.watches.remove(&user_id);
+        }
+        Ok(outcome)
+    }
+
+    async fn remove_watches(&self, user_id: u64, course_ids: &[String]) -> Result<(), StoreError> {
+        let mut state = self.state();
+        if let Some(current) = state.watches.get_mut(&user_id) {
+            current.retain(|entry| !course_ids.contains(&entry.course_id));
+            if current.is_empty() {
+                state.watches.remove(&user_id);
+            }
+        }
+        Ok(())
+    }
+
+    async fn clear_notified(&self, user_id: u64, course_id: &str) -> Result<(), StoreError> {
+        let mut state = self.state();
+        for entry in state
+            .watches
+            .get_mut(&user_id)
+            .into_iter()
+            .flatten()
+            .filter(|e| e.course_id == course_id)
+        {
+            entry.notified_at = None;
+        }
+        Ok(())
+    }
+
+    async fn record_check(
+        &self,
+        user_id: u64,
+        notified: &[&str],
+        seen: &HashMap<&str, Option<SeatCount>>,
+        at: u64,
+    ) -> Result<bool, StoreError> {
+        let mut state = self.state();
+        let Some(current) = state.watches.get_mut(&user_id) else {
+            return Ok(false);
+        };
+        for entry in current.iter_mut() {
+            if notified.contains(&entry.course_id.as_str()) {
+                entry.notified_at = Some(at);
+            }
+            if let Some(seats) = seen.get(entry.course_id.as_str()) {
+                entry.last_seen = *seats;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn set_course_limit(&self, user_id: u64, limit: Option<usize>) -> Result<(), StoreError> {
+        let mut state = self.state();
+        match limit {
+            Some(limit) => state.limits.insert(user_id, limit),
+            None => state.limits.remove(&user_id),
+        };
+        Ok(())
+    }
+
+    async fn settings(&self, user_id: u64) -> Result<UserSettings, StoreError> {
+        Ok(self
+            .state()
+            .settings
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn save_settings(&self, user_id: u64, settings: &UserSettings) -> Result<(), StoreError> {
+        self.state().settings.insert(user_id, settings.clone());
+        Ok(())
+    }
+
+    async fn update_settings(
+        &self,
+        user_id: u64,
+        update: &(dyn for<'s> Fn(&'s mut UserSettings) + Send + Sync),
+    ) -> Result<UserSettings, StoreError> {
+        let mut state = self.state();
+        let settings = state.settings.entry(user_id).or_default();
+        update(settings);
+        Ok(settings.clone())
+    }
+
+    async fn guild_settings(&self) -> Result<Vec<(u64, GuildSettings)>, StoreError> {
+        Ok(self
+            .state()
+            .guilds
+            .iter()
+            .map(|(guild_id, settings)| (*guild_id, settings.clone()))
+            .collect())
+    }
+
+    async fn save_guild_settings(
+        &self,
+        guild_id: u64,
+        settings: &GuildSettings,
+    ) -> Result<(), StoreError> {
+        self.state().guilds.insert(guild_id, settings.clone());
+        Ok(())
+    }
+
+    async fn remove_guild_settings(&self, guild_id: u64) -> Result<(), StoreError> {
+        self.state().guilds.remove(&guild_id);
+        Ok(())
+    }
+
+    async fn guild_watchlists(&self, guild_id: u64) -> Result<Vec<GuildWatchlist>, StoreError> {
+        Ok(self
+            .state()
+            .guild_watchlists
+            .get(&guild_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn all_guild_watchlists(&self) -> Result<Vec<(u64, Vec<GuildWatchlist>)>, StoreError> {
+        Ok(self
+            .state()
+            .guild_watchlists
+            .iter()
+            .map(|(guild_id, lists)| (*guild_id, lists.clone()))
+            .collect())
+    }
+
+    async fn save_guild_watchlist(
+        &self,
+        guild_id: u64,
+        list: &GuildWatchlist,
+    ) -> Result<(), StoreError> {
+        let mut state = self.state();
+        let lists = state.guild_watchlists.entry(guild_id).or_default();
+        lists.retain(|l| l.name != list.name);
+        lists.push(list.clone());
+        lists.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+
+    async fn remove_guild_watchlist(&self, guild_id: u64, name: &str) -> Result<bool, StoreError> {
+        let mut state = self.state();
+        let Some(lists) = state.guild_watchlists.get_mut(&guild_id) else {
+            return Ok(false);
+        };
+        let count = lists.len();
+        lists.retain(|l| l.name != name);
+        let removed = lists.len() < count;
+        if lists.is_empty() {
+            state.guild_watchlists.remove(&guild_id);
+        }
+        Ok(removed)
+    }
+
+    async fn record_guild_watchlist_check(
+        &self,
+        guild_id: u64,
+        open: &HashMap<String, Vec<String>>,
+    ) -> Result<(), StoreError> {
+        let mut state = self.state();
+        let Some(lists) = state.guild_watchlists.get_mut(&guild_id) else {
+            return Ok(());
+        };
+        for list in lists {
+            if let Some(courses) = open.get(&list.name) {
+                list.open = courses.clone();
+            }
+        }
+        Ok(())
+    }
+
+    async fn push_digest_events(
+        &self,
+        user_id: u64,
+        events: &[DigestEvent],
+    ) -> Result<(), StoreError> {
+        if !events.is_empty() {
+            let mut state = self.state();
+            let pending = state.digests.entry(user_id).or_default();
+            pending.extend_from_slice(events);
+        }
+        Ok(())
+    }
+
+    async fn log_notifications(
+        &self,
+        user_id: u64,
+        records: &[NotificationRecord],
+    ) -> Result<(), StoreError> {
+        if !records.is_empty() {
+            let mut state = self.state();
+            let log = state.notifications.entry(user_id).or_default();
+            log.extend_from_slice(records);
+            let overflow = log.len().saturating_sub(NOTIFICATION_LIMIT);
+            log.drain(..overflow);
+        }
+        Ok(())
+    }
+
+    async fn notifications(&self, user_id: u64) -> Result<Vec<NotificationRecord>, StoreError> {
+        let state = self.state();
+        let log = state.notifications.get(&user_id).into_iter().flatten();
+        Ok(log.rev().cloned().collect())
+    }
+
+    async fn take_digest_events(&self) -> Result<Vec<(u64, Vec<DigestEvent>)>, StoreError> {
+        Ok(std::mem::take(&mut self.state().digests)
+            .into_iter()
+            .collect())
+    }
+
+    async fn department_watches(&self, user_id: u64) -> Result<Vec<DepartmentWatch>, StoreError> {
+        Ok(self
+            .state()
+            .departments
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn all_department_watches(&self) -> Result<Vec<(u64, Vec<DepartmentWatch>)>, StoreError> {
+        Ok(self
+            .state()
+            .departments
+            .iter()
+            .map(|(user_id, list)| (*user_id, list.clone()))
+            .collect())
+    }
+
+    async fn watch_department(
+        &self,
+        user_id: u64,
+        dept_code: &str,
+        elective_only: bool,
+        limit: usize,
+    ) -> Result<DepartmentOutcome, StoreError> {
+        let mut state = self.state();
+        let current = state.departments.entry(user_id).or_default();
+        let count = current.len();
+        let outcome = match current.iter_mut().find(|w| w.dept_code == dept_code) {
+            Some(watch) if watch.elective_only == elective_only => DepartmentOutcome::Duplicate,
+            Some(watch) => {
+                watch.elective_only = elective_only;
+                // re-evaluated with the new filter at the next check
+                watch.open.clear();
+                DepartmentOutcome::Updated
+            }
+            None if count >= limit => DepartmentOutcome::LimitReached(limit),
+            None => {
+                current.push(DepartmentWatch::new(dept_code.to_owned(), elective_only));
+                DepartmentOutcome::Added
+            }
+        };
+        if current.is_empty() {
+            state.departments.remove(&user_id);
+        }
+        Ok(outcome)
+    }
+
+    async fn remove_department_watch(
+        &self,
+        user_id: u64,
+        dept_code: &str,
+    ) -> Result<bool, StoreError> {
+        let mut state = self.state();
+        let Some(current) = state.departments.get_mut(&user_id) else {
+            return Ok(false);
+        };
+        let before = current.len();
+        current.retain(|watch| watch.dept_code != dept_code);
+        let removed = current.len() != before;
+        if current.is_empty() {
+            state.departments.remove(&user_id);
+        }
+        Ok(removed)
+    }
+
+    async fn record_department_check(
+        &self,
+        user_id: u64,
+        open: &HashMap<String, Vec<String>>,
+    ) -> Result<bool, StoreError> {
+        let mut state = self.state();
+        let Some(current) = state.departments.get_mut(&user_id) else {
+            return Ok(false);
+        };
+        for watch in current.iter_mut() {
+            if let Some(courses) = open.get(&watch.dept_code) {
+                watch.open = courses.clone();
+            }
+        }
+        Ok(true)
+    }
+
+    async fn import(
+        &self,
+        user_id: u64,
+        import: &WatchlistExport,
+        canonical: &(dyn for<'s> Fn(&'s str) -> Option<String> + Send + Sync),
+        default_limit: usize,
+        department_limit: usize,
+    ) -> Result<ImportReport, StoreError> {
+        let mut state = self.state();
+        let limit = state.course_limit(user_id, default_limit);
+        let mut courses = state.watches.remove(&user_id).unwrap_or_default();
+        let mut departments = state.departments.remove(&user_id).unwrap_or_default();
+        let report = import.merge_into(
+            &mut courses,
+            &mut departments,
+            canonical,
+            limit,
+            department_limit,
+        );
+        courses.sort_by(|a, b| a.course_id.cmp(&b.course_id));
+        if !courses.is_empty() {
+            state.watches.insert(user_id, courses);
+        }
+        if !departments.is_empty() {
+            state.departments.insert(user_id, departments);
+        }
+        Ok(report)
+    }
+
+    async fn block_user(&self, user_id: u64, entry: &BlockEntry) -> Result<(), StoreError> {
+        self.state().blocks.insert(user_id, entry.clone());
+        Ok(())
+    }
+
+    async fn unblock_user(&self, user_id: u64) -> Result<bool, StoreError> {
+        Ok(self.state().blocks.remove(&user_id).is_some())
+    }
+
+    async fn is_blocked(&self, user_id: u64) -> Result<bool, StoreError> {
+        Ok(self.state().blocks.contains_key(&user_id))
+    }
+
+    async fn blocked_users(&self) -> Result<HashSet<u64>, StoreError> {
+        Ok(self.state().blocks.keys().copied().collect())
+    }
+
+    async fn record_delivery(
+        &self,
+        user_id: u64,
+        delivered: bool,
+        at: u64,
+    ) -> Result<Option<DeliveryFailures>, StoreError> {
+        let mut state = self.state();
+        if delivered {
+            let disabled = state
+                .delivery_failures
+                .get(&user_id)
+                .is_some_and(|failures| failures.disabled_at.is_some());
+            if !disabled {
+                state.delivery_failures.remove(&user_id);
+            }
+            return Ok(None);
+        }
+        let failures = state
+            .delivery_failures
+            .entry(user_id)
+            .or_insert(DeliveryFailures {
+                count: 0,
+                since: at,
+                disabled_at: None,
+            });
+        failures.count += 1;
+        Ok(Some(failures.clone()))
+    }
+
+    async fn disable_user(&self, user_id: u64, at: u64) -> Result<(), StoreError> {
+        if let Some(failures) = self.state().delivery_failures.get_mut(&user_id) {
+            failures.disabled_at = Some(at);
+        }
+        Ok(())
+    }
+
+    async fn mark_reachable(&self, user_id: u64) -> Result<bool, StoreError> {
+        let removed = self.state().delivery_failures.remove(&user_id);
+        Ok(removed.is_some_and(|failures| failures.disabled_at.is_some()))
+    }
+
+    async fn delivery_failures(&self) -> Result<Vec<(u64, DeliveryFailures)>, StoreError> {
+        Ok(self
+            .state()
+            .delivery_failures
+            .iter()
+            .map(|(user_id, failures)| (*user_id, failures.clone()))
+            .collect())
+    }
+
+    async fn forget_user(&self, user_id: u64) -> Result<(), StoreError> {
+        let mut state = self.state();
+        state.watches.remove(&user_id);
+        state.departments.remove(&user_id);
+        state.settings.remove(&user_id);
+        state.digests.remove(&user_id);
+        state.notifications.remove(&user_id);
+        state.delivery_failures.remove(&user_id);
+        Ok(())
+    }
+
+    async fn archive_semester(
+        &self,
+        semester: &str,
+        next: &str,
+    ) -> Result<Vec<(u64, Vec<String>)>, StoreError> {
+        let mut state = self.state();
+        let mut watches = BTreeMap::new();
+        state.watches.retain(|user_id, list| {
+            let (ending, later) = std::mem::take(list)
+                .into_iter()
+                .partition::<Vec<_>, _>(|e| in_semester(&e.course_id, semester));
+            if !ending.is_empty() {
+                watches.insert(*user_id, ending);
+            }
+            *list = watches_to_bare(later, next).0;
+            !list.is_empty()
+        });
+        state.seats.retain(|id, _| !in_semester(id, semester));
+        state.history.retain(|id, _| !in_semester(id, semester));
+        state.course_meta.retain(|id, _| !in_semester(id, semester));
+        keys_to_bare(&mut state.seats, next);
+        keys_to_bare(&mut state.history, next);
+        keys_to_bare(&mut state.course_meta, next);
+        let archived = watches
+            .iter()
+            .map(|(user_id, list)| {
+                let mut course_ids = list.iter().map(|e| e.course_id.clone()).collect::<Vec<_>>();
+                course_ids.sort();
+                (*user_id, course_ids)
+            })
+            .collect();
+        state
+            .archived
+            .entry(semester.to_owned())
+            .or_default()
+            .extend(watches);
+        Ok(archived)
+    }
+}
+
+#[async_trait]
+impl CourseRepository for MemoryRepository {
+    async fn seat_snapshot(&self, course_id: &str) -> Result<Option<SeatSnapshot>, StoreError> {
+        Ok(self.state().seats.get(course_id).cloned())
+    }
+
+    async fn record_seats(
+        &self,
+        course_id: &str,
+        snapshot: &SeatSnapshot,
+    ) -> Result<(), StoreError> {
+        let mut state = self.state();
+        let previous = state.seats.insert(course_id.to_owned(), snapshot.clone());
+        let was_open = previous.is_some_and(|p| p.is_open());
+        if was_open != snapshot.is_open() {
+            let history = state.history.entry(course_id.to_owned()).or_default();
+            history.push(AvailabilityChange {
+                at: snapshot.checked_at,
+                available: snapshot.seats.map(|s| s.available()).unwrap_or(0),
+            });
+            let overflow = history.len().saturating_sub(HISTORY_LIMIT);
+            history.drain(..overflow);
+        }
+        Ok(())
+    }
+
+    async fn history(&self, course_id: &str) -> Result<Vec<AvailabilityChange>, StoreError> {
+        Ok(self
+            .state()
+            .history
+            .get(course_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn course_meta(&self, course_id: &str) -> Result<Option<CourseMeta>, StoreError> {
+        Ok(self.state().course_meta.get(course_id).cloned())
+    }
+
+    async fn cache_course_meta(
+        &self,
+        courses: &[CourseInfo],
+        fetched_at: u64,
+    ) -> Result<(), StoreError> {
+        let mut state = self.state();
+        for course in courses {
+            let meta = CourseMeta::from((course, fetched_at));
+            state.course_meta.insert(course.serial_no.clone(), meta);
+        }
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<CheckerStats, StoreError> {
+        Ok(self.state().stats.clone())
+    }
+
+    async fn save_stats(&self, stats: &CheckerStats) -> Result<(), StoreError> {
+        self.state().stats = stats.clone();
+        Ok(())
+    }
+
+    async fn storage_size(&self) -> Result<Option<u64>, StoreError> {
+        Ok(None)
+    }
+
+    async fn close(&self) {}
+
+    async fn meta(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        Ok(self.state().meta.get(key).copied())
+    }
+
+    async fn set_meta(&self, key: &str, value: u64) -> Result<(), StoreError> {
+        self.state().meta.insert(key.to_owned(), value);
+        Ok(())
+    }
+
+    async fn crawler_session(&self, account: &str) -> Result<Option<String>, StoreError> {
+        Ok(self.state().crawler_sessions.get(account).cloned())
+    }
+
+    async fn save_crawler_session(&self, account: &str, cookies: &str) -> Result<(), StoreError> {
+        self.state()
+            .crawler_sessions
+            .insert(account.to_owned(), cookies.to_owned());
+        Ok(())
+    }
+
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: u64) -> Result<bool, StoreError> {
+        let now = now();
+        let mut state = self.state();
+        if let Some((current, expires_at)) = state.leases.get(name) {
+            if current != holder && *expires_at > now {
+                return Ok(false);
+            }
+        }
+        state
+            .leases
+            .insert(name.to_owned(), (holder.to_owned(), now + ttl));
+        Ok(true)
+    }
+
+    async fn release_lease(&self, name: &str, holder: &str) -> Result<(), StoreError> {
+        let mut state = self.state();
+        if state
+            .leases
+            .get(name)
+            .is_some_and(|(current, _)| current == holder)
+        {
+            state.leases.remove(name);
+        }
+        Ok(())
+    }
+
+    async fn watch_counts(&self) -> Result<WatchCounts, StoreError> {
+        let state = self.state();
+        Ok(WatchCounts {
+            users: state.watches.len(),
+            courses: state.watches.values().map(Vec::len).sum(),
+            unique_courses: state
+                .watches
+                .values()
+                .flatten()
+                .map(|entry| entry.course_id.as_str())
+                .collect::<HashSet<_>>()
+                .len(),
+            departments: state.departments.values().map(Vec::len).sum(),
+        })
+    }
+}
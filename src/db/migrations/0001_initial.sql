@@ -0,0 +1,84 @@
+CREATE TABLE IF NOT EXISTS watches (
+    user_id INTEGER NOT NULL,
+    course_id TEXT NOT NULL,
+    added_at INTEGER NOT NULL,
+    notified_at INTEGER,
+    min_seats INTEGER NOT NULL DEFAULT 1,
+    mode TEXT NOT NULL DEFAULT 'availability',
+    last_enrolled INTEGER,
+    last_quota INTEGER,
+    PRIMARY KEY (user_id, course_id)
+);
+CREATE INDEX IF NOT EXISTS watches_course ON watches (course_id);
+
+CREATE TABLE IF NOT EXISTS user_settings (
+    user_id INTEGER PRIMARY KEY,
+    public_replies INTEGER NOT NULL DEFAULT 0,
+    language TEXT,
+    notify_target TEXT NOT NULL DEFAULT 'dm',
+    notify_channel INTEGER,
+    digest TEXT NOT NULL DEFAULT 'cycle',
+    daily_summary INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS user_limits (
+    user_id INTEGER PRIMARY KEY,
+    course_limit INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS blocked_users (
+    user_id INTEGER PRIMARY KEY,
+    blocked_at INTEGER NOT NULL,
+    reason TEXT
+);
+
+CREATE TABLE IF NOT EXISTS guild_settings (
+    guild_id INTEGER PRIMARY KEY,
+    channel INTEGER NOT NULL,
+    role INTEGER,
+    language TEXT NOT NULL DEFAULT 'en'
+);
+
+CREATE TABLE IF NOT EXISTS digest_events (
+    user_id INTEGER NOT NULL,
+    course_id TEXT NOT NULL,
+    at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS course_seats (
+    course_id TEXT PRIMARY KEY,
+    enrolled INTEGER,
+    quota INTEGER,
+    checked_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS course_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    course_id TEXT NOT NULL,
+    at INTEGER NOT NULL,
+    available INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS course_history_course ON course_history (course_id, id);
+
+CREATE TABLE IF NOT EXISTS department_watches (
+    user_id INTEGER NOT NULL,
+    dept_code TEXT NOT NULL,
+    elective_only INTEGER NOT NULL DEFAULT 0,
+    added_at INTEGER NOT NULL,
+    open_courses TEXT NOT NULL DEFAULT '[]',
+    PRIMARY KEY (user_id, dept_code)
+);
+
+CREATE TABLE IF NOT EXISTS checker_stats (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    started_at INTEGER NOT NULL,
+    last_cycle_at INTEGER,
+    last_cycle_secs INTEGER,
+    query_failures INTEGER NOT NULL DEFAULT 0,
+    logins INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
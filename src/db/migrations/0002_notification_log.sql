@@ -0,0 +1,11 @@
+CREATE TABLE notification_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    course_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    enrolled INTEGER,
+    quota INTEGER,
+    delivered INTEGER NOT NULL,
+    at INTEGER NOT NULL
+);
+CREATE INDEX notification_log_user ON notification_log (user_id, id);
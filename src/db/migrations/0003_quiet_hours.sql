@@ -0,0 +1,2 @@
+ALTER TABLE user_settings ADD COLUMN quiet_start INTEGER;
+ALTER TABLE user_settings ADD COLUMN quiet_end INTEGER;
@@ -0,0 +1,7 @@
+CREATE TABLE course_meta (
+    course_id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    teacher TEXT NOT NULL,
+    quota INTEGER NOT NULL,
+    fetched_at INTEGER NOT NULL
+);
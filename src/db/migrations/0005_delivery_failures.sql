@@ -0,0 +1,6 @@
+CREATE TABLE delivery_failures (
+    user_id INTEGER PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    since INTEGER NOT NULL,
+    disabled_at INTEGER
+);
@@ -0,0 +1,4 @@
+CREATE TABLE crawler_sessions (
+    account TEXT PRIMARY KEY,
+    cookies TEXT NOT NULL
+);
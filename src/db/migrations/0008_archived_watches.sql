@@ -0,0 +1,7 @@
+CREATE TABLE archived_watches (
+    semester TEXT NOT NULL,
+    user_id INTEGER NOT NULL,
+    course_id TEXT NOT NULL,
+    added_at INTEGER NOT NULL,
+    PRIMARY KEY (semester, user_id, course_id)
+);
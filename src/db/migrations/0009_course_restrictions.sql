@@ -0,0 +1 @@
+ALTER TABLE course_meta ADD COLUMN restrictions TEXT NOT NULL DEFAULT '';
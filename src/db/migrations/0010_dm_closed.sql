@@ -0,0 +1 @@
+ALTER TABLE user_settings ADD COLUMN dm_closed INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE user_settings ADD COLUMN fill_alerts INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,9 @@
+CREATE TABLE guild_watchlists (
+    guild_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    channel INTEGER NOT NULL,
+    language TEXT NOT NULL DEFAULT 'en',
+    courses TEXT NOT NULL DEFAULT '[]',
+    open_courses TEXT NOT NULL DEFAULT '[]',
+    PRIMARY KEY (guild_id, name)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE user_settings ADD COLUMN sealed TEXT;
+ALTER TABLE course_history ADD COLUMN sealed TEXT;
@@ -0,0 +1,1095 @@
+//! Redis storage, for deployments that already run Redis or share state between replicas
+//!
+//! Records are stored as JSON, encrypted when a storage key is configured. Updates read, modify and write a whole record inside a
+//! `WATCH`/`MULTI` transaction that is retried whenever another client got there first.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use async_trait::async_trait;
+use redis::{aio::MultiplexedConnection, AsyncCommands, Pipeline, ToRedisArgs};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Mutex;
+
+use super::{
+    cipher::Cipher, watches_to_bare, AddOutcome, AvailabilityChange, BlockEntry, CheckerStats,
+    CourseMeta, CourseRepository, DeliveryFailures, DepartmentOutcome, DepartmentWatch,
+    DigestEvent, GuildSettings, GuildWatchlist, NotificationRecord, SeatSnapshot, StoreError,
+    UserRepository, UserSettings, WatchCounts, WatchEntry, WatchMode, HISTORY_LIMIT,
+    NOTIFICATION_LIMIT,
+};
+use crate::{
+    crawler::{CourseInfo, SeatCount},
+    export::{ImportReport, WatchlistExport},
+    source::{in_semester, strip_semester},
+};
+
+/// Hashes keyed by user ID
+const WATCHES: &str = "watches";
+const DEPARTMENTS: &str = "departments";
+const SETTINGS: &str = "settings";
+const LIMITS: &str = "limits";
+const BLOCKS: &str = "blocks";
+const DELIVERY_FAILURES: &str = "delivery_failures";
+const DIGESTS: &str = "digests";
+/// Hashes keyed by guild ID
+const GUILDS: &str = "guilds";
+const GUILD_WATCHLISTS: &str = "guild_watchlists";
+/// Hashes keyed by course ID
+const SEATS: &str = "seats";
+const COURSE_META: &str = "course_meta";
+const STATS: &str = "stats";
+const META: &str = "meta";
+/// Hash keyed by NTNU account
+const CRAWLER_SESSIONS: &str = "crawler_sessions";
+/// Strings named `lease:{name}` holding the holder, expired by Redis itself
+const LEASE: &str = "lease";
+/// Hashes named `archived:{semester}` keyed by user ID
+const ARCHIVED: &str = "archived";
+
+/// Set the holder and expiry unless another holder has the key
+const ACQUIRE_LEASE: &str = "local holder = redis.call('GET', KEYS[1])
+if holder == false or holder == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+    return 1
+end
+return 0";
+const RELEASE_LEASE: &str = "if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+end
+return 0";
+
+fn course_ids(list: &[WatchEntry]) -> HashSet<String> {
+    list.iter().map(|entry| entry.course_id.clone()).collect()
+}
+
+/// Handle to a Redis server, every key is namespaced under a prefix
+pub struct RedisRepository {
+    /// `WATCH` applies to a whole connection, so commands of different calls must not interleave
+    con: Mutex<MultiplexedConnection>,
+    prefix: String,
+    cipher: Option<Cipher>,
+}
+
+impl RedisRepository {
+    /// Values are encrypted when a cipher is given
+    pub async fn connect(
+        url: &str,
+        prefix: &str,
+        cipher: Option<Cipher>,
+    ) -> Result<Self, StoreError> {
+        let client = redis::Client::open(url)?;
+        let con = client.get_multiplexed_async_connection().await?;
+        Ok(Self {
+            con: Mutex::new(con),
+            prefix: prefix.to_owned(),
+            cipher,
+        })
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<String, StoreError> {
+        let raw = serde_json::to_string(value)?;
+        match &self.cipher {
+            Some(cipher) => cipher.encrypt(&raw),
+            None => Ok(raw),
+        }
+    }
+
+    /// Reads plain values too, so encryption can be turned on for existing data
+    fn decode_value<T: DeserializeOwned>(&self, raw: String) -> Result<T, StoreError> {
+        let raw = match &self.cipher {
+            Some(cipher) => cipher.decrypt(raw)?,
+            None => raw,
+        };
+        Ok(serde_json::from_str(&raw)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, raw: Option<String>) -> Result<Option<T>, StoreError> {
+        raw.map(|raw| self.decode_value(raw)).transpose()
+    }
+
+    fn decode_all<K: Ord, T: DeserializeOwned>(
+        &self,
+        raw: HashMap<K, String>,
+    ) -> Result<BTreeMap<K, T>, StoreError> {
+        raw.into_iter()
+            .map(|(key, raw)| Ok((key, self.decode_value(raw)?)))
+            .collect()
+    }
+
+    fn key(&self, name: &str) -> String {
+        format!("{}:{name}", self.prefix)
+    }
+
+    /// Set of the users watching a course, so checks do not scan every watchlist
+    fn watchers_key(&self, course_id: &str) -> String {
+        format!("{}:watchers:{course_id}", self.prefix)
+    }
+
+    fn history_key(&self, course_id: &str) -> String {
+        format!("{}:history:{course_id}", self.prefix)
+    }
+
+    /// List of a user's notifications, newest first
+    fn notifications_key(&self, user_id: u64) -> String {
+        format!("{}:notifications:{}", self.prefix, self.user_tag(user_id))
+    }
+
+    /// A user in key names and watcher sets, sealed when values are encrypted so Redis does not
+    /// list who watches what
+    fn user_tag(&self, user_id: u64) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.seal_id(user_id),
+            None => user_id.to_string(),
+        }
+    }
+
+    /// Every tag a user may be stored under, the plain ID too when it was written before
+    /// encryption was turned on
+    fn user_tags(&self, user_id: u64) -> Vec<String> {
+        let mut tags = vec![user_id.to_string()];
+        if self.cipher.is_some() {
+            tags.push(self.user_tag(user_id));
+        }
+        tags
+    }
+
+    fn user_of(&self, tag: &str) -> Result<u64, StoreError> {
+        match &self.cipher {
+            Some(cipher) => cipher.open_id(tag),
+            None => tag.parse().map_err(|_| StoreError::Undecryptable),
+        }
+    }
+
+    /// Read-modify-write one field of a hash, retried whenever another client changes the hash first
+    ///
+    /// `update` sees `None` for a missing field and deletes it by leaving `None`. It may queue more
+    /// commands into the same transaction.
+    async fn update_field<T, F, R>(
+        &self,
+        hash: &str,
+        field: F,
+        mut update: impl FnMut(&mut Option<T>, &mut Pipeline) -> R + Send,
+    ) -> Result<R, StoreError>
+    where
+        T: Serialize + DeserializeOwned + Send,
+        F: ToRedisArgs + Send + Sync + Copy,
+        R: Send,
+    {
+        let key = self.key(hash);
+        let mut con = self.con.lock().await;
+        loop {
+            redis::cmd("WATCH")
+                .arg(&key)
+                .query_async::<()>(&mut *con)
+                .await?;
+            let raw: Option<String> = con.hget(&key, field).await?;
+            let mut value = self.decode::<T>(raw)?;
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            let result = update(&mut value, &mut pipe);
+            match &value {
+                Some(value) => pipe.hset(&key, field, self.encode(value)?),
+                None => pipe.hdel(&key, field),
+            }
+            .ignore();
+            // `None` when the hash changed after `WATCH`
+            let done: Option<()> = pipe.query_async(&mut *con).await?;
+            if done.is_some() {
+                return Ok(result);
+            }
+        }
+    }
+
+    /// [`Self::update_field`] on a watchlist, keeping the watcher sets of its courses in step
+    async fn update_watches<R: Send>(
+        &self,
+        user_id: u64,
+        mut update: impl FnMut(&mut Vec<WatchEntry>) -> R + Send,
+    ) -> Result<R, StoreError> {
+        self.update_field(
+            WATCHES,
+            user_id,
+            |value: &mut Option<Vec<WatchEntry>>, pipe| {
+                let mut list = value.take().unwrap_or_default();
+                let before = course_ids(&list);
+                let result = update(&mut list);
+                let after = course_ids(&list);
+                for course_id in before.difference(&after) {
+                    pipe.srem(self.watchers_key(course_id), self.user_tags(user_id))
+                        .ignore();
+                }
+                for course_id in after.difference(&before) {
+                    pipe.sadd(self.watchers_key(course_id), self.user_tag(user_id))
+                        .ignore();
+                }
+                list.sort_by(|a, b| a.course_id.cmp(&b.course_id));
+                *value = (!list.is_empty()).then_some(list);
+                result
+            },
+        )
+        .await
+    }
+
+    async fn update_departments<R: Send>(
+        &self,
+        user_id: u64,
+        mut update: impl FnMut(&mut Vec<DepartmentWatch>) -> R + Send,
+    ) -> Result<R, StoreError> {
+        self.update_field(DEPARTMENTS, user_id, |value, _| {
+            let mut list = value.take().unwrap_or_default();
+            let result = update(&mut list);
+            *value = (!list.is_empty()).then_some(list);
+            result
+        })
+        .await
+    }
+
+    /// Blocked users and users disabled as unreachable, both left out of checks
+    async fn skipped_users(
+        &self,
+        con: &mut MultiplexedConnection,
+    ) -> Result<HashSet<u64>, StoreError> {
+        let blocked: Vec<u64> = con.hkeys(self.key(BLOCKS)).await?;
+        let failures: BTreeMap<u64, DeliveryFailures> =
+            self.decode_all(con.hgetall(self.key(DELIVERY_FAILURES)).await?)?;
+        let disabled = failures
+            .into_iter()
+            .filter(|(_, failures)| failures.disabled_at.is_some())
+            .map(|(user_id, _)| user_id);
+        Ok(blocked.into_iter().chain(disabled).collect())
+    }
+
+    async fn course_limit(&self, user_id: u64, default: usize) -> Result<usize, StoreError> {
+        let limit: Option<usize> = self
+            .con
+            .lock()
+            .await
+            .hget(self.key(LIMITS), user_id)
+            .await?;
+        Ok(limit.unwrap_or(default))
+    }
+}
+
+#[async_trait]
+impl UserRepository for RedisRepository {
+    async fn watchlist(&self, user_id: u64) -> Result<Vec<WatchEntry>, StoreError> {
+        let raw: Option<String> = self
+            .con
+            .lock()
+            .await
+            .hget(self.key(WATCHES), user_id)
+            .await?;
+        Ok(self.decode(raw)?.unwrap_or_default())
+    }
+
+    async fn watched_courses(&self) -> Result<Vec<String>, StoreError> {
+        let mut con = self.con.lock().await;
+        let watches: BTreeMap<u64, Vec<WatchEntry>> =
+            self.decode_all(con.hgetall(self.key(WATCHES)).await?)?;
+        let skipped = self.skipped_users(&mut con).await?;
+        let courses = watches
+            .into_iter()
+            .filter(|(user_id, _)| !skipped.contains(user_id))
+            .flat_map(|(_, list)| list.into_iter().map(|entry| entry.course_id))
+            .collect::<BTreeSet<_>>();
+        Ok(courses.into_iter().collect())
+    }
+
+    async fn subscribers(&self, course_id: &str) -> Result<Vec<(u64, WatchEntry)>, StoreError> {
+        let mut con = self.con.lock().await;
+        let tags: Vec<String> = con.smembers(self.watchers_key(course_id)).await?;
+        if tags.is_empty() {
+            return Ok(Vec::new());
+        }
+        let users = tags
+            .iter()
+            .map(|tag| self.user_of(tag))
+            .collect::<Result<BTreeSet<u64>, _>>()?;
+        let users = users.into_iter().collect::<Vec<_>>();
+        let lists: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(self.key(WATCHES))
+            .arg(&users)
+            .query_async(&mut *con)
+            .await?;
+        let skipped = self.skipped_users(&mut con).await?;
+        let mut subscribers = Vec::new();
+        for (user_id, raw) in users.into_iter().zip(lists) {
+            if skipped.contains(&user_id) {
+                continue;
+            }
+            let list: Vec<WatchEntry> = self.decode(raw)?.unwrap_or_default();
+            if let Some(entry) = list.into_iter().find(|e| e.course_id == course_id) {
+                subscribers.push((user_id, entry));
+            }
+        }
+        Ok(subscribers)
+    }
+
+    async fn add_watch(
+        &self,
+        user_id: u64,
+        course_id: &str,
+        min_seats: Option<u32>,
+        mode: Option<WatchMode>,
+        default_limit: usize,
+    ) -> Result<AddOutcome, StoreError> {
+        let limit = self.course_limit(user_id, default_limit).await?;
+        self.update_watches(user_id, |current| {
+            if let Some(entry) = current.iter_mut().find(|e| e.course_id == course_id) {
+                let updated = WatchEntry {
+                    min_seats: min_seats.unwrap_or(entry.min_seats),
+                    mode: mode.unwrap_or(entry.mode),
+                    ..entry.clone()
+                };
+                if updated != *entry {
+                    *entry = updated.clone();
+                    AddOutcome::Updated(updated)
+                } else {
+                    AddOutcome::Duplicate(updated)
+                }
+            } else if current.len() >= limit {
+                AddOutcome::LimitReached(limit)
+            } else {
+                current.push(WatchEntry::new(
+                    course_id.to_owned(),
+                    min_seats.unwrap_or(1),
+                    mode.unwrap_or_default(),
+                ));
+                AddOutcome::Added
+            }
+        })
+        .await
+    }
+
+    async fn remove_watches(&self, user_id: u64, course_ids: &[String]) -> Result<(), StoreError> {
+        self.update_watches(user_id, |current| {
+            current.retain(|entry| !course_ids.contains(&entry.course_id))
+        })
+        .await
+    }
+
+    async fn clear_notified(&self, user_id: u64, course_id: &str) -> Result<(), StoreError> {
+        self.update_watches(user_id, |current| {
+            for entry in current.iter_mut().filter(|e| e.course_id == course_id) {
+                entry.notified_at = None;
+            }
+        })
+        .await
+    }
+
+    async fn record_check(
+        &self,
+        user_id: u64,
+        notified: &[&str],
+        seen: &HashMap<&str, Option<SeatCount>>,
+        at: u64,
+    ) -> Result<bool, StoreError> {
+        self.update_watches(user_id, |current| {
+            for entry in current.iter_mut() {
+                if notified.contains(&entry.course_id.as_str()) {
+                    entry.notified_at = Some(at);
+                }
+                if let Some(seats) = seen.get(entry.course_id.as_str()) {
+                    entry.last_seen = *seats;
+                }
+            }
+            !current.is_empty()
+        })
+        .await
+    }
+
+    async fn set_course_limit(&self, user_id: u64, limit: Option<usize>) -> Result<(), StoreError> {
+        let mut con = self.con.lock().await;
+        match limit {
+            Some(limit) => con.hset(self.key(LIMITS), user_id, limit).await?,
+            None => con.hdel(self.key(LIMITS), user_id).await?,
+        }
+        Ok(())
+    }
+
+    async fn settings(&self, user_id: u64) -> Result<UserSettings, StoreError> {
+        let raw: Option<String> = self
+            .con
+            .lock()
+            .await
+            .hget(self.key(SETTINGS), user_id)
+            .await?;
+        Ok(self.decode(raw)?.unwrap_or_default())
+    }
+
+    async fn save_settings(&self, user_id: u64, settings: &UserSettings) -> Result<(), StoreError> {
+        let raw = self.encode(settings)?;
+        let mut con = self.con.lock().await;
+        con.hset::<_, _, _, ()>(self.key(SETTINGS), user_id, raw)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_settings(
+        &self,
+        user_id: u64,
+        update: &(dyn for<'s> Fn(&'s mut UserSettings) + Send + Sync),
+    ) -> Result<UserSettings, StoreError> {
+        self.update_field(SETTINGS, user_id, |value, _| {
+            let settings = value.get_or_insert_with(UserSettings::default);
+            update(settings);
+            settings.clone()
+        })
+        .await
+    }
+
+    async fn guild_settings(&self) -> Result<Vec<(u64, GuildSettings)>, StoreError> {
+        let raw = self.con.lock().await.hgetall(self.key(GUILDS)).await?;
+        Ok(self.decode_all(raw)?.into_iter().collect())
+    }
+
+    async fn save_guild_settings(
+        &self,
+        guild_id: u64,
+        settings: &GuildSettings,
+    ) -> Result<(), StoreError> {
+        let raw = self.encode(settings)?;
+        let mut con = self.con.lock().await;
+        con.hset::<_, _, _, ()>(self.key(GUILDS), guild_id, raw)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_guild_settings(&self, guild_id: u64) -> Result<(), StoreError> {
+        let mut con = self.con.lock().await;
+        con.hdel::<_, _, ()>(self.key(GUILDS), guild_id).await?;
+        Ok(())
+    }
+
+    async fn guild_watchlists(&self, guild_id: u64) -> Result<Vec<GuildWatchlist>, StoreError> {
+        let raw: Option<String> = self
+            .con
+            .lock()
+            .await
+            .hget(self.key(GUILD_WATCHLISTS), guild_id)
+            .await?;
+        Ok(self.decode(raw)?.unwrap_or_default())
+    }
+
+    async fn all_guild_watchlists(&self) -> Result<Vec<(u64, Vec<GuildWatchlist>)>, StoreError> {
+        let raw = self
+            .con
+            .lock()
+            .await
+            .hgetall(self.key(GUILD_WATCHLISTS))
+            .await?;
+        Ok(self.decode_all(raw)?.into_iter().collect())
+    }
+
+    async fn save_guild_watchlist(
+        &self,
+        guild_id: u64,
+        list: &GuildWatchlist,
+    ) -> Result<(), StoreError> {
+        self.update_field(GUILD_WATCHLISTS, guild_id, |value, _| {
+            let lists: &mut Vec<GuildWatchlist> = value.get_or_insert_with(Vec::new);
+            lists.retain(|l| l.name != list.name);
+            lists.push(list.clone());
+            lists.sort_by(|a, b| a.name.cmp(&b.name));
+        })
+        .await
+    }
+
+    async fn remove_guild_watchlist(&self, guild_id: u64, name: &str) -> Result<bool, StoreError> {
+        self.update_field(GUILD_WATCHLISTS, guild_id, |value, _| {
+            let mut lists: Vec<GuildWatchlist> = value.take().unwrap_or_default();
+            let count = lists.len();
+            lists.retain(|l| l.name != name);
+            let removed = lists.len() < count;
+            *value = (!lists.is_empty()).then_some(lists);
+            removed
+        })
+        .await
+    }
+
+    async fn record_guild_watchlist_check(
+        &self,
+        guild_id: u64,
+        open: &HashMap<String, Vec<String>>,
+    ) -> Result<(), StoreError> {
+        self.update_field(GUILD_WATCHLISTS, guild_id, |value, _| {
+            let lists: &mut Option<Vec<GuildWatchlist>> = value;
+            for list in lists.iter_mut().flatten() {
+                if let Some(courses) = open.get(&list.name) {
+                    list.open = courses.clone();
+                }
+            }
+        })
+        .await
+    }
+
+    async fn push_digest_events(
+        &self,
+        user_id: u64,
+        events: &[DigestEvent],
+    ) -> Result<(), StoreError> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        self.update_field(DIGESTS, user_id, |value: &mut Option<Vec<_>>, _| {
+            value.get_or_insert_with(Vec::new).extend_from_slice(events)
+        })
+        .await
+    }
+
+    async fn log_notifications(
+        &self,
+        user_id: u64,
+        records: &[NotificationRecord],
+    ) -> Result<(), StoreError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let key = self.notifications_key(user_id);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for record in records {
+            pipe.lpush(&key, self.encode(record)?).ignore();
+        }
+        pipe.ltrim(&key, 0, NOTIFICATION_LIMIT as isize - 1)
+            .ignore();
+        pipe.query_async::<()>(&mut *self.con.lock().await).await?;
+        Ok(())
+    }
+
+    async fn notifications(&self, user_id: u64) -> Result<Vec<NotificationRecord>, StoreError> {
+        let mut con = self.con.lock().await;
+        let raw: Vec<String> = con.lrange(self.notifications_key(user_id), 0, -1).await?;
+        raw.into_iter().map(|raw| self.decode_value(raw)).collect()
+    }
+
+    async fn take_digest_events(&self) -> Result<Vec<(u64, Vec<DigestEvent>)>, StoreError> {
+        let key = self.key(DIGESTS);
+        let mut con = self.con.lock().await;
+        loop {
+            redis::cmd("WATCH")
+                .arg(&key)
+                .query_async::<()>(&mut *con)
+                .await?;
+            let raw: HashMap<u64, String> = con.hgetall(&key).await?;
+            let done: Option<()> = redis::pipe()
+                .atomic()
+                .del(&key)
+                .ignore()
+                .query_async(&mut *con)
+                .await?;
+            if done.is_some() {
+                return Ok(self.decode_all(raw)?.into_iter().collect());
+            }
+        }
+    }
+
+    async fn department_watches(&self, user_id: u64) -> Result<Vec<DepartmentWatch>, StoreError> {
+        let raw: Option<String> = self
+            .con
+            .lock()
+            .await
+            .hget(self.key(DEPARTMENTS), user_id)
+            .await?;
+        Ok(self.decode(raw)?.unwrap_or_default())
+    }
+
+    async fn all_department_watches(&self) -> Result<Vec<(u64, Vec<DepartmentWatch>)>, StoreError> {
+        let raw = self.con.lock().await.hgetall(self.key(DEPARTMENTS)).await?;
+        Ok(self.decode_all(raw)?.into_iter().collect())
+    }
+
+    async fn watch_department(
+        &self,
+        user_id: u64,
+        dept_code: &str,
+        elective_only: bool,
+        limit: usize,
+    ) -> Result<DepartmentOutcome, StoreError> {
+        self.update_departments(user_id, |current| {
+            let count = current.len();
+            match current.iter_mut().find(|w| w.dept_code == dept_code) {
+                Some(watch) if watch.elective_only == elective_only => DepartmentOutcome::Duplicate,
+                Some(watch) => {
+                    watch.elective_only = elective_only;
+                    // re-evaluated with the new filter at the next check
+                    watch.open.clear();
+                    DepartmentOutcome::Updated
+                }
+                None if count >= limit => DepartmentOutcome::LimitReached(limit),
+                None => {
+                    current.push(DepartmentWatch::new(dept_code.to_owned(), elective_only));
+                    DepartmentOutcome::Added
+                }
+            }
+        })
+        .await
+    }
+
+    async fn remove_department_watch(
+        &self,
+        user_id: u64,
+        dept_code: &str,
+    ) -> Result<bool, StoreError> {
+        self.update_departments(user_id, |current| {
+            let before = current.len();
+            current.retain(|watch| watch.dept_code != dept_code);
+            current.len() != before
+        })
+        .await
+    }
+
+    async fn record_department_check(
+        &self,
+        user_id: u64,
+        open: &HashMap<String, Vec<String>>,
+    ) -> Result<bool, StoreError> {
+        self.update_departments(user_id, |current| {
+            for watch in current.iter_mut() {
+                if let Some(courses) = open.get(&watch.dept_code) {
+                    watch.open = courses.clone();
+                }
+            }
+            !current.is_empty()
+        })
+        .await
+    }
+
+    async fn import(
+        &self,
+        user_id: u64,
+        import: &WatchlistExport,
+        canonical: &(dyn for<'s> Fn(&'s str) -> Option<String> + Send + Sync),
+        default_limit: usize,
+        department_limit: usize,
+    ) -> Result<ImportReport, StoreError> {
+        let limit = self.course_limit(user_id, default_limit).await?;
+        let (watches_key, departments_key) = (self.key(WATCHES), self.key(DEPARTMENTS));
+        let mut con = self.con.lock().await;
+        loop {
+            redis::cmd("WATCH")
+                .arg(&watches_key)
+                .arg(&departments_key)
+                .query_async::<()>(&mut *con)
+                .await?;
+            let raw: Option<String> = con.hget(&watches_key, user_id).await?;
+            let mut courses: Vec<WatchEntry> = self.decode(raw)?.unwrap_or_default();
+            let raw: Option<String> = con.hget(&departments_key, user_id).await?;
+            let mut departments: Vec<DepartmentWatch> = self.decode(raw)?.unwrap_or_default();
+            let report = import.merge_into(
+                &mut courses,
+                &mut departments,
+                canonical,
+                limit,
+                department_limit,
+            );
+            courses.sort_by(|a, b| a.course_id.cmp(&b.course_id));
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            // merging only ever adds entries
+            for course_id in &report.added {
+                pipe.sadd(self.watchers_key(course_id), self.user_tag(user_id))
+                    .ignore();
+            }
+            if !courses.is_empty() {
+                pipe.hset(&watches_key, user_id, self.encode(&courses)?)
+                    .ignore();
+            }
+            if !departments.is_empty() {
+                pipe.hset(&departments_key, user_id, self.encode(&departments)?)
+                    .ignore();
+            }
+            // keeps the transaction non-empty so `EXEC` also releases the `WATCH`
+            pipe.hexists(&watches_key, user_id).ignore();
+            let done: Option<()> = pipe.query_async(&mut *con).await?;
+            if done.is_some() {
+                return Ok(report);
+            }
+        }
+    }
+
+    async fn block_user(&self, user_id: u64, entry: &BlockEntry) -> Result<(), StoreError> {
+        let raw = self.encode(entry)?;
+        let mut con = self.con.lock().await;
+        con.hset::<_, _, _, ()>(self.key(BLOCKS), user_id, raw)
+            .await?;
+        Ok(())
+    }
+
+    async fn unblock_user(&self, user_id: u64) -> Result<bool, StoreError> {
+        let removed: usize = self
+            .con
+            .lock()
+            .await
+            .hdel(self.key(BLOCKS), user_id)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn is_blocked(&self, user_id: u64) -> Result<bool, StoreError> {
+        Ok(self
+            .con
+            .lock()
+            .await
+            .hexists(self.key(BLOCKS), user_id)
+            .await?)
+    }
+
+    async fn blocked_users(&self) -> Result<HashSet<u64>, StoreError> {
+        Ok(self.con.lock().await.hkeys(self.key(BLOCKS)).await?)
+    }
+
+    async fn record_delivery(
+        &self,
+        user_id: u64,
+        delivered: bool,
+        at: u64,
+    ) -> Result<Option<DeliveryFailures>, StoreError> {
+        self.update_field(DELIVERY_FAILURES, user_id, |value, _| {
+            if delivered {
+                // a disabled user only gets checked again after `mark_reachable`
+                if value
+                    .as_ref()
+                    .is_some_and(|f: &DeliveryFailures| f.disabled_at.is_none())
+                {
+                    *value = None;
+                }
+                return None;
+            }
+            let failures = value.get_or_insert(DeliveryFailures {
+                count: 0,
+                since: at,
+                disabled_at: None,
+            });
+            failures.count += 1;
+            Some(failures.clone())
+        })
+        .await
+    }
+
+    async fn disable_user(&self, user_id: u64, at: u64) -> Result<(), StoreError> {
+        self.update_field(DELIVERY_FAILURES, user_id, |value, _| {
+            if let Some(failures) = value.as_mut() {
+                let failures: &mut DeliveryFailures = failures;
+                failures.disabled_at = Some(at);
+            }
+        })
+        .await
+    }
+
+    async fn mark_reachable(&self, user_id: u64) -> Result<bool, StoreError> {
+        self.update_field(DELIVERY_FAILURES, user_id, |value, _| {
+            value
+                .take()
+                .is_some_and(|failures: DeliveryFailures| failures.disabled_at.is_some())
+        })
+        .await
+    }
+
+    async fn delivery_failures(&self) -> Result<Vec<(u64, DeliveryFailures)>, StoreError> {
+        let raw = self
+            .con
+            .lock()
+            .await
+            .hgetall(self.key(DELIVERY_FAILURES))
+            .await?;
+        Ok(self.decode_all(raw)?.into_iter().collect())
+    }
+
+    async fn forget_user(&self, user_id: u64) -> Result<(), StoreError> {
+        let watches_key = self.key(WATCHES);
+        let mut con = self.con.lock().await;
+        loop {
+            redis::cmd("WATCH")
+                .arg(&watches_key)
+                .query_async::<()>(&mut *con)
+                .await?;
+            let raw: Option<String> = con.hget(&watches_key, user_id).await?;
+            let list: Vec<WatchEntry> = self.decode(raw)?.unwrap_or_default();
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for entry in &list {
+                pipe.srem(self.watchers_key(&entry.course_id), self.user_tags(user_id))
+                    .ignore();
+            }
+            for hash in [WATCHES, DEPARTMENTS, SETTINGS, DIGESTS, DELIVERY_FAILURES] {
+                pipe.hdel(self.key(hash), user_id).ignore();
+            }
+            for tag in self.user_tags(user_id) {
+                pipe.del(format!("{}:notifications:{tag}", self.prefix))
+                    .ignore();
+            }
+            let done: Option<()> = pipe.query_async(&mut *con).await?;
+            if done.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn archive_semester(
+        &self,
+        semester: &str,
+        next: &str,
+    ) -> Result<Vec<(u64, Vec<String>)>, StoreError> {
+        let watches_key = self.key(WATCHES);
+        let archive_key = self.key(&format!("{ARCHIVED}:{semester}"));
+        let mut con = self.con.lock().await;
+        loop {
+            redis::cmd("WATCH")
+                .arg(&watches_key)
+                .query_async::<()>(&mut *con)
+                .await?;
+            let raw: HashMap<u64, String> = con.hgetall(&watches_key).await?;
+            let mut watches: BTreeMap<u64, Vec<WatchEntry>> = self.decode_all(raw)?;
+            // every course with seat data has a snapshot, its history goes too
+            let recorded: HashMap<String, String> = con.hgetall(self.key(SEATS)).await?;
+            let described: HashMap<String, String> = con.hgetall(self.key(COURSE_META)).await?;
+            // the next semester is now the one enrolled, stored bare
+            let mut histories = Vec::new();
+            for (course_id, bare) in recorded
+                .keys()
+                .filter_map(|id| Some((id, strip_semester(id, next)?)))
+            {
+                let values: Vec<String> = con.lrange(self.history_key(course_id), 0, -1).await?;
+                histories.push((course_id, bare, values));
+            }
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            let mut rewatched = Vec::new();
+            for (user_id, list) in &mut watches {
+                let (ending, later) = std::mem::take(list)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|e| in_semester(&e.course_id, semester));
+                let (later, moved) = watches_to_bare(later, next);
+                if ending.is_empty() && moved.is_empty() {
+                    continue;
+                }
+                if !ending.is_empty() {
+                    pipe.hset(&archive_key, user_id, self.encode(&ending)?)
+                        .ignore();
+                }
+                rewatched.extend(moved.into_iter().map(|course_id| (*user_id, course_id)));
+                if later.is_empty() {
+                    pipe.hdel(&watches_key, user_id).ignore();
+                } else {
+                    pipe.hset(&watches_key, user_id, self.encode(&later)?)
+                        .ignore();
+                }
+                for course_id in ending.iter().map(|e| &e.course_id) {
+                    pipe.del(self.watchers_key(course_id)).ignore();
+                }
+                *list = ending;
+            }
+            for course_id in recorded.keys().filter(|id| in_semester(id, semester)) {
+                pipe.hdel(self.key(SEATS), course_id).ignore();
+                pipe.del(self.history_key(course_id)).ignore();
+            }
+            for course_id in described.keys().filter(|id| in_semester(id, semester)) {
+                pipe.hdel(self.key(COURSE_META), course_id).ignore();
+            }
+            // after the ending courses' watcher sets went, which hold the bare IDs
+            for (user_id, course_id) in &rewatched {
+                pipe.srem(self.watchers_key(course_id), self.user_tags(*user_id))
+                    .ignore();
+                let bare = strip_semester(course_id, next).unwrap_or(course_id);
+                pipe.sadd(self.watchers_key(bare), self.user_tag(*user_id))
+                    .ignore();
+            }
+            for (hash, data) in [(SEATS, &recorded), (COURSE_META, &described)] {
+                for (course_id, value) in data {
+                    if let Some(bare) = strip_semester(course_id, next) {
+                        pipe.hset(self.key(hash), bare, value).ignore();
+                        pipe.hdel(self.key(hash), course_id).ignore();
+                    }
+                }
+            }
+            for (course_id, bare, values) in &histories {
+                pipe.del(self.history_key(bare)).ignore();
+                if !values.is_empty() {
+                    pipe.rpush(self.history_key(bare), values).ignore();
+                }
+                pipe.del(self.history_key(course_id)).ignore();
+            }
+            let done: Option<()> = pipe.query_async(&mut *con).await?;
+            if done.is_some() {
+                watches.retain(|_, list| !list.is_empty());
+                return Ok(watches
+                    .into_iter()
+                    .map(|(user_id, list)| {
+                        let mut course_ids =
+                            list.into_iter().map(|e| e.course_id).collect::<Vec<_>>();
+                        course_ids.sort();
+                        (user_id, course_ids)
+                    })
+                    .collect());
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CourseRepository for RedisRepository {
+    async fn seat_snapshot(&self, course_id: &str) -> Result<Option<SeatSnapshot>, StoreError> {
+        let raw: Option<String> = self
+            .con
+            .lock()
+            .await
+            .hget(self.key(SEATS), course_id)
+            .await?;
+        self.decode(raw)
+    }
+
+    async fn record_seats(
+        &self,
+        course_id: &str,
+        snapshot: &SeatSnapshot,
+    ) -> Result<(), StoreError> {
+        let key = self.history_key(course_id);
+        self.update_field(SEATS, course_id, |value, pipe| {
+            let previous: &Option<SeatSnapshot> = value;
+            let was_open = previous.as_ref().is_some_and(SeatSnapshot::is_open);
+            if was_open != snapshot.is_open() {
+                let change = AvailabilityChange {
+                    at: snapshot.checked_at,
+                    available: snapshot.seats.map(|s| s.available()).unwrap_or(0),
+                };
+                pipe.rpush(&key, self.encode(&change)?).ignore();
+                pipe.ltrim(&key, -(HISTORY_LIMIT as isize), -1).ignore();
+            }
+            *value = Some(snapshot.clone());
+            Ok::<_, StoreError>(())
+        })
+        .await??;
+        Ok(())
+    }
+
+    async fn history(&self, course_id: &str) -> Result<Vec<AvailabilityChange>, StoreError> {
+        let mut con = self.con.lock().await;
+        let raw: Vec<String> = con.lrange(self.history_key(course_id), 0, -1).await?;
+        raw.into_iter().map(|raw| self.decode_value(raw)).collect()
+    }
+
+    async fn course_meta(&self, course_id: &str) -> Result<Option<CourseMeta>, StoreError> {
+        let raw: Option<String> = self
+            .con
+            .lock()
+            .await
+            .hget(self.key(COURSE_META), course_id)
+            .await?;
+        self.decode(raw)
+    }
+
+    async fn cache_course_meta(
+        &self,
+        courses: &[CourseInfo],
+        fetched_at: u64,
+    ) -> Result<(), StoreError> {
+        if courses.is_empty() {
+            return Ok(());
+        }
+        let items = courses
+            .iter()
+            .map(|course| {
+                let meta = CourseMeta::from((course, fetched_at));
+                Ok((course.serial_no.clone(), self.encode(&meta)?))
+            })
+            .collect::<Result<Vec<_>, StoreError>>()?;
+        let mut con = self.con.lock().await;
+        con.hset_multiple::<_, _, _, ()>(self.key(COURSE_META), &items)
+            .await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<CheckerStats, StoreError> {
+        let raw: Option<String> = self.con.lock().await.get(self.key(STATS)).await?;
+        Ok(self.decode(raw)?.unwrap_or_default())
+    }
+
+    async fn save_stats(&self, stats: &CheckerStats) -> Result<(), StoreError> {
+        let raw = self.encode(stats)?;
+        let mut con = self.con.lock().await;
+        con.set::<_, _, ()>(self.key(STATS), raw).await?;
+        Ok(())
+    }
+
+    /// The server's memory is shared with other prefixes and applications
+    async fn storage_size(&self) -> Result<Option<u64>, StoreError> {
+        Ok(None)
+    }
+
+    /// Every write is sent before its call returns, waiting for the connection is enough
+    async fn close(&self) {
+        drop(self.con.lock().await);
+    }
+
+    async fn meta(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        Ok(self.con.lock().await.hget(self.key(META), key).await?)
+    }
+
+    async fn set_meta(&self, key: &str, value: u64) -> Result<(), StoreError> {
+        let mut con = self.con.lock().await;
+        con.hset::<_, _, _, ()>(self.key(META), key, value).await?;
+        Ok(())
+    }
+
+    async fn crawler_session(&self, account: &str) -> Result<Option<String>, StoreError> {
+        let raw: Option<String> = self
+            .con
+            .lock()
+            .await
+            .hget(self.key(CRAWLER_SESSIONS), account)
+            .await?;
+        self.decode(raw)
+    }
+
+    async fn save_crawler_session(&self, account: &str, cookies: &str) -> Result<(), StoreError> {
+        let raw = self.encode(cookies)?;
+        let mut con = self.con.lock().await;
+        con.hset::<_, _, _, ()>(self.key(CRAWLER_SESSIONS), account, raw)
+            .await?;
+        Ok(())
+    }
+
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: u64) -> Result<bool, StoreError> {
+        let acquired: i64 = redis::cmd("EVAL")
+            .arg(ACQUIRE_LEASE)
+            .arg(1)
+            .arg(self.key(&format!("{LEASE}:{name}")))
+            .arg(holder)
+            .arg(ttl.max(1))
+            .query_async(&mut *self.con.lock().await)
+            .await?;
+        Ok(acquired == 1)
+    }
+
+    async fn release_lease(&self, name: &str, holder: &str) -> Result<(), StoreError> {
+        redis::cmd("EVAL")
+            .arg(RELEASE_LEASE)
+            .arg(1)
+            .arg(self.key(&format!("{LEASE}:{name}")))
+            .arg(holder)
+            .query_async::<()>(&mut *self.con.lock().await)
+            .await?;
+        Ok(())
+    }
+
+    async fn watch_counts(&self) -> Result<WatchCounts, StoreError> {
+        let mut con = self.con.lock().await;
+        let watches: BTreeMap<u64, Vec<WatchEntry>> =
+            self.decode_all(con.hgetall(self.key(WATCHES)).await?)?;
+        let departments: BTreeMap<u64, Vec<DepartmentWatch>> =
+            self.decode_all(con.hgetall(self.key(DEPARTMENTS)).await?)?;
+        Ok(WatchCounts {
+            users: watches.len(),
+            courses: watches.values().map(Vec::len).sum(),
+            unique_courses: watches
+                .values()
+                .flatten()
+                .map(|entry| entry.course_id.as_str())
+                .collect::<HashSet<_>>()
+                .len(),
+            departments: departments.values().map(Vec::len).sum(),
+        })
+    }
+}
@@ -0,0 +1,1599 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions},
+    FromRow, Sqlite, SqliteConnection, Transaction,
+};
+use tracing::info;
+
+use super::{
+    cipher::Cipher, now, AddOutcome, AvailabilityChange, BlockEntry, CheckerStats, CourseMeta,
+    CourseRepository, DeliveryFailures, DepartmentOutcome, DepartmentWatch, DigestEvent,
+    GuildSettings, GuildWatchlist, NotificationKind, NotificationRecord, QuietHours, SeatSnapshot,
+    StoreError, UserRepository, UserSettings, WatchCounts, WatchEntry, WatchMode, HISTORY_LIMIT,
+    META_STORAGE_KEY, NOTIFICATION_LIMIT,
+};
+use crate::{
+    crawler::{CourseInfo, SeatCount},
+    export::{ImportReport, WatchlistExport},
+    i18n::Lang,
+    notify::{DigestMode, NotifyTarget},
+};
+
+/// Schema changes in order, `PRAGMA user_version` counts how many were applied
+///
+/// Never edit a released migration, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/0001_initial.sql"),
+    include_str!("migrations/0002_notification_log.sql"),
+    include_str!("migrations/0003_quiet_hours.sql"),
+    include_str!("migrations/0004_course_meta.sql"),
+    include_str!("migrations/0005_delivery_failures.sql"),
+    include_str!("migrations/0006_leases.sql"),
+    include_str!("migrations/0007_crawler_sessions.sql"),
+    include_str!("migrations/0008_archived_watches.sql"),
+    include_str!("migrations/0009_course_restrictions.sql"),
+    include_str!("migrations/0010_dm_closed.sql"),
+    include_str!("migrations/0011_fill_alerts.sql"),
+    include_str!("migrations/0012_guild_watchlists.sql"),
+    include_str!("migrations/0013_sealed_values.sql"),
+];
+
+#[derive(FromRow)]
+struct WatchRow {
+    user_id: i64,
+    course_id: String,
+    added_at: i64,
+    notified_at: Option<i64>,
+    min_seats: i64,
+    mode: WatchMode,
+    last_enrolled: Option<i64>,
+    last_quota: Option<i64>,
+}
+
+impl From<WatchRow> for WatchEntry {
+    fn from(row: WatchRow) -> Self {
+        Self {
+            course_id: row.course_id,
+            added_at: row.added_at as u64,
+            notified_at: row.notified_at.map(|at| at as u64),
+            min_seats: row.min_seats as u32,
+            mode: row.mode,
+            last_seen: seat_count(row.last_enrolled, row.last_quota),
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct NotificationRow {
+    course_id: String,
+    kind: NotificationKind,
+    enrolled: Option<i64>,
+    quota: Option<i64>,
+    delivered: bool,
+    at: i64,
+}
+
+impl From<NotificationRow> for NotificationRecord {
+    fn from(row: NotificationRow) -> Self {
+        Self {
+            course_id: row.course_id,
+            kind: row.kind,
+            seats: seat_count(row.enrolled, row.quota),
+            delivered: row.delivered,
+            at: row.at as u64,
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct DepartmentRow {
+    user_id: i64,
+    dept_code: String,
+    elective_only: bool,
+    added_at: i64,
+    open_courses: String,
+}
+
+impl From<DepartmentRow> for DepartmentWatch {
+    fn from(row: DepartmentRow) -> Self {
+        Self {
+            dept_code: row.dept_code,
+            elective_only: row.elective_only,
+            added_at: row.added_at as u64,
+            open: serde_json::from_str(&row.open_courses).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct GuildWatchlistRow {
+    guild_id: i64,
+    name: String,
+    channel: i64,
+    language: Lang,
+    courses: String,
+    open_courses: String,
+}
+
+impl From<GuildWatchlistRow> for GuildWatchlist {
+    fn from(row: GuildWatchlistRow) -> Self {
+        Self {
+            name: row.name,
+            channel: row.channel as u64,
+            language: row.language,
+            courses: serde_json::from_str(&row.courses).unwrap_or_default(),
+            open: serde_json::from_str(&row.open_courses).unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct SettingsRow {
+    public_replies: bool,
+    language: Option<Lang>,
+    notify_target: NotifyTarget,
+    notify_channel: Option<i64>,
+    digest: DigestMode,
+    daily_summary: bool,
+    quiet_start: Option<i64>,
+    quiet_end: Option<i64>,
+    dm_closed: bool,
+    fill_alerts: bool,
+    sealed: Option<String>,
+}
+
+fn seat_count(enrolled: Option<i64>, quota: Option<i64>) -> Option<SeatCount> {
+    Some(SeatCount {
+        enrolled: enrolled? as i32,
+        quota: quota? as i32,
+    })
+}
+
+/// Group `(user, item)` rows, already ordered by user, into one list per user, or per guild
+fn group_by_user<T>(rows: impl IntoIterator<Item = (i64, T)>) -> Vec<(u64, Vec<T>)> {
+    let mut grouped: Vec<(u64, Vec<T>)> = Vec::new();
+    for (user_id, item) in rows {
+        let user_id = user_id as u64;
+        match grouped.last_mut() {
+            Some((last, items)) if *last == user_id => items.push(item),
+            _ => grouped.push((user_id, vec![item])),
+        }
+    }
+    grouped
+}
+
+const WATCH_COLUMNS: &str =
+    "user_id, course_id, added_at, notified_at, min_seats, mode, last_enrolled, last_quota";
+
+const DEPARTMENT_COLUMNS: &str = "user_id, dept_code, elective_only, added_at, open_courses";
+
+const GUILD_WATCHLIST_COLUMNS: &str = "guild_id, name, channel, language, courses, open_courses";
+
+/// Handle to the SQLite database, cheap to clone
+///
+/// With a cipher, user IDs in `watches` are sealed so rows no longer tie a Discord ID to a course,
+/// and settings and availability history are kept encrypted in the `sealed` column of their row.
+#[derive(Clone)]
+pub struct Db {
+    pub(super) pool: SqlitePool,
+    cipher: Option<Arc<Cipher>>,
+}
+
+impl Db {
+    /// Open (creating if needed) the database at `url` and bring its schema up to date
+    ///
+    /// Data stored before a cipher was given is sealed with it first.
+    pub async fn connect(url: &str, cipher: Option<Cipher>) -> Result<Self, StoreError> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        // a single connection serializes writers, so the read-then-write transactions below
+        // never fail with SQLITE_BUSY; the bot's load is far below what this can serve
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?;
+        migrate(&pool).await?;
+        let db = Self {
+            pool,
+            cipher: cipher.map(Arc::new),
+        };
+        db.seal_existing().await?;
+        Ok(db)
+    }
+
+    /// Seal the user IDs and values stored in plain, once, when a cipher is first given
+    ///
+    /// The key is recorded by sealing user ID 0, which no Discord user has.
+    async fn seal_existing(&self) -> Result<(), StoreError> {
+        let recorded = self.meta(META_STORAGE_KEY).await?;
+        let cipher = match (&self.cipher, recorded) {
+            (None, None) => return Ok(()),
+            (Some(cipher), Some(recorded)) if recorded == cipher.seal_user(0) => return Ok(()),
+            (Some(cipher), None) => cipher,
+            _ => return Err(StoreError::Undecryptable),
+        };
+        let mut tx = self.pool.begin().await?;
+        for table in ["watches", "archived_watches"] {
+            let users: Vec<i64> =
+                sqlx::query_scalar(&format!("SELECT DISTINCT user_id FROM {table}"))
+                    .fetch_all(&mut *tx)
+                    .await?;
+            for user_id in users {
+                sqlx::query(&format!("UPDATE {table} SET user_id = ? WHERE user_id = ?"))
+                    .bind(self.user_key(user_id as u64))
+                    .bind(user_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+        let users: Vec<i64> =
+            sqlx::query_scalar("SELECT user_id FROM user_settings WHERE sealed IS NULL")
+                .fetch_all(&mut *tx)
+                .await?;
+        for user_id in users {
+            let settings = self.load_settings(&mut tx, user_id as u64).await?;
+            self.store_settings(&mut tx, user_id as u64, &settings)
+                .await?;
+        }
+        let changes = sqlx::query_as::<_, (i64, i64, i32)>(
+            "SELECT id, at, available FROM course_history WHERE sealed IS NULL",
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for (id, at, available) in changes {
+            let change = AvailabilityChange {
+                at: at as u64,
+                available,
+            };
+            sqlx::query("UPDATE course_history SET at = 0, available = 0, sealed = ? WHERE id = ?")
+                .bind(self.seal(&change)?)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        sqlx::query("INSERT INTO meta (key, value) VALUES (?, ?)")
+            .bind(META_STORAGE_KEY)
+            .bind(cipher.seal_user(0) as i64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        info!("Sealed the stored user data with the storage key");
+        Ok(())
+    }
+
+    /// What a user ID is stored as in `watches`, sealed when there is a cipher
+    fn user_key(&self, user_id: u64) -> i64 {
+        match &self.cipher {
+            Some(cipher) => cipher.seal_user(user_id) as i64,
+            None => user_id as i64,
+        }
+    }
+
+    fn user_of(&self, key: i64) -> u64 {
+        match &self.cipher {
+            Some(cipher) => cipher.open_user(key as u64),
+            None => key as u64,
+        }
+    }
+
+    /// Encrypted JSON of `value` for a `sealed` column, `None` without a cipher
+    fn seal(&self, value: &impl Serialize) -> Result<Option<String>, StoreError> {
+        match &self.cipher {
+            Some(cipher) => Ok(Some(cipher.encrypt(&serde_json::to_string(value)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn open<T: DeserializeOwned>(&self, sealed: String) -> Result<T, StoreError> {
+        let cipher = self.cipher.as_ref().ok_or(StoreError::Undecryptable)?;
+        Ok(serde_json::from_str(&cipher.decrypt(sealed)?)?)
+    }
+
+    /// Keys in `watches` of blocked users and users no longer checked, whose watches are skipped
+    async fn skipped_users(&self) -> Result<HashSet<i64>, StoreError> {
+        let users: Vec<i64> = sqlx::query_scalar(
+            "SELECT user_id FROM blocked_users
+             UNION SELECT user_id FROM delivery_failures WHERE disabled_at IS NOT NULL",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(users
+            .into_iter()
+            .map(|user_id| self.user_key(user_id as u64))
+            .collect())
+    }
+
+    /// Write a consistent copy of the whole database to `path`, which must not exist yet
+    pub async fn snapshot(&self, path: &Path) -> Result<(), StoreError> {
+        sqlx::query("VACUUM INTO ?")
+            .bind(path.to_string_lossy())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Apply every migration the database has not seen yet
+///
+/// Refuses databases written by a newer version of the bot rather than guessing at their layout.
+async fn migrate(pool: &SqlitePool) -> Result<(), StoreError> {
+    let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(pool)
+        .await?;
+    let version = version as usize;
+    if version > MIGRATIONS.len() {
+        return Err(StoreError::UnknownVersion {
+            found: version,
+            supported: MIGRATIONS.len(),
+        });
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let mut tx = pool.begin().await?;
+        sqlx::raw_sql(migration).execute(&mut *tx).await?;
+        // pragmas cannot take bound parameters
+        sqlx::raw_sql(&format!("PRAGMA user_version = {}", index + 1))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        info!("Applied database migration {}", index + 1);
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl UserRepository for Db {
+    async fn watchlist(&self, user_id: u64) -> Result<Vec<WatchEntry>, StoreError> {
+        let rows = sqlx::query_as::<_, WatchRow>(&format!(
+            "SELECT {WATCH_COLUMNS} FROM watches WHERE user_id = ? ORDER BY course_id"
+        ))
+        .bind(self.user_key(user_id))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(WatchEntry::from).collect())
+    }
+
+    async fn watched_courses(&self) -> Result<Vec<String>, StoreError> {
+        // user IDs may be sealed here, so skipped users are matched outside SQL
+        let skipped = self.skipped_users().await?;
+        let rows = sqlx::query_as::<_, (i64, String)>(
+            "SELECT user_id, course_id FROM watches ORDER BY course_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut courses: Vec<String> = rows
+            .into_iter()
+            .filter(|(user_id, _)| !skipped.contains(user_id))
+            .map(|(_, course_id)| course_id)
+            .collect();
+        courses.dedup();
+        Ok(courses)
+    }
+
+    async fn subscribers(&self, course_id: &str) -> Result<Vec<(u64, WatchEntry)>, StoreError> {
+        // served by the `watches_course` index instead of a scan over every watchlist
+        let skipped = self.skipped_users().await?;
+        let rows = sqlx::query_as::<_, WatchRow>(&format!(
+            "SELECT {WATCH_COLUMNS} FROM watches WHERE course_id = ?"
+        ))
+        .bind(course_id)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut subscribers: Vec<(u64, WatchEntry)> = rows
+            .into_iter()
+            .filter(|row| !skipped.contains(&row.user_id))
+            .map(|row| (self.user_of(row.user_id), row.into()))
+            .collect();
+        subscribers.sort_by_key(|(user_id, _)| *user_id);
+        Ok(subscribers)
+    }
+
+    async fn add_watch(
+        &self,
+        user_id: u64,
+        course_id: &str,
+        min_seats: Option<u32>,
+        mode: Option<WatchMode>,
+        default_limit: usize,
+    ) -> Result<AddOutcome, StoreError> {
+        let mut tx = self.pool.begin().await?;
+        let limit = course_limit(&mut tx, user_id, default_limit).await?;
+        let existing = sqlx::query_as::<_, WatchRow>(&format!(
+            "SELECT {WATCH_COLUMNS} FROM watches WHERE user_id = ? AND course_id = ?"
+        ))
+        .bind(self.user_key(user_id))
+        .bind(course_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .map(WatchEntry::from);
+        let outcome = match existing {
+            Some(entry) => {
+                let updated = WatchEntry {
+                    min_seats: min_seats.unwrap_or(entry.min_seats),
+                    mode: mode.unwrap_or(entry.mode),
+                    ..entry.clone()
+                };
+                if updated != entry {
+                    sqlx::query(
+                        "UPDATE watches SET min_seats = ?, mode = ? WHERE user_id = ? AND course_id = ?",
+                    )
+                    .bind(updated.min_seats)
+                    .bind(updated.mode)
+                    .bind(self.user_key(user_id))
+                    .bind(course_id)
+                    .execute(&mut *tx)
+                    .await?;
+                    AddOutcome::Updated(updated)
+                } else {
+                    AddOutcome::Duplicate(updated)
+                }
+            }
+            None => {
+                let count: i64 =
+                    sqlx::query_scalar("SELECT COUNT(*) FROM watches WHERE user_id = ?")
+                        .bind(self.user_key(user_id))
+                        .fetch_one(&mut *tx)
+                        .await?;
+                if count as usize >= limit {
+                    AddOutcome::LimitReached(limit)
+                } else {
+                    let entry = WatchEntry::new(
+                        course_id.to_owned(),
+                        min_seats.unwrap_or(1),
+                        mode.unwrap_or_default(),
+                    );
+                    self.insert_watch(&mut tx, user_id, &entry).await?;
+                    AddOutcome::Added
+                }
+            }
+        };
+        tx.commit().await?;
+        Ok(outcome)
+    }
+
+    async fn remove_watches(&self, user_id: u64, course_ids: &[String]) -> Result<(), StoreError> {
+        let mut tx = self.pool.begin().await?;
+        for course_id in course_ids {
+            sqlx::query("DELETE FROM watches WHERE user_id = ? AND course_id = ?")
+                .bind(self.user_key(user_id))
+                .bind(course_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        Ok(tx.commit().await?)
+    }
+
+    async fn clear_notified(&self, user_id: u64, course_id: &str) -> Result<(), StoreError> {
+        sqlx::query("UPDATE watches SET notified_at = NULL WHERE user_id = ? AND course_id = ?")
+            .bind(self.user_key(user_id))
+            .bind(course_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn record_check(
+        &self,
+        user_id: u64,
+        notified: &[&str],
+        seen: &HashMap<&str, Option<SeatCount>>,
+        at: u64,
+    ) -> Result<bool, StoreError> {
+        let mut tx = self.pool.begin().await?;
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM watches WHERE user_id = ?")
+            .bind(self.user_key(user_id))
+            .fetch_one(&mut *tx)
+            .await?;
+        if count == 0 {
+            return Ok(false);
+        }
+        for course_id in notified {
+            sqlx::query("UPDATE watches SET notified_at = ? WHERE user_id = ? AND course_id = ?")
+                .bind(at as i64)
+                .bind(self.user_key(user_id))
+                .bind(course_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        for (course_id, seats) in seen {
+            sqlx::query(
+                "UPDATE watches SET last_enrolled = ?, last_quota = ? WHERE user_id = ? AND course_id = ?",
+            )
+            .bind(seats.map(|s| s.enrolled))
+            .bind(seats.map(|s| s.quota))
+            .bind(self.user_key(user_id))
+            .bind(course_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn set_course_limit(&self, user_id: u64, limit: Option<usize>) -> Result<(), StoreError> {
+        match limit {
+            Some(limit) => sqlx::query(
+                "INSERT INTO user_limits (user_id, course_limit) VALUES (?, ?)
+                 ON CONFLICT (user_id) DO UPDATE SET course_limit = excluded.course_limit",
+            )
+            .bind(user_id as i64)
+            .bind(limit as i64),
+            None => sqlx::query("DELETE FROM user_limits WHERE user_id = ?").bind(user_id as i64),
+        }
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn settings(&self, user_id: u64) -> Result<UserSettings, StoreError> {
+        let mut conn = self.pool.acquire().await?;
+        self.load_settings(&mut conn, user_id).await
+    }
+
+    async fn save_settings(&self, user_id: u64, settings: &UserSettings) -> Result<(), StoreError> {
+        let mut conn = self.pool.acquire().await?;
+        self.store_settings(&mut conn, user_id, settings).await
+    }
+
+    async fn update_settings(
+        &self,
+        user_id: u64,
+        update: &(dyn for<'s> Fn(&'s mut UserSettings) + Send + Sync),
+    ) -> Result<UserSettings, StoreError> {
+        let mut tx = self.pool.begin().await?;
+        let mut settings = self.load_settings(&mut tx, user_id).await?;
+        update(&mut settings);
+        self.store_settings(&mut tx, user_id, &settings).await?;
+        tx.commit().await?;
+        Ok(settings)
+    }
+
+    async fn guild_settings(&self) -> Result<Vec<(u64, GuildSettings)>, StoreError> {
+        let rows = sqlx::query_as::<_, (i64, i64, Option<i64>, Lang)>(
+            "SELECT guild_id, channel, role, language FROM guild_settings",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(guild_id, channel, role, language)| {
+                let settings = GuildSettings {
+                    channel: channel as u64,
+                    role: role.map(|r| r as u64),
+                    language,
+                };
+                (guild_id as u64, settings)
+            })
+            .collect())
+    }
+
+    async fn save_guild_settings(
+        &self,
+        guild_id: u64,
+        settings: &GuildSettings,
+    ) -> Result<(), StoreError> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO guild_settings (guild_id, channel, role, language)
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(guild_id as i64)
+        .bind(settings.channel as i64)
+        .bind(settings.role.map(|r| r as i64))
+        .bind(settings.language)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_guild_settings(&self, guild_id: u64) -> Result<(), StoreError> {
+        sqlx::query("DELETE FROM guild_settings WHERE guild_id = ?")
+            .bind(guild_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn guild_watchlists(&self, guild_id: u64) -> Result<Vec<GuildWatchlist>, StoreError> {
+        let rows = sqlx::query_as::<_, GuildWatchlistRow>(&format!(
+            "SELECT {GUILD_WATCHLIST_COLUMNS} FROM guild_watchlists WHERE guild_id = ? ORDER BY name"
+        ))
+        .bind(guild_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(GuildWatchlist::from).collect())
+    }
+
+    async fn all_guild_watchlists(&self) -> Result<Vec<(u64, Vec<GuildWatchlist>)>, StoreError> {
+        let rows = sqlx::query_as::<_, GuildWatchlistRow>(&format!(
+            "SELECT {GUILD_WATCHLIST_COLUMNS} FROM guild_watchlists ORDER BY guild_id, name"
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(group_by_user(
+            rows.into_iter().map(|row| (row.guild_id, row.into())),
+        ))
+    }
+
+    async fn save_guild_watchlist(
+        &self,
+        guild_id: u64,
+        list: &GuildWatchlist,
+    ) -> Result<(), StoreError> {
+        sqlx::query(&format!(
+            "INSERT OR REPLACE INTO guild_watchlists ({GUILD_WATCHLIST_COLUMNS})
+             VALUES (?, ?, ?, ?, ?, ?)"
+        ))
+        .bind(guild_id as i64)
+        .bind(&list.name)
+        .bind(list.channel as i64)
+        .bind(list.language)
+        .bind(serde_json::to_string(&list.courses).unwrap_or_default())
+        .bind(serde_json::to_string(&list.open).unwrap_or_default())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remove_guild_watchlist(&self, guild_id: u64, name: &str) -> Result<bool, StoreError> {
+        let result = sqlx::query("DELETE FROM guild_watchlists WHERE guild_id = ? AND name = ?")
+            .bind(guild_id as i64)
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn record_guild_watchlist_check(
+        &self,
+        guild_id: u64,
+        open: &HashMap<String, Vec<String>>,
+    ) -> Result<(), StoreError> {
+        let mut tx = self.pool.begin().await?;
+        for (name, courses) in open {
+            sqlx::query(
+                "UPDATE guild_watchlists SET open_courses = ? WHERE guild_id = ? AND name = ?",
+            )
+            .bind(serde_json::to_string(courses).unwrap_or_default())
+            .bind(guild_id as i64)
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(tx.commit().await?)
+    }
+
+    async fn push_digest_events(
+        &self,
+        user_id: u64,
+        events: &[DigestEvent],
+    ) -> Result<(), StoreError> {
+        let mut tx = self.pool.begin().await?;
+        for event in events {
+            sqlx::query("INSERT INTO digest_events (user_id, course_id, at) VALUES (?, ?, ?)")
+                .bind(user_id as i64)
+                .bind(&event.course_id)
+                .bind(event.at as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        Ok(tx.commit().await?)
+    }
+
+    async fn log_notifications(
+        &self,
+        user_id: u64,
+        records: &[NotificationRecord],
+    ) -> Result<(), StoreError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let mut tx = self.pool.begin().await?;
+        for record in records {
+            sqlx::query(
+                "INSERT INTO notification_log (user_id, course_id, kind, enrolled, quota, delivered, at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(user_id as i64)
+            .bind(&record.course_id)
+            .bind(record.kind)
+            .bind(record.seats.map(|s| s.enrolled))
+            .bind(record.seats.map(|s| s.quota))
+            .bind(record.delivered)
+            .bind(record.at as i64)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query(
+            "DELETE FROM notification_log WHERE user_id = ? AND id NOT IN
+             (SELECT id FROM notification_log WHERE user_id = ? ORDER BY id DESC LIMIT ?)",
+        )
+        .bind(user_id as i64)
+        .bind(user_id as i64)
+        .bind(NOTIFICATION_LIMIT as i64)
+        .execute(&mut *tx)
+        .await?;
+        Ok(tx.commit().await?)
+    }
+
+    async fn notifications(&self, user_id: u64) -> Result<Vec<NotificationRecord>, StoreError> {
+        let rows = sqlx::query_as::<_, NotificationRow>(
+            "SELECT course_id, kind, enrolled, quota, delivered, at FROM notification_log
+             WHERE user_id = ? ORDER BY id DESC",
+        )
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(NotificationRecord::from).collect())
+    }
+
+    async fn take_digest_events(&self) -> Result<Vec<(u64, Vec<DigestEvent>)>, StoreError> {
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query_as::<_, (i64, String, i64)>(
+            "SELECT user_id, course_id, at FROM digest_events ORDER BY user_id, at",
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM digest_events")
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(group_by_user(rows.into_iter().map(
+            |(user_id, course_id, at)| {
+                (
+                    user_id,
+                    DigestEvent {
+                        course_id,
+                        at: at as u64,
+                    },
+                )
+            },
+        )))
+    }
+
+    async fn department_watches(&self, user_id: u64) -> Result<Vec<DepartmentWatch>, StoreError> {
+        let rows = sqlx::query_as::<_, DepartmentRow>(&format!(
+            "SELECT {DEPARTMENT_COLUMNS} FROM department_watches WHERE user_id = ? ORDER BY added_at"
+        ))
+        .bind(user_id as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(DepartmentWatch::from).collect())
+    }
+
+    async fn all_department_watches(&self) -> Result<Vec<(u64, Vec<DepartmentWatch>)>, StoreError> {
+        let rows = sqlx::query_as::<_, DepartmentRow>(&format!(
+            "SELECT {DEPARTMENT_COLUMNS} FROM department_watches ORDER BY user_id, added_at"
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(group_by_user(
+            rows.into_iter().map(|row| (row.user_id, row.into())),
+        ))
+    }
+
+    async fn watch_department(
+        &self,
+        user_id: u64,
+        dept_code: &str,
+        elective_only: bool,
+        limit: usize,
+    ) -> Result<DepartmentOutcome, StoreError> {
+        let mut tx = self.pool.begin().await?;
+        let existing: Option<bool> = sqlx::query_scalar(
+            "SELECT elective_only FROM department_watches WHERE user_id = ? AND dept_code = ?",
+        )
+        .bind(user_id as i64)
+        .bind(dept_code)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let outcome = match existing {
+            Some(current) if current == elective_only => DepartmentOutcome::Duplicate,
+            Some(_) => {
+                // re-evaluated with the new filter at the next check
+                sqlx::query(
+                    "UPDATE department_watches SET elective_only = ?, open_courses = '[]'
+                     WHERE user_id = ? AND dept_code = ?",
+                )
+                .bind(elective_only)
+                .bind(user_id as i64)
+                .bind(dept_code)
+                .execute(&mut *tx)
+                .await?;
+                DepartmentOutcome::Updated
+            }
+            None => {
+                let count: i64 =
+                    sqlx::query_scalar("SELECT COUNT(*) FROM department_watches WHERE user_id = ?")
+                        .bind(user_id as i64)
+                        .fetch_one(&mut *tx)
+                        .await?;
+                if count as usize >= limit {
+                    DepartmentOutcome::LimitReached(limit)
+                } else {
+                    let watch = DepartmentWatch::new(dept_code.to_owned(), elective_only);
+                    insert_department_watch(&mut tx, user_id, &watch).await?;
+                    DepartmentOutcome::Added
+                }
+            }
+        };
+        tx.commit().await?;
+        Ok(outcome)
+    }
+
+    async fn remove_department_watch(
+        &self,
+        user_id: u64,
+        dept_code: &str,
+    ) -> Result<bool, StoreError> {
+        let result =
+            sqlx::query("DELETE FROM department_watches WHERE user_id = ? AND dept_code = ?")
+                .bind(user_id as i64)
+                .bind(dept_code)
+                .execute(&self.pool)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn record_department_check(
+        &self,
+        user_id: u64,
+        open: &HashMap<String, Vec<String>>,
+    ) -> Result<bool, StoreError> {
+        let mut tx = self.pool.begin().await?;
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM department_watches WHERE user_id = ?")
+                .bind(user_id as i64)
+                .fetch_one(&mut *tx)
+                .await?;
+        if count == 0 {
+            return Ok(false);
+        }
+        for (dept_code, courses) in open {
+            sqlx::query(
+                "UPDATE department_watches SET open_courses = ? WHERE user_id = ? AND dept_code = ?",
+            )
+            .bind(serde_json::to_string(courses).unwrap_or_default())
+            .bind(user_id as i64)
+            .bind(dept_code)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn import(
+        &self,
+        user_id: u64,
+        import: &WatchlistExport,
+        canonical: &(dyn for<'s> Fn(&'s str) -> Option<String> + Send + Sync),
+        default_limit: usize,
+        department_limit: usize,
+    ) -> Result<ImportReport, StoreError> {
+        let mut tx = self.pool.begin().await?;
+        let limit = course_limit(&mut tx, user_id, default_limit).await?;
+        let mut courses = sqlx::query_as::<_, WatchRow>(&format!(
+            "SELECT {WATCH_COLUMNS} FROM watches WHERE user_id = ?"
+        ))
+        .bind(self.user_key(user_id))
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(WatchEntry::from)
+        .collect::<Vec<_>>();
+        let mut departments = sqlx::query_as::<_, DepartmentRow>(&format!(
+            "SELECT {DEPARTMENT_COLUMNS} FROM department_watches WHERE user_id = ?"
+        ))
+        .bind(user_id as i64)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(DepartmentWatch::from)
+        .collect::<Vec<_>>();
+        let report = import.merge_into(
+            &mut courses,
+            &mut departments,
+            canonical,
+            limit,
+            department_limit,
+        );
+        for entry in courses
+            .iter()
+            .filter(|e| report.added.contains(&e.course_id))
+        {
+            self.insert_watch(&mut tx, user_id, entry).await?;
+        }
+        for watch in departments
+            .iter()
+            .filter(|w| report.added.contains(&w.dept_code))
+        {
+            insert_department_watch(&mut tx, user_id, watch).await?;
+        }
+        tx.commit().await?;
+        Ok(report)
+    }
+
+    async fn block_user(&self, user_id: u64, entry: &BlockEntry) -> Result<(), StoreError> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO blocked_users (user_id, blocked_at, reason) VALUES (?, ?, ?)",
+        )
+        .bind(user_id as i64)
+        .bind(entry.blocked_at as i64)
+        .bind(&entry.reason)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn unblock_user(&self, user_id: u64) -> Result<bool, StoreError> {
+        let result = sqlx::query("DELETE FROM blocked_users WHERE user_id = ?")
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn is_blocked(&self, user_id: u64) -> Result<bool, StoreError> {
+        let found: Option<i64> =
+            sqlx::query_scalar("SELECT user_id FROM blocked_users WHERE user_id = ?")
+                .bind(user_id as i64)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(found.is_some())
+    }
+
+    async fn blocked_users(&self) -> Result<HashSet<u64>, StoreError> {
+        let ids: Vec<i64> = sqlx::query_scalar("SELECT user_id FROM blocked_users")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(ids.into_iter().map(|id| id as u64).collect())
+    }
+
+    async fn record_delivery(
+        &self,
+        user_id: u64,
+        delivered: bool,
+        at: u64,
+    ) -> Result<Option<DeliveryFailures>, StoreError> {
+        if delivered {
+            // a disabled user only gets alerts again after `mark_reachable`
+            sqlx::query("DELETE FROM delivery_failures WHERE user_id = ? AND disabled_at IS NULL")
+                .bind(user_id as i64)
+                .execute(&self.pool)
+                .await?;
+            return Ok(None);
+        }
+        let (count, since, disabled_at) = sqlx::query_as::<_, (i64, i64, Option<i64>)>(
+            "INSERT INTO delivery_failures (user_id, failures, since) VALUES (?, 1, ?)
+             ON CONFLICT (user_id) DO UPDATE SET failures = failures + 1
+             RETURNING failures, since, disabled_at",
+        )
+        .bind(user_id as i64)
+        .bind(at as i64)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Some(DeliveryFailures {
+            count: count as u32,
+            since: since as u64,
+            disabled_at: disabled_at.map(|at| at as u64),
+        }))
+    }
+
+    async fn disable_user(&self, user_id: u64, at: u64) -> Result<(), StoreError> {
+        sqlx::query("UPDATE delivery_failures SET disabled_at = ? WHERE user_id = ?")
+            .bind(at as i64)
+            .bind(user_id as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn mark_reachable(&self, user_id: u64) -> Result<bool, StoreError> {
+        let disabled: Option<Option<i64>> = sqlx::query_scalar(
+            "DELETE FROM delivery_failures WHERE user_id = ? RETURNING disabled_at",
+        )
+        .bind(user_id as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(disabled.flatten().is_some())
+    }
+
+    async fn delivery_failures(&self) -> Result<Vec<(u64, DeliveryFailures)>, StoreError> {
+        let rows = sqlx::query_as::<_, (i64, i64, i64, Option<i64>)>(
+            "SELECT user_id, failures, since, disabled_at FROM delivery_failures ORDER BY user_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(user_id, count, since, disabled_at)| {
+                let failures = DeliveryFailures {
+                    count: count as u32,
+                    since: since as u64,
+                    disabled_at: disabled_at.map(|at| at as u64),
+                };
+                (user_id as u64, failures)
+            })
+            .collect())
+    }
+
+    async fn forget_user(&self, user_id: u64) -> Result<(), StoreError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM watches WHERE user_id = ?")
+            .bind(self.user_key(user_id))
+            .execute(&mut *tx)
+            .await?;
+        for table in [
+            "department_watches",
+            "user_settings",
+            "digest_events",
+            "notification_log",
+            "delivery_failures",
+        ] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE user_id = ?"))
+                .bind(user_id as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+        Ok(tx.commit().await?)
+    }
+
+    async fn archive_semester(
+        &self,
+        semester: &str,
+        next: &str,
+    ) -> Result<Vec<(u64, Vec<String>)>, StoreError> {
+        // IDs without a semester are the ones of the semester ending
+        const IN_SEMESTER: &str = "(course_id NOT LIKE '%@%' OR course_id LIKE '%@' || ?)";
+        let mut tx = self.pool.begin().await?;
+        let rows = sqlx::query_as::<_, (i64, String)>(&format!(
+            "SELECT user_id, course_id FROM watches WHERE {IN_SEMESTER}"
+        ))
+        .bind(semester)
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "INSERT OR REPLACE INTO archived_watches (semester, user_id, course_id, added_at)
+             SELECT ?, user_id, course_id, added_at FROM watches WHERE {IN_SEMESTER}"
+        ))
+        .bind(semester)
+        .bind(semester)
+        .execute(&mut *tx)
+        .await?;
+        for table in ["watches", "course_seats", "course_history", "course_meta"] {
+            sqlx::query(&format!("DELETE FROM {table} WHERE {IN_SEMESTER}"))
+                .bind(semester)
+                .execute(&mut *tx)
+                .await?;
+        }
+        // the next semester is now the one enrolled, stored bare; a course already stored bare
+        // keeps its row and the suffixed one goes
+        for table in ["watches", "course_seats", "course_history", "course_meta"] {
+            sqlx::query(&format!(
+                "UPDATE OR IGNORE {table}
+                 SET course_id = substr(course_id, 1, length(course_id) - length(?) - 1)
+                 WHERE course_id LIKE '%@' || ?"
+            ))
+            .bind(next)
+            .bind(next)
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query(&format!(
+                "DELETE FROM {table} WHERE course_id LIKE '%@' || ?"
+            ))
+            .bind(next)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        let mut rows: Vec<(u64, String)> = rows
+            .into_iter()
+            .map(|(user_id, course_id)| (self.user_of(user_id), course_id))
+            .collect();
+        rows.sort();
+        let mut archived: Vec<(u64, Vec<String>)> = Vec::new();
+        for (user_id, course_id) in rows {
+            match archived.last_mut() {
+                Some((last, course_ids)) if *last == user_id => course_ids.push(course_id),
+                _ => archived.push((user_id, vec![course_id])),
+            }
+        }
+        Ok(archived)
+    }
+}
+
+#[async_trait]
+impl CourseRepository for Db {
+    async fn seat_snapshot(&self, course_id: &str) -> Result<Option<SeatSnapshot>, StoreError> {
+        let row = sqlx::query_as::<_, (Option<i64>, Option<i64>, i64)>(
+            "SELECT enrolled, quota, checked_at FROM course_seats WHERE course_id = ?",
+        )
+        .bind(course_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(enrolled, quota, checked_at)| SeatSnapshot {
+            seats: seat_count(enrolled, quota),
+            checked_at: checked_at as u64,
+        }))
+    }
+
+    async fn record_seats(
+        &self,
+        course_id: &str,
+        snapshot: &SeatSnapshot,
+    ) -> Result<(), StoreError> {
+        let mut tx = self.pool.begin().await?;
+        let previous = sqlx::query_as::<_, (Option<i64>, Option<i64>)>(
+            "SELECT enrolled, quota FROM course_seats WHERE course_id = ?",
+        )
+        .bind(course_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let was_open = previous
+            .and_then(|(enrolled, quota)| seat_count(enrolled, quota))
+            .is_some_and(|seats| seats.available() > 0);
+        sqlx::query(
+            "INSERT OR REPLACE INTO course_seats (course_id, enrolled, quota, checked_at)
+             VALUES (?, ?, ?, ?)",
+        )
+        .bind(course_id)
+        .bind(snapshot.seats.map(|s| s.enrolled))
+        .bind(snapshot.seats.map(|s| s.quota))
+        .bind(snapshot.checked_at as i64)
+        .execute(&mut *tx)
+        .await?;
+        if was_open != snapshot.is_open() {
+            let available = snapshot.seats.map(|s| s.available()).unwrap_or(0);
+            let change = AvailabilityChange {
+                at: snapshot.checked_at,
+                available,
+            };
+            self.push_history(&mut tx, course_id, &change).await?;
+        }
+        Ok(tx.commit().await?)
+    }
+
+    async fn history(&self, course_id: &str) -> Result<Vec<AvailabilityChange>, StoreError> {
+        let rows = sqlx::query_as::<_, (i64, i32, Option<String>)>(
+            "SELECT at, available, sealed FROM course_history WHERE course_id = ? ORDER BY id",
+        )
+        .bind(course_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(at, available, sealed)| match sealed {
+                Some(sealed) => self.open(sealed),
+                None => Ok(AvailabilityChange {
+                    at: at as u64,
+                    available,
+                }),
+            })
+            .collect()
+    }
+
+    async fn course_meta(&self, course_id: &str) -> Result<Option<CourseMeta>, StoreError> {
+        let row = sqlx::query_as::<_, (String, String, i32, i64, String)>(
+            "SELECT name, teacher, quota, fetched_at, restrictions FROM course_meta
+             WHERE course_id = ?",
+        )
+        .bind(course_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(
+            |(name, teacher, quota, fetched_at, restrictions)| CourseMeta {
+                name,
+                teacher,
+                quota,
+                fetched_at: fetched_at as u64,
+                restrictions: restrictions.lines().map(str::to_owned).collect(),
+            },
+        ))
+    }
+
+    async fn cache_course_meta(
+        &self,
+        courses: &[CourseInfo],
+        fetched_at: u64,
+    ) -> Result<(), StoreError> {
+        if courses.is_empty() {
+            return Ok(());
+        }
+        let mut tx = self.pool.begin().await?;
+        for course in courses {
+            sqlx::query(
+                "INSERT OR REPLACE INTO course_meta
+                 (course_id, name, teacher, quota, fetched_at, restrictions)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&course.serial_no)
+            .bind(&course.name)
+            .bind(&course.teacher)
+            .bind(course.seats.quota)
+            .bind(fetched_at as i64)
+            .bind(course.restrictions.join("\n"))
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(tx.commit().await?)
+    }
+
+    async fn stats(&self) -> Result<CheckerStats, StoreError> {
+        let row = sqlx::query_as::<_, (i64, Option<i64>, Option<i64>, i64, i64)>(
+            "SELECT started_at, last_cycle_at, last_cycle_secs, query_failures, logins
+             FROM checker_stats WHERE id = 1",
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row
+            .map(
+                |(started_at, last_cycle_at, last_cycle_secs, query_failures, logins)| {
+                    CheckerStats {
+                        started_at: started_at as u64,
+                        last_cycle_at: last_cycle_at.map(|v| v as u64),
+                        last_cycle_secs: last_cycle_secs.map(|v| v as u64),
+                        query_failures: query_failures as u64,
+                        logins: logins as u64,
+                    }
+                },
+            )
+            .unwrap_or_default())
+    }
+
+    async fn save_stats(&self, stats: &CheckerStats) -> Result<(), StoreError> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO checker_stats
+             (id, started_at, last_cycle_at, last_cycle_secs, query_failures, logins)
+             VALUES (1, ?, ?, ?, ?, ?)",
+        )
+        .bind(stats.started_at as i64)
+        .bind(stats.last_cycle_at.map(|v| v as i64))
+        .bind(stats.last_cycle_secs.map(|v| v as i64))
+        .bind(stats.query_failures as i64)
+        .bind(stats.logins as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn storage_size(&self) -> Result<Option<u64>, StoreError> {
+        let bytes: i64 = sqlx::query_scalar(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Some(bytes as u64))
+    }
+
+    async fn close(&self) {
+        // waits for the connection to come back, so a write in progress completes
+        self.pool.close().await;
+    }
+
+    async fn meta(&self, key: &str) -> Result<Option<u64>, StoreError> {
+        let value: Option<i64> = sqlx::query_scalar("SELECT value FROM meta WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(value.map(|v| v as u64))
+    }
+
+    async fn set_meta(&self, key: &str, value: u64) -> Result<(), StoreError> {
+        sqlx::query("INSERT OR REPLACE INTO meta (key, value) VALUES (?, ?)")
+            .bind(key)
+            .bind(value as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn crawler_session(&self, account: &str) -> Result<Option<String>, StoreError> {
+        Ok(
+            sqlx::query_scalar("SELECT cookies FROM crawler_sessions WHERE account = ?")
+                .bind(account)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn save_crawler_session(&self, account: &str, cookies: &str) -> Result<(), StoreError> {
+        sqlx::query("INSERT OR REPLACE INTO crawler_sessions (account, cookies) VALUES (?, ?)")
+            .bind(account)
+            .bind(cookies)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn acquire_lease(&self, name: &str, holder: &str, ttl: u64) -> Result<bool, StoreError> {
+        let now = now();
+        let result = sqlx::query(
+            "INSERT INTO leases (name, holder, expires_at) VALUES (?, ?, ?)
+             ON CONFLICT (name) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+             WHERE leases.holder = excluded.holder OR leases.expires_at <= ?",
+        )
+        .bind(name)
+        .bind(holder)
+        .bind((now + ttl) as i64)
+        .bind(now as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn release_lease(&self, name: &str, holder: &str) -> Result<(), StoreError> {
+        sqlx::query("DELETE FROM leases WHERE name = ? AND holder = ?")
+            .bind(name)
+            .bind(holder)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn watch_counts(&self) -> Result<WatchCounts, StoreError> {
+        let (users, courses, unique_courses) = sqlx::query_as::<_, (i64, i64, i64)>(
+            "SELECT COUNT(DISTINCT user_id), COUNT(*), COUNT(DISTINCT course_id) FROM watches",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        let departments: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM department_watches")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(WatchCounts {
+            users: users as usize,
+            courses: courses as usize,
+            unique_courses: unique_courses as usize,
+            departments: departments as usize,
+        })
+    }
+}
+
+impl Db {
+    async fn load_settings(
+        &self,
+        conn: &mut SqliteConnection,
+        user_id: u64,
+    ) -> Result<UserSettings, StoreError> {
+        let row = sqlx::query_as::<_, SettingsRow>(
+            "SELECT public_replies, language, notify_target, notify_channel, digest, daily_summary,
+             quiet_start, quiet_end, dm_closed, fill_alerts, sealed FROM user_settings
+             WHERE user_id = ?",
+        )
+        .bind(user_id as i64)
+        .fetch_optional(conn)
+        .await?;
+        let Some(row) = row else {
+            return Ok(UserSettings::default());
+        };
+        if let Some(sealed) = row.sealed {
+            return self.open(sealed);
+        }
+        Ok(UserSettings {
+            public_replies: row.public_replies,
+            language: row.language,
+            notify_target: row.notify_target,
+            notify_channel: row.notify_channel.map(|c| c as u64),
+            digest: row.digest,
+            daily_summary: row.daily_summary,
+            quiet_hours: row
+                .quiet_start
+                .zip(row.quiet_end)
+                .map(|(start, end)| QuietHours {
+                    start: start as u8,
+                    end: end as u8,
+                }),
+            dm_closed: row.dm_closed,
+            fill_alerts: row.fill_alerts,
+        })
+    }
+
+    /// Sealed settings leave the plain columns at their defaults
+    async fn store_settings(
+        &self,
+        conn: &mut SqliteConnection,
+        user_id: u64,
+        settings: &UserSettings,
+    ) -> Result<(), StoreError> {
+        let sealed = self.seal(settings)?;
+        let plain = match sealed {
+            Some(_) => &UserSettings::default(),
+            None => settings,
+        };
+        sqlx::query(
+            "INSERT OR REPLACE INTO user_settings
+             (user_id, public_replies, language, notify_target, notify_channel, digest,
+              daily_summary, quiet_start, quiet_end, dm_closed, fill_alerts, sealed)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(user_id as i64)
+        .bind(plain.public_replies)
+        .bind(plain.language)
+        .bind(plain.notify_target)
+        .bind(plain.notify_channel.map(|c| c as i64))
+        .bind(plain.digest)
+        .bind(plain.daily_summary)
+        .bind(plain.quiet_hours.map(|q| q.start))
+        .bind(plain.quiet_hours.map(|q| q.end))
+        .bind(plain.dm_closed)
+        .bind(plain.fill_alerts)
+        .bind(sealed)
+        .execute(conn)
+        .await?;
+        Ok(())
+    }
+
+    pub(super) async fn insert_watch(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        user_id: u64,
+        entry: &WatchEntry,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "INSERT OR REPLACE INTO watches ({WATCH_COLUMNS}) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        ))
+        .bind(self.user_key(user_id))
+        .bind(&entry.course_id)
+        .bind(entry.added_at as i64)
+        .bind(entry.notified_at.map(|at| at as i64))
+        .bind(entry.min_seats)
+        .bind(entry.mode)
+        .bind(entry.last_seen.map(|s| s.enrolled))
+        .bind(entry.last_seen.map(|s| s.quota))
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Append an availability transition, dropping the oldest beyond [`HISTORY_LIMIT`]
+    pub(super) async fn push_history(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        course_id: &str,
+        change: &AvailabilityChange,
+    ) -> Result<(), StoreError> {
+        let sealed = self.seal(change)?;
+        let (at, available) = match sealed {
+            Some(_) => (0, 0),
+            None => (change.at as i64, change.available),
+        };
+        sqlx::query(
+            "INSERT INTO course_history (course_id, at, available, sealed) VALUES (?, ?, ?, ?)",
+        )
+        .bind(course_id)
+        .bind(at)
+        .bind(available)
+        .bind(sealed)
+        .execute(&mut **tx)
+        .await?;
+        sqlx::query(
+            "DELETE FROM course_history WHERE course_id = ? AND id NOT IN
+             (SELECT id FROM course_history WHERE course_id = ? ORDER BY id DESC LIMIT ?)",
+        )
+        .bind(course_id)
+        .bind(course_id)
+        .bind(HISTORY_LIMIT as i64)
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+}
+
+/// Watchlist cap for a user, honoring owner overrides
+pub(super) async fn course_limit(
+    conn: &mut SqliteConnection,
+    user_id: u64,
+    default: usize,
+) -> Result<usize, sqlx::Error> {
+    let limit: Option<i64> =
+        sqlx::query_scalar("SELECT course_limit FROM user_limits WHERE user_id = ?")
+            .bind(user_id as i64)
+            .fetch_optional(conn)
+            .await?;
+    Ok(limit.map(|l| l as usize).unwrap_or(default))
+}
+
+pub(super) async fn insert_department_watch(
+    tx: &mut Transaction<'_, Sqlite>,
+    user_id: u64,
+    watch: &DepartmentWatch,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT OR REPLACE INTO department_watches ({DEPARTMENT_COLUMNS}) VALUES (?, ?, ?, ?, ?)"
+    ))
+    .bind(user_id as i64)
+    .bind(&watch.dept_code)
+    .bind(watch.elective_only)
+    .bind(watch.added_at as i64)
+    .bind(serde_json::to_string(&watch.open).unwrap_or_default())
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_migrate() -> Result<(), StoreError> {
+        let db = Db::connect("sqlite::memory:", None).await?;
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&db.pool)
+            .await?;
+        assert_eq!(version as usize, MIGRATIONS.len());
+        // running again is a no-op
+        migrate(&db.pool).await?;
+        assert!(db.storage_size().await?.is_some_and(|bytes| bytes > 0));
+
+        sqlx::raw_sql("PRAGMA user_version = 999")
+            .execute(&db.pool)
+            .await?;
+        assert!(matches!(
+            migrate(&db.pool).await,
+            Err(StoreError::UnknownVersion { found: 999, .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sealed() -> Result<(), StoreError> {
+        let path =
+            std::env::temp_dir().join(format!("course-bot-sealed-{}.db", std::process::id()));
+        let url = format!("sqlite://{}", path.display());
+        let key = || Cipher::from_base64("AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=");
+        let settings = UserSettings {
+            daily_summary: true,
+            ..Default::default()
+        };
+        {
+            let db = Db::connect(&url, None).await?;
+            db.add_watch(42, "0042", None, None, 20).await?;
+            db.save_settings(42, &settings).await?;
+            let mut tx = db.pool.begin().await?;
+            let change = AvailabilityChange {
+                at: 10,
+                available: 3,
+            };
+            db.push_history(&mut tx, "0042", &change).await?;
+            tx.commit().await?;
+            db.pool.close().await;
+        }
+
+        // turning the key on seals what was stored before
+        let db = Db::connect(&url, Some(key()?)).await?;
+        assert_eq!(db.watchlist(42).await?.len(), 1);
+        assert_eq!(db.subscribers("0042").await?[0].0, 42);
+        assert_eq!(db.settings(42).await?, settings);
+        assert_eq!(db.history("0042").await?[0].available, 3);
+        let stored: Vec<i64> = sqlx::query_scalar("SELECT user_id FROM watches")
+            .fetch_all(&db.pool)
+            .await?;
+        assert_ne!(stored, vec![42]);
+        let plain: (bool, i32) = sqlx::query_as(
+            "SELECT daily_summary, (SELECT available FROM course_history) FROM user_settings",
+        )
+        .fetch_one(&db.pool)
+        .await?;
+        assert_eq!(plain, (false, 0));
+        db.pool.close().await;
+
+        assert!(matches!(
+            Db::connect(&url, None).await,
+            Err(StoreError::Undecryptable)
+        ));
+        let other = Cipher::from_base64("BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=")?;
+        assert!(matches!(
+            Db::connect(&url, Some(other)).await,
+            Err(StoreError::Undecryptable)
+        ));
+        std::fs::remove_file(&path).ok();
+        Ok(())
+    }
+}
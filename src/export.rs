@@ -0,0 +1,242 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crawler::normalize_dept_code,
+    db::{now, DepartmentWatch, WatchEntry, WatchMode},
+};
+
+/// Bumped whenever the file layout changes incompatibly
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Portable form of a user's watchlist, written by `/export`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchlistExport {
+    pub version: u32,
+    pub exported_at: u64,
+    pub courses: Vec<ExportedCourse>,
+    #[serde(default)]
+    pub departments: Vec<ExportedDepartment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedCourse {
+    pub course_id: String,
+    #[serde(default)]
+    pub added_at: u64,
+    #[serde(default = "default_min_seats")]
+    pub min_seats: u32,
+    #[serde(default)]
+    pub mode: WatchMode,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedDepartment {
+    pub dept_code: String,
+    #[serde(default)]
+    pub elective_only: bool,
+    #[serde(default)]
+    pub added_at: u64,
+}
+
+fn default_min_seats() -> u32 {
+    1
+}
+
+impl WatchlistExport {
+    pub fn new(courses: &[WatchEntry], departments: &[DepartmentWatch]) -> Self {
+        Self {
+            version: EXPORT_VERSION,
+            exported_at: now(),
+            courses: courses
+                .iter()
+                .map(|entry| ExportedCourse {
+                    course_id: entry.course_id.clone(),
+                    added_at: entry.added_at,
+                    min_seats: entry.min_seats,
+                    mode: entry.mode,
+                })
+                .collect(),
+            departments: departments
+                .iter()
+                .map(|watch| ExportedDepartment {
+                    dept_code: watch.dept_code.clone(),
+                    elective_only: watch.elective_only,
+                    added_at: watch.added_at,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Why an imported entry was left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    Invalid,
+    Duplicate,
+    LimitReached,
+}
+
+/// Outcome of merging an import into a watchlist, by course ID or department code
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub added: Vec<String>,
+    pub skipped: Vec<(String, SkipReason)>,
+}
+
+impl ImportReport {
+    fn extend(&mut self, other: ImportReport) {
+        self.added.extend(other.added);
+        self.skipped.extend(other.skipped);
+    }
+}
+
+impl WatchlistExport {
+    /// Merge the courses and departments into a user's lists, never touching existing entries
+    pub fn merge_into(
+        &self,
+        courses: &mut Vec<WatchEntry>,
+        departments: &mut Vec<DepartmentWatch>,
+        canonical: &dyn Fn(&str) -> Option<String>,
+        course_limit: usize,
+        department_limit: usize,
+    ) -> ImportReport {
+        let mut report = merge_courses(courses, &self.courses, canonical, course_limit);
+        report.extend(merge_departments(
+            departments,
+            &self.departments,
+            department_limit,
+        ));
+        report
+    }
+}
+
+fn merge_courses(
+    current: &mut Vec<WatchEntry>,
+    imported: &[ExportedCourse],
+    canonical: &dyn Fn(&str) -> Option<String>,
+    limit: usize,
+) -> ImportReport {
+    let mut report = ImportReport::default();
+    for course in imported {
+        let Some(id) = canonical(&course.course_id).filter(|_| course.min_seats > 0) else {
+            report
+                .skipped
+                .push((course.course_id.clone(), SkipReason::Invalid));
+            continue;
+        };
+        let reason = if current.iter().any(|entry| entry.course_id == id) {
+            Some(SkipReason::Duplicate)
+        } else if current.len() >= limit {
+            Some(SkipReason::LimitReached)
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => report.skipped.push((id, reason)),
+            None => {
+                let mut entry = WatchEntry::new(id.clone(), course.min_seats, course.mode);
+                if course.added_at > 0 {
+                    entry.added_at = course.added_at;
+                }
+                current.push(entry);
+                report.added.push(id);
+            }
+        }
+    }
+    current.sort_by(|a, b| a.course_id.cmp(&b.course_id));
+    report
+}
+
+fn merge_departments(
+    current: &mut Vec<DepartmentWatch>,
+    imported: &[ExportedDepartment],
+    limit: usize,
+) -> ImportReport {
+    let mut report = ImportReport::default();
+    for department in imported {
+        let Some(code) = normalize_dept_code(&department.dept_code) else {
+            report
+                .skipped
+                .push((department.dept_code.clone(), SkipReason::Invalid));
+            continue;
+        };
+        if current.iter().any(|watch| watch.dept_code == code) {
+            report.skipped.push((code, SkipReason::Duplicate));
+        } else if current.len() >= limit {
+            report.skipped.push((code, SkipReason::LimitReached));
+        } else {
+            let mut watch = DepartmentWatch::new(code.clone(), department.elective_only);
+            if department.added_at > 0 {
+                watch.added_at = department.added_at;
+            }
+            current.push(watch);
+            report.added.push(code);
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::crawler::validate_serial_no;
+
+    #[test]
+    fn test_import_merge() -> Result<(), serde_json::Error> {
+        let file = r#"{
+            "version": 1,
+            "exported_at": 1700000000,
+            "courses": [
+                {"course_id": "0042", "added_at": 1690000000, "min_seats": 2, "mode": "Changes"},
+                {"course_id": "1234"},
+                {"course_id": "12a4"},
+                {"course_id": "0100"},
+                {"course_id": "0200"}
+            ],
+            "departments": [{"dept_code": "csu", "elective_only": true}, {"dept_code": "C S"}]
+        }"#;
+        let import: WatchlistExport = serde_json::from_str(file)?;
+        let mut courses = vec![WatchEntry::new(
+            "1234".to_owned(),
+            1,
+            WatchMode::Availability,
+        )];
+        let mut departments = Vec::new();
+        let range = 1..=9000;
+        let canonical = |id: &str| {
+            validate_serial_no(id, &range)
+                .is_ok()
+                .then(|| id.to_owned())
+        };
+        let report = import.merge_into(&mut courses, &mut departments, &canonical, 3, 5);
+        assert_eq!(
+            report,
+            ImportReport {
+                added: vec!["0042".to_owned(), "0100".to_owned(), "CSU".to_owned()],
+                skipped: vec![
+                    ("1234".to_owned(), SkipReason::Duplicate),
+                    ("12a4".to_owned(), SkipReason::Invalid),
+                    ("0200".to_owned(), SkipReason::LimitReached),
+                    ("C S".to_owned(), SkipReason::Invalid),
+                ],
+            }
+        );
+        assert_eq!(
+            courses
+                .iter()
+                .map(|e| e.course_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["0042", "0100", "1234"]
+        );
+        assert_eq!(courses[0].added_at, 1690000000);
+        assert_eq!(courses[0].min_seats, 2);
+        assert_eq!(courses[0].mode, WatchMode::Changes);
+        assert!(departments[0].elective_only);
+
+        // an export of the merged lists reads back the same
+        let export = WatchlistExport::new(&courses, &departments);
+        let text = serde_json::to_string(&export)?;
+        assert_eq!(serde_json::from_str::<WatchlistExport>(&text)?, export);
+        Ok(())
+    }
+}
@@ -0,0 +1,289 @@
+//! gRPC admin service for operator tooling, separate from the user facing web pages
+//!
+//! The service code is generated by `build.rs` around the messages below; `proto/admin.proto`
+//! describes the same service for generating clients. Every call must carry the shared
+//! `BOT_GRPC_TOKEN` as `authorization: Bearer {token}`.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, PoisonError},
+};
+
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::info;
+
+use crate::{
+    db::{Repository, StoreError, WatchMode},
+    metrics::{LATENCIES, METRICS},
+};
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/course_bot.admin.Admin.rs"));
+}
+
+use generated::admin_server::{Admin, AdminServer};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListUsersRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListUsersResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub users: Vec<User>,
+}
+
+/// A user watching at least one course or department, or blocked
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct User {
+    #[prost(uint64, tag = "1")]
+    pub user_id: u64,
+    #[prost(uint32, tag = "2")]
+    pub courses: u32,
+    #[prost(uint32, tag = "3")]
+    pub departments: u32,
+    #[prost(bool, tag = "4")]
+    pub blocked: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetWatchesRequest {
+    #[prost(uint64, tag = "1")]
+    pub user_id: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetWatchesResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub courses: Vec<CourseWatch>,
+    #[prost(message, repeated, tag = "2")]
+    pub departments: Vec<DepartmentWatch>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CourseWatch {
+    #[prost(string, tag = "1")]
+    pub course_id: String,
+    #[prost(uint64, tag = "2")]
+    pub added_at: u64,
+    #[prost(uint64, optional, tag = "3")]
+    pub notified_at: Option<u64>,
+    #[prost(uint32, tag = "4")]
+    pub min_seats: u32,
+    /// Alert on any change of the seat numbers instead of free seats
+    #[prost(bool, tag = "5")]
+    pub any_change: bool,
+    /// Seats at the last check, unset when never checked or not listed
+    #[prost(int32, optional, tag = "6")]
+    pub enrolled: Option<i32>,
+    #[prost(int32, optional, tag = "7")]
+    pub quota: Option<i32>,
+    #[prost(uint64, optional, tag = "8")]
+    pub checked_at: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DepartmentWatch {
+    #[prost(string, tag = "1")]
+    pub dept_code: String,
+    #[prost(bool, tag = "2")]
+    pub elective_only: bool,
+    #[prost(uint64, tag = "3")]
+    pub added_at: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TriggerCycleRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TriggerCycleResponse {
+    /// False when a cycle was already waiting to start
+    #[prost(bool, tag = "1")]
+    pub queued: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DumpMetricsRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DumpMetricsResponse {
+    /// The `/metrics` page, in the Prometheus text format
+    #[prost(string, tag = "1")]
+    pub text: String,
+}
+
+pub struct AdminService {
+    db: Arc<dyn Repository>,
+    /// Wakes the periodic checker, like `/force_update everyone`
+    update_sender: Sender<()>,
+}
+
+impl AdminService {
+    pub fn new(db: Arc<dyn Repository>, update_sender: Sender<()>) -> Self {
+        Self { db, update_sender }
+    }
+}
+
+fn user(users: &mut BTreeMap<u64, User>, user_id: u64) -> &mut User {
+    users.entry(user_id).or_insert_with(|| User {
+        user_id,
+        ..Default::default()
+    })
+}
+
+fn internal(e: StoreError) -> Status {
+    Status::internal(e.to_string())
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn list_users(
+        &self,
+        _: Request<ListUsersRequest>,
+    ) -> Result<Response<ListUsersResponse>, Status> {
+        let mut users = BTreeMap::new();
+        for course_id in self.db.watched_courses().await.map_err(internal)? {
+            for (user_id, _) in self.db.subscribers(&course_id).await.map_err(internal)? {
+                user(&mut users, user_id).courses += 1;
+            }
+        }
+        for (user_id, watches) in self.db.all_department_watches().await.map_err(internal)? {
+            user(&mut users, user_id).departments = watches.len() as u32;
+        }
+        for user_id in self.db.blocked_users().await.map_err(internal)? {
+            user(&mut users, user_id).blocked = true;
+        }
+        let users = users.into_values().collect();
+        Ok(Response::new(ListUsersResponse { users }))
+    }
+
+    async fn get_watches(
+        &self,
+        request: Request<GetWatchesRequest>,
+    ) -> Result<Response<GetWatchesResponse>, Status> {
+        let user_id = request.into_inner().user_id;
+        let mut courses = Vec::new();
+        for entry in self.db.watchlist(user_id).await.map_err(internal)? {
+            let snapshot = self
+                .db
+                .seat_snapshot(&entry.course_id)
+                .await
+                .map_err(internal)?;
+            let seats = snapshot.as_ref().and_then(|snapshot| snapshot.seats);
+            courses.push(CourseWatch {
+                added_at: entry.added_at,
+                notified_at: entry.notified_at,
+                min_seats: entry.min_seats,
+                any_change: entry.mode == WatchMode::Changes,
+                enrolled: seats.map(|seats| seats.enrolled),
+                quota: seats.map(|seats| seats.quota),
+                checked_at: snapshot.map(|snapshot| snapshot.checked_at),
+                course_id: entry.course_id,
+            });
+        }
+        let departments = self
+            .db
+            .department_watches(user_id)
+            .await
+            .map_err(internal)?
+            .into_iter()
+            .map(|watch| DepartmentWatch {
+                dept_code: watch.dept_code,
+                elective_only: watch.elective_only,
+                added_at: watch.added_at,
+            })
+            .collect();
+        Ok(Response::new(GetWatchesResponse {
+            courses,
+            departments,
+        }))
+    }
+
+    async fn trigger_cycle(
+        &self,
+        _: Request<TriggerCycleRequest>,
+    ) -> Result<Response<TriggerCycleResponse>, Status> {
+        let queued = match self.update_sender.try_send(()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(())) => false,
+            Err(TrySendError::Closed(())) => {
+                return Err(Status::unavailable("the checker has stopped"))
+            }
+        };
+        info!("Check cycle triggered over gRPC");
+        Ok(Response::new(TriggerCycleResponse { queued }))
+    }
+
+    async fn dump_metrics(
+        &self,
+        _: Request<DumpMetricsRequest>,
+    ) -> Result<Response<DumpMetricsResponse>, Status> {
+        let stats = self.db.stats().await.map_err(internal)?;
+        let size = self.db.storage_size().await.map_err(internal)?;
+        let text = METRICS.render(
+            &stats,
+            &LATENCIES.lock().unwrap_or_else(PoisonError::into_inner),
+            size,
+        );
+        Ok(Response::new(DumpMetricsResponse { text }))
+    }
+}
+
+/// Whether the `authorization` metadata carries the token, compared in constant time
+fn authorized(request: &Request<()>, token: &str) -> bool {
+    let Some(given) = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub async fn serve(port: u16, token: String, service: AdminService) -> anyhow::Result<()> {
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    info!("Serving the gRPC admin service on port {port}");
+    // tonic fixes the error type of interceptors
+    #[allow(clippy::result_large_err)]
+    let check = move |request: Request<()>| {
+        if authorized(&request, &token) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("missing or wrong bearer token"))
+        }
+    };
+    Server::builder()
+        .add_service(AdminServer::with_interceptor(service, check))
+        .serve(address)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_authorized() {
+        let request = |value: &str| {
+            let mut request = Request::new(());
+            request
+                .metadata_mut()
+                .insert("authorization", value.parse().unwrap());
+            request
+        };
+        assert!(authorized(&request("Bearer secret"), "secret"));
+        assert!(!authorized(&request("Bearer secreT"), "secret"));
+        assert!(!authorized(&request("Bearer secret2"), "secret"));
+        assert!(!authorized(&request("secret"), "secret"));
+        assert!(!authorized(&Request::new(()), "secret"));
+    }
+}
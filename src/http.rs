@@ -0,0 +1,507 @@
+//! Minimal HTTP server for operators, serving `/metrics`, the `/healthz` liveness probe and the
+//! OpenAPI description of every route at `/openapi.json`, plus the user [`Dashboard`] when
+//! configured
+
+use std::{
+    sync::{atomic::Ordering, Arc, PoisonError},
+    time::Duration,
+};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::Config,
+    dashboard::Dashboard,
+    db::{now, CheckerStats, Repository},
+    metrics::{LATENCIES, METRICS},
+};
+
+/// Headers beyond this are not read, only the request line and cookies matter
+const MAX_HEADER_LINES: usize = 100;
+
+const TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+const JSON: &str = "application/json";
+const HTML: &str = "text/html; charset=utf-8";
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    /// Extra headers such as `Location` and `Set-Cookie`
+    pub headers: Vec<(&'static str, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: TEXT,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn html(status: &'static str, body: String) -> Self {
+        Self {
+            content_type: HTML,
+            ..Self::text(status, body)
+        }
+    }
+
+    pub fn redirect(location: &str) -> Self {
+        Self::text("302 Found", "").with_header("Location", location.to_owned())
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn serialize(&self) -> String {
+        let mut head = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        );
+        for (name, value) in &self.headers {
+            head += &format!("{name}: {value}\r\n");
+        }
+        head + "\r\n" + &self.body
+    }
+}
+
+pub struct Server {
+    db: Arc<dyn Repository>,
+    client: reqwest::Client,
+    /// Empty when captchas are read locally
+    captcha_uris: Vec<String>,
+    /// Seconds without a finished check cycle before the bot counts as stuck
+    max_cycle_age: u64,
+    /// `None` while the OAuth2 client is not configured
+    dashboard: Option<Dashboard>,
+}
+
+impl Server {
+    pub fn new(config: &Config, db: Arc<dyn Repository>) -> Self {
+        Self {
+            db,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(3))
+                .build()
+                .unwrap(),
+            captcha_uris: config
+                .captcha_uris()
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            max_cycle_age: config.health_max_cycle_age,
+            dashboard: Dashboard::new(config),
+        }
+    }
+
+    /// Any answer counts, the service has no dedicated health route; one instance is enough
+    async fn captcha_reachable(&self) -> bool {
+        if self.captcha_uris.is_empty() {
+            return true;
+        }
+        for uri in &self.captcha_uris {
+            if self.client.get(uri).send().await.is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+pub async fn serve(port: u16, server: Server) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    info!("Serving metrics and health checks on port {port}");
+    let server = Arc::new(server);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("fail to accept HTTP connection: {e}");
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            match timeout(Duration::from_secs(10), handle(stream, &server)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => debug!("HTTP connection failed: {e}"),
+                Err(_) => debug!("HTTP connection timed out"),
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, server: &Server) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request = String::new();
+    stream.read_line(&mut request).await?;
+    // drain the headers so closing the connection does not reset it
+    let mut cookies = None;
+    let mut line = String::new();
+    for _ in 0..MAX_HEADER_LINES {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("cookie") {
+                cookies = Some(value.trim().to_owned());
+            }
+        }
+    }
+
+    let response = match route(&request) {
+        Some(path) => match Route::from_path(path) {
+            Some(route) => respond(server, route, &request, cookies.as_deref()).await,
+            None => Response::text("404 Not Found", "not found\n"),
+        },
+        None => Response::text("405 Method Not Allowed", "only GET is served\n"),
+    };
+    stream
+        .get_mut()
+        .write_all(response.serialize().as_bytes())
+        .await?;
+    stream.get_mut().shutdown().await
+}
+
+async fn respond(server: &Server, route: Route, request: &str, cookies: Option<&str>) -> Response {
+    match route {
+        Route::Metrics => match (server.db.stats().await, server.db.storage_size().await) {
+            (Ok(stats), Ok(size)) => Response::text(
+                "200 OK",
+                METRICS.render(
+                    &stats,
+                    &LATENCIES.lock().unwrap_or_else(PoisonError::into_inner),
+                    size,
+                ),
+            ),
+            (Err(e), _) | (_, Err(e)) => {
+                warn!("fail to read metrics from storage: {e}");
+                Response::text("500 Internal Server Error", "storage unavailable\n")
+            }
+        },
+        Route::OpenApi => Response {
+            content_type: JSON,
+            ..Response::text("200 OK", openapi().to_string())
+        },
+        Route::Healthz => {
+            let stats = server.db.stats().await;
+            if let Err(e) = &stats {
+                warn!("fail to read checker stats for health check: {e}");
+            }
+            let health = Health {
+                cycle_age: stats.ok().map(|stats| cycle_age(&stats, now())),
+                max_cycle_age: server.max_cycle_age,
+                gateway_connected: METRICS.gateway_connected.load(Ordering::Relaxed),
+                captcha_reachable: server.captcha_reachable().await,
+            };
+            let status = if health.is_healthy() {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            Response::text(status, health.report())
+        }
+        Route::Dashboard | Route::OAuthCallback | Route::Logout => match &server.dashboard {
+            Some(dashboard) => {
+                let query = query(request);
+                dashboard
+                    .handle(route.path(), &query, cookies, server.db.as_ref())
+                    .await
+            }
+            None => Response::text("404 Not Found", "not found\n"),
+        },
+    }
+}
+
+/// Seconds since the last check cycle finished, or since start before the first one
+fn cycle_age(stats: &CheckerStats, now: u64) -> u64 {
+    now.saturating_sub(stats.last_cycle_at.unwrap_or(stats.started_at))
+}
+
+struct Health {
+    /// `None` when the storage could not be read
+    cycle_age: Option<u64>,
+    max_cycle_age: u64,
+    gateway_connected: bool,
+    captcha_reachable: bool,
+}
+
+impl Health {
+    fn cycle_ok(&self) -> bool {
+        self.cycle_age.is_some_and(|age| age <= self.max_cycle_age)
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.cycle_ok() && self.gateway_connected && self.captcha_reachable
+    }
+
+    /// One line per check, `ok` or `fail`
+    fn report(&self) -> String {
+        let status = |ok: bool| if ok { "ok" } else { "fail" };
+        let cycle = match self.cycle_age {
+            Some(age) => format!("{} (last finished {age}s ago)", status(self.cycle_ok())),
+            None => "fail (storage unavailable)".to_owned(),
+        };
+        format!(
+            "cycle: {cycle}\ndiscord: {}\ncaptcha: {}\n",
+            status(self.gateway_connected),
+            status(self.captcha_reachable)
+        )
+    }
+}
+
+/// Every path served, `handle` dispatches on these and [`openapi`] documents each one
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Route {
+    Metrics,
+    Healthz,
+    OpenApi,
+    Dashboard,
+    OAuthCallback,
+    Logout,
+}
+
+impl Route {
+    const ALL: [Self; 6] = [
+        Self::Metrics,
+        Self::Healthz,
+        Self::OpenApi,
+        Self::Dashboard,
+        Self::OAuthCallback,
+        Self::Logout,
+    ];
+
+    fn path(self) -> &'static str {
+        match self {
+            Self::Metrics => "/metrics",
+            Self::Healthz => "/healthz",
+            Self::OpenApi => "/openapi.json",
+            Self::Dashboard => "/dashboard",
+            Self::OAuthCallback => "/oauth/callback",
+            Self::Logout => "/logout",
+        }
+    }
+
+    fn from_path(path: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|route| route.path() == path)
+    }
+
+    /// OpenAPI operation object of the `GET` served at this path
+    fn operation(self) -> serde_json::Value {
+        let text = |description: &str| {
+            json!({
+                "description": description,
+                "content": { "text/plain": { "schema": { "type": "string" } } },
+            })
+        };
+        let redirect = |description: &str| json!({ "description": description });
+        let not_configured = text("The dashboard is not configured");
+        match self {
+            Self::Metrics => json!({
+                "summary": "Counters and gauges in the Prometheus text format",
+                "responses": {
+                    "200": text("Prometheus exposition, every name prefixed `course_bot_`"),
+                    "500": text("The storage could not be read"),
+                },
+            }),
+            Self::Healthz => json!({
+                "summary": "Liveness probe",
+                "description": "Checks that a check cycle finished recently, the Discord \
+                    gateway is connected and the captcha service answers. The body has one \
+                    `name: ok` or `name: fail` line per check.",
+                "responses": {
+                    "200": text("Every check passed"),
+                    "503": text("At least one check failed"),
+                },
+            }),
+            Self::OpenApi => json!({
+                "summary": "This document",
+                "responses": {
+                    "200": {
+                        "description": "OpenAPI 3 description of the API",
+                        "content": { "application/json": { "schema": { "type": "object" } } },
+                    },
+                },
+            }),
+            Self::Dashboard => json!({
+                "summary": "Watchlist page of the signed-in user",
+                "description": "Served when the dashboard is configured. Without a session the \
+                    browser is sent to sign in with Discord first.",
+                "responses": {
+                    "200": {
+                        "description": "The user's watches and recent alerts",
+                        "content": { "text/html": { "schema": { "type": "string" } } },
+                    },
+                    "302": redirect("Discord's consent screen, when not signed in"),
+                    "404": not_configured,
+                    "500": text("The storage could not be read"),
+                },
+            }),
+            Self::OAuthCallback => json!({
+                "summary": "Where Discord sends the browser back to after sign-in",
+                "parameters": [
+                    {
+                        "name": "code",
+                        "in": "query",
+                        "description": "Authorization code, missing when the user cancelled",
+                        "schema": { "type": "string" },
+                    },
+                    {
+                        "name": "state",
+                        "in": "query",
+                        "required": true,
+                        "description": "State issued when sign-in started",
+                        "schema": { "type": "string" },
+                    },
+                ],
+                "responses": {
+                    "302": redirect("`/dashboard`, setting the session cookie"),
+                    "400": text("Sign-in expired or was cancelled"),
+                    "404": not_configured,
+                    "502": text("Discord did not confirm the sign-in"),
+                },
+            }),
+            Self::Logout => json!({
+                "summary": "End the dashboard session",
+                "responses": {
+                    "200": text("Signed out, the session cookie is cleared"),
+                    "404": not_configured,
+                },
+            }),
+        }
+    }
+}
+
+/// OpenAPI 3 description of the routes, for generating clients
+fn openapi() -> serde_json::Value {
+    let paths = Route::ALL
+        .into_iter()
+        .map(|route| (route.path().to_owned(), json!({ "get": route.operation() })))
+        .collect::<serde_json::Map<_, _>>();
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "course-bot operator API",
+            "description": "Monitoring endpoints and user dashboard of the NTNU course seat bot",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+    })
+}
+
+/// Path of a `GET` request line, without any query string
+fn route(request: &str) -> Option<&str> {
+    let mut parts = request.split_whitespace();
+    if parts.next() != Some("GET") {
+        return None;
+    }
+    let target = parts.next()?;
+    Some(target.split('?').next().unwrap_or(target))
+}
+
+/// Decoded query string of a request line
+fn query(request: &str) -> Vec<(String, String)> {
+    let target = request.split_whitespace().nth(1).unwrap_or("/");
+    match reqwest::Url::parse("http://localhost").and_then(|base| base.join(target)) {
+        Ok(url) => url.query_pairs().into_owned().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route("GET /metrics HTTP/1.1\r\n"), Some("/metrics"));
+        assert_eq!(route("GET /metrics?x=1 HTTP/1.1\r\n"), Some("/metrics"));
+        assert_eq!(route("POST /metrics HTTP/1.1\r\n"), None);
+        assert_eq!(route(""), None);
+
+        assert_eq!(
+            query("GET /oauth/callback?code=a%20b&state=x HTTP/1.1\r\n"),
+            [
+                ("code".to_owned(), "a b".to_owned()),
+                ("state".to_owned(), "x".to_owned())
+            ]
+        );
+        assert!(query("GET /dashboard HTTP/1.1\r\n").is_empty());
+    }
+
+    #[test]
+    fn test_openapi() {
+        let spec = openapi();
+        let mut paths = spec["paths"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        paths.sort();
+        // every path `handle` serves is documented, and nothing else
+        let mut served = Route::ALL.map(Route::path).to_vec();
+        served.sort();
+        assert_eq!(paths, served);
+        for path in served {
+            let request = format!("GET {path}?x=1 HTTP/1.1\r\n");
+            assert_eq!(
+                route(&request).and_then(Route::from_path).map(Route::path),
+                Some(path)
+            );
+            assert!(spec["paths"][path]["get"]["responses"].is_object());
+        }
+        assert_eq!(Route::from_path("/nope"), None);
+        assert_eq!(spec["info"]["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn test_health() {
+        let stats = CheckerStats {
+            started_at: 1000,
+            ..Default::default()
+        };
+        // no cycle finished yet, measured from the start
+        assert_eq!(cycle_age(&stats, 1300), 300);
+        let stats = CheckerStats {
+            last_cycle_at: Some(1200),
+            ..stats
+        };
+        assert_eq!(cycle_age(&stats, 1300), 100);
+
+        let mut health = Health {
+            cycle_age: Some(100),
+            max_cycle_age: 900,
+            gateway_connected: true,
+            captcha_reachable: true,
+        };
+        assert!(health.is_healthy());
+        assert_eq!(
+            health.report(),
+            "cycle: ok (last finished 100s ago)\ndiscord: ok\ncaptcha: ok\n"
+        );
+        health.cycle_age = Some(1000);
+        assert!(!health.is_healthy());
+        health.cycle_age = None;
+        assert!(health
+            .report()
+            .starts_with("cycle: fail (storage unavailable)\n"));
+        health.cycle_age = Some(100);
+        health.captcha_reachable = false;
+        assert!(!health.is_healthy());
+        assert!(health.report().ends_with("captcha: fail\n"));
+    }
+}
@@ -0,0 +1,1640 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crawler::{CourseInfo, CourseQuery, CourseStatus, SeatCount, SerialNoError, SERIAL_NO_LEN},
+    db::{
+        CourseMeta, DigestEvent, NotificationKind, NotificationRecord, QuietHours, SeatSnapshot,
+        WatchMode,
+    },
+    export::{ImportReport, SkipReason},
+    notify::{DigestMode, NotifyTarget},
+    source::SOURCES,
+};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    poise::ChoiceParameter,
+    sqlx::Type,
+)]
+#[sqlx(rename_all = "snake_case")]
+pub enum Lang {
+    #[default]
+    #[name = "English"]
+    En,
+    #[name = "繁體中文"]
+    ZhTw,
+}
+
+impl Lang {
+    /// Map a Discord locale code onto a supported language
+    pub fn from_locale(locale: &str) -> Option<Self> {
+        match locale {
+            "zh-TW" | "zh-CN" => Some(Self::ZhTw),
+            l if l.starts_with("en") => Some(Self::En),
+            _ => None,
+        }
+    }
+}
+
+impl NotificationKind {
+    pub fn label(self, lang: Lang) -> &'static str {
+        match (self, lang) {
+            (Self::Available, Lang::En) => "free seats",
+            (Self::SeatsChanged, Lang::En) => "seat change",
+            (Self::Department, Lang::En) => "department",
+            (Self::FilledUp, Lang::En) => "filled up",
+            (Self::Available, Lang::ZhTw) => "有空位",
+            (Self::SeatsChanged, Lang::ZhTw) => "名額變動",
+            (Self::Department, Lang::ZhTw) => "系所",
+            (Self::FilledUp, Lang::ZhTw) => "已額滿",
+        }
+    }
+}
+
+/// Fixed text of the web dashboard
+pub struct DashboardText {
+    pub title: &'static str,
+    pub watchlist: &'static str,
+    pub course: &'static str,
+    pub alerts_on: &'static str,
+    pub any_change: &'static str,
+    pub last_seen: &'static str,
+    pub not_listed: &'static str,
+    pub not_checked: &'static str,
+    pub no_watches: &'static str,
+    pub notifications: &'static str,
+    pub sent_at: &'static str,
+    pub kind: &'static str,
+    pub seats: &'static str,
+    pub not_delivered: &'static str,
+    pub no_notifications: &'static str,
+    pub blocked: &'static str,
+}
+
+impl Lang {
+    pub fn dashboard(self) -> &'static DashboardText {
+        match self {
+            Self::En => &DashboardText {
+                title: "Course seat alerts",
+                watchlist: "Watched courses",
+                course: "Course",
+                alerts_on: "Alerts on",
+                any_change: "any seat change",
+                last_seen: "Last seen",
+                not_listed: "not listed",
+                not_checked: "not checked yet",
+                no_watches: "You are not watching any course. Add one with /add_course.",
+                notifications: "Recent alerts",
+                sent_at: "Sent",
+                kind: "Kind",
+                seats: "Seats",
+                not_delivered: "not delivered",
+                no_notifications: "You have not been sent any alerts yet.",
+                blocked: "You are blocked from using this bot.",
+            },
+            Self::ZhTw => &DashboardText {
+                title: "選課空位通知",
+                watchlist: "追蹤中的課程",
+                course: "課程",
+                alerts_on: "通知條件",
+                any_change: "人數變動",
+                last_seen: "最近查詢",
+                not_listed: "未開課",
+                not_checked: "尚未查詢",
+                no_watches: "你還沒有追蹤任何課程，可以用 /add_course 新增。",
+                notifications: "最近的通知",
+                sent_at: "時間",
+                kind: "類型",
+                seats: "空位",
+                not_delivered: "傳送失敗",
+                no_notifications: "目前還沒有寄給你的通知。",
+                blocked: "你已被禁止使用此機器人。",
+            },
+        }
+    }
+
+    /// Free seats out of the quota, as shown on the dashboard
+    pub fn seats_free(self, seats: SeatCount) -> String {
+        match self {
+            Self::En => format!("{}/{} free", seats.available(), seats.quota),
+            Self::ZhTw => format!("空位 {}/{}", seats.available(), seats.quota),
+        }
+    }
+
+    pub fn min_seats(self, min_seats: u32) -> String {
+        match self {
+            Self::En if min_seats == 1 => "a free seat".to_owned(),
+            Self::En => format!("≥ {min_seats} free seats"),
+            Self::ZhTw => format!("空位 ≥ {min_seats}"),
+        }
+    }
+}
+
+/// Every user facing message the bot can send
+pub enum Msg<'a> {
+    InvalidCourseId {
+        course_id: &'a str,
+        error: &'a SerialNoError,
+    },
+    AlreadyWatching {
+        course_id: &'a str,
+        added_at: u64,
+    },
+    LimitReached {
+        course_id: &'a str,
+        limit: usize,
+    },
+    CourseAdded {
+        course_id: &'a str,
+    },
+    CourseListHeader,
+    CourseListEntry {
+        course_id: &'a str,
+        added_at: u64,
+        min_seats: u32,
+        mode: WatchMode,
+        snapshot: Option<&'a SeatSnapshot>,
+        meta: Option<&'a CourseMeta>,
+    },
+    WatchUpdated {
+        course_id: &'a str,
+        min_seats: u32,
+        mode: WatchMode,
+    },
+    SeatsChanged {
+        changes: &'a [SeatChange<'a>],
+    },
+    NoCourse,
+    ExportReady {
+        courses: usize,
+        departments: usize,
+    },
+    ImportInvalid,
+    ForgetMeConfirm,
+    ForgetMeButton,
+    CancelButton,
+    ForgetMeDone,
+    ForgetMeCancelled,
+    ImportResult {
+        report: &'a ImportReport,
+    },
+    /// Courses another user offers from their watchlist, sent to the friend by DM
+    ShareOffer {
+        user_id: u64,
+        courses: usize,
+    },
+    SharePlaceholder,
+    ShareSent {
+        user_id: u64,
+    },
+    ShareUndeliverable {
+        user_id: u64,
+    },
+    ShareInvalidFriend,
+    Blocked,
+    /// An alert could not be DMed to the user
+    DmsClosed,
+    CourseRemoved {
+        course_ids: &'a [String],
+    },
+    SelectToRemove,
+    SelectionTimedOut,
+    Selected {
+        course_ids: &'a [String],
+    },
+    /// `/force_update` queued a check
+    ForceUpdate,
+    ForceUpdateRunning {
+        cycle: u64,
+    },
+    ForceUpdateDone {
+        checked: u64,
+        available: u64,
+    },
+    /// The check was aborted or skipped outside the enrollment phases
+    ForceUpdateSkipped,
+    /// No check started or finished in time, such as on a standby replica
+    ForceUpdateTimedOut,
+    ForceUpdateCooldown {
+        minutes: u64,
+    },
+    /// Heads the courses `/force_update` checked so far
+    CheckingOwnCourses {
+        done: usize,
+        count: usize,
+    },
+    /// Heads the live seats `/force_update` found for the user's courses
+    OwnCoursesChecked {
+        count: usize,
+    },
+    CheckCourseCooldown {
+        seconds: u64,
+    },
+    /// How much a member watches, for moderators, `last_alert` being unix time
+    MemberWatches {
+        user_id: u64,
+        courses: usize,
+        departments: usize,
+        last_alert: Option<u64>,
+    },
+    /// Seats stored before `/check_course` queried, `None` when the course was never checked
+    StoredSeats {
+        snapshot: Option<&'a SeatSnapshot>,
+    },
+    /// What a query just found for a course, `None` when it failed
+    CourseChecked {
+        course_id: &'a str,
+        status: Option<CourseStatus>,
+        meta: Option<&'a CourseMeta>,
+    },
+    PublicReplies {
+        enabled: bool,
+    },
+    LanguageSet,
+    NotifyTargetSet {
+        target: NotifyTarget,
+        channel: Option<u64>,
+    },
+    NotifyChannelRequired,
+    GuildNotifySet {
+        channel: u64,
+        role: Option<u64>,
+    },
+    GuildNotifyDisabled,
+    CourseAvailable {
+        /// Course IDs, labelled with the course name when known
+        courses: &'a [String],
+        cooldown_minutes: u64,
+    },
+    GuildCourseAvailable {
+        course_ids: &'a [&'a str],
+    },
+    GuildWatchAdded {
+        name: &'a str,
+        course_id: &'a str,
+        channel: u64,
+    },
+    AlreadyInGuildWatchlist {
+        name: &'a str,
+        course_id: &'a str,
+    },
+    GuildWatchlistLimitReached {
+        limit: usize,
+    },
+    GuildWatchlistFull {
+        name: &'a str,
+        limit: usize,
+    },
+    GuildWatchRemoved {
+        name: &'a str,
+        course_id: &'a str,
+    },
+    GuildWatchlistRemoved {
+        name: &'a str,
+    },
+    NoGuildWatchlist {
+        name: &'a str,
+    },
+    NotInGuildWatchlist {
+        name: &'a str,
+        course_id: &'a str,
+    },
+    NoGuildWatchlists,
+    GuildWatchlistHeader {
+        name: &'a str,
+        channel: u64,
+    },
+    GuildWatchEntry {
+        course_id: &'a str,
+        snapshot: Option<&'a SeatSnapshot>,
+        meta: Option<&'a CourseMeta>,
+    },
+    /// Courses of a guild's shared watchlist that just opened up
+    GuildWatchlistAvailable {
+        name: &'a str,
+        /// Course IDs, labelled with the course name when known
+        courses: &'a [String],
+    },
+    ReaddButton {
+        course_id: &'a str,
+    },
+    CourseReadded {
+        course_id: &'a str,
+    },
+    /// Courses the user was alerted about ran out of seats again
+    CourseFilledUp {
+        courses: &'a [String],
+    },
+    DigestSet {
+        digest: DigestMode,
+        daily_summary: bool,
+    },
+    FillAlertsSet {
+        enabled: bool,
+    },
+    DailySummary {
+        events: &'a [DigestEvent],
+    },
+    QuietHoursSet {
+        quiet_hours: Option<QuietHours>,
+    },
+    InvalidQuietHours,
+    NoHistory {
+        course_id: &'a str,
+    },
+    History {
+        course_id: &'a str,
+        last_opened: u64,
+        open_now: bool,
+        openings: usize,
+        /// Seconds, only known once at least one opening has closed
+        average_duration: Option<u64>,
+    },
+    NoNotifications,
+    NotificationListHeader,
+    NotificationListEntry {
+        record: &'a NotificationRecord,
+    },
+    NoSearchResults {
+        keyword: &'a str,
+    },
+    UnknownCourse {
+        course_id: &'a str,
+    },
+    UnknownSource {
+        source: &'a str,
+    },
+    CourseRestricted {
+        course_id: &'a str,
+        restrictions: &'a [String],
+    },
+    PhaseUpcoming {
+        name: &'a str,
+        start: u64,
+        end: u64,
+    },
+    SemesterArchived {
+        semester: &'a str,
+        course_ids: &'a [String],
+    },
+    /// Watches removed because their courses are no longer offered
+    WatchesDropped {
+        course_ids: &'a [String],
+    },
+    CourseInfo {
+        course: &'a CourseInfo,
+    },
+    SearchResults {
+        keyword: &'a str,
+        courses: &'a [CourseInfo],
+        /// Matches before truncation
+        total: usize,
+    },
+    InvalidDeptCode {
+        dept_code: &'a str,
+    },
+    CourseIdOrCodeRequired,
+    SearchTermRequired,
+    InvalidCourseCode {
+        code: &'a str,
+    },
+    UnknownCourseCode {
+        code: &'a str,
+    },
+    CourseCodeResolved {
+        code: &'a str,
+        course_ids: &'a [String],
+    },
+    DepartmentWatched {
+        dept_code: &'a str,
+        elective_only: bool,
+    },
+    AlreadyWatchingDepartment {
+        dept_code: &'a str,
+    },
+    DepartmentLimitReached {
+        limit: usize,
+    },
+    DepartmentRemoved {
+        dept_code: &'a str,
+    },
+    NotWatchingDepartment {
+        dept_code: &'a str,
+    },
+    DepartmentListHeader,
+    DepartmentListEntry {
+        dept_code: &'a str,
+        elective_only: bool,
+        /// Matching courses with free seats at the last check
+        open: usize,
+    },
+    DepartmentAvailable {
+        dept_code: &'a str,
+        courses: &'a [CourseInfo],
+        /// Newly opened courses before truncation
+        total: usize,
+    },
+    Status {
+        started_at: u64,
+        /// When the last check finished and how many seconds it took
+        last_check: Option<(u64, u64)>,
+        /// `None` while a check is running
+        next_check: Option<u64>,
+        /// Whether the last check got through to the enrollment system
+        session_healthy: bool,
+        /// Whether every enrollment system account is locked, which stops checks until restart
+        disabled: bool,
+    },
+}
+
+/// Seat numbers of a course that moved between two checks
+pub struct SeatChange<'a> {
+    pub course_id: &'a str,
+    pub before: SeatCount,
+    pub after: SeatCount,
+}
+
+/// One line of a course listing, shared by search results and department alerts
+fn course_line(course: &CourseInfo, lang: Lang) -> String {
+    match lang {
+        Lang::En => format!(
+            "- `{}` {} ({}) — {}/{} seats free",
+            course.serial_no,
+            course.name,
+            course.teacher,
+            course.seats.available(),
+            course.seats.quota
+        ),
+        Lang::ZhTw => format!(
+            "- `{}` {}（{}）— 空位 {}/{}",
+            course.serial_no,
+            course.name,
+            course.teacher,
+            course.seats.available(),
+            course.seats.quota
+        ),
+    }
+}
+
+/// What a search looked for, as quoted back in its results
+pub fn search_label(keyword: Option<&str>, filters: &CourseQuery, lang: Lang) -> String {
+    let mut parts = keyword.map(str::to_owned).into_iter().collect::<Vec<_>>();
+    parts.extend(filters.dept_code.clone());
+    if let Some(domain) = &filters.general_core {
+        parts.push(match lang {
+            Lang::En => format!("general education {domain}"),
+            Lang::ZhTw => format!("通識{domain}"),
+        });
+    }
+    if let Some(grade) = filters.grade {
+        parts.push(match lang {
+            Lang::En => format!("year {grade}"),
+            Lang::ZhTw => format!("{grade} 年級"),
+        });
+    }
+    match lang {
+        Lang::En => parts.join(", "),
+        Lang::ZhTw => parts.join("、"),
+    }
+}
+
+/// A course ID followed by the course name and restrictions when they are cached
+pub fn course_label(course_id: &str, meta: Option<&CourseMeta>) -> String {
+    match meta {
+        Some(meta) if !meta.restrictions.is_empty() => {
+            format!(
+                "{course_id} {} ⚠️ {}",
+                meta.name,
+                meta.restrictions.join(" ")
+            )
+        }
+        Some(meta) => format!("{course_id} {}", meta.name),
+        None => course_id.to_owned(),
+    }
+}
+
+/// Human friendly rendering of a duration in seconds
+fn duration(secs: u64, lang: Lang) -> String {
+    let minutes = secs / 60;
+    match (lang, minutes) {
+        (Lang::En, 0) => format!("{secs} sec"),
+        (Lang::En, 1..=90) => format!("{minutes} min"),
+        (Lang::En, _) => format!("{:.1} hours", secs as f64 / 3600.0),
+        (Lang::ZhTw, 0) => format!("{secs} 秒"),
+        (Lang::ZhTw, 1..=90) => format!("{minutes} 分鐘"),
+        (Lang::ZhTw, _) => format!("{:.1} 小時", secs as f64 / 3600.0),
+    }
+}
+
+impl Msg<'_> {
+    pub fn render(&self, lang: Lang) -> String {
+        match lang {
+            Lang::En => self.en(),
+            Lang::ZhTw => self.zh_tw(),
+        }
+    }
+
+    fn en(&self) -> String {
+        match self {
+            Self::InvalidCourseId { course_id, error } => {
+                let reason = match error {
+                    SerialNoError::NotDigits => "course ID consists only by decimal digits".into(),
+                    SerialNoError::Length(len) => {
+                        format!("course ID should be exactly {SERIAL_NO_LEN} digits, got {len}")
+                    }
+                    SerialNoError::OutOfRange(min, max) => {
+                        format!("course ID should be between {min:04} and {max:04} this semester")
+                    }
+                };
+                format!("`{course_id}` is not a valid course ID: {reason}.")
+            }
+            Self::AlreadyWatching {
+                course_id,
+                added_at: 0,
+            } => format!("Course {course_id} is already on your list."),
+            Self::AlreadyWatching {
+                course_id,
+                added_at,
+            } => format!("Course {course_id} is already on your list (added on <t:{added_at}:D>)."),
+            Self::LimitReached { course_id, limit } => format!(
+                "You are already watching {limit} courses, which is the most allowed. Remove some before adding {course_id}."
+            ),
+            Self::CourseAdded { course_id } => format!("Course added for {course_id}."),
+            Self::CourseListHeader => "Current registered courses:".into(),
+            Self::CourseListEntry {
+                course_id,
+                added_at,
+                min_seats,
+                mode,
+                snapshot,
+                meta,
+            } => {
+                let mut line = course_label(course_id, *meta);
+                match snapshot {
+                    Some(SeatSnapshot {
+                        seats: Some(seats),
+                        checked_at,
+                    }) => {
+                        line += &format!(
+                            " — {}/{} seats free, checked <t:{checked_at}:R>",
+                            seats.available(),
+                            seats.quota
+                        )
+                    }
+                    Some(SeatSnapshot {
+                        seats: None,
+                        checked_at,
+                    }) => line += &format!(" — not listed, checked <t:{checked_at}:R>"),
+                    None => {}
+                }
+                match mode {
+                    WatchMode::Changes => line += " (any seat change)",
+                    WatchMode::Availability if *min_seats > 1 => {
+                        line += &format!(" (≥ {min_seats} seats)")
+                    }
+                    WatchMode::Availability => {}
+                }
+                if *added_at > 0 {
+                    line += &format!(" (added <t:{added_at}:R>)");
+                }
+                line
+            }
+            Self::WatchUpdated {
+                course_id,
+                mode: WatchMode::Availability,
+                min_seats,
+            } => format!(
+                "Course {course_id} will now alert when at least {min_seats} seats are free."
+            ),
+            Self::WatchUpdated {
+                course_id,
+                mode: WatchMode::Changes,
+                ..
+            } => format!("Course {course_id} will now alert on any seat change."),
+            Self::SeatsChanged { changes } => {
+                let lines = changes
+                    .iter()
+                    .map(|c| {
+                        format!(
+                            "- {}: enrolled {} → {}, quota {} → {}",
+                            c.course_id,
+                            c.before.enrolled,
+                            c.after.enrolled,
+                            c.before.quota,
+                            c.after.quota
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                format!("Seat numbers changed:\n{}", lines.join("\n"))
+            }
+            Self::NoCourse => "No course registered!".into(),
+            Self::ExportReady {
+                courses,
+                departments,
+            } => format!(
+                "Here is your watchlist ({courses} courses, {departments} departments), `/import` it to restore."
+            ),
+            Self::ImportInvalid => {
+                "That file is not a watchlist exported by `/export`.".into()
+            }
+            Self::ForgetMeConfirm => "This deletes your watchlist, watched departments, settings and pending daily summary. It cannot be undone, continue?".into(),
+            Self::ForgetMeButton => "Delete my data".into(),
+            Self::CancelButton => "Cancel".into(),
+            Self::ForgetMeDone => "All your data has been deleted.".into(),
+            Self::ForgetMeCancelled => "Nothing was deleted.".into(),
+            Self::ImportResult { report } => {
+                let mut text = if report.added.is_empty() {
+                    "Nothing was added.".to_owned()
+                } else {
+                    format!("Added: {}", report.added.join(", "))
+                };
+                for (id, reason) in &report.skipped {
+                    let reason = match reason {
+                        SkipReason::Invalid => "invalid",
+                        SkipReason::Duplicate => "already watched",
+                        SkipReason::LimitReached => "limit reached",
+                    };
+                    text += &format!("\nSkipped {id}: {reason}");
+                }
+                text
+            }
+            Self::ShareOffer { user_id, courses } => format!(
+                "<@{user_id}> shared {courses} courses from their watchlist with you. Pick the ones you want to watch too:"
+            ),
+            Self::SharePlaceholder => "Courses to watch".into(),
+            Self::ShareSent { user_id } => {
+                format!("Sent your watchlist to <@{user_id}>, they can pick the courses to watch.")
+            }
+            Self::ShareUndeliverable { user_id } => format!(
+                "Could not message <@{user_id}>, they may not accept direct messages from this bot."
+            ),
+            Self::ShareInvalidFriend => "Share your watchlist with another user, not yourself or a bot.".into(),
+            Self::Blocked => "You are not allowed to use this bot.".into(),
+            Self::DmsClosed => "⚠️ An alert could not reach you because your DMs are closed to \
+                this bot. Allow direct messages from members of a server you share with the bot \
+                (Privacy Settings) so you do not miss the next one."
+                .into(),
+            Self::CourseRemoved { course_ids } => {
+                format!("Course removed for {}.", course_ids.join(" & "))
+            }
+            Self::SelectToRemove => "Select courses to remove:".into(),
+            Self::SelectionTimedOut => "Selection timed out, nothing removed.".into(),
+            Self::Selected { course_ids } => format!("Selected: {}", course_ids.join(", ")),
+            Self::ForceUpdate => {
+                "⏳ Check queued...\n (Do not abuse and spam this command!)".into()
+            }
+            Self::ForceUpdateRunning { cycle } => format!("🔄 Running check #{cycle}..."),
+            Self::ForceUpdateDone { checked, available } => format!(
+                "✅ Check done: {checked} courses checked, {available} with free seats."
+            ),
+            Self::ForceUpdateSkipped => {
+                "⏸️ The check was skipped or aborted, see /status for why.".into()
+            }
+            Self::ForceUpdateTimedOut => {
+                "⌛ The check has not finished yet, alerts still arrive once it does.".into()
+            }
+            Self::ForceUpdateCooldown { minutes } => format!(
+                "A check was forced recently, try again in {minutes} minutes. Courses are checked on schedule meanwhile."
+            ),
+            Self::CheckCourseCooldown { seconds } => format!(
+                "A course was checked on demand recently, try again in {}.",
+                duration(*seconds, Lang::En)
+            ),
+            Self::MemberWatches {
+                user_id,
+                courses,
+                departments,
+                last_alert,
+            } => {
+                let alerted = match last_alert {
+                    Some(at) => format!("last alerted <t:{at}:R>"),
+                    None => "never alerted".to_owned(),
+                };
+                format!("<@{user_id}> watches {courses} courses and {departments} departments, {alerted}.")
+            }
+            Self::StoredSeats { snapshot } => match snapshot {
+                Some(SeatSnapshot {
+                    seats: Some(seats),
+                    checked_at,
+                }) => format!(
+                    "Stored before: {}/{} seats free, checked <t:{checked_at}:R>",
+                    seats.available(),
+                    seats.quota
+                ),
+                Some(SeatSnapshot {
+                    seats: None,
+                    checked_at,
+                }) => format!("Stored before: not listed, checked <t:{checked_at}:R>"),
+                None => "Stored before: never checked".into(),
+            },
+            Self::CheckingOwnCourses { done, count } => {
+                format!("🔄 Checking your {count} courses... {done}/{count}")
+            }
+            Self::OwnCoursesChecked { count } => format!(
+                "Live seats of your {count} courses, alerts still come with the regular check:"
+            ),
+            Self::CourseChecked {
+                course_id,
+                status,
+                meta,
+            } => {
+                let label = course_label(course_id, *meta);
+                match status {
+                    Some(CourseStatus::Available(seats)) => format!(
+                        "✅ {label} — {}/{} seats free",
+                        seats.available(),
+                        seats.quota
+                    ),
+                    Some(CourseStatus::Restricted(seats)) => format!(
+                        "⚠️ {label} — {}/{} seats free, for some students only",
+                        seats.available(),
+                        seats.quota
+                    ),
+                    Some(CourseStatus::Full(seats)) => {
+                        format!("❌ {label} — full, {}/{}", seats.enrolled, seats.quota)
+                    }
+                    Some(CourseStatus::NotFound) => format!("❔ {label} — not listed this term"),
+                    None => format!("⚠️ {label} — could not be checked, try again later"),
+                }
+            }
+            Self::PublicReplies { enabled: true } => {
+                "Replies will now be visible to everyone in the channel.".into()
+            }
+            Self::PublicReplies { enabled: false } => {
+                "Replies will now only be visible to you.".into()
+            }
+            Self::LanguageSet => "Language set to English.".into(),
+            Self::NotifyTargetSet { target, channel } => match (target, channel) {
+                (NotifyTarget::Dm, _) | (_, None) => {
+                    "Availability alerts will be sent by direct message.".into()
+                }
+                (NotifyTarget::Channel, Some(channel)) => {
+                    format!("Availability alerts will be posted in <#{channel}>.")
+                }
+                (NotifyTarget::Both, Some(channel)) => format!(
+                    "Availability alerts will be sent by direct message and posted in <#{channel}>."
+                ),
+            },
+            Self::NotifyChannelRequired => {
+                "Pick a channel, or run this command inside a server channel.".into()
+            }
+            Self::GuildNotifySet {
+                channel,
+                role: None,
+            } => format!("Alerts for courses watched by members will be posted in <#{channel}>."),
+            Self::GuildNotifySet {
+                channel,
+                role: Some(role),
+            } => format!(
+                "Alerts for courses watched by members will be posted in <#{channel}> pinging <@&{role}>."
+            ),
+            Self::GuildNotifyDisabled => "Guild alerts disabled.".into(),
+            Self::GuildCourseAvailable { course_ids } => format!(
+                "Course {} has open seats! Go get your course.",
+                course_ids.join(" & ")
+            ),
+            Self::GuildWatchAdded {
+                name,
+                course_id,
+                channel,
+            } => format!(
+                "Added {course_id} to the server watchlist \"{name}\", its openings are posted in <#{channel}>."
+            ),
+            Self::AlreadyInGuildWatchlist { name, course_id } => {
+                format!("{course_id} is already on the server watchlist \"{name}\".")
+            }
+            Self::GuildWatchlistLimitReached { limit } => {
+                format!("This server already has {limit} watchlists, remove one first.")
+            }
+            Self::GuildWatchlistFull { name, limit } => {
+                format!("The server watchlist \"{name}\" already has {limit} courses.")
+            }
+            Self::GuildWatchRemoved { name, course_id } => {
+                format!("Removed {course_id} from the server watchlist \"{name}\".")
+            }
+            Self::GuildWatchlistRemoved { name } => {
+                format!("Deleted the server watchlist \"{name}\".")
+            }
+            Self::NoGuildWatchlist { name } => {
+                format!("This server has no watchlist named \"{name}\".")
+            }
+            Self::NotInGuildWatchlist { name, course_id } => {
+                format!("{course_id} is not on the server watchlist \"{name}\".")
+            }
+            Self::NoGuildWatchlists => "This server has no shared watchlists yet.".into(),
+            Self::GuildWatchlistHeader { name, channel } => {
+                format!("**{name}**, posted in <#{channel}>:")
+            }
+            Self::GuildWatchEntry {
+                course_id,
+                snapshot,
+                meta,
+            } => {
+                let mut line = format!("- {}", course_label(course_id, *meta));
+                match snapshot {
+                    Some(SeatSnapshot {
+                        seats: Some(seats),
+                        checked_at,
+                    }) => {
+                        line += &format!(
+                            " — {}/{} seats free, checked <t:{checked_at}:R>",
+                            seats.available(),
+                            seats.quota
+                        )
+                    }
+                    Some(SeatSnapshot {
+                        seats: None,
+                        checked_at,
+                    }) => line += &format!(" — not listed, checked <t:{checked_at}:R>"),
+                    None => {}
+                }
+                line
+            }
+            Self::GuildWatchlistAvailable { name, courses } => format!(
+                "{}: course {} has open seats! Go get your course.",
+                name,
+                courses.join(" & ")
+            ),
+            Self::ReaddButton { course_id } => format!("Re-add {course_id}"),
+            Self::CourseFilledUp { courses } => format!(
+                "Course {} filled up again before you got a seat. Still watching, you will be alerted as soon as seats free up.",
+                courses.join(" & ")
+            ),
+            Self::FillAlertsSet { enabled: true } => "You will be told when a course you were alerted about fills up again, and alerted again as soon as it reopens.".into(),
+            Self::FillAlertsSet { enabled: false } => {
+                "You will no longer be told when alerted courses fill up again.".into()
+            }
+            Self::DigestSet {
+                digest,
+                daily_summary,
+            } => {
+                let digest = match digest {
+                    DigestMode::PerCourse => "You will get one message per available course.",
+                    DigestMode::Cycle => "Alerts from each check will be grouped into one message.",
+                };
+                if *daily_summary {
+                    format!("{digest} A summary of the day's alerts will be sent daily.")
+                } else {
+                    digest.to_owned()
+                }
+            }
+            Self::DailySummary { events } => {
+                let lines = events
+                    .iter()
+                    .map(|e| format!("- {} at <t:{}:t>", e.course_id, e.at))
+                    .collect::<Vec<_>>();
+                format!("Today's availability alerts:\n{}", lines.join("\n"))
+            }
+            Self::QuietHoursSet {
+                quiet_hours: Some(QuietHours { start, end }),
+            } => format!(
+                "Alerts will be held back from {start}:00 to {end}:00 (Taiwan time). Courses that still have free seats afterwards alert again."
+            ),
+            Self::QuietHoursSet { quiet_hours: None } => "Quiet hours turned off.".into(),
+            Self::InvalidQuietHours => {
+                "Give both a start and an end hour between 0 and 23 that differ, or neither to turn quiet hours off.".into()
+            }
+            Self::NoHistory { course_id } => {
+                format!("Course {course_id} has not had free seats since tracking started.")
+            }
+            Self::History {
+                course_id,
+                last_opened,
+                open_now,
+                openings,
+                average_duration,
+            } => {
+                let mut text = if *open_now {
+                    format!("Course {course_id} has had free seats since <t:{last_opened}:R>.")
+                } else {
+                    format!("Course {course_id} last had free seats <t:{last_opened}:R>.")
+                };
+                text += &format!("\nIt opened {openings} times since tracking started");
+                match average_duration {
+                    Some(average) => {
+                        text += &format!(", staying open {} on average.", duration(*average, Lang::En))
+                    }
+                    None => text += ".",
+                }
+                text
+            }
+            Self::NoNotifications => "You have not been sent any alerts yet.".into(),
+            Self::NotificationListHeader => "Recent alerts:".into(),
+            Self::NotificationListEntry { record } => {
+                let kind = record.kind.label(Lang::En);
+                let mut line = format!("- <t:{}:f> {} ({kind})", record.at, record.course_id);
+                if let Some(seats) = record.seats {
+                    line += &format!(" — {}/{} seats free", seats.available(), seats.quota);
+                }
+                if !record.delivered {
+                    line += " — not delivered";
+                }
+                line
+            }
+            Self::NoSearchResults { keyword } => format!("No course matches \"{keyword}\"."),
+            Self::UnknownCourse { course_id } => {
+                format!("Course {course_id} is not offered this semester.")
+            }
+            Self::UnknownSource { source } => format!(
+                "This bot does not watch courses of `{source}`, it knows {}.",
+                SOURCES.join(", ")
+            ),
+            Self::CourseRestricted {
+                course_id,
+                restrictions,
+            } => format!(
+                "⚠️ Course {course_id} is restricted ({}), a free seat may not be yours to take.",
+                restrictions.join(", ")
+            ),
+            Self::PhaseUpcoming { name, start, end } => format!(
+                "📅 Enrollment phase {name} opens <t:{start}:F> and closes <t:{end}:F>. Your watches are checked again from then on."
+            ),
+            Self::SemesterArchived {
+                semester,
+                course_ids,
+            } => format!(
+                "🗂️ A new semester has started and serial numbers were reassigned, so your {semester} watches were archived: {}. Add this semester's courses again with /add_course.",
+                course_ids.join(", ")
+            ),
+            Self::WatchesDropped { course_ids } => format!(
+                "🧹 These courses are no longer offered this term and were removed from your watchlist: {}.",
+                course_ids.join(", ")
+            ),
+            Self::CourseInfo { course } => {
+                let mut text = format!(
+                    "`{}` {} ({})\nCourse code: {}\n{} of {} seats taken on the public course list, which may lag behind.",
+                    course.serial_no,
+                    course.name,
+                    course.teacher,
+                    course.course_code,
+                    course.seats.enrolled,
+                    course.seats.quota
+                );
+                if !course.restrictions.is_empty() {
+                    text += &format!("\nRestrictions: {}", course.restrictions.join(", "));
+                }
+                text
+            }
+            Self::SearchResults {
+                keyword,
+                courses,
+                total,
+            } => {
+                let mut text = format!("Courses matching \"{keyword}\":");
+                for course in courses.iter() {
+                    text += &format!("\n{}", course_line(course, Lang::En));
+                }
+                if *total > courses.len() {
+                    text += &format!(
+                        "\n…and {} more, try a more specific keyword.",
+                        total - courses.len()
+                    );
+                }
+                text
+            }
+            Self::InvalidDeptCode { dept_code } => format!(
+                "\"{dept_code}\" is not a department code, use the letters shown in the course system (e.g. CSU)."
+            ),
+            Self::CourseIdOrCodeRequired => {
+                "Give either a course ID or a course code.".into()
+            }
+            Self::SearchTermRequired => {
+                "Give a keyword, or a department, domain or grade to list.".into()
+            }
+            Self::InvalidCourseCode { code } => format!(
+                "\"{code}\" is not a course code, it looks like CSU0001."
+            ),
+            Self::UnknownCourseCode { code } => {
+                format!("Course code {code} is not offered this semester.")
+            }
+            Self::CourseCodeResolved { code, course_ids } => format!(
+                "{code} is offered as {} this semester:",
+                course_ids.join(", ")
+            ),
+            Self::DepartmentWatched {
+                dept_code,
+                elective_only: false,
+            } => format!("Watching every course of {dept_code} for free seats."),
+            Self::DepartmentWatched {
+                dept_code,
+                elective_only: true,
+            } => format!("Watching every elective of {dept_code} for free seats."),
+            Self::AlreadyWatchingDepartment { dept_code } => {
+                format!("Department {dept_code} is already on your watchlist.")
+            }
+            Self::DepartmentLimitReached { limit } => {
+                format!("You can watch at most {limit} departments, remove one first.")
+            }
+            Self::DepartmentRemoved { dept_code } => {
+                format!("Department {dept_code} removed.")
+            }
+            Self::NotWatchingDepartment { dept_code } => {
+                format!("Department {dept_code} is not on your watchlist.")
+            }
+            Self::DepartmentListHeader => "Watched departments:".into(),
+            Self::DepartmentListEntry {
+                dept_code,
+                elective_only,
+                open,
+            } => {
+                let kind = if *elective_only {
+                    "electives"
+                } else {
+                    "all courses"
+                };
+                format!("{dept_code} ({kind}) — {open} open at the last check")
+            }
+            Self::DepartmentAvailable {
+                dept_code,
+                courses,
+                total,
+            } => {
+                let mut text = format!("New free seats in {dept_code}:");
+                for course in courses.iter() {
+                    text += &format!("\n{}", course_line(course, Lang::En));
+                }
+                if *total > courses.len() {
+                    text += &format!("\n…and {} more.", total - courses.len());
+                }
+                text
+            }
+            Self::CourseReadded { course_id } => format!(
+                "Course {course_id} is on your list again, you will be alerted on the next check."
+            ),
+            Self::CourseAvailable {
+                courses,
+                cooldown_minutes,
+            } => format!(
+                "Course {} available detected! Go get your course.\n (Courses stay on your list, you will be reminded again in {cooldown_minutes} minutes if seats remain)",
+                courses.join(" & ")
+            ),
+            Self::Status {
+                started_at,
+                last_check,
+                next_check,
+                session_healthy,
+                disabled,
+            } => {
+                let last = match last_check {
+                    Some((at, secs)) => format!(
+                        "finished <t:{at}:R>, took {}",
+                        duration(*secs, Lang::En)
+                    ),
+                    None => "none finished yet".into(),
+                };
+                let next = match next_check {
+                    Some(at) => format!("<t:{at}:R>"),
+                    None => "running now".into(),
+                };
+                let session = if *disabled {
+                    "disabled, the account is locked and the owner has been told"
+                } else if *session_healthy {
+                    "reachable"
+                } else {
+                    "unreachable, alerts are delayed until it recovers"
+                };
+                format!(
+                    "Running since <t:{started_at}:R>.\n\
+                     Last check: {last}.\n\
+                     Next check: {next}.\n\
+                     Enrollment system: {session}."
+                )
+            }
+        }
+    }
+
+    fn zh_tw(&self) -> String {
+        match self {
+            Self::InvalidCourseId { course_id, error } => {
+                let reason = match error {
+                    SerialNoError::NotDigits => "開課序號只能包含數字".into(),
+                    SerialNoError::Length(len) => {
+                        format!("開課序號應為 {SERIAL_NO_LEN} 位數，但輸入了 {len} 位")
+                    }
+                    SerialNoError::OutOfRange(min, max) => {
+                        format!("本學期的開課序號應介於 {min:04} 到 {max:04} 之間")
+                    }
+                };
+                format!("`{course_id}` 不是有效的開課序號：{reason}。")
+            }
+            Self::AlreadyWatching {
+                course_id,
+                added_at: 0,
+            } => format!("課程 {course_id} 已在你的清單中。"),
+            Self::AlreadyWatching {
+                course_id,
+                added_at,
+            } => format!("課程 {course_id} 已在你的清單中（於 <t:{added_at}:D> 加入）。"),
+            Self::LimitReached { course_id, limit } => {
+                format!("你已追蹤 {limit} 門課程，已達上限。請先移除部分課程再加入 {course_id}。")
+            }
+            Self::CourseAdded { course_id } => format!("已加入課程 {course_id}。"),
+            Self::CourseListHeader => "目前追蹤的課程：".into(),
+            Self::CourseListEntry {
+                course_id,
+                added_at,
+                min_seats,
+                mode,
+                snapshot,
+                meta,
+            } => {
+                let mut line = course_label(course_id, *meta);
+                match snapshot {
+                    Some(SeatSnapshot {
+                        seats: Some(seats),
+                        checked_at,
+                    }) => {
+                        line += &format!(
+                            " — 空位 {}/{}，<t:{checked_at}:R> 檢查",
+                            seats.available(),
+                            seats.quota
+                        )
+                    }
+                    Some(SeatSnapshot {
+                        seats: None,
+                        checked_at,
+                    }) => line += &format!(" — 查無此課程，<t:{checked_at}:R> 檢查"),
+                    None => {}
+                }
+                match mode {
+                    WatchMode::Changes => line += "（人數變動即通知）",
+                    WatchMode::Availability if *min_seats > 1 => {
+                        line += &format!("（至少 {min_seats} 個空位）")
+                    }
+                    WatchMode::Availability => {}
+                }
+                if *added_at > 0 {
+                    line += &format!("（<t:{added_at}:R> 加入）");
+                }
+                line
+            }
+            Self::WatchUpdated {
+                course_id,
+                mode: WatchMode::Availability,
+                min_seats,
+            } => format!("課程 {course_id} 將在至少有 {min_seats} 個空位時通知你。"),
+            Self::WatchUpdated {
+                course_id,
+                mode: WatchMode::Changes,
+                ..
+            } => format!("課程 {course_id} 的人數有任何變動時都會通知你。"),
+            Self::SeatsChanged { changes } => {
+                let lines = changes
+                    .iter()
+                    .map(|c| {
+                        format!(
+                            "- {}：選課人數 {} → {}，限修人數 {} → {}",
+                            c.course_id,
+                            c.before.enrolled,
+                            c.after.enrolled,
+                            c.before.quota,
+                            c.after.quota
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                format!("選課人數有變動：\n{}", lines.join("\n"))
+            }
+            Self::NoCourse => "尚未追蹤任何課程！".into(),
+            Self::ExportReady {
+                courses,
+                departments,
+            } => format!(
+                "這是你的追蹤清單（{courses} 門課程、{departments} 個系所），可用 `/import` 匯入還原。"
+            ),
+            Self::ImportInvalid => "這不是由 `/export` 匯出的追蹤清單檔案。".into(),
+            Self::ForgetMeConfirm => {
+                "這會刪除你的追蹤清單、追蹤系所、設定以及尚未寄出的每日摘要，且無法復原，確定要繼續嗎？".into()
+            }
+            Self::ForgetMeButton => "刪除我的資料".into(),
+            Self::CancelButton => "取消".into(),
+            Self::ForgetMeDone => "你的所有資料已刪除。".into(),
+            Self::ForgetMeCancelled => "未刪除任何資料。".into(),
+            Self::ImportResult { report } => {
+                let mut text = if report.added.is_empty() {
+                    "沒有新增任何項目。".to_owned()
+                } else {
+                    format!("已新增：{}", report.added.join("、"))
+                };
+                for (id, reason) in &report.skipped {
+                    let reason = match reason {
+                        SkipReason::Invalid => "格式錯誤",
+                        SkipReason::Duplicate => "已在清單中",
+                        SkipReason::LimitReached => "已達上限",
+                    };
+                    text += &format!("\n略過 {id}：{reason}");
+                }
+                text
+            }
+            Self::ShareOffer { user_id, courses } => format!(
+                "<@{user_id}> 分享了追蹤清單中的 {courses} 門課程給你，請選擇你也想追蹤的課程："
+            ),
+            Self::SharePlaceholder => "要追蹤的課程".into(),
+            Self::ShareSent { user_id } => {
+                format!("已將你的追蹤清單傳送給 <@{user_id}>，對方可以選擇要追蹤的課程。")
+            }
+            Self::ShareUndeliverable { user_id } => {
+                format!("無法傳送訊息給 <@{user_id}>，對方可能不接受此機器人的私訊。")
+            }
+            Self::ShareInvalidFriend => "請將追蹤清單分享給其他使用者，而不是自己或機器人。".into(),
+            Self::Blocked => "你已被禁止使用此機器人。".into(),
+            Self::DmsClosed => "⚠️ 你關閉了私訊，有則通知沒能送達。請在與機器人共同的伺服器的「隱私設定」中\
+                允許來自伺服器成員的私訊，以免錯過下一則通知。"
+                .into(),
+            Self::CourseRemoved { course_ids } => {
+                format!("已移除課程 {}。", course_ids.join("、"))
+            }
+            Self::SelectToRemove => "請選擇要移除的課程：".into(),
+            Self::SelectionTimedOut => "選擇逾時，未移除任何課程。".into(),
+            Self::Selected { course_ids } => format!("已選擇：{}", course_ids.join("、")),
+            Self::ForceUpdate => "⏳ 已排入強制更新……\n（請勿濫用或洗版此指令！）".into(),
+            Self::ForceUpdateRunning { cycle } => format!("🔄 正在執行第 {cycle} 次檢查……"),
+            Self::ForceUpdateDone { checked, available } => {
+                format!("✅ 檢查完成：查詢了 {checked} 門課程，其中 {available} 門有空位。")
+            }
+            Self::ForceUpdateSkipped => "⏸️ 這次檢查被略過或中止，原因請見 /status。".into(),
+            Self::ForceUpdateTimedOut => "⌛ 檢查尚未完成，完成後仍會照常通知。".into(),
+            Self::ForceUpdateCooldown { minutes } => {
+                format!("最近已強制更新過，請於 {minutes} 分鐘後再試。課程仍會照常定期檢查。")
+            }
+            Self::CheckCourseCooldown { seconds } => {
+                format!("最近已有人查詢過課程，請於 {} 後再試。", duration(*seconds, Lang::ZhTw))
+            }
+            Self::MemberWatches {
+                user_id,
+                courses,
+                departments,
+                last_alert,
+            } => {
+                let alerted = match last_alert {
+                    Some(at) => format!("上次通知於 <t:{at}:R>"),
+                    None => "從未收到通知".to_owned(),
+                };
+                format!("<@{user_id}> 追蹤了 {courses} 門課程與 {departments} 個系所，{alerted}。")
+            }
+            Self::StoredSeats { snapshot } => match snapshot {
+                Some(SeatSnapshot {
+                    seats: Some(seats),
+                    checked_at,
+                }) => format!(
+                    "先前紀錄：空位 {}/{}，<t:{checked_at}:R> 檢查",
+                    seats.available(),
+                    seats.quota
+                ),
+                Some(SeatSnapshot {
+                    seats: None,
+                    checked_at,
+                }) => format!("先前紀錄：查無此課程，<t:{checked_at}:R> 檢查"),
+                None => "先前紀錄：尚未檢查過".into(),
+            },
+            Self::CheckingOwnCourses { done, count } => {
+                format!("🔄 正在檢查你的 {count} 門課程…… {done}/{count}")
+            }
+            Self::OwnCoursesChecked { count } => {
+                format!("你的 {count} 門課程目前的空位如下，通知仍會隨定期檢查送出：")
+            }
+            Self::CourseChecked {
+                course_id,
+                status,
+                meta,
+            } => {
+                let label = course_label(course_id, *meta);
+                match status {
+                    Some(CourseStatus::Available(seats)) => {
+                        format!("✅ {label} — 空位 {}/{}", seats.available(), seats.quota)
+                    }
+                    Some(CourseStatus::Restricted(seats)) => format!(
+                        "⚠️ {label} — 空位 {}/{}，僅限部分學生",
+                        seats.available(),
+                        seats.quota
+                    ),
+                    Some(CourseStatus::Full(seats)) => {
+                        format!("❌ {label} — 已額滿，{}/{}", seats.enrolled, seats.quota)
+                    }
+                    Some(CourseStatus::NotFound) => format!("❔ {label} — 本學期查無此課程"),
+                    None => format!("⚠️ {label} — 查詢失敗，請稍後再試"),
+                }
+            }
+            Self::PublicReplies { enabled: true } => "之後的回覆將對頻道中的所有人顯示。".into(),
+            Self::PublicReplies { enabled: false } => "之後的回覆將只有你看得到。".into(),
+            Self::LanguageSet => "語言已設定為繁體中文。".into(),
+            Self::NotifyTargetSet { target, channel } => match (target, channel) {
+                (NotifyTarget::Dm, _) | (_, None) => "空位通知將以私訊傳送。".into(),
+                (NotifyTarget::Channel, Some(channel)) => {
+                    format!("空位通知將發送至 <#{channel}>。")
+                }
+                (NotifyTarget::Both, Some(channel)) => {
+                    format!("空位通知將以私訊傳送，並同時發送至 <#{channel}>。")
+                }
+            },
+            Self::NotifyChannelRequired => "請選擇頻道，或在伺服器頻道中使用此指令。".into(),
+            Self::GuildNotifySet {
+                channel,
+                role: None,
+            } => format!("成員追蹤的課程空位通知將發送至 <#{channel}>。"),
+            Self::GuildNotifySet {
+                channel,
+                role: Some(role),
+            } => format!("成員追蹤的課程空位通知將發送至 <#{channel}> 並提及 <@&{role}>。"),
+            Self::GuildNotifyDisabled => "已停用伺服器通知。".into(),
+            Self::GuildCourseAvailable { course_ids } => {
+                format!("課程 {} 有空位！快去搶課吧。", course_ids.join("、"))
+            }
+            Self::GuildWatchAdded {
+                name,
+                course_id,
+                channel,
+            } => format!(
+                "已將 {course_id} 加入伺服器追蹤清單「{name}」，空位通知將發送至 <#{channel}>。"
+            ),
+            Self::AlreadyInGuildWatchlist { name, course_id } => {
+                format!("{course_id} 已在伺服器追蹤清單「{name}」中。")
+            }
+            Self::GuildWatchlistLimitReached { limit } => {
+                format!("此伺服器已有 {limit} 個追蹤清單，請先移除一個。")
+            }
+            Self::GuildWatchlistFull { name, limit } => {
+                format!("伺服器追蹤清單「{name}」已有 {limit} 門課程。")
+            }
+            Self::GuildWatchRemoved { name, course_id } => {
+                format!("已將 {course_id} 從伺服器追蹤清單「{name}」移除。")
+            }
+            Self::GuildWatchlistRemoved { name } => format!("已刪除伺服器追蹤清單「{name}」。"),
+            Self::NoGuildWatchlist { name } => format!("此伺服器沒有名為「{name}」的追蹤清單。"),
+            Self::NotInGuildWatchlist { name, course_id } => {
+                format!("{course_id} 不在伺服器追蹤清單「{name}」中。")
+            }
+            Self::NoGuildWatchlists => "此伺服器還沒有共用的追蹤清單。".into(),
+            Self::GuildWatchlistHeader { name, channel } => {
+                format!("**{name}**，發送至 <#{channel}>：")
+            }
+            Self::GuildWatchEntry {
+                course_id,
+                snapshot,
+                meta,
+            } => {
+                let mut line = format!("- {}", course_label(course_id, *meta));
+                match snapshot {
+                    Some(SeatSnapshot {
+                        seats: Some(seats),
+                        checked_at,
+                    }) => {
+                        line += &format!(
+                            " — 空位 {}/{}，<t:{checked_at}:R> 檢查",
+                            seats.available(),
+                            seats.quota
+                        )
+                    }
+                    Some(SeatSnapshot {
+                        seats: None,
+                        checked_at,
+                    }) => line += &format!(" — 查無此課程，<t:{checked_at}:R> 檢查"),
+                    None => {}
+                }
+                line
+            }
+            Self::GuildWatchlistAvailable { name, courses } => {
+                format!("{name}：課程 {} 有空位！快去搶課吧。", courses.join("、"))
+            }
+            Self::ReaddButton { course_id } => format!("重新加入 {course_id}"),
+            Self::CourseFilledUp { courses } => format!(
+                "課程 {} 在你選上前又額滿了。仍會繼續追蹤，一有空位就會立即通知。",
+                courses.join("、")
+            ),
+            Self::FillAlertsSet { enabled: true } => {
+                "通知過的課程再次額滿時會告訴你，並在重新出現空位時立即通知。".into()
+            }
+            Self::FillAlertsSet { enabled: false } => "通知過的課程再次額滿時將不再告訴你。".into(),
+            Self::DigestSet {
+                digest,
+                daily_summary,
+            } => {
+                let digest = match digest {
+                    DigestMode::PerCourse => "每門有空位的課程將分別通知。",
+                    DigestMode::Cycle => "每次檢查的通知將合併為一則訊息。",
+                };
+                if *daily_summary {
+                    format!("{digest}每天也會傳送當日通知摘要。")
+                } else {
+                    digest.to_owned()
+                }
+            }
+            Self::DailySummary { events } => {
+                let lines = events
+                    .iter()
+                    .map(|e| format!("- {}（<t:{}:t>）", e.course_id, e.at))
+                    .collect::<Vec<_>>();
+                format!("今日空位通知：\n{}", lines.join("\n"))
+            }
+            Self::QuietHoursSet {
+                quiet_hours: Some(QuietHours { start, end }),
+            } => format!(
+                "{start}:00 至 {end}:00（台灣時間）將暫停通知，之後仍有空位的課程會再次通知。"
+            ),
+            Self::QuietHoursSet { quiet_hours: None } => "已關閉勿擾時段。".into(),
+            Self::InvalidQuietHours => {
+                "請同時提供 0 到 23 之間且不相同的開始與結束時間，或都不填以關閉勿擾時段。".into()
+            }
+            Self::NoHistory { course_id } => {
+                format!("自開始記錄以來，課程 {course_id} 尚未出現空位。")
+            }
+            Self::History {
+                course_id,
+                last_opened,
+                open_now,
+                openings,
+                average_duration,
+            } => {
+                let mut text = if *open_now {
+                    format!("課程 {course_id} 自 <t:{last_opened}:R> 起有空位。")
+                } else {
+                    format!("課程 {course_id} 最近一次有空位是 <t:{last_opened}:R>。")
+                };
+                text += &format!("\n自開始記錄以來共出現 {openings} 次空位");
+                match average_duration {
+                    Some(average) => {
+                        text += &format!("，平均維持 {}。", duration(*average, Lang::ZhTw))
+                    }
+                    None => text += "。",
+                }
+                text
+            }
+            Self::NoNotifications => "目前還沒有寄給你的通知。".into(),
+            Self::NotificationListHeader => "最近的通知：".into(),
+            Self::NotificationListEntry { record } => {
+                let kind = record.kind.label(Lang::ZhTw);
+                let mut line = format!("- <t:{}:f> {}（{kind}）", record.at, record.course_id);
+                if let Some(seats) = record.seats {
+                    line += &format!("— 空位 {}/{}", seats.available(), seats.quota);
+                }
+                if !record.delivered {
+                    line += "— 傳送失敗";
+                }
+                line
+            }
+            Self::NoSearchResults { keyword } => format!("找不到符合「{keyword}」的課程。"),
+            Self::UnknownCourse { course_id } => format!("本學期沒有開課序號 {course_id}。"),
+            Self::UnknownSource { source } => format!(
+                "本機器人不支援 `{source}` 的課程，目前支援：{}。",
+                SOURCES.join("、")
+            ),
+            Self::CourseRestricted {
+                course_id,
+                restrictions,
+            } => format!(
+                "⚠️ 課程 {course_id} 有選課限制（{}），空出的名額你不一定能選。",
+                restrictions.join("、")
+            ),
+            Self::PhaseUpcoming { name, start, end } => format!(
+                "📅 選課階段「{name}」將於 <t:{start}:F> 開始，<t:{end}:F> 結束，屆時會恢復查詢你關注的課程。"
+            ),
+            Self::SemesterArchived {
+                semester,
+                course_ids,
+            } => format!(
+                "🗂️ 新學期開始，開課序號已重新編排，你在 {semester} 學期關注的課程已封存：{}。請用 /add_course 重新加入本學期的課程。",
+                course_ids.join("、")
+            ),
+            Self::WatchesDropped { course_ids } => format!(
+                "🧹 以下課程本學期已不再開設，已從你的關注清單移除：{}。",
+                course_ids.join("、")
+            ),
+            Self::CourseInfo { course } => {
+                let mut text = format!(
+                    "`{}` {}（{}）\n科目代碼：{}\n公開課程查詢顯示已選 {}/{} 人，可能與選課系統有落差。",
+                    course.serial_no,
+                    course.name,
+                    course.teacher,
+                    course.course_code,
+                    course.seats.enrolled,
+                    course.seats.quota
+                );
+                if !course.restrictions.is_empty() {
+                    text += &format!("\n選課限制：{}", course.restrictions.join("、"));
+                }
+                text
+            }
+            Self::SearchResults {
+                keyword,
+                courses,
+                total,
+            } => {
+                let mut text = format!("符合「{keyword}」的課程：");
+                for course in courses.iter() {
+                    text += &format!("\n{}", course_line(course, Lang::ZhTw));
+                }
+                if *total > courses.len() {
+                    text += &format!(
+                        "\n…還有 {} 筆，請換個更精確的關鍵字。",
+                        total - courses.len()
+                    );
+                }
+                text
+            }
+            Self::InvalidDeptCode { dept_code } => {
+                format!("「{dept_code}」不是系所代碼，請使用選課系統上的代碼（例如 CSU）。")
+            }
+            Self::CourseIdOrCodeRequired => "請提供開課序號或科目代碼。".into(),
+            Self::SearchTermRequired => "請提供關鍵字，或要列出的系所、通識領域或年級。".into(),
+            Self::InvalidCourseCode { code } => {
+                format!("「{code}」不是科目代碼，格式類似 CSU0001。")
+            }
+            Self::UnknownCourseCode { code } => format!("本學期沒有開設科目 {code}。"),
+            Self::CourseCodeResolved { code, course_ids } => {
+                format!("{code} 本學期的開課序號為 {}：", course_ids.join("、"))
+            }
+            Self::DepartmentWatched {
+                dept_code,
+                elective_only: false,
+            } => format!("開始追蹤 {dept_code} 所有課程的空位。"),
+            Self::DepartmentWatched {
+                dept_code,
+                elective_only: true,
+            } => format!("開始追蹤 {dept_code} 所有選修課程的空位。"),
+            Self::AlreadyWatchingDepartment { dept_code } => {
+                format!("系所 {dept_code} 已在追蹤清單中。")
+            }
+            Self::DepartmentLimitReached { limit } => {
+                format!("最多只能追蹤 {limit} 個系所，請先移除一個。")
+            }
+            Self::DepartmentRemoved { dept_code } => format!("已移除系所 {dept_code}。"),
+            Self::NotWatchingDepartment { dept_code } => {
+                format!("系所 {dept_code} 不在追蹤清單中。")
+            }
+            Self::DepartmentListHeader => "追蹤中的系所：".into(),
+            Self::DepartmentListEntry {
+                dept_code,
+                elective_only,
+                open,
+            } => {
+                let kind = if *elective_only {
+                    "選修"
+                } else {
+                    "所有課程"
+                };
+                format!("{dept_code}（{kind}）— 上次檢查有 {open} 門課有空位")
+            }
+            Self::DepartmentAvailable {
+                dept_code,
+                courses,
+                total,
+            } => {
+                let mut text = format!("{dept_code} 有新的空位：");
+                for course in courses.iter() {
+                    text += &format!("\n{}", course_line(course, Lang::ZhTw));
+                }
+                if *total > courses.len() {
+                    text += &format!("\n…還有 {} 門。", total - courses.len());
+                }
+                text
+            }
+            Self::CourseReadded { course_id } => {
+                format!("課程 {course_id} 已重新加入清單，下次檢查時會再通知你。")
+            }
+            Self::CourseAvailable {
+                courses,
+                cooldown_minutes,
+            } => format!(
+                "偵測到課程 {} 有空位！快去搶課吧。\n（課程仍保留在清單中，若仍有空位將於 {cooldown_minutes} 分鐘後再次提醒）",
+                courses.join("、")
+            ),
+            Self::Status {
+                started_at,
+                last_check,
+                next_check,
+                session_healthy,
+                disabled,
+            } => {
+                let last = match last_check {
+                    Some((at, secs)) => {
+                        format!("<t:{at}:R>完成，耗時 {}", duration(*secs, Lang::ZhTw))
+                    }
+                    None => "尚未完成任何檢查".into(),
+                };
+                let next = match next_check {
+                    Some(at) => format!("<t:{at}:R>"),
+                    None => "正在進行中".into(),
+                };
+                let session = if *disabled {
+                    "已停用，帳號遭鎖定，已通知管理者"
+                } else if *session_healthy {
+                    "連線正常"
+                } else {
+                    "無法連線，恢復前通知會延遲"
+                };
+                format!(
+                    "啟動於 <t:{started_at}:R>。\n\
+                     上次檢查：{last}。\n\
+                     下次檢查：{next}。\n\
+                     選課系統：{session}。"
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Picks the one replica that runs the checker when several share the storage
+//!
+//! Every replica serves commands, but only the holder of a lease in the storage queries the
+//! enrollment system and sends alerts. The holder renews the lease well before it expires; when
+//! it stops, another replica takes the lease over once it runs out.
+
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use tokio::{sync::watch, time::sleep};
+use tracing::{info, warn};
+
+use crate::{db::Repository, metrics::METRICS};
+
+/// Name of the lease the checker runs under
+const LEASE: &str = "checker";
+
+/// Name of this replica in the lease, unique even for processes on one host
+pub fn instance_id() -> String {
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "bot".to_owned());
+    let mut nonce = [0; 4];
+    getrandom::getrandom(&mut nonce).expect("no OS randomness");
+    format!(
+        "{host}-{}-{:08x}",
+        std::process::id(),
+        u32::from_le_bytes(nonce)
+    )
+}
+
+/// Hold the lease for `ttl` seconds at a time, telling `leader` whether this replica has it
+///
+/// A storage error counts as losing the lease, since another replica may take it meanwhile. The
+/// lease is released on shutdown, so a standby takes over without waiting for it to expire.
+pub async fn run(
+    db: Arc<dyn Repository>,
+    holder: String,
+    ttl: u64,
+    leader: watch::Sender<bool>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    info!("Running as instance {holder} with a {ttl}s checker lease");
+    let renew = Duration::from_secs(ttl / 3).max(Duration::from_secs(1));
+    let mut was_held = None;
+    loop {
+        let held = match db.acquire_lease(LEASE, &holder, ttl).await {
+            Ok(held) => held,
+            Err(e) => {
+                warn!("fail to renew the checker lease: {e}");
+                false
+            }
+        };
+        if was_held != Some(held) {
+            was_held = Some(held);
+            if held {
+                info!("This instance now runs the checker");
+            } else {
+                info!("Another instance runs the checker, standing by");
+            }
+        }
+        METRICS.leader.store(held, Ordering::Relaxed);
+        leader.send_replace(held);
+        tokio::select! {
+            _ = sleep(renew) => (),
+            _ = shutdown.changed() => break,
+        }
+    }
+    if *leader.borrow() {
+        if let Err(e) = db.release_lease(LEASE, &holder).await {
+            warn!("fail to release the checker lease: {e}");
+        }
+    }
+}
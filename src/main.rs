@@ -1,136 +1,1646 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    future::Future,
+    sync::{atomic::Ordering, Arc, PoisonError},
+    time::{Duration, Instant},
+};
 
 use anyhow::Ok;
+use breaker::BreakerEvent;
 use config::Config;
-use crawler::NtnuCrawlerManager;
-use envconfig::Envconfig;
-use kv::{Msgpack, Store};
-use log::{error, info, trace, warn};
-use serenity::all::{CreateMessage, UserId};
-use tokio::signal::unix::{signal, SignalKind};
-use tokio::time::sleep;
+use crawler::{
+    validate_serial_no, AccountEvent, CourseInfo, CourseStatus, FailureCause, NtnuCrawlerManager,
+    SeatCount,
+};
+use db::{
+    local_day, now, CheckerStats, CourseMeta, Db, DigestEvent, GuildWatchlist, NotificationKind,
+    NotificationRecord, Repository, SeatSnapshot, UserSettings, WatchMode, META_ANNOUNCED_PHASE,
+    META_LAST_DAILY_SUMMARY, META_LAST_WATCH_SWEEP, META_SEMESTER,
+};
+use i18n::{course_label, Msg, SeatChange};
+use metrics::{Degradation, LATENCIES, METRICS};
+use notify::{notify_channel, notify_guild, notify_user, readd_buttons, Delivery, DigestMode};
+use ntu::NtuSource;
+use open_course::OpenCourseCrawler;
+use progress::{CheckerState, CycleOutcome};
+use reload::SharedConfig;
+use serenity::{
+    all::{ChannelId, GuildId, UserId},
+    http::Http,
+};
+use source::Sources;
+use tokio::sync::watch;
+use tokio::time::{sleep, sleep_until, timeout};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
 
 mod bot;
+mod breaker;
+mod captcha;
+mod cli;
 mod config;
 mod crawler;
+mod dashboard;
+mod db;
+mod export;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod http;
+mod i18n;
+mod leader;
+mod metrics;
+mod notify;
+mod ntu;
+#[cfg(feature = "local-captcha")]
+mod ocr;
+mod open_course;
+mod page;
+mod paginate;
+mod permission;
+mod progress;
+mod ratelimit;
+mod reload;
+mod schedule;
+mod shutdown;
+mod snapshot;
+mod source;
+mod systemd;
 
-async fn periodic_checker(
-    db: Arc<tokio::sync::RwLock<Store>>,
+/// How long a shutdown waits for the running check to wind down
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(60);
+
+/// Settings of a user for messages sent by the checker, the defaults when they cannot be read so
+/// the message still goes out by DM
+async fn user_settings(db: &dyn Repository, user_id: u64) -> UserSettings {
+    db.settings(user_id).await.unwrap_or_else(|e| {
+        warn!("fail to read the settings of user {user_id}, using the defaults: {e:?}");
+        UserSettings::default()
+    })
+}
+
+/// Once per (Taiwan) day, flush the collected alerts of users who asked for a summary
+async fn send_daily_summaries(
+    db: &dyn Repository,
+    http: &Http,
     config: &Config,
-    mut update_receiver: tokio::sync::mpsc::Receiver<()>,
+) -> anyhow::Result<()> {
+    let today = local_day(now());
+    let last = db.meta(META_LAST_DAILY_SUMMARY).await?;
+    if last == Some(today) {
+        return Ok(());
+    }
+    db.set_meta(META_LAST_DAILY_SUMMARY, today).await?;
+    // nothing recorded yet on a fresh database
+    if last.is_none() {
+        return Ok(());
+    }
+    let pending = db.take_digest_events().await?;
+    for (user_id, events) in pending {
+        let settings = user_settings(db, user_id).await;
+        let content = Msg::DailySummary { events: &events }.render(settings.lang());
+        let user = UserId::new(user_id);
+        let fallback = config.dm_fallback_channel.map(ChannelId::new);
+        let delivery = notify_user(http, user, &settings, &content, Vec::new(), fallback).await;
+        if !delivery.delivered {
+            warn!("fail to send daily summary (user: {user})");
+        }
+        track_delivery(db, user_id, delivery, config.unreachable_after).await;
+    }
+    Ok(())
+}
+
+/// Owner alerts quote at most this many bytes of the error
+const OWNER_ERROR_LIMIT: usize = 1500;
+
+/// Department alerts list at most this many courses
+const DEPARTMENT_ALERT_LIMIT: usize = 15;
+
+/// Expand department watches into their courses and alert on the ones that newly opened
+///
+/// Returns the tally of the department queries.
+async fn check_departments(
+    db: &dyn Repository,
+    crawler: &tokio::sync::Mutex<NtnuCrawlerManager>,
+    http: &Http,
+    skipped: &HashSet<u64>,
+    config: &Config,
+) -> QueryTally {
+    let mut tally = QueryTally::default();
+    let watches = match db.all_department_watches().await {
+        Result::Ok(watches) => watches,
+        Result::Err(e) => {
+            error!("fail to load the department watches: {e:?}");
+            return tally;
+        }
+    };
+    // several users often watch the same department, query it once per check
+    let mut departments: HashMap<String, Option<Vec<CourseInfo>>> = HashMap::new();
+    for (user_id, list) in watches {
+        if skipped.contains(&user_id) {
+            continue;
+        }
+        let checked = async {
+            let settings = db.settings(user_id).await?;
+            // comparing against the last check before quiet hours alerts on what opened meanwhile
+            if settings.is_quiet(now()) {
+                debug!("quiet hours, holding department alerts back");
+                return Ok(());
+            }
+            let lang = settings.lang();
+            let user = UserId::new(user_id);
+            let mut updates: HashMap<String, Vec<String>> = HashMap::new();
+            let mut alerts = Vec::new();
+            for watch in &list {
+                if !departments.contains_key(&watch.dept_code) {
+                    let (result, latency) = {
+                        let mut crawler = crawler.lock().await;
+                        let start = Instant::now();
+                        (crawler.department(&watch.dept_code).await, start.elapsed())
+                    };
+                    tally.sent(latency);
+                    let courses = match result {
+                        Result::Ok(courses) => {
+                            if let Result::Err(e) = db.cache_course_meta(&courses, now()).await {
+                                warn!("fail to cache the courses of {}: {e:?}", watch.dept_code);
+                            }
+                            Some(courses)
+                        }
+                        Result::Err(e) => {
+                            warn!("fail to check department {}: {e:?}", watch.dept_code);
+                            tally.fail(e);
+                            None
+                        }
+                    };
+                    departments.insert(watch.dept_code.clone(), courses);
+                }
+                let Some(courses) = &departments[&watch.dept_code] else {
+                    continue;
+                };
+                let open = watch.open_courses(courses);
+                let opened = courses
+                    .iter()
+                    .filter(|c| open.contains(&c.serial_no) && !watch.open.contains(&c.serial_no))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if !opened.is_empty() {
+                    let shown = &opened[..opened.len().min(DEPARTMENT_ALERT_LIMIT)];
+                    let content = Msg::DepartmentAvailable {
+                        dept_code: &watch.dept_code,
+                        courses: shown,
+                        total: opened.len(),
+                    }
+                    .render(lang);
+                    alerts.push((watch.dept_code.clone(), content, shown.to_vec()));
+                }
+                updates.insert(watch.dept_code.clone(), open);
+            }
+
+            // the user ran `/forget_me` while their departments were being checked
+            if !db.record_department_check(user_id, &updates).await? {
+                return Ok(());
+            }
+
+            let mut records = Vec::new();
+            for (dept_code, content, courses) in alerts {
+                let fallback = config.dm_fallback_channel.map(ChannelId::new);
+                let delivery =
+                    notify_user(http, user, &settings, &content, Vec::new(), fallback).await;
+                if !delivery.delivered {
+                    warn!(
+                        "fail to notify user department available (user: {user}, department: {dept_code})"
+                    );
+                }
+                track_delivery(db, user_id, delivery, config.unreachable_after).await;
+                let at = now();
+                records.extend(courses.into_iter().map(|course| NotificationRecord {
+                    course_id: course.serial_no,
+                    kind: NotificationKind::Department,
+                    seats: Some(course.seats),
+                    delivered: delivery.delivered,
+                    at,
+                }));
+            }
+            db.log_notifications(user_id, &records).await?;
+            Ok(())
+        }
+        .instrument(info_span!("user", user_id))
+        .await;
+        if let Result::Err(e) = checked {
+            warn!("fail to check the departments of user {user_id}: {e:?}");
+        }
+    }
+    tally
+}
+
+/// Remember whether an alert reached a user, disabling them after `limit` failures in a row
+async fn track_delivery(db: &dyn Repository, user_id: u64, delivery: Delivery, limit: u32) {
+    if delivery.dm_closed {
+        if let Result::Err(e) = db.update_settings(user_id, &|s| s.dm_closed = true).await {
+            warn!("fail to remember that user {user_id} has closed DMs: {e:?}");
+        }
+    }
+    let failures = match db.record_delivery(user_id, delivery.delivered, now()).await {
+        Result::Ok(Some(failures)) => failures,
+        Result::Ok(None) => return,
+        Result::Err(e) => {
+            warn!("fail to record the delivery to user {user_id}: {e:?}");
+            return;
+        }
+    };
+    if failures.count >= limit && failures.disabled_at.is_none() {
+        warn!(
+            "Disabling unreachable user {user_id} after {} failed alerts since {}",
+            failures.count, failures.since
+        );
+        if let Result::Err(e) = db.disable_user(user_id, now()).await {
+            warn!("fail to disable user {user_id}: {e:?}");
+        }
+    }
+}
+
+/// Delete the data of users disabled for longer than `purge_after` seconds
+///
+/// Returns the users that stay disabled.
+async fn purge_unreachable(db: &dyn Repository, purge_after: u64) -> HashSet<u64> {
+    let mut disabled = HashSet::new();
+    let failures = match db.delivery_failures().await {
+        Result::Ok(failures) => failures,
+        Result::Err(e) => {
+            warn!("fail to load the unreachable users: {e:?}");
+            return disabled;
+        }
+    };
+    for (user_id, failures) in failures {
+        let Some(disabled_at) = failures.disabled_at else {
+            continue;
+        };
+        if now().saturating_sub(disabled_at) >= purge_after {
+            info!(
+                "Purging data of user {user_id}, unreachable since {}",
+                failures.since
+            );
+            if let Result::Err(e) = db.forget_user(user_id).await {
+                warn!("fail to purge the data of user {user_id}: {e:?}");
+                disabled.insert(user_id);
+            }
+        } else {
+            disabled.insert(user_id);
+        }
+    }
+    disabled
+}
+
+/// Cached metadata of a course, looked up again once older than `ttl` seconds
+///
+/// The seat query does not return names, so this costs one `lookup` of the course's school per
+/// course every `ttl`; `lookup` is only awaited then.
+async fn course_meta(
+    db: &dyn Repository,
+    course_id: &str,
+    ttl: u64,
+    lookup: impl Future<Output = anyhow::Result<Option<CourseInfo>>>,
+) -> Option<CourseMeta> {
+    let cached = db.course_meta(course_id).await.unwrap_or_else(|e| {
+        warn!("fail to read the cached course {course_id}: {e:?}");
+        None
+    });
+    if cached
+        .as_ref()
+        .is_some_and(|meta| !meta.is_stale(ttl, now()))
+    {
+        return cached;
+    }
+    match lookup.await {
+        Result::Ok(course) => {
+            // stored under the ID it is watched as, which may name its school
+            let courses = course
+                .into_iter()
+                .map(|c| CourseInfo {
+                    serial_no: course_id.to_owned(),
+                    ..c
+                })
+                .collect::<Vec<_>>();
+            let at = now();
+            if let Result::Err(e) = db.cache_course_meta(&courses, at).await {
+                warn!("fail to cache course {course_id}: {e:?}");
+            }
+            courses
+                .first()
+                .map(|course| CourseMeta::from((course, at)))
+                .or(cached)
+        }
+        Result::Err(e) => {
+            warn!("fail to look up course {course_id}: {e:?}");
+            cached
+        }
+    }
+}
+
+/// Fold the outcome of one check into the stored checker stats and latency statistics
+async fn record_cycle(
+    db: &dyn Repository,
+    duration: Duration,
+    queries: u64,
+    tally: &QueryTally,
+    logins: u64,
+) -> anyhow::Result<()> {
+    metrics::inc(&METRICS.cycles);
+    METRICS.cycle_queries.store(queries, Ordering::Relaxed);
+    // a panic elsewhere leaves the statistics usable
+    let degradation = LATENCIES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .record_cycle(duration.as_millis() as u64, &tally.latencies);
+    if let Some(Degradation { median, usual }) = degradation {
+        warn!(
+            median_ms = median,
+            usual_ms = usual,
+            "enrollment system responses degraded, consider raising the retry and timeout settings"
+        );
+    }
+    let failures = tally.failed;
+    let mut stats = db.stats().await?;
+    stats.last_cycle_at = Some(now());
+    stats.last_cycle_secs = Some(duration.as_secs());
+    stats.query_failures += failures;
+    stats.logins = logins;
+    db.save_stats(&stats).await?;
+    Ok(())
+}
+
+/// Enrollment system queries of one check, to tell when none of them get through
+#[derive(Default)]
+struct QueryTally {
+    attempted: u64,
+    failed: u64,
+    last_error: Option<anyhow::Error>,
+    /// Milliseconds each query took, failed ones included
+    latencies: Vec<u64>,
+    /// Courses whose seats were read
+    checked: u64,
+    /// Of those, courses with free seats
+    available: u64,
+}
+
+impl QueryTally {
+    fn sent(&mut self, latency: Duration) {
+        self.attempted += 1;
+        self.latencies.push(latency.as_millis() as u64);
+    }
+
+    fn fail(&mut self, error: anyhow::Error) {
+        self.failed += 1;
+        self.last_error = Some(error);
+    }
+
+    fn merge(&mut self, other: QueryTally) {
+        self.attempted += other.attempted;
+        self.failed += other.failed;
+        self.latencies.extend(other.latencies);
+        if other.last_error.is_some() {
+            self.last_error = other.last_error;
+        }
+    }
+
+    /// Whether queries were sent and every one of them failed
+    fn all_failed(&self) -> bool {
+        self.attempted > 0 && self.failed == self.attempted
+    }
+}
+
+/// DM the owner when no query of a check got through, and again once queries work
+///
+/// Failing checks in a row are counted, so the owner is told only once.
+async fn report_crawler_health(http: &Http, config: &Config, tally: &QueryTally) {
+    let content = if tally.all_failed() {
+        if METRICS.failing_checks.fetch_add(1, Ordering::Relaxed) > 0 {
+            return;
+        }
+        let Some(error) = &tally.last_error else {
+            return;
+        };
+        let cause = FailureCause::of(error, &config.captcha_uris());
+        error!(
+            queries = tally.attempted,
+            %cause,
+            "every query of the check failed: {error:#}"
+        );
+        let mut detail = format!("{error:#}");
+        detail.truncate(detail.floor_char_boundary(OWNER_ERROR_LIMIT));
+        format!(
+            "⚠️ All {} enrollment system queries of the last check failed: {cause}.\nLast error: `{detail}`",
+            tally.attempted
+        )
+    } else if tally.attempted > 0 {
+        let failing_checks = METRICS.failing_checks.swap(0, Ordering::Relaxed);
+        if failing_checks == 0 {
+            return;
+        }
+        info!("queries work again");
+        format!("✅ Enrollment system queries work again after {failing_checks} failed checks.")
+    } else {
+        return;
+    };
+    alert_owner(http, config, &content).await;
+}
+
+/// DM the owner when queries pause because the enrollment system keeps failing, and when they
+/// resume
+async fn report_breaker(
+    http: &Http,
+    config: &Config,
+    crawler: &tokio::sync::Mutex<NtnuCrawlerManager>,
 ) {
-    let mut ntnu_crawler = NtnuCrawlerManager::new(config, 1);
-    let http_client = Arc::new(serenity::http::Http::new(&config.discord_token));
-    loop {
-        info!("Start scraping ntnu course site");
-        let lists = {
-            let bucket = db
-                .read()
-                .await
-                .bucket::<String, Msgpack<Vec<String>>>(Some("user_courses"))
-                .unwrap();
-            bucket
+    let events = crawler.lock().await.take_breaker_events();
+    for event in events {
+        let content = match event {
+            BreakerEvent::Opened { failures, backoff } => format!(
+                "⛔ {failures} enrollment system queries failed in a row, pausing queries for {}s.",
+                backoff.as_secs()
+            ),
+            BreakerEvent::Closed { paused } => format!(
+                "✅ Enrollment system queries resumed after a {}s pause.",
+                paused.as_secs()
+            ),
+        };
+        alert_owner(http, config, &content).await;
+    }
+}
+
+/// DM the owner right away about accounts the enrollment system locked or wants a new password
+/// for, with what to do about it
+async fn report_accounts(
+    http: &Http,
+    config: &Config,
+    crawler: &tokio::sync::Mutex<NtnuCrawlerManager>,
+) {
+    let (events, disabled) = {
+        let mut crawler = crawler.lock().await;
+        (crawler.take_account_events(), crawler.disabled())
+    };
+    for event in events {
+        let content = match event {
+            AccountEvent::Locked(account) => {
+                let consequence = if disabled {
+                    "No account is left to query with, so checks are disabled."
+                } else {
+                    "The other accounts carry on without it."
+                };
+                format!(
+                    "🔒 The enrollment system locked account {account}, the bot no longer logs in \
+                     with it. {consequence} Unlock it with the registry office or wait the lock \
+                     out, then restart the bot."
+                )
+            }
+            AccountEvent::CredentialAction(account) => format!(
+                "🔑 The enrollment system wants the password of account {account} changed and \
+                 skips checks until then. Log into {} as {account} in a browser, change the \
+                 password, put the new one in BOT_NTNU_PASSWORD or BOT_NTNU_ACCOUNTS and restart \
+                 the bot.",
+                config.ntnu_url
+            ),
+        };
+        alert_owner(http, config, &content).await;
+    }
+}
+
+/// Tell users with watches about the next enrollment phase once it is less than the notice
+/// period away
+async fn announce_phase(db: &dyn Repository, http: &Http, config: &Config) -> anyhow::Result<()> {
+    let now = now();
+    let Some(phase) = schedule::next_phase(&config.enrollment_phases.0, now) else {
+        return Ok(());
+    };
+    if phase.start > now + config.phase_notice
+        || db.meta(META_ANNOUNCED_PHASE).await? == Some(phase.start)
+    {
+        return Ok(());
+    }
+    db.set_meta(META_ANNOUNCED_PHASE, phase.start).await?;
+    let mut users = BTreeSet::new();
+    for course_id in db.watched_courses().await? {
+        let subscribers = db.subscribers(&course_id).await?;
+        users.extend(subscribers.into_iter().map(|(user_id, _)| user_id));
+    }
+    let departments = db.all_department_watches().await?;
+    users.extend(departments.into_iter().map(|(user_id, _)| user_id));
+    let blocked = db.blocked_users().await?;
+    info!(
+        phase = phase.name,
+        users = users.len(),
+        "announcing the next enrollment phase"
+    );
+    for user_id in users.into_iter().filter(|id| !blocked.contains(id)) {
+        let settings = user_settings(db, user_id).await;
+        let content = Msg::PhaseUpcoming {
+            name: &phase.name,
+            start: phase.start,
+            end: phase.end,
+        }
+        .render(settings.lang());
+        let fallback = config.dm_fallback_channel.map(ChannelId::new);
+        let delivery = notify_user(
+            http,
+            UserId::new(user_id),
+            &settings,
+            &content,
+            Vec::new(),
+            fallback,
+        )
+        .await;
+        track_delivery(db, user_id, delivery, config.unreachable_after).await;
+    }
+    Ok(())
+}
+
+/// Remove the watches of courses no longer offered and tell their users, once every
+/// `BOT_WATCH_SWEEP_INTERVAL`
+///
+/// The checker would otherwise query serial numbers nobody can enroll in forever. Courses whose
+/// lookup fails are kept for the next sweep.
+async fn sweep_watches(
+    db: &dyn Repository,
+    http: &Http,
+    config: &Config,
+    sources: &Sources,
+    open_courses: &OpenCourseCrawler,
+) -> anyhow::Result<()> {
+    if config.watch_sweep_interval == 0 {
+        return Ok(());
+    }
+    let last = db.meta(META_LAST_WATCH_SWEEP).await?.unwrap_or(0);
+    if now().saturating_sub(last) < config.watch_sweep_interval {
+        return Ok(());
+    }
+    db.set_meta(META_LAST_WATCH_SWEEP, now()).await?;
+    let courses = db.watched_courses().await?;
+    let mut listed = 0;
+    let mut dead = Vec::new();
+    let current = config.term(now());
+    for course_id in &courses {
+        let Some((source_name, source, id, term)) = sources.route(course_id) else {
+            continue;
+        };
+        // later semesters may not be published yet
+        if term.is_some_and(|term| term != current) {
+            continue;
+        }
+        let course = if source_name == "ntnu" {
+            open_courses.course(id, term).await
+        } else {
+            source.lock().await.fetch_info(id, term).await
+        };
+        match course {
+            Result::Ok(Some(_)) => listed += 1,
+            Result::Ok(None) => dead.push(course_id.as_str()),
+            Result::Err(e) => warn!("fail to look up watched course {course_id}: {e:#}"),
+        }
+    }
+    // nothing listed at all means the term's courses are not published yet
+    if listed == 0 {
+        if !dead.is_empty() {
+            warn!("no watched course is listed this term, keeping the watches");
+        }
+        return Ok(());
+    }
+    info!(
+        courses = courses.len(),
+        dead = dead.len(),
+        "swept the watchlists for courses no longer offered"
+    );
+    let mut removed: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    for course_id in dead {
+        for (user_id, _) in db.subscribers(course_id).await? {
+            removed
+                .entry(user_id)
+                .or_default()
+                .push(course_id.to_owned());
+        }
+    }
+    let blocked = db.blocked_users().await?;
+    for (user_id, course_ids) in removed {
+        if let Result::Err(e) = db.remove_watches(user_id, &course_ids).await {
+            warn!("fail to remove the dead watches of user {user_id}: {e:?}");
+            continue;
+        }
+        if blocked.contains(&user_id) {
+            continue;
+        }
+        let settings = user_settings(db, user_id).await;
+        let content = Msg::WatchesDropped {
+            course_ids: &course_ids,
+        }
+        .render(settings.lang());
+        let fallback = config.dm_fallback_channel.map(ChannelId::new);
+        let delivery = notify_user(
+            http,
+            UserId::new(user_id),
+            &settings,
+            &content,
+            Vec::new(),
+            fallback,
+        )
+        .await;
+        track_delivery(db, user_id, delivery, config.unreachable_after).await;
+    }
+    Ok(())
+}
+
+/// Archive every course watch once the semester enrolled in changes and tell their users
+///
+/// A fresh database only records the current semester.
+async fn roll_over_semester(
+    db: &dyn Repository,
+    http: &Http,
+    config: &Config,
+) -> anyhow::Result<()> {
+    let (year, term) = config.term(now());
+    let semester = u64::from(year * 10 + term);
+    let last = db.meta(META_SEMESTER).await?;
+    if last == Some(semester) {
+        return Ok(());
+    }
+    let Some(last) = last else {
+        db.set_meta(META_SEMESTER, semester).await?;
+        return Ok(());
+    };
+    let label = format!("{}-{}", last / 10, last % 10);
+    let archived = db
+        .archive_semester(&label, &format!("{year}-{term}"))
+        .await?;
+    db.set_meta(META_SEMESTER, semester).await?;
+    info!(
+        semester = label,
+        users = archived.len(),
+        "archived the watches of the previous semester"
+    );
+    let blocked = db.blocked_users().await?;
+    for (user_id, course_ids) in archived {
+        if blocked.contains(&user_id) {
+            continue;
+        }
+        let settings = user_settings(db, user_id).await;
+        let content = Msg::SemesterArchived {
+            semester: &label,
+            course_ids: &course_ids,
+        }
+        .render(settings.lang());
+        let fallback = config.dm_fallback_channel.map(ChannelId::new);
+        let delivery = notify_user(
+            http,
+            UserId::new(user_id),
+            &settings,
+            &content,
+            Vec::new(),
+            fallback,
+        )
+        .await;
+        track_delivery(db, user_id, delivery, config.unreachable_after).await;
+    }
+    Ok(())
+}
+
+/// Count a check skipped outside the enrollment phases as done, so health checks pass, and
+/// describe the pause
+async fn idle_cycle(db: &dyn Repository, config: &Config) -> String {
+    let now = now();
+    let saved = async {
+        let mut stats = db.stats().await?;
+        stats.last_cycle_at = Some(now);
+        db.save_stats(&stats).await
+    };
+    if let Result::Err(e) = saved.await {
+        warn!("fail to record the skipped check: {e:?}");
+    }
+    match schedule::next_phase(&config.enrollment_phases.0, now) {
+        Some(phase) => format!(
+            "Outside enrollment phases, {} opens in {}h",
+            phase.name,
+            (phase.start - now).div_ceil(3600)
+        ),
+        None => "Outside enrollment phases, none scheduled".to_owned(),
+    }
+}
+
+/// Reset the crawler after a check ran past its deadline and tell the owner
+///
+/// The check was dropped by then, which releases the crawler unless a command holds it.
+async fn abort_cycle(
+    http: &Http,
+    config: &Config,
+    crawler: &tokio::sync::Mutex<NtnuCrawlerManager>,
+    cycle: u64,
+) {
+    metrics::inc(&METRICS.aborted_cycles);
+    let deadline = config.cycle_deadline;
+    error!(cycle, "check did not finish within {deadline}s, aborted");
+    let reset = match timeout(Duration::from_secs(deadline), crawler.lock()).await {
+        Result::Ok(mut crawler) => {
+            crawler.reset(config);
+            "the crawler was reset"
+        }
+        Result::Err(_) => {
+            error!("crawler still busy after the aborted check, not reset");
+            "the crawler is still busy and could not be reset"
+        }
+    };
+    alert_owner(
+        http,
+        config,
+        &format!("⏱️ Check #{cycle} did not finish within {deadline}s and was aborted, {reset}."),
+    )
+    .await;
+}
+
+async fn alert_owner(http: &Http, config: &Config, content: &str) {
+    let Some(owner) = config.owner_id else {
+        return;
+    };
+    let delivery = notify_user(
+        http,
+        UserId::new(owner),
+        &UserSettings::default(),
+        content,
+        Vec::new(),
+        None,
+    )
+    .await;
+    if !delivery.delivered {
+        warn!("fail to alert the owner about the crawler");
+    }
+}
+
+/// What one check found for a single user
+#[derive(Default)]
+struct UserCheck<'a> {
+    /// Courses in availability mode that reached their threshold
+    available: Vec<&'a str>,
+    /// Courses in availability mode that dropped below their threshold since an alert
+    filled: Vec<&'a str>,
+    changes: Vec<SeatChange<'a>>,
+    seen: HashMap<&'a str, Option<SeatCount>>,
+}
+
+/// Alert a user about what one check found for their courses
+///
+/// Returns the courses they were told opened up.
+async fn notify_checked_user<'a>(
+    db: &dyn Repository,
+    config: &Config,
+    http: &Http,
+    user_id: u64,
+    check: UserCheck<'a>,
+    metas: &HashMap<&str, Option<CourseMeta>>,
+) -> anyhow::Result<Vec<&'a str>> {
+    let UserCheck {
+        available: success_list,
+        filled,
+        changes,
+        seen,
+    } = check;
+    // most users have nothing to hear about, record what was seen without touching Discord
+    if success_list.is_empty() && filled.is_empty() && changes.is_empty() {
+        db.record_check(user_id, &[], &seen, now()).await?;
+        return Ok(Vec::new());
+    }
+    let user_id = UserId::new(user_id);
+    let settings = db.settings(user_id.get()).await?;
+    let filled = if settings.fill_alerts {
+        // re-armed, the next opening alerts at once rather than after the cooldown
+        for course_id in &filled {
+            db.clear_notified(user_id.get(), course_id).await?;
+        }
+        filled
+    } else {
+        Vec::new()
+    };
+    // available courses stay unnotified and alert again once quiet hours end,
+    // seat changes and fill-ups are only reported as they happen
+    let (success_list, filled, changes) = if settings.is_quiet(now()) {
+        debug!("quiet hours, holding alerts back");
+        (Vec::new(), Vec::new(), Vec::new())
+    } else {
+        (success_list, filled, changes)
+    };
+    // the user ran `/forget_me` while their courses were being checked
+    let found = db
+        .record_check(user_id.get(), &success_list, &seen, now())
+        .await?;
+    if !found {
+        return Ok(Vec::new());
+    }
+
+    // notify user
+    let mut records = Vec::new();
+    if !changes.is_empty() {
+        let content = Msg::SeatsChanged { changes: &changes }.render(settings.lang());
+        let fallback = config.dm_fallback_channel.map(ChannelId::new);
+        let delivery = notify_user(http, user_id, &settings, &content, Vec::new(), fallback).await;
+        if !delivery.delivered {
+            warn!("fail to notify user seat changes (user: {user_id})")
+        }
+        track_delivery(db, user_id.get(), delivery, config.unreachable_after).await;
+        let at = now();
+        records.extend(changes.iter().map(|change| NotificationRecord {
+            course_id: change.course_id.to_owned(),
+            kind: NotificationKind::SeatsChanged,
+            seats: Some(change.after),
+            delivered: delivery.delivered,
+            at,
+        }));
+    }
+    if !filled.is_empty() {
+        let labels = filled
+            .iter()
+            .map(|id| course_label(id, metas.get(id).and_then(Option::as_ref)))
+            .collect::<Vec<_>>();
+        let content = Msg::CourseFilledUp { courses: &labels }.render(settings.lang());
+        let fallback = config.dm_fallback_channel.map(ChannelId::new);
+        let delivery = notify_user(http, user_id, &settings, &content, Vec::new(), fallback).await;
+        if !delivery.delivered {
+            warn!("fail to notify user courses filled up (user: {user_id})")
+        }
+        track_delivery(db, user_id.get(), delivery, config.unreachable_after).await;
+        let at = now();
+        records.extend(filled.iter().map(|id| NotificationRecord {
+            course_id: id.to_string(),
+            kind: NotificationKind::FilledUp,
+            seats: seen.get(id).copied().flatten(),
+            delivered: delivery.delivered,
+            at,
+        }));
+    }
+    if !success_list.is_empty() {
+        let lang = settings.lang();
+        let batches = match settings.digest {
+            DigestMode::Cycle => vec![success_list.clone()],
+            DigestMode::PerCourse => success_list.iter().map(|id| vec![*id]).collect(),
+        };
+        for batch in batches {
+            let labels = batch
+                .iter()
+                .map(|id| course_label(id, metas.get(id).and_then(Option::as_ref)))
+                .collect::<Vec<_>>();
+            let content = Msg::CourseAvailable {
+                courses: &labels,
+                cooldown_minutes: config.notify_cooldown / 60,
+            }
+            .render(lang);
+            let buttons = readd_buttons(&batch, lang);
+            let fallback = config.dm_fallback_channel.map(ChannelId::new);
+            let delivery = notify_user(http, user_id, &settings, &content, buttons, fallback).await;
+            if !delivery.delivered {
+                warn!("fail to notify user course available (user: {user_id}, sucess_list: {batch:?})")
+            }
+            track_delivery(db, user_id.get(), delivery, config.unreachable_after).await;
+            let at = now();
+            records.extend(batch.iter().map(|id| NotificationRecord {
+                course_id: id.to_string(),
+                kind: NotificationKind::Available,
+                seats: seen.get(id).copied().flatten(),
+                delivered: delivery.delivered,
+                at,
+            }));
+        }
+        if settings.daily_summary {
+            let at = now();
+            let events = success_list
                 .iter()
-                .filter(Result::is_ok)
-                .map(Result::unwrap)
-                .map(|m| {
-                    (
-                        m.key::<String>().unwrap(),
-                        m.value::<Msgpack<Vec<String>>>().unwrap().0,
-                    )
+                .map(|id| DigestEvent {
+                    course_id: id.to_string(),
+                    at,
                 })
-                .collect::<Vec<_>>()
-        };
-        for (user_id, list) in lists {
-            let user_id = UserId::new(user_id.parse().unwrap());
-            let private_channel = user_id
-                .create_dm_channel(http_client.clone())
-                .await
-                .unwrap();
-            let typeing_stopper = private_channel.start_typing(&http_client);
-            let mut success_list: Vec<&str> = Vec::new();
-            for ref course_id in &list {
-                match ntnu_crawler.query(&course_id).await {
-                    Result::Ok(q) => {
-                        if q {
-                            success_list.push(course_id);
+                .collect::<Vec<_>>();
+            db.push_digest_events(user_id.get(), &events).await?;
+        }
+    }
+    db.log_notifications(user_id.get(), &records).await?;
+    Ok(success_list)
+}
+
+/// Query every watched course and department once and send the resulting alerts
+async fn check_cycle(
+    db: &dyn Repository,
+    config: &Config,
+    crawler: &tokio::sync::Mutex<NtnuCrawlerManager>,
+    sources: &Sources,
+    open_courses: &OpenCourseCrawler,
+    http: &Http,
+    shutdown: &watch::Receiver<bool>,
+) -> QueryTally {
+    info!("Start scraping ntnu course site");
+    let cycle_start = Instant::now();
+    let queries_before = METRICS.queries.load(Ordering::Relaxed);
+    let mut tally = QueryTally::default();
+    let loaded = async {
+        anyhow::Ok((
+            db.guild_settings().await?,
+            db.blocked_users().await?,
+            db.watched_courses().await?,
+            db.all_guild_watchlists().await?,
+        ))
+    };
+    // nothing can be checked without them, the next check tries again
+    let (guilds, mut skipped, mut courses, guild_watchlists) = match loaded.await {
+        Result::Ok(loaded) => loaded,
+        Result::Err(e) => {
+            error!("fail to load the watches: {e:?}");
+            return tally;
+        }
+    };
+    let guilds = guilds
+        .into_iter()
+        .map(|(guild_id, guild)| (GuildId::new(guild_id), guild))
+        .collect::<Vec<_>>();
+    courses.extend(
+        guild_watchlists
+            .iter()
+            .flat_map(|(_, lists)| lists)
+            .flat_map(|list| list.courses.iter().cloned()),
+    );
+    courses.sort();
+    courses.dedup();
+    skipped.extend(purge_unreachable(db, config.unreachable_purge_days * 86400).await);
+    let mut guild_events: HashMap<GuildId, BTreeSet<String>> = HashMap::new();
+    // query every course once, however many users watch it
+    let mut checks: BTreeMap<u64, UserCheck> = BTreeMap::new();
+    let mut metas: HashMap<&str, Option<CourseMeta>> = HashMap::new();
+    // seats of every course checked, for the guilds' shared watchlists
+    let mut seen: HashMap<&str, Option<SeatCount>> = HashMap::new();
+    // set once the enrollment system asks for a new password, which no query gets past
+    let mut credential_action = false;
+    // failed courses go to the back with the time they are tried again, after the others
+    let mut queue = courses
+        .iter()
+        .map(|course_id| (course_id.as_str(), 0, None))
+        .collect::<VecDeque<(_, u32, Option<Instant>)>>();
+    while let Some((course_id, attempt, due)) = queue.pop_front() {
+        if let Some(due) = due {
+            let mut shutdown = shutdown.clone();
+            tokio::select! {
+                _ = sleep_until(due.into()) => (),
+                _ = shutdown.changed() => (),
+            }
+        }
+        // the courses checked so far still get their alerts, their new seats are already stored
+        if *shutdown.borrow() {
+            info!("shutting down, skipping the remaining courses");
+            break;
+        }
+        if credential_action {
+            info!("the enrollment system wants a new password, skipping the remaining courses");
+            break;
+        }
+        if let Some(left) = crawler.lock().await.paused() {
+            info!(
+                "queries paused for another {}s, skipping the remaining courses",
+                left.as_secs()
+            );
+            break;
+        }
+        let checked = async {
+            let Some((source_name, source, id, term)) = sources.route(course_id) else {
+                warn!("course {course_id} is in a school this deployment does not query");
+                tally.fail(anyhow::anyhow!("no source for course {course_id}"));
+                return Ok(());
+            };
+            // the enrollment system only serves the term being enrolled, later ones wait for it
+            if source_name == "ntnu" && term.is_some_and(|term| term != config.term(now())) {
+                debug!("waiting for its semester to be enrolled");
+                return Ok(());
+            }
+            // lock per query so commands can use the source in between
+            let (result, latency) = {
+                let mut source = source.lock().await;
+                // time the query only, not the wait for the lock
+                let start = Instant::now();
+                (source.query(id, term).await, start.elapsed())
+            };
+            tally.sent(latency);
+            let status = match result {
+                Result::Ok(status) => status,
+                Result::Err(e) => {
+                    warn!(attempt, "fail to check course {course_id}: {e:?}");
+                    credential_action = FailureCause::of(&e, &[]) == FailureCause::CredentialAction;
+                    if attempt < config.course_retries {
+                        let backoff =
+                            Duration::from_secs(config.course_retry_backoff << attempt.min(10));
+                        queue.push_back((course_id, attempt + 1, Some(Instant::now() + backoff)));
+                    }
+                    tally.fail(e);
+                    return Ok(());
+                }
+            };
+            match status {
+                CourseStatus::NotFound => debug!("course is not offered this term"),
+                // alerts still go out, labelled with who may enroll
+                CourseStatus::Restricted(_) => debug!("seats left for restricted students only"),
+                CourseStatus::Available(_) | CourseStatus::Full(_) => (),
+            }
+            let seats = status.seats();
+            tally.checked += 1;
+            if seats.is_some_and(|seats| seats.available() > 0) {
+                tally.available += 1;
+            }
+            let snapshot = SeatSnapshot {
+                seats,
+                checked_at: now(),
+            };
+            db.record_seats(course_id, &snapshot).await?;
+            seen.insert(course_id, seats);
+            // unlisted courses cannot be looked up either
+            let meta = if seats.is_some() {
+                let ttl = config.course_meta_ttl;
+                if source_name == "ntnu" {
+                    // the public site answers without spending a login
+                    course_meta(db, course_id, ttl, open_courses.course(id, term)).await
+                } else {
+                    let lookup = async { source.lock().await.fetch_info(id, term).await };
+                    course_meta(db, course_id, ttl, lookup).await
+                }
+            } else {
+                db.course_meta(course_id).await?
+            };
+            metas.insert(course_id, meta);
+            for (user_id, entry) in db.subscribers(course_id).await? {
+                let check = checks.entry(user_id).or_default();
+                check.seen.insert(course_id, seats);
+                match entry.mode {
+                    WatchMode::Availability => {
+                        let enough =
+                            seats.is_some_and(|seats| seats.available() >= entry.min_seats as i32);
+                        let was_enough = entry
+                            .last_seen
+                            .is_some_and(|seats| seats.available() >= entry.min_seats as i32);
+                        if !enough && was_enough && entry.notified_at.is_some() {
+                            debug!(user_id, "filled up again since its alert");
+                            check.filled.push(course_id);
+                        } else if !enough {
+                            debug!(user_id, min_seats = entry.min_seats, "not enough seats");
+                        } else if entry.in_cooldown(config.notify_cooldown, now()) {
+                            debug!(user_id, "alert held back by cooldown");
+                        } else {
+                            check.available.push(course_id);
                         }
                     }
-                    Result::Err(e) => {
-                        warn!("fail to check course {course_id}: {e:?}");
+                    WatchMode::Changes => {
+                        // the first observation only sets the baseline
+                        if let (Some(before), Some(after)) = (entry.last_seen, seats) {
+                            if before != after {
+                                check.changes.push(SeatChange {
+                                    course_id,
+                                    before,
+                                    after,
+                                });
+                            }
+                        }
                     }
                 }
             }
+            Ok(())
+        }
+        .instrument(info_span!("course", course_id))
+        .await;
+        // the course is checked again next time, its subscribers still hear about the rest
+        if let Result::Err(e) = checked {
+            warn!("fail to process course {course_id}: {e:?}");
+        }
+    }
 
-            // write back
-            {
-                let bucket = db
-                    .write()
-                    .await
-                    .bucket::<String, Msgpack<Vec<String>>>(Some("user_courses"))
-                    .unwrap();
-                let mut current = bucket
-                    .get(&user_id.to_string())
-                    .unwrap()
-                    .map(|v| v.0)
-                    .unwrap_or(Vec::new());
-                current.retain(|id| !success_list.contains(&id.as_str()));
-                bucket.set(&user_id.to_string(), &Msgpack(current)).unwrap();
-            }
-
-            // notify user
-            typeing_stopper.stop();
-            if success_list.len() > 0 {
-                let builder = CreateMessage::new().content(format!(
-                "Course {} available detected! Go get your course.\n (Courses listed above are remove from list, added again if you did not get the course)",
-                success_list.join(" & ")
-            ));
-                if let Err(e) = user_id.direct_message(http_client.clone(), builder).await {
-                    warn!("fail to notify user course available (user: {user_id}, sucess_list: {success_list:?}): {:?}", e)
+    for (user_id, check) in checks {
+        let alerted = notify_checked_user(db, config, http, user_id, check, &metas)
+            .instrument(info_span!("user", user_id))
+            .await;
+        let alerted = match alerted {
+            Result::Ok(alerted) if !alerted.is_empty() => alerted,
+            Result::Ok(_) => continue,
+            Result::Err(e) => {
+                warn!("fail to alert user {user_id}: {e:?}");
+                continue;
+            }
+        };
+        for (guild_id, _) in &guilds {
+            // look the member up only for courses the guild is not told about yet
+            let known = guild_events.get(guild_id);
+            if known.is_some_and(|known| alerted.iter().all(|id| known.contains(*id))) {
+                continue;
+            }
+            if guild_id.member(http, UserId::new(user_id)).await.is_ok() {
+                guild_events
+                    .entry(*guild_id)
+                    .or_default()
+                    .extend(alerted.iter().map(|id| id.to_string()));
+            }
+        }
+    }
+    if !*shutdown.borrow() && !credential_action && crawler.lock().await.paused().is_none() {
+        tally.merge(check_departments(db, crawler, http, &skipped, config).await);
+    }
+    for (guild_id, guild) in &guilds {
+        let Some(course_ids) = guild_events.get(guild_id) else {
+            continue;
+        };
+        let course_ids = course_ids.iter().map(String::as_str).collect::<Vec<_>>();
+        let content = Msg::GuildCourseAvailable {
+            course_ids: &course_ids,
+        }
+        .render(guild.language);
+        notify_guild(http, guild, &content).await;
+    }
+    notify_guild_watchlists(db, http, &guild_watchlists, &seen, &metas).await;
+    let (logins, sessions) = {
+        let mut crawler = crawler.lock().await;
+        (crawler.logins(), crawler.take_sessions())
+    };
+    // the next start picks the sessions up instead of solving captchas again
+    for (account, cookies) in sessions {
+        if let Err(e) = db.save_crawler_session(&account, &cookies).await {
+            warn!("fail to store the enrollment system session of {account}: {e}");
+        }
+    }
+    let queries = METRICS.queries.load(Ordering::Relaxed) - queries_before;
+    if let Result::Err(e) = record_cycle(db, cycle_start.elapsed(), queries, &tally, logins).await {
+        warn!("fail to record the check: {e:?}");
+    }
+    info!("Done scraping ntnu course site");
+    tally
+}
+
+/// Post the courses of the guilds' shared watchlists that opened up since the previous check
+async fn notify_guild_watchlists(
+    db: &dyn Repository,
+    http: &Http,
+    guild_watchlists: &[(u64, Vec<GuildWatchlist>)],
+    seen: &HashMap<&str, Option<SeatCount>>,
+    metas: &HashMap<&str, Option<CourseMeta>>,
+) {
+    for (guild_id, lists) in guild_watchlists {
+        let mut open = HashMap::new();
+        for list in lists {
+            // courses left unchecked this time keep their previous state
+            let open_now = list
+                .courses
+                .iter()
+                .filter(|id| match seen.get(id.as_str()) {
+                    Some(seats) => seats.is_some_and(|seats| seats.available() > 0),
+                    None => list.open.contains(id),
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            let opened = open_now
+                .iter()
+                .filter(|id| !list.open.contains(id))
+                .map(|id| course_label(id, metas.get(id.as_str()).and_then(Option::as_ref)))
+                .collect::<Vec<_>>();
+            if !opened.is_empty() {
+                let content = Msg::GuildWatchlistAvailable {
+                    name: &list.name,
+                    courses: &opened,
+                }
+                .render(list.language);
+                notify_channel(http, ChannelId::new(list.channel), None, &content).await;
+            }
+            if open_now != list.open {
+                open.insert(list.name.clone(), open_now);
+            }
+        }
+        if open.is_empty() {
+            continue;
+        }
+        if let Result::Err(e) = db.record_guild_watchlist_check(*guild_id, &open).await {
+            warn!("fail to record the shared watchlists of guild {guild_id}: {e:?}");
+        }
+    }
+}
+
+/// Keep the enrollment system session alive every `interval` seconds, forever
+async fn keep_session_alive(crawler: &tokio::sync::Mutex<NtnuCrawlerManager>, interval: u64) {
+    if interval == 0 {
+        return std::future::pending().await;
+    }
+    loop {
+        sleep(Duration::from_secs(interval)).await;
+        if let Result::Err(e) = crawler.lock().await.keep_alive().await {
+            debug!("keep-alive failed, the next check logs in again: {e:#}");
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn periodic_checker(
+    db: Arc<dyn Repository>,
+    shared_config: &SharedConfig,
+    crawler: Arc<tokio::sync::Mutex<NtnuCrawlerManager>>,
+    sources: Arc<Sources>,
+    open_courses: Arc<OpenCourseCrawler>,
+    mut update_receiver: tokio::sync::mpsc::Receiver<()>,
+    progress: watch::Sender<CheckerState>,
+    mut leader: watch::Receiver<bool>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let http_client = Arc::new(serenity::http::Http::new(
+        &shared_config.get().discord_token,
+    ));
+    let mut ready = false;
+    let mut started = false;
+    for cycle in 1u64.. {
+        if !*leader.borrow() {
+            let status = "Standing by, another instance runs the checker";
+            if ready {
+                systemd::status(status);
+            } else {
+                ready = true;
+                systemd::ready(status);
+            }
+            tokio::select! {
+                // fails once the lease task stops at shutdown, which disables this branch
+                Result::Ok(_) = leader.wait_for(|leader| *leader) => (),
+                _ = shutdown.changed() => break,
+                _ = systemd::feed_watchdog() => (),
+            }
+        }
+        progress.send_replace(CheckerState {
+            cycle,
+            running: true,
+            outcome: None,
+        });
+        // the stats are shared with the other replicas, so only the one checking resets them
+        if !started {
+            started = true;
+            let stats = CheckerStats {
+                started_at: now(),
+                ..Default::default()
+            };
+            if let Result::Err(e) = db.save_stats(&stats).await {
+                warn!("fail to reset the checker stats: {e:?}");
+            }
+        }
+        // settings reloaded meanwhile apply from the next check on
+        let snapshot = shared_config.get();
+        let config = snapshot.as_ref();
+        if let Result::Err(e) = send_daily_summaries(db.as_ref(), &http_client, config).await {
+            error!("fail to send the daily summaries: {e:?}");
+        }
+        if let Result::Err(e) = roll_over_semester(db.as_ref(), &http_client, config).await {
+            error!("fail to roll the watches over to the new semester: {e:?}");
+        }
+        let swept = sweep_watches(db.as_ref(), &http_client, config, &sources, &open_courses);
+        if let Result::Err(e) = swept.await {
+            error!("fail to sweep the watchlists: {e:?}");
+        }
+        if let Result::Err(e) = announce_phase(db.as_ref(), &http_client, config).await {
+            error!("fail to announce the next enrollment phase: {e:?}");
+        }
+        let in_phase = schedule::in_phase(&config.enrollment_phases.0, now());
+        if in_phase {
+            let deadline = Duration::from_secs(config.cycle_deadline);
+            let checked = timeout(
+                deadline,
+                check_cycle(
+                    db.as_ref(),
+                    config,
+                    &crawler,
+                    &sources,
+                    &open_courses,
+                    &http_client,
+                    &shutdown,
+                )
+                .instrument(info_span!("cycle", cycle)),
+            )
+            .await;
+            systemd::watchdog();
+            let outcome = checked.as_ref().ok().map(|tally| CycleOutcome {
+                checked: tally.checked,
+                available: tally.available,
+            });
+            progress.send_replace(CheckerState {
+                cycle,
+                running: false,
+                outcome,
+            });
+            match checked {
+                Result::Ok(tally) => {
+                    report_crawler_health(&http_client, config, &tally).await;
+                    let status = format!(
+                        "Check #{cycle} done, {} of {} queries failed",
+                        tally.failed, tally.attempted
+                    );
+                    // up once the enrollment system answers, or nothing needs to be asked
+                    if !ready && !tally.all_failed() {
+                        ready = true;
+                        systemd::ready(&status);
+                    } else {
+                        systemd::status(&status);
+                    }
                 }
+                Result::Err(_) => {
+                    abort_cycle(&http_client, config, &crawler, cycle).await;
+                    systemd::status(&format!("Check #{cycle} aborted"));
+                }
+            }
+            report_breaker(&http_client, config, &crawler).await;
+            report_accounts(&http_client, config, &crawler).await;
+        } else {
+            progress.send_replace(CheckerState {
+                cycle,
+                running: false,
+                outcome: None,
+            });
+            let status = idle_cycle(db.as_ref(), config).await;
+            if ready {
+                systemd::status(&status);
+            } else {
+                ready = true;
+                systemd::ready(&status);
             }
         }
-        info!("Done scraping ntnu course site");
+        if *shutdown.borrow() {
+            break;
+        }
         tokio::select! {
-            _ = sleep(Duration::from_secs(180)) => (),
+            _ = sleep(Duration::from_secs(config.check_interval)) => (),
             _ = update_receiver.recv() => (),
+            // the session may as well time out between phases
+            _ = keep_session_alive(&crawler, if in_phase { config.ntnu_keepalive } else { 0 }) => (),
+            _ = shutdown.changed() => break,
+            _ = systemd::feed_watchdog() => (),
         };
     }
+    info!("Checker stopped");
+}
+
+/// Every school courses can be watched in, NTNU's enrollment system being `ntnu`
+fn course_sources(config: &Config, ntnu: Arc<tokio::sync::Mutex<NtnuCrawlerManager>>) -> Sources {
+    Sources::new(&config.course_source).with("ntnu", ntnu).with(
+        "ntu",
+        Arc::new(tokio::sync::Mutex::new(NtuSource::new(config))),
+    )
+}
+
+/// Query courses the way the checker does and print what the enrollment system answers
+///
+/// Nothing is stored or sent, so this is safe to run next to the bot.
+async fn check_once(config: &Config, course_id: Option<String>) -> anyhow::Result<()> {
+    let crawler = Arc::new(tokio::sync::Mutex::new(NtnuCrawlerManager::new(config)));
+    let sources = course_sources(config, crawler);
+    let course_ids = match course_id {
+        Some(course_id) => {
+            match sources.route(&course_id) {
+                Some(("ntnu", _, id, _)) => validate_serial_no(id, &config.serial_no_range())?,
+                Some(_) => (),
+                None => anyhow::bail!("no source for course {course_id}"),
+            }
+            vec![course_id]
+        }
+        None => open_storage(config).await?.watched_courses().await?,
+    };
+    let open_courses = OpenCourseCrawler::new(config);
+    let mut ready = HashSet::new();
+    let mut failed = 0;
+    for course_id in &course_ids {
+        let Some((source_name, source, id, term)) = sources.route(course_id) else {
+            failed += 1;
+            println!("{course_id}: no source for this school");
+            continue;
+        };
+        let mut source = source.lock().await;
+        // log in before timing, so the first query takes as long as the others
+        if ready.insert(source_name) {
+            if let Result::Err(e) = source.init().await {
+                warn!("fail to log into {source_name}: {e:#}");
+            }
+        }
+        let start = Instant::now();
+        let seats = match source.query(id, term).await {
+            Result::Ok(CourseStatus::NotFound) => "not listed".to_owned(),
+            Result::Ok(CourseStatus::Full(seats)) => {
+                format!("full ({} of {} enrolled)", seats.enrolled, seats.quota)
+            }
+            Result::Ok(
+                status @ (CourseStatus::Available(seats) | CourseStatus::Restricted(seats)),
+            ) => {
+                let restricted = if matches!(status, CourseStatus::Restricted(_)) {
+                    ", restricted"
+                } else {
+                    ""
+                };
+                format!(
+                    "{} of {} seats free ({} enrolled{restricted})",
+                    seats.available(),
+                    seats.quota,
+                    seats.enrolled
+                )
+            }
+            Result::Err(e) => {
+                failed += 1;
+                format!("query failed: {e:#}")
+            }
+        };
+        let elapsed = start.elapsed();
+        let course = if source_name == "ntnu" {
+            open_courses.course(id, term).await
+        } else {
+            source.fetch_info(id, term).await
+        };
+        let name = match course {
+            Result::Ok(course) => course.map_or("(not listed)".to_owned(), |c| {
+                format!("{} {} ({})", c.course_code, c.name, c.teacher)
+            }),
+            Result::Err(e) => format!("(course lookup failed: {e:#})"),
+        };
+        println!("{course_id} {name}: {seats} in {}ms", elapsed.as_millis());
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} queries failed", course_ids.len());
+    }
+    Ok(())
+}
+
+/// Open the backend named by the storage URL, SQLite unless it is a Redis URL
+async fn open_storage(config: &Config) -> anyhow::Result<Arc<dyn Repository>> {
+    let url = config.storage_url.as_str();
+    let cipher = config
+        .storage_key
+        .as_deref()
+        .filter(|key| !key.trim().is_empty())
+        .map(db::Cipher::from_base64)
+        .transpose()?;
+    if url.starts_with("redis://") || url.starts_with("rediss://") {
+        #[cfg(feature = "redis")]
+        {
+            return Ok(Arc::new(
+                db::RedisRepository::connect(url, "course-bot", cipher).await?,
+            ));
+        }
+        #[cfg(not(feature = "redis"))]
+        anyhow::bail!("redis storage requires building with the `redis` feature");
+    }
+    let db = Db::connect(url, cipher).await?;
+    let quarantined = db::import_kv(&db, &config.db_path).await?;
+    if quarantined > 0 {
+        error!("{quarantined} records of the kv database could not be imported");
+        let http = Http::new(&config.discord_token);
+        let content = format!(
+            "⚠️ {quarantined} records of the old kv database at `{}` could not be read and were \
+             moved to its `corrupt` bucket instead of being imported.",
+            config.db_path
+        );
+        alert_owner(&http, config, &content).await;
+    }
+    if let Some(dir) = &config.snapshot_dir {
+        tokio::spawn(snapshot::run(
+            db.clone(),
+            dir.into(),
+            Duration::from_secs(config.snapshot_interval),
+            config.snapshot_keep,
+        ));
+    }
+    Ok(Arc::new(db))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = cli::parse(std::env::args().skip(1))?;
     dotenv::dotenv().ok();
-    env_logger::init();
-    let config = Config::init_from_env()?;
-    let db_config = kv::Config::new(config.db_path.as_str()).use_compression(true);
-    let db = Arc::new(tokio::sync::RwLock::from(Store::new(db_config).unwrap()));
-    let (update_sender, mut update_receiver) = tokio::sync::mpsc::channel::<()>(1);
-    let mut bot = crate::bot::Bot::new(&config, db.clone(), update_sender);
-    let mut signal_terminate = signal(SignalKind::terminate()).unwrap();
-    let mut signal_interrupt = signal(SignalKind::interrupt()).unwrap();
-    tokio::select! {
-        _ = periodic_checker(db.clone(), &config, update_receiver) => Ok(()),
+    let config = Config::load(args.config.as_deref())?;
+    // kept alive until exit so queued reports are flushed
+    let sentry_guard = config.sentry_dsn.as_deref().map(|dsn| {
+        sentry::init((
+            dsn,
+            sentry::ClientOptions {
+                release: sentry::release_name!(),
+                ..Default::default()
+            },
+        ))
+    });
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(std::io::stderr)
+                .with_filter(EnvFilter::from_default_env()),
+        )
+        // errors become reports, warnings and info their breadcrumbs
+        .with(
+            sentry_guard
+                .is_some()
+                .then(|| sentry::integrations::tracing::layer().with_filter(LevelFilter::INFO)),
+        )
+        .init();
+    if let cli::Mode::CheckOnce(course_id) = args.mode {
+        return check_once(&config, course_id).await;
+    }
+    let db = open_storage(&config).await?;
+    if let Some(port) = config.http_port {
+        let server = http::Server::new(&config, db.clone());
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(port, server).await {
+                error!("HTTP server stopped: {e:?}");
+            }
+        });
+    }
+    let (update_sender, update_receiver) = tokio::sync::mpsc::channel::<()>(1);
+    let (progress_sender, progress_receiver) = watch::channel(CheckerState::default());
+    #[cfg(feature = "grpc")]
+    if let Some(port) = config.grpc_port {
+        let Some(token) = config.grpc_token.clone() else {
+            anyhow::bail!("the gRPC admin service needs BOT_GRPC_TOKEN");
+        };
+        // an empty token would let `Bearer ` through with no secret at all
+        if token.trim().is_empty() {
+            anyhow::bail!("BOT_GRPC_TOKEN must not be empty");
+        }
+        let service = grpc::AdminService::new(db.clone(), update_sender.clone());
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(port, token, service).await {
+                error!("gRPC admin service stopped: {e:?}");
+            }
+        });
+    }
+    #[cfg(not(feature = "grpc"))]
+    if config.grpc_port.is_some() {
+        anyhow::bail!("BOT_GRPC_PORT requires building with the `grpc` feature");
+    }
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let (leader_sender, leader_receiver) = watch::channel(config.leader_lease.is_none());
+    let leader_task = config.leader_lease.map(|ttl| {
+        tokio::spawn(leader::run(
+            db.clone(),
+            leader::instance_id(),
+            ttl,
+            leader_sender,
+            shutdown_receiver.clone(),
+        ))
+    });
+    let mut crawler = NtnuCrawlerManager::new(&config);
+    let accounts = crawler.accounts().map(str::to_owned).collect::<Vec<_>>();
+    for account in accounts {
+        if let Some(cookies) = db.crawler_session(&account).await? {
+            match crawler.restore_session(&account, &cookies) {
+                Result::Ok(()) => info!("Continuing the stored session of {account}"),
+                Result::Err(e) => warn!("fail to restore the session of {account}: {e}"),
+            }
+        }
+    }
+    let crawler = Arc::new(tokio::sync::Mutex::new(crawler));
+    let sources = Arc::new(course_sources(&config, crawler.clone()));
+    let open_courses = Arc::new(OpenCourseCrawler::new(&config));
+    let config = Arc::new(SharedConfig::new(config, args.config));
+    tokio::spawn(reload::on_hangup(config.clone(), crawler.clone()));
+    let mut bot = crate::bot::Bot::new(
+        config.clone(),
+        db.clone(),
+        update_sender,
+        progress_receiver,
+        crawler.clone(),
+        sources.clone(),
+        open_courses.clone(),
+    );
+    let mut client = bot.client().await?;
+    let shard_manager = client.shard_manager.clone();
+    // runs until told to shut down
+    let mut checker = std::pin::pin!(periodic_checker(
+        db.clone(),
+        &config,
+        crawler,
+        sources,
+        open_courses,
+        update_receiver,
+        progress_sender,
+        leader_receiver,
+        shutdown_receiver
+    ));
+    let result = tokio::select! {
+        _ = &mut checker => return Ok(()),
         result = async {
-            match bot.client().await {
-                Result::Ok(mut client) => loop {
-                    match client.start().await {
-                        Result::Ok(_) => break Ok(()),
-                        Result::Err(e) => {
-                            error!("bot encounter merely fatal error: {e}");
-                        }
+            loop {
+                match client.start().await {
+                    Result::Ok(_) => break Ok(()),
+                    Result::Err(e) => {
+                        error!("bot encounter merely fatal error: {e}");
                     }
-                },
-                Result::Err(e) => Result::Err(e),
+                }
             }
         } => result,
-        _ = signal_terminate.recv() => {
-            info!("Start gracefully shutdown");
-            Ok(())
-        },
-        _ = signal_interrupt.recv() => {
-            info!("Start gracefully shutdown");
-            Ok(())
-        }
+        _ = shutdown::requested() => Ok(()),
+    };
+
+    info!("Start gracefully shutdown");
+    systemd::stopping();
+    // stop taking commands first, the ones already running finish their writes before the
+    // storage closes
+    shard_manager.shutdown_all().await;
+    // the running check stops after its current course and still sends the alerts it found
+    shutdown_sender.send_replace(true);
+    if timeout(SHUTDOWN_GRACE, checker).await.is_err() {
+        warn!("checker did not stop within {SHUTDOWN_GRACE:?}, dropping the rest of the check");
+    }
+    // hand the lease over before the storage closes
+    if let Some(task) = leader_task {
+        let _ = task.await;
     }
+    db.close().await;
+    info!("Shut down");
+    result
 }
@@ -0,0 +1,430 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::db::CheckerStats;
+
+/// Counters of the running process, exported in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    pub cycles: AtomicU64,
+    /// Requests to the enrollment system, by the checker and by commands
+    pub queries: AtomicU64,
+    /// Queries sent during the last check cycle
+    pub cycle_queries: AtomicU64,
+    pub captcha_attempts: AtomicU64,
+    /// Captchas that led to a successful login
+    pub captcha_solved: AtomicU64,
+    /// Captchas skipped because too few readings agreed
+    pub captcha_doubtful: AtomicU64,
+    /// Captchas whose answer the enrollment system rejected
+    pub captcha_rejected: AtomicU64,
+    /// Alerts that reached at least one destination
+    pub notifications: AtomicU64,
+    /// Whether the Discord gateway connection is up
+    pub gateway_connected: AtomicBool,
+    /// Whether this replica runs the checker, always when no lease is configured
+    pub leader: AtomicBool,
+    /// Checks in a row in which every enrollment system query failed
+    pub failing_checks: AtomicU64,
+    /// Check cycles aborted by the watchdog
+    pub aborted_cycles: AtomicU64,
+    /// Whether queries are paused after the enrollment system kept failing
+    pub breaker_open: AtomicBool,
+    /// Accounts the enrollment system locked, left alone until restart
+    pub locked_accounts: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    cycles: AtomicU64::new(0),
+    queries: AtomicU64::new(0),
+    cycle_queries: AtomicU64::new(0),
+    captcha_attempts: AtomicU64::new(0),
+    captcha_solved: AtomicU64::new(0),
+    captcha_doubtful: AtomicU64::new(0),
+    captcha_rejected: AtomicU64::new(0),
+    notifications: AtomicU64::new(0),
+    gateway_connected: AtomicBool::new(false),
+    leader: AtomicBool::new(true),
+    failing_checks: AtomicU64::new(0),
+    aborted_cycles: AtomicU64::new(0),
+    breaker_open: AtomicBool::new(false),
+    locked_accounts: AtomicU64::new(0),
+};
+
+/// Query latencies kept for the rolling statistics
+const QUERY_WINDOW: usize = 500;
+/// Check durations kept for the rolling statistics
+const CYCLE_WINDOW: usize = 50;
+/// Queries needed before a check is compared against the usual latency
+const MIN_BASELINE: usize = 50;
+/// A check whose median query takes this many times the usual median counts as degraded
+const DEGRADED_FACTOR: u64 = 2;
+/// Quantiles exported and shown to owners
+pub const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// The most recent durations in milliseconds, plus totals since start for Prometheus summaries
+pub struct Window {
+    samples: VecDeque<u64>,
+    capacity: usize,
+    sum: u64,
+    count: u64,
+}
+
+impl Window {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            samples: VecDeque::new(),
+            capacity,
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    pub fn push(&mut self, ms: u64) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ms);
+        self.sum += ms;
+        self.count += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Nearest-rank quantile of the kept samples, `None` while empty
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let mut sorted = self.samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        quantile(&sorted, q)
+    }
+}
+
+fn quantile(sorted: &[u64], q: f64) -> Option<u64> {
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.saturating_sub(1)).copied()
+}
+
+/// Rolling statistics of check durations and enrollment system query latencies
+pub struct Latencies {
+    pub queries: Window,
+    pub cycles: Window,
+    /// Whether the last check was much slower than usual
+    pub degraded: bool,
+}
+
+/// Median query latency of a slow check against the usual one, in milliseconds
+#[derive(Debug, PartialEq)]
+pub struct Degradation {
+    pub median: u64,
+    pub usual: u64,
+}
+
+impl Latencies {
+    /// Add the durations of one check, comparing its queries against the ones before
+    ///
+    /// The slowdown is returned only when the check turns degraded, so it is reported once.
+    pub fn record_cycle(&mut self, cycle_ms: u64, query_ms: &[u64]) -> Option<Degradation> {
+        self.cycles.push(cycle_ms);
+        let usual = Some(&self.queries)
+            .filter(|queries| queries.len() >= MIN_BASELINE)
+            .and_then(|queries| queries.quantile(0.5));
+        for ms in query_ms {
+            self.queries.push(*ms);
+        }
+        let mut sorted = query_ms.to_vec();
+        sorted.sort_unstable();
+        let (Some(median), Some(usual)) = (quantile(&sorted, 0.5), usual) else {
+            return None;
+        };
+        let was_degraded = self.degraded;
+        self.degraded = median > usual.max(1) * DEGRADED_FACTOR;
+        (self.degraded && !was_degraded).then_some(Degradation { median, usual })
+    }
+}
+
+pub static LATENCIES: Mutex<Latencies> = Mutex::new(Latencies {
+    queries: Window::new(QUERY_WINDOW),
+    cycles: Window::new(CYCLE_WINDOW),
+    degraded: false,
+});
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    /// Prometheus exposition of the counters, the checker stats, latencies and the storage size
+    pub fn render(
+        &self,
+        stats: &CheckerStats,
+        latencies: &Latencies,
+        storage_bytes: Option<u64>,
+    ) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP course_bot_{name} {help}");
+            let _ = writeln!(out, "# TYPE course_bot_{name} {kind}");
+            let _ = writeln!(out, "course_bot_{name} {value}");
+        };
+        metric(
+            "cycles_total",
+            "counter",
+            "Completed check cycles",
+            load(&self.cycles),
+        );
+        metric(
+            "queries_total",
+            "counter",
+            "Queries sent to the enrollment system",
+            load(&self.queries),
+        );
+        metric(
+            "cycle_queries",
+            "gauge",
+            "Queries sent during the last check cycle",
+            load(&self.cycle_queries),
+        );
+        metric(
+            "query_failures_total",
+            "counter",
+            "Queries that failed after all retries",
+            stats.query_failures,
+        );
+        metric(
+            "captcha_attempts_total",
+            "counter",
+            "Captchas sent to the solver",
+            load(&self.captcha_attempts),
+        );
+        metric(
+            "captcha_solved_total",
+            "counter",
+            "Captchas that led to a successful login",
+            load(&self.captcha_solved),
+        );
+        metric(
+            "captcha_doubtful_total",
+            "counter",
+            "Captchas skipped because too few readings agreed",
+            load(&self.captcha_doubtful),
+        );
+        metric(
+            "captcha_rejected_total",
+            "counter",
+            "Captchas whose answer the enrollment system rejected",
+            load(&self.captcha_rejected),
+        );
+        metric(
+            "logins_total",
+            "counter",
+            "Logins into the enrollment system",
+            stats.logins,
+        );
+        metric(
+            "notifications_total",
+            "counter",
+            "Alerts delivered to users and guilds",
+            load(&self.notifications),
+        );
+        metric(
+            "gateway_connected",
+            "gauge",
+            "Whether the Discord gateway connection is up",
+            self.gateway_connected.load(Ordering::Relaxed).into(),
+        );
+        metric(
+            "leader",
+            "gauge",
+            "Whether this instance runs the checker",
+            self.leader.load(Ordering::Relaxed).into(),
+        );
+        metric(
+            "failing_checks",
+            "gauge",
+            "Checks in a row in which every query failed",
+            load(&self.failing_checks),
+        );
+        metric(
+            "aborted_cycles_total",
+            "counter",
+            "Check cycles aborted for running past the deadline",
+            load(&self.aborted_cycles),
+        );
+        metric(
+            "breaker_open",
+            "gauge",
+            "Whether queries are paused after repeated failures",
+            self.breaker_open.load(Ordering::Relaxed).into(),
+        );
+        metric(
+            "locked_accounts",
+            "gauge",
+            "Accounts the enrollment system locked, no longer logged in with",
+            load(&self.locked_accounts),
+        );
+        if let Some(secs) = stats.last_cycle_secs {
+            metric(
+                "last_cycle_seconds",
+                "gauge",
+                "Duration of the last check cycle",
+                secs,
+            );
+        }
+        if let Some(at) = stats.last_cycle_at {
+            metric(
+                "last_cycle_timestamp_seconds",
+                "gauge",
+                "When the last check cycle finished",
+                at,
+            );
+        }
+        if let Some(bytes) = storage_bytes {
+            metric("storage_bytes", "gauge", "Size of the stored data", bytes);
+        }
+        metric(
+            "latency_degraded",
+            "gauge",
+            "Whether the last check's queries were much slower than usual",
+            latencies.degraded.into(),
+        );
+        summary(
+            &mut out,
+            "query_latency_seconds",
+            "Latency of enrollment system queries during checks",
+            &latencies.queries,
+        );
+        summary(
+            &mut out,
+            "cycle_duration_seconds",
+            "Duration of check cycles",
+            &latencies.cycles,
+        );
+        let solved = load(&self.captcha_solved);
+        let answered = solved + load(&self.captcha_rejected);
+        if answered > 0 {
+            let name = "course_bot_captcha_solve_ratio";
+            let _ = writeln!(
+                out,
+                "# HELP {name} Share of answered captchas the enrollment system accepted"
+            );
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {}", solved as f64 / answered as f64);
+        }
+        out
+    }
+}
+
+/// Quantiles over the kept samples, sum and count since start
+fn summary(out: &mut String, name: &str, help: &str, window: &Window) {
+    let _ = writeln!(out, "# HELP course_bot_{name} {help}");
+    let _ = writeln!(out, "# TYPE course_bot_{name} summary");
+    for q in QUANTILES {
+        if let Some(ms) = window.quantile(q) {
+            let _ = writeln!(
+                out,
+                "course_bot_{name}{{quantile=\"{q}\"}} {}",
+                ms as f64 / 1000.0
+            );
+        }
+    }
+    let _ = writeln!(out, "course_bot_{name}_sum {}", window.sum as f64 / 1000.0);
+    let _ = writeln!(out, "course_bot_{name}_count {}", window.count);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        inc(&metrics.cycles);
+        inc(&metrics.cycles);
+        let stats = CheckerStats {
+            query_failures: 3,
+            last_cycle_secs: Some(42),
+            ..Default::default()
+        };
+        let mut latencies = Latencies {
+            queries: Window::new(10),
+            cycles: Window::new(10),
+            degraded: false,
+        };
+        latencies.record_cycle(2500, &[1000, 1500, 500]);
+        let text = metrics.render(&stats, &latencies, Some(4096));
+        assert!(text.contains(
+            "# HELP course_bot_cycles_total Completed check cycles\n\
+             # TYPE course_bot_cycles_total counter\n\
+             course_bot_cycles_total 2\n"
+        ));
+        assert!(text.contains("\ncourse_bot_query_failures_total 3\n"));
+        assert!(text.contains("\ncourse_bot_last_cycle_seconds 42\n"));
+        assert!(text.contains("\ncourse_bot_storage_bytes 4096\n"));
+        // no captcha answered yet
+        assert!(!text.contains("captcha_solve_ratio"));
+        inc(&metrics.captcha_solved);
+        inc(&metrics.captcha_solved);
+        inc(&metrics.captcha_solved);
+        inc(&metrics.captcha_rejected);
+        let text = metrics.render(&stats, &latencies, Some(4096));
+        assert!(text.contains("\ncourse_bot_captcha_solve_ratio 0.75\n"));
+        // no cycle finished yet
+        assert!(!text.contains("last_cycle_timestamp_seconds"));
+        assert!(text.contains(
+            "# TYPE course_bot_query_latency_seconds summary\n\
+             course_bot_query_latency_seconds{quantile=\"0.5\"} 1\n\
+             course_bot_query_latency_seconds{quantile=\"0.9\"} 1.5\n\
+             course_bot_query_latency_seconds{quantile=\"0.99\"} 1.5\n\
+             course_bot_query_latency_seconds_sum 3\n\
+             course_bot_query_latency_seconds_count 3\n"
+        ));
+    }
+
+    #[test]
+    fn test_latency_window() {
+        let mut window = Window::new(3);
+        assert_eq!(window.quantile(0.5), None);
+        for ms in [40, 10, 30, 20] {
+            window.push(ms);
+        }
+        // the oldest sample is dropped, the totals keep it
+        assert_eq!(window.len(), 3);
+        assert_eq!(window.quantile(0.5), Some(20));
+        assert_eq!(window.quantile(0.99), Some(30));
+        assert_eq!((window.sum, window.count), (100, 4));
+    }
+
+    #[test]
+    fn test_degradation() {
+        let mut latencies = Latencies {
+            queries: Window::new(QUERY_WINDOW),
+            cycles: Window::new(CYCLE_WINDOW),
+            degraded: false,
+        };
+        // nothing to compare the first check against
+        assert_eq!(latencies.record_cycle(1000, &[200; MIN_BASELINE]), None);
+        assert_eq!(latencies.record_cycle(1000, &[300, 350]), None);
+        assert_eq!(
+            latencies.record_cycle(5000, &[900, 1000, 1100]),
+            Some(Degradation {
+                median: 1000,
+                usual: 200
+            })
+        );
+        assert!(latencies.degraded);
+        // reported once while it lasts
+        assert_eq!(latencies.record_cycle(5000, &[1000]), None);
+        assert!(latencies.degraded);
+        assert_eq!(latencies.record_cycle(1000, &[200]), None);
+        assert!(!latencies.degraded);
+    }
+}
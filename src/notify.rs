@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use serenity::{
+    all::{
+        ButtonStyle, ChannelId, CreateActionRow, CreateAllowedMentions, CreateButton,
+        CreateMessage, RoleId, UserId,
+    },
+    http::{Http, HttpError},
+};
+use tracing::warn;
+
+use crate::{
+    bot::READD_BUTTON_PREFIX,
+    db::{GuildSettings, UserSettings},
+    i18n::{Lang, Msg},
+    metrics::{self, METRICS},
+};
+
+/// Where a user wants availability alerts delivered
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    poise::ChoiceParameter,
+    sqlx::Type,
+)]
+#[sqlx(rename_all = "snake_case")]
+pub enum NotifyTarget {
+    #[default]
+    #[name = "Direct message"]
+    Dm,
+    #[name = "Guild channel"]
+    Channel,
+    #[name = "Both"]
+    Both,
+}
+
+/// How availability events of a single check are grouped into messages
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    poise::ChoiceParameter,
+    sqlx::Type,
+)]
+#[sqlx(rename_all = "snake_case")]
+pub enum DigestMode {
+    #[name = "One message per course"]
+    PerCourse,
+    #[default]
+    #[name = "One digest per check"]
+    Cycle,
+}
+
+/// One "re-add" button per course, five to a row as Discord requires
+pub fn readd_buttons(course_ids: &[&str], lang: Lang) -> Vec<CreateActionRow> {
+    course_ids
+        .chunks(5)
+        .take(5)
+        .map(|chunk| {
+            CreateActionRow::Buttons(
+                chunk
+                    .iter()
+                    .map(|course_id| {
+                        CreateButton::new(format!("{READD_BUTTON_PREFIX}{course_id}"))
+                            .style(ButtonStyle::Secondary)
+                            .label(Msg::ReaddButton { course_id }.render(lang))
+                    })
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// How a message to a user went out
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Delivery {
+    /// Whether at least one destination accepted it
+    pub delivered: bool,
+    /// Whether the user does not accept DMs from the bot
+    pub dm_closed: bool,
+}
+
+/// Discord's answer to a DM the user's privacy settings refuse
+const DM_CLOSED_CODE: isize = 50007;
+
+/// Deliver `content` to every destination the user asked for
+///
+/// Failures are logged per destination so one broken path does not hide the other. A failed DM
+/// that no channel of the user's makes up for goes to `fallback`, mentioning the user.
+pub async fn notify_user(
+    http: &Http,
+    user_id: UserId,
+    settings: &UserSettings,
+    content: &str,
+    components: Vec<CreateActionRow>,
+    fallback: Option<ChannelId>,
+) -> Delivery {
+    let channel = settings.notify_channel.map(ChannelId::new);
+    let (dm, channel) = match (settings.notify_target, channel) {
+        (NotifyTarget::Dm, _) => (true, None),
+        // asked for a channel but never told us which one
+        (NotifyTarget::Channel, None) | (NotifyTarget::Both, None) => (true, None),
+        (NotifyTarget::Channel, Some(channel)) => (false, Some(channel)),
+        (NotifyTarget::Both, Some(channel)) => (true, Some(channel)),
+    };
+
+    let mut delivered = false;
+    let mut dm_closed = false;
+    let mut channel = channel;
+    if dm {
+        let builder = CreateMessage::new()
+            .content(content)
+            .components(components.clone());
+        match user_id.direct_message(http, builder).await {
+            Ok(_) => delivered = true,
+            Err(e) => {
+                dm_closed = matches!(
+                    &e,
+                    serenity::Error::Http(HttpError::UnsuccessfulRequest(response))
+                        if response.error.code == DM_CLOSED_CODE
+                );
+                warn!("fail to notify user by DM (user: {user_id}): {e:?}");
+                channel = channel.or(fallback);
+            }
+        }
+    }
+    if let Some(channel) = channel {
+        let builder = CreateMessage::new()
+            .content(format!("<@{user_id}> {content}"))
+            .allowed_mentions(CreateAllowedMentions::new().users([user_id]))
+            .components(components);
+        match channel.send_message(http, builder).await {
+            Ok(_) => delivered = true,
+            Err(e) => {
+                warn!("fail to notify user in channel (user: {user_id}, channel: {channel}): {e:?}")
+            }
+        }
+    }
+    if delivered {
+        metrics::inc(&METRICS.notifications);
+    }
+    Delivery {
+        delivered,
+        dm_closed,
+    }
+}
+
+/// Post `content` publicly in a guild's alert channel, pinging its role if configured
+pub async fn notify_guild(http: &Http, guild: &GuildSettings, content: &str) -> bool {
+    notify_channel(http, ChannelId::new(guild.channel), guild.role, content).await
+}
+
+/// Post `content` publicly in a guild channel, pinging `role` if any
+pub async fn notify_channel(
+    http: &Http,
+    channel: ChannelId,
+    role: Option<u64>,
+    content: &str,
+) -> bool {
+    let builder = match role.map(RoleId::new) {
+        Some(role) => CreateMessage::new()
+            .content(format!("<@&{role}> {content}"))
+            .allowed_mentions(CreateAllowedMentions::new().roles([role])),
+        None => CreateMessage::new().content(content),
+    };
+    match channel.send_message(http, builder).await {
+        Ok(_) => {
+            metrics::inc(&METRICS.notifications);
+            true
+        }
+        Err(e) => {
+            warn!("fail to post guild alert (channel: {channel}): {e:?}");
+            false
+        }
+    }
+}
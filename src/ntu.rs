@@ -0,0 +1,218 @@
+//! National Taiwan University's course search, watched as `ntu:{serial number}`
+//!
+//! NTU lists every course with its enrollment cap and how many students have selected it on the
+//! public course search, so this source needs no account, login or captcha. The listing is an
+//! HTML table whose columns are found by their headers rather than their positions.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use scraper::{ElementRef, Html, Selector};
+use tracing::instrument;
+
+use crate::{
+    config::Config,
+    crawler::{CourseInfo, CourseStatus, SeatCount},
+    metrics::{self, METRICS},
+    open_course::academic_term,
+    page::PageError,
+    source::CourseSource,
+};
+
+const PAGE: &str = "NTU course search";
+
+pub struct NtuSource {
+    client: reqwest::Client,
+    endpoint_root: String,
+    /// Academic year in the ROC calendar and term, derived from the date when unset
+    term: Option<(u32, u32)>,
+}
+
+impl NtuSource {
+    pub fn new(config: &Config) -> Self {
+        let builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        let builder = match config.proxy() {
+            Some(proxy) => builder.proxy(proxy),
+            None => builder,
+        };
+        Self {
+            client: builder.build().unwrap(),
+            endpoint_root: config.ntu_url.trim_end_matches('/').to_owned(),
+            term: config.academic_term.0,
+        }
+    }
+
+    /// The course with serial number `course_id`, `None` when it is not offered in `term`, the
+    /// term being enrolled when `None`
+    #[instrument(skip(self))]
+    async fn course(
+        &self,
+        course_id: &str,
+        term: Option<(u32, u32)>,
+    ) -> Result<Option<CourseInfo>> {
+        let (year, term) = term
+            .or(self.term)
+            .unwrap_or_else(|| academic_term(crate::db::now()));
+        metrics::inc(&METRICS.queries);
+        let html = self
+            .client
+            .get(format!(
+                "{}/nol/coursesearch/search_result.php",
+                self.endpoint_root
+            ))
+            .query(&[
+                ("current_sem", format!("{year}-{term}").as_str()),
+                ("cstype", "5"),
+                ("csname", course_id),
+                ("alltime", "yes"),
+                ("allproced", "yes"),
+                ("allsel", "yes"),
+                ("page_cnt", "100"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        // the search matches prefixes, keep the exact hit only
+        Ok(parse_courses(&html)?
+            .into_iter()
+            .find(|c| c.serial_no == course_id))
+    }
+}
+
+#[async_trait]
+impl CourseSource for NtuSource {
+    async fn init(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn query(&mut self, course_id: &str, term: Option<(u32, u32)>) -> Result<CourseStatus> {
+        Ok(match self.course(course_id, term).await? {
+            Some(course) => CourseStatus::new(course.seats, !course.restrictions.is_empty()),
+            None => CourseStatus::NotFound,
+        })
+    }
+
+    async fn fetch_info(
+        &mut self,
+        course_id: &str,
+        term: Option<(u32, u32)>,
+    ) -> Result<Option<CourseInfo>> {
+        self.course(course_id, term).await
+    }
+}
+
+/// Every course of a search result page
+fn parse_courses(html: &str) -> Result<Vec<CourseInfo>, PageError> {
+    let html = Html::parse_document(html);
+    let rows = Selector::parse("tr").unwrap();
+    let cells = Selector::parse("th, td").unwrap();
+    let texts = |row: ElementRef| {
+        row.select(&cells)
+            .map(|cell| cell.text().collect::<String>().trim().to_owned())
+            .collect::<Vec<_>>()
+    };
+    let mut rows = html.select(&rows).map(texts);
+    let header = rows
+        .by_ref()
+        .find(|cells| cells.iter().any(|cell| cell == "流水號"))
+        .ok_or(PageError::Missing {
+            page: PAGE,
+            what: "course table",
+        })?;
+    let columns = header
+        .iter()
+        .enumerate()
+        .map(|(index, title)| (title.as_str(), index))
+        .collect::<HashMap<_, _>>();
+    let column = |titles: &[&'static str]| {
+        titles
+            .iter()
+            .find_map(|title| columns.get(title).copied())
+            .ok_or(PageError::MissingField(titles[0]))
+    };
+    let serial_no = column(&["流水號"])?;
+    let name = column(&["課程名稱"])?;
+    let course_code = column(&["課號"])?;
+    let teacher = column(&["授課教師"])?;
+    let option_code = column(&["必/選修"])?;
+    let enrolled = column(&["已選人數", "選課人數"])?;
+    let quota = column(&["總人數", "限額"])?;
+    let restrictions = column(&["選課限制條件"]).ok();
+    let number = |row: &[String], index: usize, field: &'static str| {
+        row[index].parse().map_err(|_| PageError::NotNumber {
+            field,
+            value: row[index].clone(),
+        })
+    };
+    rows.filter(|row| row.len() == header.len() && !row[serial_no].is_empty())
+        .map(|row| {
+            Ok(CourseInfo {
+                serial_no: row[serial_no].clone(),
+                course_code: row[course_code].clone(),
+                name: row[name].clone(),
+                teacher: row[teacher].clone(),
+                option_code: row[option_code].clone(),
+                seats: SeatCount {
+                    enrolled: number(&row, enrolled, "已選人數")?,
+                    quota: number(&row, quota, "總人數")?,
+                },
+                restrictions: restrictions
+                    .map(|index| row[index].clone())
+                    .filter(|restriction| !restriction.is_empty())
+                    .into_iter()
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_courses() {
+        let html = r#"<html><body><table>
+            <tr><td>共 2 筆</td></tr>
+            <tr><th>流水號</th><th>課號</th><th>課程名稱</th><th>必/選修</th><th>授課教師</th>
+                <th>已選人數</th><th>總人數</th><th>選課限制條件</th></tr>
+            <tr><td>12345</td><td>CSIE1212</td><td><a href="/nol/print_table">資料結構與演算法</a></td>
+                <td>必</td><td>林小明</td><td>118</td><td>120</td><td>限本系所學生</td></tr>
+            <tr><td>12346</td><td>CSIE1212</td><td>資料結構與演算法</td><td>必</td>
+                <td>王大同</td><td>60</td><td>60</td><td></td></tr>
+        </table></body></html>"#;
+        let courses = parse_courses(html).unwrap();
+        assert_eq!(courses.len(), 2);
+        assert_eq!(courses[0].serial_no, "12345");
+        assert_eq!(courses[0].name, "資料結構與演算法");
+        assert_eq!(courses[0].teacher, "林小明");
+        assert_eq!(courses[0].seats.available(), 2);
+        assert_eq!(courses[1].seats.available(), 0);
+        assert_eq!(courses[0].restrictions, ["限本系所學生"]);
+        assert!(courses[1].restrictions.is_empty());
+
+        let html = html.replace(
+            "<td>60</td><td>60</td><td></td>",
+            "<td>60</td><td>不限</td><td></td>",
+        );
+        assert_eq!(
+            parse_courses(&html).unwrap_err(),
+            PageError::NotNumber {
+                field: "總人數",
+                value: "不限".to_owned()
+            }
+        );
+        let html = html.replace("<th>已選人數</th>", "<th>備註</th>");
+        assert_eq!(
+            parse_courses(&html).unwrap_err(),
+            PageError::MissingField("已選人數")
+        );
+        assert!(matches!(
+            parse_courses("<html><body>查無資料</body></html>"),
+            Err(PageError::Missing { page: PAGE, .. })
+        ));
+    }
+}
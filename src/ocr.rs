@@ -0,0 +1,31 @@
+//! Reads captchas on the machine itself, for running without a captcha service
+//!
+//! Built with the `local-captcha` feature, behind `BOT_CAPTCHA_BACKEND=local`. Tesseract
+//! and its `eng` data have to be installed; `TESSDATA_PREFIX` points it to data elsewhere.
+
+use anyhow::Result;
+use tesseract::{PageSegMode, Tesseract};
+
+/// What arithmetic captchas such as `3+4` are written with
+const ARITHMETIC: &str = "0123456789+-x";
+/// What the other captchas are written with
+const ALPHANUMERIC: &str = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Readings of a captcha image, as arithmetic first, like the captcha service answers
+///
+/// Blocks while Tesseract runs.
+pub fn read(img: &[u8]) -> Result<Vec<String>> {
+    let mut readings = Vec::new();
+    for chars in [ARITHMETIC, ALPHANUMERIC] {
+        let mut tesseract = Tesseract::new(None, Some("eng"))?
+            .set_variable("tessedit_char_whitelist", chars)?
+            .set_image_from_mem(img)?;
+        tesseract.set_page_seg_mode(PageSegMode::PsmSingleLine);
+        let text = tesseract.recognize()?.get_text()?;
+        let text = text.split_whitespace().collect::<String>();
+        if !text.is_empty() {
+            readings.push(text);
+        }
+    }
+    Ok(readings)
+}
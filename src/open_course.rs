@@ -0,0 +1,149 @@
+//! Looks courses up on the public course query site, without logging in
+//!
+//! Names, teachers, course codes and whether a serial number exists at all are public, so asking
+//! for them spends no login, captcha or enrollment system request; the authenticated crawler is
+//! left to the seat counts. The public enrollment numbers may lag and are not used for alerts.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use tracing::instrument;
+
+use crate::{
+    config::Config,
+    crawler::{CourseInfo, CourseQuery},
+    page::{Grid, GridRow},
+    schedule::civil_from_days,
+};
+
+pub struct OpenCourseCrawler {
+    client: reqwest::Client,
+    endpoint_root: String,
+    /// Academic year in the ROC calendar and term, derived from the date when unset
+    term: Option<(u32, u32)>,
+}
+
+impl OpenCourseCrawler {
+    pub fn new(config: &Config) -> Self {
+        let builder = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        let builder = match config.proxy() {
+            Some(proxy) => builder.proxy(proxy),
+            None => builder,
+        };
+        Self {
+            client: builder.build().unwrap(),
+            endpoint_root: config.open_course_url.trim_end_matches('/').to_owned(),
+            term: config.academic_term.0,
+        }
+    }
+
+    /// Every course matching all filters of `query` this term
+    pub async fn search(&self, query: &CourseQuery) -> Result<Vec<CourseInfo>> {
+        self.search_term(query, None).await
+    }
+
+    /// Every course matching all filters of `query` in `term`, the term being enrolled when
+    /// `None`
+    #[instrument(skip(self))]
+    async fn search_term(
+        &self,
+        query: &CourseQuery,
+        term: Option<(u32, u32)>,
+    ) -> Result<Vec<CourseInfo>> {
+        let (year, term) = term
+            .or(self.term)
+            .unwrap_or_else(|| academic_term(crate::db::now()));
+        let (year, term) = (year.to_string(), term.to_string());
+        let field = |value: &Option<String>| value.clone().unwrap_or_default();
+        let text = self
+            .client
+            .get(format!("{}/CofopdlCtrl", self.endpoint_root))
+            .query(&[
+                ("action", "showGrid"),
+                ("actionButton", "query"),
+                ("language", "chinese"),
+                ("acadmYear", &year),
+                ("acadmTerm", &term),
+                ("serialNo", &field(&query.serial_no)),
+                ("chn", &field(&query.name)),
+                ("teacher", &field(&query.teacher)),
+                ("deptCode", &field(&query.dept_code)),
+                ("courseCode", &field(&query.course_code)),
+                ("start", "0"),
+                ("limit", "99999"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(Grid::parse(&text)
+            .context("public course query site")?
+            .rows
+            .iter()
+            .map(GridRow::course)
+            .collect::<Result<_, _>>()?)
+    }
+
+    /// The course with serial number `course_id`, `None` when it is not offered in `term`, the
+    /// term being enrolled when `None`
+    pub async fn course(
+        &self,
+        course_id: &str,
+        term: Option<(u32, u32)>,
+    ) -> Result<Option<CourseInfo>> {
+        let query = CourseQuery {
+            serial_no: Some(course_id.to_owned()),
+            ..Default::default()
+        };
+        let courses = self.search_term(&query, term).await?;
+        Ok(courses.into_iter().find(|c| c.serial_no == course_id))
+    }
+
+    /// Serial numbers of every section of `course_code` this term
+    pub async fn resolve_course_code(&self, course_code: &str) -> Result<Vec<String>> {
+        let courses = self
+            .search(&CourseQuery {
+                course_code: Some(course_code.to_owned()),
+                ..Default::default()
+            })
+            .await?;
+        // the site matches prefixes, keep exact hits only
+        let mut serial_nos = courses
+            .into_iter()
+            .filter(|c| c.course_code.eq_ignore_ascii_case(course_code))
+            .map(|c| c.serial_no)
+            .collect::<Vec<_>>();
+        serial_nos.sort();
+        serial_nos.dedup();
+        Ok(serial_nos)
+    }
+}
+
+/// Academic year in the ROC calendar and term whose courses are enrolled in at unix time `now`
+///
+/// The first term is enrolled in from July, the second from January, in Taiwan time.
+pub fn academic_term(now: u64) -> (u32, u32) {
+    let days = (now + 8 * 3600) / 86400;
+    let (year, month) = civil_from_days(days as i64);
+    if month >= 7 {
+        (year - 1911, 1)
+    } else {
+        (year - 1912, 2)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_academic_term() {
+        // 2024-09-01 00:00 UTC
+        assert_eq!(academic_term(1725148800), (113, 1));
+        // 2025-01-15, enrolling for the second term
+        assert_eq!(academic_term(1736899200), (113, 2));
+        // 2025-06-30 17:00 UTC is July 1st in Taiwan
+        assert_eq!(academic_term(1751302800), (114, 1));
+    }
+}
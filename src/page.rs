@@ -0,0 +1,270 @@
+//! Reads what the enrollment system answers into typed values
+//!
+//! The login and index pages are Ext JS apps whose values sit in inline scripts, so the scripts
+//! are picked out of the HTML and only the JavaScript literals of interest are read. The query
+//! grid is JSON, at times with single quotes. A page that no longer has the expected structure
+//! is a [`PageError`] naming what is missing instead of a panic.
+
+use scraper::{Html, Selector};
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+use crate::crawler::{CourseInfo, SeatCount};
+
+#[derive(Debug, Error, PartialEq)]
+pub enum PageError {
+    #[error("{page} page has no {what}, its markup may have changed")]
+    Missing {
+        page: &'static str,
+        what: &'static str,
+    },
+    #[error("course grid is unreadable, its format may have changed: {0}")]
+    Grid(String),
+    #[error("course grid has no `{0}`")]
+    MissingField(&'static str),
+    #[error("`{field}` of the course grid is not a number: {value:?}")]
+    NotNumber { field: &'static str, value: String },
+}
+
+/// The login form
+#[derive(Debug, PartialEq)]
+pub struct LoginPage {
+    /// Goes into the URL the credentials are posted to
+    pub magic: String,
+}
+
+impl LoginPage {
+    pub fn parse(html: &str) -> Result<Self, PageError> {
+        // url:'LoginCheckCtrl?action=login&id=' + '{magic}'
+        let magic = scripts(html)
+            .iter()
+            .find_map(|script| {
+                script.match_indices("id='").find_map(|(at, pattern)| {
+                    let rest = script[at + pattern.len()..].trim_start();
+                    js_string(rest.strip_prefix('+')?.trim_start())
+                })
+            })
+            .ok_or(PageError::Missing {
+                page: "login",
+                what: "login form URL",
+            })?;
+        Ok(Self { magic })
+    }
+}
+
+/// The page right after logging in
+#[derive(Debug, PartialEq)]
+pub struct IndexPage {
+    /// Confirmed back to the enrollment system before it lets the session query
+    pub student_name: String,
+}
+
+impl IndexPage {
+    pub fn parse(html: &str) -> Result<Self, PageError> {
+        // {name: 'stdName', ..., value: '{student name}'}
+        let student_name = scripts(html)
+            .iter()
+            .find_map(|script| {
+                let field = &script[script.find("'stdName'")?..];
+                let field = &field[..field.find('}').unwrap_or(field.len())];
+                let value = &field[field.find("value:")? + "value:".len()..];
+                js_string(value.trim_start())
+            })
+            .ok_or(PageError::Missing {
+                page: "index",
+                what: "student name",
+            })?;
+        Ok(Self { student_name })
+    }
+}
+
+/// Text of every inline script of `html`
+fn scripts(html: &str) -> Vec<String> {
+    let selector = Selector::parse("script").unwrap();
+    Html::parse_document(html)
+        .select(&selector)
+        .map(|script| script.text().collect())
+        .collect()
+}
+
+/// The JavaScript string literal `code` starts with
+fn js_string(code: &str) -> Option<String> {
+    let mut chars = code.chars();
+    let quote = chars.next().filter(|c| *c == '\'' || *c == '"')?;
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c == quote => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'u' => {
+                    let hex = chars.by_ref().take(4).collect::<String>();
+                    value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => value.push(c),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+/// Answer to the course query form
+#[derive(Debug, Deserialize)]
+pub struct Grid {
+    #[serde(rename = "Count")]
+    pub count: u32,
+    #[serde(rename = "List", default)]
+    pub rows: Vec<GridRow>,
+}
+
+impl Grid {
+    pub fn parse(text: &str) -> Result<Self, PageError> {
+        json5::from_str(text).map_err(|e| PageError::Grid(e.to_string()))
+    }
+}
+
+/// One course of the grid, with the fields the bot uses
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GridRow {
+    serial_no: Scalar,
+    course_code: Option<Scalar>,
+    chn_name: Option<Scalar>,
+    teacher: Option<Scalar>,
+    option_code: Option<Scalar>,
+    limit_count_h: Scalar,
+    counter: Scalar,
+    /// Restriction columns, blank when the course is open to anyone
+    restrict1: Option<Scalar>,
+    restrict2: Option<Scalar>,
+}
+
+impl GridRow {
+    pub fn seats(&self) -> Result<SeatCount, PageError> {
+        Ok(SeatCount {
+            enrolled: self.counter.number("counter")?,
+            quota: self.limit_count_h.number("limitCountH")?,
+        })
+    }
+
+    pub fn course(&self) -> Result<CourseInfo, PageError> {
+        let text = |value: &Option<Scalar>| value.as_ref().map(|v| v.0.clone()).unwrap_or_default();
+        Ok(CourseInfo {
+            serial_no: self.serial_no.0.clone(),
+            course_code: text(&self.course_code),
+            name: self
+                .chn_name
+                .as_ref()
+                .ok_or(PageError::MissingField("chnName"))?
+                .0
+                .clone(),
+            teacher: text(&self.teacher),
+            option_code: text(&self.option_code),
+            seats: self.seats()?,
+            restrictions: self.restrictions(),
+        })
+    }
+
+    /// Who may enroll, empty when anyone may
+    pub fn restrictions(&self) -> Vec<String> {
+        [&self.restrict1, &self.restrict2]
+            .into_iter()
+            .flatten()
+            .map(|restriction| restriction.0.clone())
+            .filter(|restriction| !restriction.is_empty())
+            .collect()
+    }
+}
+
+/// Grid value, which comes quoted or bare
+#[derive(Debug, Clone, PartialEq)]
+struct Scalar(String);
+
+impl Scalar {
+    fn number(&self, field: &'static str) -> Result<i32, PageError> {
+        self.0.parse().map_err(|_| PageError::NotNumber {
+            field,
+            value: self.0.clone(),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Scalar {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Value {
+            Text(String),
+            Int(i64),
+            Float(f64),
+        }
+        Ok(Self(match Value::deserialize(deserializer)? {
+            Value::Text(text) => text.trim().to_owned(),
+            Value::Int(number) => number.to_string(),
+            Value::Float(number) => number.to_string(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_login_page() {
+        let html = r#"<html><head><script type="text/javascript">
+            Ext.onReady(function() {
+                form.submit({
+                    url:'LoginCheckCtrl?action=login&id=' + '5F3A9C',
+                    method: 'POST'
+                });
+            });
+        </script></head><body></body></html>"#;
+        assert_eq!(
+            LoginPage::parse(html),
+            Ok(LoginPage {
+                magic: "5F3A9C".to_owned()
+            })
+        );
+        assert!(matches!(
+            LoginPage::parse("<html><body>系統忙碌中</body></html>"),
+            Err(PageError::Missing { page: "login", .. })
+        ));
+    }
+
+    #[test]
+    fn test_index_page() {
+        let html = "<script>var items = [{\r\n  xtype: 'textfield', name: 'stdName',\r\n  readOnly: true,\r\n  value: '王\\'小明'\r\n}, {name: 'other', value: 'x'}];</script>";
+        assert_eq!(
+            IndexPage::parse(html),
+            Ok(IndexPage {
+                student_name: "王'小明".to_owned()
+            })
+        );
+        let html = "<script>[{name: 'stdName'}, {value: 'not the name'}]</script>";
+        assert!(IndexPage::parse(html).is_err());
+    }
+
+    #[test]
+    fn test_grid() {
+        let grid = Grid::parse(
+            r#"{'Count': 1, 'List': [{'serialNo': 42, 'limitCountH': ' 50 ', 'counter': '-'}]}"#,
+        )
+        .unwrap();
+        assert_eq!(grid.count, 1);
+        assert_eq!(
+            grid.rows[0].seats(),
+            Err(PageError::NotNumber {
+                field: "counter",
+                value: "-".to_owned()
+            })
+        );
+        assert_eq!(
+            grid.rows[0].course().unwrap_err(),
+            PageError::MissingField("chnName")
+        );
+        assert!(matches!(Grid::parse("<html>"), Err(PageError::Grid(_))));
+    }
+}
@@ -0,0 +1,166 @@
+//! Long replies split into pages browsed with buttons
+//!
+//! Discord rejects messages over 2000 characters, which a long watchlist or the full command list
+//! can exceed. Replies are cut between lines into pages, each repeating the heading of the group
+//! it continues, and shown one at a time with buttons to turn the page for whoever asked.
+
+use std::time::Duration;
+
+use poise::CreateReply;
+use serenity::all::{
+    ButtonStyle, ComponentInteractionCollector, CreateActionRow, CreateButton, CreateEmbed,
+    CreateEmbedFooter, CreateInteractionResponse, CreateInteractionResponseMessage,
+};
+
+use crate::bot::{Context, Error};
+
+/// Characters a page holds at most, leaving room below Discord's limit
+pub const PAGE_LEN: usize = 1800;
+
+/// How long the buttons keep working after the last press
+const NAVIGATION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// A heading and the lines listed under it
+pub struct Group {
+    pub heading: String,
+    pub lines: Vec<String>,
+}
+
+/// Cut `groups` into pages of at most `limit` characters, breaking between lines only
+///
+/// A group running onto another page has its heading repeated there, groups without lines are
+/// left out. A line too long for any page gets one of its own.
+pub fn pages(groups: &[Group], limit: usize) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    // characters rather than bytes, as Discord counts them
+    let mut page_len = 0;
+    // the group whose heading the page already shows
+    let mut shown = None;
+    for (index, group) in groups.iter().enumerate() {
+        for line in &group.lines {
+            let chunk = |shown: Option<usize>| {
+                if shown == Some(index) {
+                    line.clone()
+                } else {
+                    format!("{}\n{line}", group.heading)
+                }
+            };
+            let separator = |shown: Option<usize>| match shown {
+                None => "",
+                Some(shown) if shown == index => "\n",
+                Some(_) => "\n\n",
+            };
+            let mut text = format!("{}{}", separator(shown), chunk(shown));
+            if shown.is_some() && page_len + text.chars().count() > limit {
+                pages.push(std::mem::take(&mut page));
+                page_len = 0;
+                text = chunk(None);
+            }
+            page_len += text.chars().count();
+            page += &text;
+            shown = Some(index);
+        }
+    }
+    if !page.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+/// Reply with `pages`, as plain text when there is only one
+pub async fn paginate(ctx: Context<'_>, pages: &[String], ephemeral: bool) -> Result<(), Error> {
+    let Some(first) = pages.first() else {
+        return Ok(());
+    };
+    if pages.len() == 1 {
+        let reply = CreateReply::default().content(first).ephemeral(ephemeral);
+        ctx.send(reply).await?;
+        return Ok(());
+    }
+    let prefix = format!("{}page_", ctx.id());
+    let (previous_id, next_id) = (format!("{prefix}previous"), format!("{prefix}next"));
+    let embed = |index: usize| {
+        let footer = CreateEmbedFooter::new(format!("{}/{}", index + 1, pages.len()));
+        CreateEmbed::new().description(&pages[index]).footer(footer)
+    };
+    let buttons = CreateActionRow::Buttons(vec![
+        CreateButton::new(&previous_id)
+            .emoji('◀')
+            .style(ButtonStyle::Secondary),
+        CreateButton::new(&next_id)
+            .emoji('▶')
+            .style(ButtonStyle::Secondary),
+    ]);
+    let reply = CreateReply::default()
+        .embed(embed(0))
+        .components(vec![buttons])
+        .ephemeral(ephemeral);
+    let handle = ctx.send(reply).await?;
+
+    let mut index = 0;
+    loop {
+        let prefix = prefix.clone();
+        let press = ComponentInteractionCollector::new(ctx)
+            .author_id(ctx.author().id)
+            .channel_id(ctx.channel_id())
+            .timeout(NAVIGATION_TIMEOUT)
+            .filter(move |press| press.data.custom_id.starts_with(&prefix))
+            .await;
+        let Some(press) = press else {
+            break;
+        };
+        index = if press.data.custom_id == next_id {
+            (index + 1) % pages.len()
+        } else {
+            (index + pages.len() - 1) % pages.len()
+        };
+        let page = CreateInteractionResponseMessage::new().embed(embed(index));
+        press
+            .create_response(ctx, CreateInteractionResponse::UpdateMessage(page))
+            .await?;
+    }
+    // the buttons no longer do anything
+    handle
+        .edit(
+            ctx,
+            CreateReply::default()
+                .embed(embed(index))
+                .components(vec![]),
+        )
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pages() {
+        let group = |heading: &str, lines: &[&str]| Group {
+            heading: heading.to_owned(),
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+        };
+        let groups = [
+            group("Courses:", &["1234 甲", "1235 乙", "1236 丙"]),
+            group("Empty:", &[]),
+            group("Departments:", &["CSU"]),
+        ];
+        assert_eq!(
+            pages(&groups, 2000),
+            ["Courses:\n1234 甲\n1235 乙\n1236 丙\n\nDepartments:\nCSU"]
+        );
+        // the heading comes back on the page the group continues on
+        assert_eq!(
+            pages(&groups, 24),
+            [
+                "Courses:\n1234 甲\n1235 乙",
+                "Courses:\n1236 丙",
+                "Departments:\nCSU"
+            ]
+        );
+        assert_eq!(pages(&groups, 1).len(), 4);
+        assert!(pages(&[], 2000).is_empty());
+    }
+}
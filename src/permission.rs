@@ -0,0 +1,51 @@
+//! Who may run which commands
+//!
+//! Users are owners, admins or everyone else. Owners are the application's owners on Discord,
+//! `BOT_OWNER_ID` and `BOT_OWNER_IDS`; admins are `BOT_ADMIN_IDS` and members holding one of
+//! `BOT_ADMIN_ROLE_IDS` in the guild a command runs in. Each tier may do what the ones below it
+//! may, and commands ask for a tier with a check rather than testing IDs themselves.
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Tier {
+    User,
+    Admin,
+    Owner,
+}
+
+impl Tier {
+    /// The tier of `user_id` holding `roles` in the current guild, besides the application's
+    /// owners who are always [`Tier::Owner`]
+    pub fn of(config: &Config, user_id: u64, roles: &[u64]) -> Self {
+        if config.owner_id == Some(user_id) || config.owner_ids.0.contains(&user_id) {
+            Self::Owner
+        } else if config.admin_ids.0.contains(&user_id)
+            || roles
+                .iter()
+                .any(|role| config.admin_role_ids.0.contains(role))
+        {
+            Self::Admin
+        } else {
+            Self::User
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tier() {
+        let file = "owner_id = 1\nowner_ids = [2]\nadmin_ids = [3]\nadmin_role_ids = [10]\n\
+            [discord]\ntoken = \"t\"\n[ntnu]\naccount = \"a\"\npassword = \"b\"";
+        let config = Config::from_sources(Some(file), []).unwrap();
+        assert_eq!(Tier::of(&config, 1, &[]), Tier::Owner);
+        assert_eq!(Tier::of(&config, 2, &[]), Tier::Owner);
+        assert_eq!(Tier::of(&config, 3, &[]), Tier::Admin);
+        assert_eq!(Tier::of(&config, 4, &[11, 10]), Tier::Admin);
+        assert_eq!(Tier::of(&config, 4, &[11]), Tier::User);
+        assert!(Tier::Owner > Tier::Admin && Tier::Admin > Tier::User);
+    }
+}
@@ -0,0 +1,73 @@
+//! What the periodic checker is doing, for `/force_update` to follow the check it asked for
+//!
+//! The checker publishes every check it starts and finishes on a watch channel. Requests made
+//! while a check is queued or running are served by the next one to start, so however many
+//! users ask at once, one extra check runs.
+
+/// What a finished check found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleOutcome {
+    /// Courses whose seats were read
+    pub checked: u64,
+    /// Of those, courses with free seats
+    pub available: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckerState {
+    /// Latest check started, 0 before the first
+    pub cycle: u64,
+    pub running: bool,
+    /// What `cycle` found once it is done, `None` when it was skipped or aborted
+    pub outcome: Option<CycleOutcome>,
+}
+
+impl CheckerState {
+    /// The check a request made now is served by
+    pub fn next_cycle(&self) -> u64 {
+        self.cycle + 1
+    }
+
+    /// Whether check `cycle` has started
+    pub fn started(&self, cycle: u64) -> bool {
+        self.cycle >= cycle
+    }
+
+    /// Whether check `cycle` is over
+    pub fn finished(&self, cycle: u64) -> bool {
+        self.cycle > cycle || (self.cycle == cycle && !self.running)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checker_state() {
+        let running = CheckerState {
+            cycle: 3,
+            running: true,
+            outcome: None,
+        };
+        // the running check started before the request, the next one serves it
+        let wanted = running.next_cycle();
+        assert_eq!(wanted, 4);
+        assert!(!running.started(wanted));
+        let started = CheckerState {
+            cycle: 4,
+            ..running
+        };
+        assert!(started.started(wanted) && !started.finished(wanted));
+        let done = CheckerState {
+            cycle: 4,
+            running: false,
+            outcome: Some(CycleOutcome {
+                checked: 10,
+                available: 2,
+            }),
+        };
+        assert!(done.finished(wanted));
+        assert_eq!(done.next_cycle(), 5);
+    }
+}
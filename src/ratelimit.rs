@@ -0,0 +1,175 @@
+//! Keeps the requests to the enrollment system within a budget
+//!
+//! Every crawler shares one token bucket, so `/force_update` running next to the periodic check
+//! or several accounts taking turns cannot add up to a burst. Requests wait their turn in order.
+//! Queries users ask for by command are spaced out on top, so one user cannot spend the budget.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::time::sleep;
+use tracing::trace;
+
+/// Token bucket refilled at `per_minute` tokens a minute, holding at most a minute's worth
+#[derive(Debug)]
+struct Bucket {
+    /// 0 for no budget
+    per_minute: u32,
+    min_gap: Duration,
+    tokens: f64,
+    refilled: Instant,
+    last: Option<Instant>,
+}
+
+impl Bucket {
+    fn new(per_minute: u32, min_gap: Duration, now: Instant) -> Self {
+        Self {
+            per_minute,
+            min_gap,
+            tokens: per_minute as f64,
+            refilled: now,
+            last: None,
+        }
+    }
+
+    /// Take a token at `now`, or tell how long to wait before trying again
+    fn take(&mut self, now: Instant) -> Option<Duration> {
+        let mut wait = self.last.map_or(Duration::ZERO, |last| {
+            (last + self.min_gap).saturating_duration_since(now)
+        });
+        if self.per_minute > 0 {
+            let rate = self.per_minute as f64 / 60.0;
+            let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * rate).min(self.per_minute as f64);
+            self.refilled = now;
+            if self.tokens < 1.0 {
+                wait = wait.max(Duration::from_secs_f64((1.0 - self.tokens) / rate));
+            }
+        }
+        if !wait.is_zero() {
+            return Some(wait);
+        }
+        if self.per_minute > 0 {
+            self.tokens -= 1.0;
+        }
+        self.last = Some(now);
+        None
+    }
+}
+
+/// Requests allowed to the enrollment system, shared by every crawler
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    /// Held while waiting, so requests go in the order they asked
+    queue: tokio::sync::Mutex<()>,
+}
+
+impl RateLimiter {
+    /// At most `per_minute` requests a minute, 0 for any number, at least `min_gap` apart
+    pub fn new(per_minute: u32, min_gap: Duration) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket::new(per_minute, min_gap, Instant::now())),
+            queue: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Take up a reloaded budget, the requests already made still count
+    pub fn configure(&self, per_minute: u32, min_gap: Duration) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.per_minute = per_minute;
+        bucket.min_gap = min_gap;
+        bucket.tokens = bucket.tokens.min(per_minute as f64);
+    }
+
+    /// Wait until another request fits in the budget
+    pub async fn acquire(&self) {
+        let _turn = self.queue.lock().await;
+        loop {
+            let wait = self.bucket.lock().unwrap().take(Instant::now());
+            match wait {
+                Some(wait) => {
+                    trace!("holding a request back for {wait:?}");
+                    sleep(wait).await;
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    /// No limit at all
+    fn default() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+}
+
+/// Spacing of the queries users ask for by command, overall and per user
+#[derive(Debug, Default)]
+pub struct CommandCooldown {
+    /// Unix time of the last admitted request
+    last: Option<u64>,
+    users: HashMap<u64, u64>,
+}
+
+impl CommandCooldown {
+    /// Admit a request of `user_id` at unix time `now`, or tell the seconds until one would be
+    pub fn admit(&mut self, user_id: u64, now: u64, global: u64, per_user: u64) -> Result<(), u64> {
+        let left =
+            |at: Option<u64>, cooldown: u64| at.map_or(0, |at| (at + cooldown).saturating_sub(now));
+        let wait = left(self.last, global).max(left(self.users.get(&user_id).copied(), per_user));
+        if wait > 0 {
+            return Err(wait);
+        }
+        self.last = Some(now);
+        // users whose cooldown is over need not be remembered
+        self.users.retain(|_, at| *at + per_user > now);
+        self.users.insert(user_id, now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(2, Duration::from_secs(1), start);
+        assert_eq!(bucket.take(start), None);
+        // too soon after the last one
+        assert_eq!(bucket.take(start), Some(Duration::from_secs(1)));
+        let second = start + Duration::from_secs(1);
+        assert_eq!(bucket.take(second), None);
+        // the bucket is empty, about half a token came back meanwhile
+        let wait = bucket.take(second + Duration::from_secs(15)).unwrap();
+        assert!(wait > Duration::from_secs(10) && wait < Duration::from_secs(15));
+        assert_eq!(bucket.take(second + Duration::from_secs(30)), None);
+
+        let mut unlimited = Bucket::new(0, Duration::ZERO, start);
+        for _ in 0..100 {
+            assert_eq!(unlimited.take(start), None);
+        }
+    }
+
+    #[test]
+    fn test_command_cooldown() {
+        let mut limiter = CommandCooldown::default();
+        assert_eq!(limiter.admit(1, 1000, 600, 3600), Ok(()));
+        // the global cooldown holds back everyone
+        assert_eq!(limiter.admit(2, 1100, 600, 3600), Err(500));
+        assert_eq!(limiter.admit(2, 1600, 600, 3600), Ok(()));
+        // the user's own lasts longer
+        assert_eq!(limiter.admit(1, 2300, 600, 3600), Err(2300));
+        assert_eq!(limiter.admit(1, 4600, 600, 3600), Ok(()));
+        // no cooldowns configured
+        let mut open = CommandCooldown::default();
+        assert_eq!(open.admit(1, 0, 0, 0), Ok(()));
+        assert_eq!(open.admit(1, 0, 0, 0), Ok(()));
+    }
+}
@@ -0,0 +1,153 @@
+//! Settings that change without a restart, on SIGHUP or `/reload_config`
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::bail;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::{config::Config, crawler::NtnuCrawlerManager};
+
+/// The current settings, swapped as a whole on reload
+///
+/// Readers take a snapshot with [`SharedConfig::get`] so one check or command sees one version.
+pub struct SharedConfig {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Config>>,
+}
+
+impl SharedConfig {
+    pub fn new(config: Config, path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            current: RwLock::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Read the config file again and apply it, the crawler keeps its session
+    ///
+    /// Environment variables of a running process cannot change, they keep overriding the file.
+    /// Returns the settings that changed but only apply on restart, which keep their old values.
+    pub async fn reload(
+        &self,
+        crawler: &Mutex<NtnuCrawlerManager>,
+    ) -> anyhow::Result<Vec<&'static str>> {
+        let Some(path) = &self.path else {
+            bail!("no config file to reload, start the bot with --config");
+        };
+        let (config, ignored) = keep_startup_settings(&self.get(), Config::load(Some(path))?);
+        crawler.lock().await.configure(&config);
+        *self.current.write().unwrap() = Arc::new(config);
+        info!("Reloaded settings from {}", path.display());
+        if !ignored.is_empty() {
+            warn!("{} only change on restart", ignored.join(", "));
+        }
+        Ok(ignored)
+    }
+}
+
+/// Undo changes to settings read once at start, naming the ones that were changed
+fn keep_startup_settings(old: &Config, mut new: Config) -> (Config, Vec<&'static str>) {
+    let mut ignored = Vec::new();
+    macro_rules! keep {
+        ($($field:ident),*) => {
+            $(
+                if new.$field != old.$field {
+                    ignored.push(stringify!($field));
+                    new.$field = old.$field.clone();
+                }
+            )*
+        };
+    }
+    keep!(
+        course_source,
+        ntnu_account,
+        ntnu_password,
+        ntnu_accounts,
+        ntnu_url,
+        ntnu_fallback_urls,
+        http_proxy,
+        ntnu_user_agents,
+        open_course_url,
+        ntu_url,
+        academic_term,
+        captcha_backend,
+        captcha_service_uris,
+        captcha_api_key,
+        discord_token,
+        discord_prefix,
+        discord_mention_prefix,
+        discord_prefix_commands,
+        dev_guild_id,
+        sentry_dsn,
+        storage_url,
+        storage_key,
+        db_path,
+        http_port,
+        public_url,
+        oauth_client_id,
+        oauth_client_secret,
+        grpc_port,
+        grpc_token,
+        leader_lease,
+        health_max_cycle_age,
+        snapshot_dir,
+        snapshot_interval,
+        snapshot_keep
+    );
+    (new, ignored)
+}
+
+/// Reload on every SIGHUP, Windows has no such signal
+pub async fn on_hangup(config: Arc<SharedConfig>, crawler: Arc<Mutex<NtnuCrawlerManager>>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("fail to listen for SIGHUP: {e}");
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("SIGHUP received, reloading settings");
+            if let Err(e) = config.reload(&crawler).await {
+                error!("fail to reload settings: {e:#}");
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (config, crawler);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keep_startup_settings() {
+        let load = |retry: u32, token: &str| {
+            let file = format!(
+                "[ntnu]\naccount = \"a\"\npassword = \"b\"\nretry = {retry}\n\
+                 [discord]\ntoken = \"{token}\""
+            );
+            Config::from_sources(Some(&file), []).unwrap()
+        };
+        let (config, ignored) = keep_startup_settings(&load(10, "old"), load(3, "new"));
+        assert_eq!(config.api_retry, 3);
+        assert_eq!(config.discord_token, "old");
+        assert_eq!(ignored, ["discord_token"]);
+
+        let (_, ignored) = keep_startup_settings(&load(10, "old"), load(10, "old"));
+        assert!(ignored.is_empty());
+    }
+}
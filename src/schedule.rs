@@ -0,0 +1,151 @@
+//! Enrollment phases, outside of which the checker leaves the enrollment system alone
+//!
+//! Seats only move while students can enroll, so polling between phases burns logins for
+//! nothing. Phases are configured as Taiwan time ranges; with none configured the checker runs
+//! all year.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+/// One period in which students can enroll, such as the first round or add/drop
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    pub name: String,
+    /// Unix time it opens
+    pub start: u64,
+    /// Unix time it closes
+    pub end: u64,
+}
+
+/// `{name}={start}/{end}` with times as `2025-01-06T09:00` in Taiwan time, the name optional
+impl FromStr for Phase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, range) = match s.split_once('=') {
+            Some((name, range)) => (name.trim(), range),
+            None => ("", s),
+        };
+        let Some((start, end)) = range.split_once('/') else {
+            bail!("expected `name=start/end`, got `{s}`");
+        };
+        let start = local_time(start.trim())?;
+        let end = local_time(end.trim())?;
+        if end <= start {
+            bail!("phase `{s}` ends before it starts");
+        }
+        let name = if name.is_empty() {
+            "enrollment".to_owned()
+        } else {
+            name.to_owned()
+        };
+        Ok(Self { name, start, end })
+    }
+}
+
+/// Whether students can enroll at `now`, always when no phase is configured
+pub fn in_phase(phases: &[Phase], now: u64) -> bool {
+    phases.is_empty()
+        || phases
+            .iter()
+            .any(|phase| (phase.start..phase.end).contains(&now))
+}
+
+/// The phase opening next after `now`
+pub fn next_phase(phases: &[Phase], now: u64) -> Option<&Phase> {
+    phases
+        .iter()
+        .filter(|phase| phase.start > now)
+        .min_by_key(|phase| phase.start)
+}
+
+/// Unix time of `2025-01-06T09:00` in Taiwan time
+fn local_time(text: &str) -> anyhow::Result<u64> {
+    let parse = || -> Option<u64> {
+        let (date, time) = text.split_once('T')?;
+        let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+        let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+        let (hour, minute) = time.split_once(':')?;
+        let (hour, minute) = (hour.parse::<u64>().ok()?, minute.parse::<u64>().ok()?);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+            return None;
+        }
+        let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+        (days * 86400 + hour * 3600 + minute * 60).checked_sub(8 * 3600)
+    };
+    parse().with_context(|| format!("expected a time such as `2025-01-06T09:00`, got `{text}`"))
+}
+
+/// Days from 1970-01-01 to a Gregorian date, after Howard Hinnant's algorithm
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Gregorian year and month of a day counted from 1970-01-01, the inverse of
+/// [`days_from_civil`]
+pub fn civil_from_days(days: i64) -> (u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year as u32, month as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_phase() {
+        let phase: Phase = "初選=2025-01-06T09:00/2025-01-10T17:00".parse().unwrap();
+        assert_eq!(
+            phase,
+            Phase {
+                name: "初選".to_owned(),
+                // 2025-01-06 01:00 UTC
+                start: 1736125200,
+                end: 1736499600,
+            }
+        );
+        let unnamed: Phase = "2025-02-17T09:00/2025-02-28T17:00".parse().unwrap();
+        assert_eq!(unnamed.name, "enrollment");
+        assert!("2025-01-10T17:00/2025-01-06T09:00"
+            .parse::<Phase>()
+            .is_err());
+        assert!("2025-13-01T09:00/2025-13-02T09:00"
+            .parse::<Phase>()
+            .is_err());
+        assert!("tomorrow".parse::<Phase>().is_err());
+
+        let phases = [phase, unnamed];
+        assert!(in_phase(&[], 0));
+        assert!(in_phase(&phases, 1736125200));
+        assert!(!in_phase(&phases, 1736499600));
+        assert_eq!(
+            next_phase(&phases, 1736499600).map(|p| p.name.as_str()),
+            Some("enrollment")
+        );
+        assert_eq!(next_phase(&phases, u64::MAX), None);
+    }
+
+    #[test]
+    fn test_civil() {
+        assert_eq!(civil_from_days(0), (1970, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2));
+        for days in [0, 11016, 20089, 20454] {
+            let (year, month) = civil_from_days(days);
+            let first = days_from_civil(year.into(), month.into(), 1);
+            assert!(first <= days && days - first < 31);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! Shutdown requests from the OS, on every platform the bot runs on
+
+use tokio::signal;
+use tracing::error;
+
+/// Resolves on the first request to stop
+///
+/// Ctrl-C counts everywhere, plus SIGTERM on Unix and closing the console or logging off on
+/// Windows. A signal that cannot be listened to is logged and never fires.
+pub async fn requested() {
+    let interrupt = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("fail to listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        _ = interrupt => (),
+        _ = terminate() => (),
+    }
+}
+
+#[cfg(unix)]
+async fn terminate() {
+    use signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        Err(e) => {
+            error!("fail to listen for SIGTERM: {e}");
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn terminate() {
+    use signal::windows::{ctrl_close, ctrl_shutdown};
+
+    match (ctrl_close(), ctrl_shutdown()) {
+        (Ok(mut close), Ok(mut shutdown)) => {
+            tokio::select! {
+                _ = close.recv() => (),
+                _ = shutdown.recv() => (),
+            }
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            error!("fail to listen for console close: {e}");
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+async fn terminate() {
+    std::future::pending::<()>().await;
+}
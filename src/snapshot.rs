@@ -0,0 +1,82 @@
+use std::{path::PathBuf, time::Duration};
+
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::db::{now, Db};
+
+const PREFIX: &str = "course-bot-";
+const SUFFIX: &str = ".sqlite";
+
+/// Snapshot the database into `dir` every `interval`, keeping the newest `keep` files
+pub async fn run(db: Db, dir: PathBuf, interval: Duration, keep: usize) {
+    loop {
+        if let Err(e) = take(&db, &dir, keep).await {
+            error!("fail to snapshot database into {}: {e:?}", dir.display());
+        }
+        sleep(interval).await;
+    }
+}
+
+async fn take(db: &Db, dir: &PathBuf, keep: usize) -> anyhow::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("{PREFIX}{}{SUFFIX}", now()));
+    db.snapshot(&path).await?;
+    info!("Saved database snapshot {}", path.display());
+
+    let mut names = Vec::new();
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if let Some(name) = entry.file_name().to_str() {
+            names.push(name.to_owned());
+        }
+    }
+    for name in expired(names, keep) {
+        if let Err(e) = tokio::fs::remove_file(dir.join(&name)).await {
+            warn!("fail to delete old snapshot {name}: {e}");
+        }
+    }
+    Ok(())
+}
+
+/// Snapshot files beyond the newest `keep`, other files in the directory are left alone
+fn expired(names: Vec<String>, keep: usize) -> Vec<String> {
+    let mut snapshots = names
+        .into_iter()
+        .filter_map(|name| {
+            let timestamp = name.strip_prefix(PREFIX)?.strip_suffix(SUFFIX)?;
+            Some((timestamp.parse::<u64>().ok()?, name))
+        })
+        .collect::<Vec<_>>();
+    snapshots.sort();
+    let overflow = snapshots.len().saturating_sub(keep);
+    snapshots
+        .into_iter()
+        .take(overflow)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expired() {
+        let names = [
+            "course-bot-1700000300.sqlite",
+            "notes.txt",
+            "course-bot-999.sqlite",
+            "course-bot-1700000100.sqlite",
+            "course-bot-latest.sqlite",
+        ]
+        .map(str::to_owned)
+        .to_vec();
+        assert_eq!(
+            expired(names.clone(), 2),
+            vec!["course-bot-999.sqlite".to_owned()]
+        );
+        assert_eq!(expired(names.clone(), 0).len(), 3);
+        assert!(expired(names, 5).is_empty());
+    }
+}
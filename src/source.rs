@@ -0,0 +1,261 @@
+//! Enrollment systems courses can be watched in
+//!
+//! Every school's system is a [`CourseSource`]. A course ID may name its source as
+//! `{source}:{id}`, such as `ntnu:1234`; bare IDs belong to the deployment's default source,
+//! `BOT_COURSE_SOURCE`, so existing watches keep working.
+//!
+//! Serial numbers are reassigned every semester, so a course of another semester than the one
+//! being enrolled, such as the next one during pre-registration, is `{id}@{semester}`, as in
+//! `ntu:12345@114-1`. IDs without a semester belong to the one being enrolled.
+
+use std::{ops::RangeInclusive, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::{
+    config::AcademicTerm,
+    crawler::{validate_serial_no, CourseInfo, CourseStatus},
+};
+
+/// Names of the sources this build knows
+pub const SOURCES: [&str; 2] = ["ntnu", "ntu"];
+
+/// A school's enrollment system
+#[async_trait]
+pub trait CourseSource: Send {
+    /// Get ready to query, such as by logging in; queries also do so whenever they need to
+    async fn init(&mut self) -> Result<()>;
+
+    /// Seats of a course in `term`, or the term being enrolled when `None`, and whether the
+    /// system lists it at all
+    async fn query(&mut self, course_id: &str, term: Option<(u32, u32)>) -> Result<CourseStatus>;
+
+    /// Name, teacher and seats of a course in `term`, `None` when the system does not list it
+    async fn fetch_info(
+        &mut self,
+        course_id: &str,
+        term: Option<(u32, u32)>,
+    ) -> Result<Option<CourseInfo>>;
+}
+
+/// A source shared by the checker and the commands, which take turns
+pub type SharedSource = Arc<Mutex<dyn CourseSource>>;
+
+/// Every source the deployment uses, by name
+pub struct Sources {
+    default: String,
+    sources: Vec<(&'static str, SharedSource)>,
+}
+
+impl Sources {
+    /// No source yet, bare course IDs going to `default`
+    pub fn new(default: &str) -> Self {
+        Self {
+            default: default.to_owned(),
+            sources: Vec::new(),
+        }
+    }
+
+    pub fn with(mut self, name: &'static str, source: SharedSource) -> Self {
+        self.sources.push((name, source));
+        self
+    }
+
+    /// How `course_id` is stored, bare when it is in the default source and `current`, the
+    /// semester being enrolled
+    pub fn canonical(&self, course_id: &str, current: (u32, u32)) -> String {
+        let (course_id, term) = split_semester(course_id);
+        let course_id = match split_course_id(course_id) {
+            (Some(name), id) if name != self.default => format!("{name}:{id}"),
+            (_, id) => id.to_owned(),
+        };
+        match term {
+            Some((year, term)) if (year, term) != current => format!("{course_id}@{year}-{term}"),
+            _ => course_id,
+        }
+    }
+
+    /// How `course_id` is stored, `None` when its school is not set up or, for NTNU, its serial
+    /// number is not one of `serial_no_range`
+    pub fn checked(
+        &self,
+        course_id: &str,
+        current: (u32, u32),
+        serial_no_range: &RangeInclusive<u32>,
+    ) -> Option<String> {
+        let (name, _, id, _) = self.route(course_id)?;
+        if name == "ntnu" && validate_serial_no(id, serial_no_range).is_err() {
+            return None;
+        }
+        Some(self.canonical(course_id, current))
+    }
+
+    /// The source of `course_id`, the ID within it and its semester when it names one, `None`
+    /// when the source is not set up
+    pub fn route<'a>(&self, course_id: &'a str) -> Option<Route<'_, 'a>> {
+        let (course_id, term) = split_semester(course_id);
+        let (name, id) = split_course_id(course_id);
+        let name = name.unwrap_or(&self.default);
+        self.sources
+            .iter()
+            .find(|(source, _)| *source == name)
+            .map(|(name, source)| (*name, source, id, term))
+    }
+}
+
+/// Source of a course, the ID within it and the semester it names
+pub type Route<'s, 'a> = (&'static str, &'s SharedSource, &'a str, Option<(u32, u32)>);
+
+/// `course_id` without its semester, and the semester, `None` when it names none
+pub fn split_semester(course_id: &str) -> (&str, Option<(u32, u32)>) {
+    let semester = course_id
+        .rsplit_once('@')
+        .and_then(|(id, semester)| Some((id, semester.parse::<AcademicTerm>().ok()?.0?)));
+    match semester {
+        Some((id, semester)) => (id, Some(semester)),
+        None => (course_id, None),
+    }
+}
+
+/// Whether `course_id` is watched for `semester`, as `113-2`, bare IDs being the ones of the
+/// semester being enrolled
+#[cfg(any(test, feature = "redis"))]
+pub fn in_semester(course_id: &str, semester: &str) -> bool {
+    match course_id.rsplit_once('@') {
+        Some((_, named)) => named == semester,
+        None => true,
+    }
+}
+
+/// `course_id` without its `@{semester}` suffix, `None` when it names another semester or none
+#[cfg(any(test, feature = "redis"))]
+pub fn strip_semester<'a>(course_id: &'a str, semester: &str) -> Option<&'a str> {
+    course_id.strip_suffix(semester)?.strip_suffix('@')
+}
+
+/// Source named by `course_id`, `None` for a bare ID, and the ID within it
+pub fn split_course_id(course_id: &str) -> (Option<&str>, &str) {
+    match course_id.split_once(':') {
+        Some((source, id)) => (Some(source), id),
+        None => (None, course_id),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        crawler::SeatCount,
+        export::{ImportReport, SkipReason, WatchlistExport},
+    };
+
+    struct Fixed;
+
+    #[async_trait]
+    impl CourseSource for Fixed {
+        async fn init(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn query(
+            &mut self,
+            _course_id: &str,
+            _term: Option<(u32, u32)>,
+        ) -> Result<CourseStatus> {
+            Ok(CourseStatus::Available(SeatCount {
+                enrolled: 1,
+                quota: 2,
+            }))
+        }
+
+        async fn fetch_info(
+            &mut self,
+            _course_id: &str,
+            _term: Option<(u32, u32)>,
+        ) -> Result<Option<CourseInfo>> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route() -> Result<()> {
+        assert_eq!(split_course_id("1234"), (None, "1234"));
+        assert_eq!(split_course_id("ntu:CSIE1212"), (Some("ntu"), "CSIE1212"));
+
+        let sources = Sources::new("ntnu").with("ntnu", Arc::new(Mutex::new(Fixed)));
+        let (name, source, id, term) = sources.route("ntnu:0042").unwrap();
+        assert_eq!((name, id, term), ("ntnu", "0042", None));
+        assert!(source.lock().await.query(id, term).await?.seats().is_some());
+        assert_eq!(
+            sources
+                .route("0042@114-1")
+                .map(|(name, _, id, term)| (name, id, term)),
+            Some(("ntnu", "0042", Some((114, 1))))
+        );
+        assert!(sources.route("ntu:CSIE1212").is_none());
+        let current = (113, 2);
+        assert_eq!(sources.canonical("ntnu:0042", current), "0042");
+        assert_eq!(sources.canonical("ntu:CSIE1212", current), "ntu:CSIE1212");
+        assert_eq!(sources.canonical("ntnu:0042@113-2", current), "0042");
+        assert_eq!(sources.canonical("0042@114-1", current), "0042@114-1");
+
+        assert_eq!(
+            split_semester("ntu:12345@114-1"),
+            ("ntu:12345", Some((114, 1)))
+        );
+        // not a semester, left to the ID checks
+        assert_eq!(split_semester("0042@next"), ("0042@next", None));
+        assert!(in_semester("0042", "113-2") && in_semester("0042@113-2", "113-2"));
+        assert!(!in_semester("0042@114-1", "113-2"));
+        assert_eq!(
+            strip_semester("ntu:12345@114-1", "114-1"),
+            Some("ntu:12345")
+        );
+        assert_eq!(strip_semester("0042@114-1", "113-2"), None);
+        assert_eq!(strip_semester("0042", "113-2"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_ids() -> Result<(), serde_json::Error> {
+        let sources = Sources::new("ntnu")
+            .with("ntnu", Arc::new(Mutex::new(Fixed)))
+            .with("ntu", Arc::new(Mutex::new(Fixed)));
+        let range = 1..=9000;
+        let canonical = |id: &str| sources.checked(id, (113, 2), &range);
+        let import: WatchlistExport = serde_json::from_str(
+            r#"{
+                "version": 1,
+                "exported_at": 1700000000,
+                "courses": [
+                    {"course_id": "ntu:CSIE1212"},
+                    {"course_id": "ntnu:0042@114-1"},
+                    {"course_id": "0100@113-2"},
+                    {"course_id": "0042@114-1"},
+                    {"course_id": "ntnu:9999"},
+                    {"course_id": "nthu:1234"}
+                ]
+            }"#,
+        )?;
+        let mut courses = Vec::new();
+        let report = import.merge_into(&mut courses, &mut Vec::new(), &canonical, 10, 5);
+        assert_eq!(
+            report,
+            ImportReport {
+                added: vec![
+                    "ntu:CSIE1212".to_owned(),
+                    "0042@114-1".to_owned(),
+                    "0100".to_owned()
+                ],
+                skipped: vec![
+                    ("0042@114-1".to_owned(), SkipReason::Duplicate),
+                    ("ntnu:9999".to_owned(), SkipReason::Invalid),
+                    ("nthu:1234".to_owned(), SkipReason::Invalid),
+                ],
+            }
+        );
+        Ok(())
+    }
+}
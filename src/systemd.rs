@@ -0,0 +1,67 @@
+//! Service notifications for systemd, which do nothing unless it started the bot
+//!
+//! A unit with `Type=notify` waits for the first check before counting the bot as started, and
+//! `WatchdogSec=` restarts it once the checker stops pinging. Keep `WatchdogSec=` above
+//! `BOT_CYCLE_DEADLINE` so the bot gets to abort a hung check itself first, and
+//! `TimeoutStartSec=` above the time the first check takes.
+
+use std::time::Duration;
+
+#[cfg(unix)]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        tracing::warn!("fail to notify systemd: {e}");
+    }
+}
+
+/// Tell systemd the bot is up, with `status` shown by `systemctl status`
+pub fn ready(status: &str) {
+    #[cfg(unix)]
+    notify(&[
+        sd_notify::NotifyState::Ready,
+        sd_notify::NotifyState::Status(status),
+    ]);
+    #[cfg(not(unix))]
+    let _ = status;
+}
+
+/// Update the line shown by `systemctl status`
+pub fn status(status: &str) {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Status(status)]);
+    #[cfg(not(unix))]
+    let _ = status;
+}
+
+/// Reset the watchdog timer, proof the checker still makes progress
+pub fn watchdog() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Watchdog]);
+}
+
+pub fn stopping() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+/// Half the watchdog timeout, `None` when systemd does not watch the bot
+pub fn watchdog_interval() -> Option<Duration> {
+    #[cfg(unix)]
+    {
+        let mut usec = 0;
+        sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec / 2))
+    }
+    #[cfg(not(unix))]
+    None
+}
+
+/// Ping the watchdog forever, for while the checker waits for the next check
+pub async fn feed_watchdog() {
+    let Some(interval) = watchdog_interval() else {
+        return std::future::pending().await;
+    };
+    loop {
+        tokio::time::sleep(interval).await;
+        watchdog();
+    }
+}